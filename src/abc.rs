@@ -0,0 +1,1044 @@
+use std::{collections::HashMap, fmt::Write, fs, iter::Peekable, str::Chars};
+
+use crate::{
+    midi_file::{Song, SongNote, SongTrack},
+    music_entities::{Interval, Key, Letter, Mode, Note, Pitch, SpelledNote},
+    notation::{Clef, MeasureAccidentals, NotationEvent, Score, DIVISIONS},
+    recorder::DEFAULT_VELOCITY,
+};
+
+/// Ticks of the eighth note exported tunes count their lengths in.
+const UNIT_TICKS: u32 = DIVISIONS / 2;
+const MEASURES_PER_LINE: usize = 4;
+/// Tunes without a Q: field are played at 120 quarter notes a minute.
+const DEFAULT_SECONDS_PER_WHOLE: f64 = 2.0;
+
+/// Writes `score` as an ABC tune with a voice for each staff.
+pub fn export_abc(score: &Score, path: &str) -> Result<(), String> {
+    fs::write(path, abc(score)).map_err(|err| format!("couldn't write {}: {}", path, err))
+}
+
+fn abc(score: &Score) -> String {
+    let time_signature = score.time_signature;
+    let mut text = String::new();
+    text.push_str("X:1\n");
+    text.push_str("T:Performance\n");
+    let _ = writeln!(
+        text,
+        "M:{}/{}",
+        time_signature.beats_per_bar, time_signature.beat_value
+    );
+    text.push_str("L:1/8\n");
+    let _ = writeln!(text, "Q:1/{}={:.0}", time_signature.beat_value, score.bpm);
+    text.push_str("%%score {1 | 2}\n");
+    let mode = match score.key.mode {
+        Mode::Major => "",
+        Mode::Minor => "m",
+    };
+    let _ = writeln!(text, "K:{}{}", score.key.tonic, mode);
+    for (index, staff) in score.staves.iter().enumerate() {
+        let clef = match staff.clef {
+            Clef::Treble => "treble",
+            Clef::Bass => "bass",
+        };
+        let _ = writeln!(text, "V:{} clef={}", index + 1, clef);
+        for (number, measure) in staff.measures.iter().enumerate() {
+            let mut accidentals = MeasureAccidentals::new(score.key);
+            let events = &measure.events;
+            for (event_index, event) in events.iter().enumerate() {
+                // A run of triplets is written as a single tuplet.
+                let starts_run = event_index == 0 || !events[event_index - 1].value.triplet;
+                if event.value.triplet && starts_run {
+                    let run = events[event_index..]
+                        .iter()
+                        .take_while(|event| event.value.triplet)
+                        .count();
+                    let _ = write!(text, "(3:2:{}", run);
+                }
+                write_event(&mut text, score, event, &mut accidentals);
+                text.push(' ');
+            }
+            match number + 1 == staff.measures.len() {
+                true => text.push_str("|]\n"),
+                false if (number + 1) % MEASURES_PER_LINE == 0 => text.push_str("|\n"),
+                false => text.push_str("| "),
+            }
+        }
+    }
+    text
+}
+
+fn write_event(
+    text: &mut String,
+    score: &Score,
+    event: &NotationEvent,
+    accidentals: &mut MeasureAccidentals,
+) {
+    if event.is_rest() {
+        match event.value.ticks() == score.measure_ticks() {
+            true => text.push('Z'),
+            false => {
+                text.push('z');
+                text.push_str(&length(event));
+            }
+        }
+        return;
+    }
+    let names: Vec<String> = event
+        .pitches
+        .iter()
+        .map(|pitch| {
+            let (spelled, octave) = score.key.spell_pitch(*pitch);
+            let accidental = accidentals.show(spelled, octave, event.tie_stop);
+            pitch_name(spelled, octave, accidental)
+        })
+        .collect();
+    match names.as_slice() {
+        [name] => text.push_str(name),
+        names => {
+            let _ = write!(text, "[{}]", names.concat());
+        }
+    }
+    text.push_str(&length(event));
+    if event.tie_start {
+        text.push('-');
+    }
+}
+
+/// The note with the accidental to show, in upper case from middle C up an
+/// octave and in lower case the octave above, marked further up with ' and
+/// further down with ,.
+fn pitch_name(spelled: SpelledNote, octave: i8, accidental: Option<i8>) -> String {
+    let accidental = match accidental {
+        Some(2) => "^^",
+        Some(1) => "^",
+        Some(0) => "=",
+        Some(-1) => "_",
+        Some(_) => "__",
+        None => "",
+    };
+    let letter = format!("{:?}", spelled.letter);
+    match octave >= 5 {
+        true => format!(
+            "{}{}{}",
+            accidental,
+            letter.to_lowercase(),
+            "'".repeat((octave - 5) as usize)
+        ),
+        false => format!(
+            "{}{}{}",
+            accidental,
+            letter,
+            ",".repeat((4 - octave).max(0) as usize)
+        ),
+    }
+}
+
+/// The length in eighth notes, counted before the tuplet shortens it.
+fn length(event: &NotationEvent) -> String {
+    let mut ticks = event.value.ticks();
+    if event.value.triplet {
+        ticks = ticks * 3 / 2;
+    }
+    let divisor = gcd(ticks, UNIT_TICKS);
+    match (ticks / divisor, UNIT_TICKS / divisor) {
+        (1, 1) => String::new(),
+        (numerator, 1) => numerator.to_string(),
+        (1, denominator) => format!("/{}", denominator),
+        (numerator, denominator) => format!("{}/{}", numerator, denominator),
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    match b {
+        0 => a,
+        _ => gcd(b, a % b),
+    }
+}
+
+/// Reads the first tune in the ABC file at `path`, with a track for each
+/// voice. Repeats and first and second endings are played out, while
+/// decorations, grace notes and chord symbols are left out.
+pub fn import_tune(path: &str) -> Result<Song, String> {
+    let text =
+        fs::read_to_string(path).map_err(|err| format!("couldn't read {}: {}", path, err))?;
+    let song = read_tune(&text);
+    match song.tracks.is_empty() {
+        true => Err(format!("couldn't find any notes in {}", path)),
+        false => Ok(song),
+    }
+}
+
+fn read_tune(text: &str) -> Song {
+    let mut reader = TuneReader::new();
+    let mut lines = text.lines();
+    // The tune starts at its X: field, if the file has one, and ends at the
+    // next tune or the first empty line of its music.
+    if text.lines().any(|line| line.starts_with("X:")) {
+        lines.by_ref().find(|line| line.starts_with("X:"));
+    }
+    for line in lines {
+        if line.starts_with("X:") || (reader.in_body && line.trim().is_empty()) {
+            break;
+        }
+        let line = match line.find('%') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        match field(line.trim()) {
+            Some((name, value)) => reader.read_field(name, value),
+            None if reader.in_body => reader.read_music(line),
+            None => {}
+        }
+    }
+    reader.into_song()
+}
+
+/// The name and value of a field line such as "K:G".
+fn field(line: &str) -> Option<(char, &str)> {
+    let mut chars = line.chars();
+    let name = chars.next().filter(|name| name.is_ascii_alphabetic())?;
+    let value = chars.as_str().strip_prefix(':')?;
+    Some((name, value.trim()))
+}
+
+/// Seconds per whole note at the tempo of a Q: field, e.g. "1/4=120" or
+/// "\"Allegro\" 3/8=60". A tempo without a beat counts `unit`s.
+fn tempo(value: &str, unit: f64) -> Option<f64> {
+    let value: String = value.split('"').step_by(2).collect();
+    let (beats, per_minute) = match value.split_once('=') {
+        Some((beats, per_minute)) => (
+            beats.split_whitespace().filter_map(fraction).sum::<f64>(),
+            per_minute,
+        ),
+        None => (unit, value.as_str()),
+    };
+    let per_minute: f64 = per_minute.trim().parse().ok()?;
+    (beats > 0.0 && per_minute > 0.0).then(|| 60.0 / (beats * per_minute))
+}
+
+fn fraction(value: &str) -> Option<f64> {
+    let (numerator, denominator) = value.trim().split_once('/')?;
+    let numerator: f64 = numerator.parse().ok()?;
+    let denominator: f64 = denominator.parse().ok()?;
+    (denominator > 0.0).then(|| numerator / denominator)
+}
+
+/// Whole notes in a bar of an M: field, e.g. "6/8", "C" or "2+3/8".
+fn meter(value: &str) -> Option<f64> {
+    match value.split_whitespace().next()? {
+        "C" => Some(1.0),
+        "C|" => Some(1.0),
+        meter => {
+            let (beats, beat_value) = meter.split_once('/')?;
+            let beats: f64 = beats
+                .split('+')
+                .map(|beats| beats.parse::<f64>().ok())
+                .sum::<Option<f64>>()?;
+            fraction(&format!("{}/{}", beats, beat_value))
+        }
+    }
+}
+
+/// Sharps in the signature of a K: field, negative for flats, e.g. "G",
+/// "F#m", "Bb" or "D mix". Modes other than major and minor are read by
+/// their first three letters.
+fn signature(value: &str) -> i8 {
+    let mut chars = value.chars();
+    let letter = match chars.next() {
+        Some(letter) => letter,
+        None => return 0,
+    };
+    let rest = chars.as_str();
+    let accidentals = rest.len() - rest.trim_start_matches(['#', 'b']).len();
+    // Keys without a tonic, such as "none", have no signature.
+    let tonic = match format!("{}{}", letter, &rest[..accidentals]).parse::<SpelledNote>() {
+        Ok(tonic) => tonic,
+        Err(_) => return 0,
+    };
+    let mode = rest[accidentals..]
+        .split_whitespace()
+        .next()
+        .filter(|mode| !mode.contains('='))
+        .unwrap_or("")
+        .to_lowercase();
+    let offset = match mode.get(..3).unwrap_or(mode.as_str()) {
+        "m" | "min" | "aeo" => -3,
+        "mix" => -1,
+        "dor" => -2,
+        "phr" => -4,
+        "lyd" => 1,
+        "loc" => -5,
+        _ => 0,
+    };
+    Key::new(tonic, Mode::Major).fifths() + offset
+}
+
+/// Where the notes of a voice have got to.
+struct Voice {
+    name: String,
+    notes: Vec<SongNote>,
+    /// Seconds from the start of the tune.
+    time: f64,
+    /// Accidentals written so far in the bar, by letter and octave.
+    accidentals: HashMap<(Letter, i8), i8>,
+    /// Notes of the last chord, note or rest and its length in seconds.
+    last: (Vec<usize>, f64),
+    /// The last notes were tied to the next ones.
+    tied: bool,
+    /// Length multiplier of a tuplet and the notes it has left.
+    tuplet: Option<(f64, u32)>,
+    /// Length multiplier the next note gets from a broken rhythm.
+    broken: f64,
+    /// Note index and time of the start of the repeated section and of its
+    /// first ending.
+    repeat_start: (usize, f64),
+    first_ending: Option<(usize, f64)>,
+}
+
+impl Voice {
+    fn new(name: &str, time: f64) -> Self {
+        Voice {
+            name: name.to_string(),
+            notes: Vec::new(),
+            time,
+            accidentals: HashMap::new(),
+            last: (Vec::new(), 0.0),
+            tied: false,
+            tuplet: None,
+            broken: 1.0,
+            repeat_start: (0, time),
+            first_ending: None,
+        }
+    }
+
+    /// Adds a chord, note or rest of `length` seconds, continuing the last
+    /// notes where they were tied.
+    fn play(&mut self, pitches: &[Pitch], mut length: f64) {
+        if let Some((multiplier, notes_left)) = self.tuplet.as_mut() {
+            length *= *multiplier;
+            *notes_left -= 1;
+            if *notes_left == 0 {
+                self.tuplet = None;
+            }
+        }
+        length *= self.broken;
+        self.broken = 1.0;
+        let tied = match self.tied {
+            true => self.last.0.clone(),
+            false => Vec::new(),
+        };
+        let mut played = Vec::new();
+        for pitch in pitches {
+            let end = self.time + length;
+            match tied
+                .iter()
+                .find(|index| self.notes[**index].pitch == *pitch)
+            {
+                Some(index) => {
+                    self.notes[*index].end = end;
+                    played.push(*index);
+                }
+                None => {
+                    self.notes.push(SongNote {
+                        start: self.time,
+                        end,
+                        pitch: *pitch,
+                        velocity: DEFAULT_VELOCITY,
+                    });
+                    played.push(self.notes.len() - 1);
+                }
+            }
+        }
+        self.time += length;
+        self.last = (played, length);
+        self.tied = false;
+    }
+
+    /// Lengthens the last notes by `multiplier` and shortens the next by
+    /// what they gained, e.g. 1.5 for "a>b".
+    fn break_rhythm(&mut self, multiplier: f64) {
+        let (notes, length) = &self.last;
+        let extra = length * (multiplier - 1.0);
+        for index in notes.iter() {
+            self.notes[*index].end += extra;
+        }
+        self.time += extra;
+        self.broken = 2.0 - multiplier;
+    }
+
+    /// Marks the start of a repeated section, which any first ending
+    /// before it isn't part of.
+    fn start_repeat(&mut self) {
+        self.repeat_start = (self.notes.len(), self.time);
+        self.first_ending = None;
+    }
+
+    /// Plays the section since the last repeat start again, leaving out
+    /// its first ending.
+    fn repeat(&mut self) {
+        let (start_index, start_time) = self.repeat_start;
+        let (end_index, end_time) = self
+            .first_ending
+            .take()
+            .filter(|(index, _)| *index >= start_index)
+            .unwrap_or((self.notes.len(), self.time));
+        let offset = self.time - start_time;
+        let repeated: Vec<SongNote> = self.notes[start_index..end_index]
+            .iter()
+            .map(|note| SongNote {
+                start: note.start + offset,
+                end: note.end + offset,
+                ..*note
+            })
+            .collect();
+        self.notes.extend(repeated);
+        self.time += end_time - start_time;
+        self.last = (Vec::new(), 0.0);
+        self.start_repeat();
+    }
+}
+
+/// Reads the fields and music lines of a tune into voices.
+struct TuneReader {
+    title: String,
+    /// Length of a note without a length, in whole notes.
+    unit: Option<f64>,
+    /// Whole notes in a bar.
+    bar: f64,
+    seconds_per_whole: f64,
+    /// Tempo given before the unit length was known.
+    tempo: Option<String>,
+    signature: i8,
+    /// The header ends with the first K: field.
+    in_body: bool,
+    voices: Vec<Voice>,
+    voice: usize,
+}
+
+impl TuneReader {
+    fn new() -> Self {
+        TuneReader {
+            title: String::from("ABC tune"),
+            unit: None,
+            bar: 1.0,
+            seconds_per_whole: DEFAULT_SECONDS_PER_WHOLE,
+            tempo: None,
+            signature: 0,
+            in_body: false,
+            voices: Vec::new(),
+            voice: 0,
+        }
+    }
+
+    /// The unit length, which defaults to an eighth, or a sixteenth in
+    /// meters shorter than 3/4.
+    fn unit(&self) -> f64 {
+        self.unit.unwrap_or(match self.bar < 0.75 {
+            true => 1.0 / 16.0,
+            false => 1.0 / 8.0,
+        })
+    }
+
+    fn read_field(&mut self, name: char, value: &str) {
+        match name {
+            'T' if self.voices.is_empty() => self.title = value.to_string(),
+            'L' => self.unit = fraction(value).or(self.unit),
+            'M' => self.bar = meter(value).unwrap_or(self.bar),
+            'Q' => self.tempo = Some(value.to_string()),
+            'K' => {
+                self.signature = signature(value);
+                self.in_body = true;
+            }
+            'V' => {
+                let name = value.split_whitespace().next().unwrap_or("1");
+                self.voice = match self.voices.iter().position(|voice| voice.name == name) {
+                    Some(voice) => voice,
+                    None => {
+                        self.voices.push(Voice::new(name, 0.0));
+                        self.voices.len() - 1
+                    }
+                };
+            }
+            _ => {}
+        }
+        // The tempo may count unit lengths, so in the header it is worked out
+        // once the unit is known.
+        if self.in_body {
+            if let Some(tempo) = self
+                .tempo
+                .take()
+                .and_then(|value| tempo(&value, self.unit()))
+            {
+                self.seconds_per_whole = tempo;
+            }
+        }
+    }
+
+    fn voice(&mut self) -> &mut Voice {
+        if self.voices.is_empty() {
+            self.voices.push(Voice::new(&self.title, 0.0));
+        }
+        &mut self.voices[self.voice]
+    }
+
+    fn read_music(&mut self, line: &str) {
+        let mut chars = line.chars().peekable();
+        while let Some(symbol) = chars.next() {
+            match symbol {
+                '"' => skip_past(&mut chars, '"'),
+                '!' => skip_past(&mut chars, '!'),
+                '+' => skip_past(&mut chars, '+'),
+                '{' => skip_past(&mut chars, '}'),
+                '[' => match chars.peek().copied() {
+                    Some(next) if next.is_ascii_digit() => {
+                        self.read_ending(&mut chars);
+                    }
+                    Some('|') => {
+                        chars.next();
+                        self.read_bar_line(&mut chars, "[|");
+                    }
+                    Some(next) if next.is_ascii_alphabetic() && is_inline_field(&chars) => {
+                        let inline: String = chars.by_ref().take_while(|c| *c != ']').collect();
+                        if let Some((name, value)) = field(&inline) {
+                            self.read_field(name, value);
+                        }
+                    }
+                    _ => self.read_chord(&mut chars),
+                },
+                '|' | ':' => self.read_bar_line(&mut chars, &symbol.to_string()),
+                '(' => self.read_tuplet(&mut chars),
+                '-' => self.voice().tied = true,
+                '>' | '<' => {
+                    let mut count = 1;
+                    while chars.next_if_eq(&symbol).is_some() {
+                        count += 1;
+                    }
+                    let shortened = 0.5f64.powi(count);
+                    let multiplier = match symbol {
+                        '>' => 2.0 - shortened,
+                        _ => shortened,
+                    };
+                    self.voice().break_rhythm(multiplier);
+                }
+                'z' | 'x' => {
+                    if let Some(length) = self.note_length(&mut chars) {
+                        self.voice().play(&[], length);
+                    }
+                }
+                'Z' | 'X' => {
+                    let bars = read_number(&mut chars).unwrap_or(1) as f64;
+                    let length = bars * self.bar * self.seconds_per_whole;
+                    self.voice().play(&[], length);
+                }
+                '^' | '_' | '=' | 'A'..='G' | 'a'..='g' => {
+                    let pitch = self.read_pitch(symbol, &mut chars);
+                    let length = self.note_length(&mut chars);
+                    if let (Some(pitch), Some(length)) = (pitch, length) {
+                        self.voice().play(&[pitch], length);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads the accidentals, letter and octave marks of a note starting
+    /// with `symbol`, applying the key signature and earlier accidentals in
+    /// the bar.
+    fn read_pitch(&mut self, mut symbol: char, chars: &mut Peekable<Chars>) -> Option<Pitch> {
+        let mut accidental = None;
+        loop {
+            let change = match symbol {
+                '^' => 1,
+                '_' => -1,
+                '=' => 0,
+                _ => break,
+            };
+            accidental = Some(accidental.unwrap_or(0) + change);
+            symbol = chars.next()?;
+        }
+        let letter = symbol.to_string().parse::<SpelledNote>().ok()?.letter;
+        let mut octave = match symbol.is_ascii_lowercase() {
+            true => 5,
+            false => 4,
+        };
+        while let Some(mark) = chars.next_if(|mark| *mark == '\'' || *mark == ',') {
+            octave += if mark == '\'' { 1 } else { -1 };
+        }
+        let signature = self.signature;
+        let voice = self.voice();
+        let key = (letter, octave);
+        let accidental = match accidental {
+            Some(accidental) => {
+                voice.accidentals.insert(key, accidental);
+                accidental
+            }
+            None => voice
+                .accidentals
+                .get(&key)
+                .copied()
+                .unwrap_or_else(|| letter.in_signature(signature)),
+        };
+        let semitones = letter.natural().semitone() as i32 + accidental as i32;
+        Some(Pitch::new(Note::C, octave) + Interval(semitones))
+    }
+
+    /// Reads a length such as "2", "3/2", "/" or "//" in seconds, or None
+    /// for a length divided by zero.
+    fn note_length(&self, chars: &mut Peekable<Chars>) -> Option<f64> {
+        let mut length = read_number(chars).unwrap_or(1) as f64;
+        while chars.next_if_eq(&'/').is_some() {
+            length /= read_number(chars).unwrap_or(2) as f64;
+        }
+        let length = length * self.unit() * self.seconds_per_whole;
+        length.is_finite().then_some(length)
+    }
+
+    /// Reads a chord such as "[CEG]2", which lasts as long as its first
+    /// note times its own length. Notes with unreadable lengths are left
+    /// out, as is the whole chord if its own length is unreadable.
+    fn read_chord(&mut self, chars: &mut Peekable<Chars>) {
+        let mut pitches = Vec::new();
+        let mut length = None;
+        while let Some(symbol) = chars.next() {
+            match symbol {
+                ']' => break,
+                '^' | '_' | '=' | 'A'..='G' | 'a'..='g' => {
+                    let pitch = self.read_pitch(symbol, chars);
+                    if let (Some(pitch), Some(note_length)) = (pitch, self.note_length(chars)) {
+                        pitches.push(pitch);
+                        length.get_or_insert(note_length);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let unit_length = self.unit() * self.seconds_per_whole;
+        if let Some(chord_length) = self.note_length(chars) {
+            let length = length.unwrap_or(unit_length) * chord_length / unit_length;
+            self.voice().play(&pitches, length);
+        }
+    }
+
+    /// Reads the rest of a bar line starting with `start`, e.g. "|", "||",
+    /// "|]", "|:", ":|", "::" or ":|2".
+    fn read_bar_line(&mut self, chars: &mut Peekable<Chars>, start: &str) {
+        let mut bar_line = start.to_string();
+        while let Some(symbol) = chars.next_if(|symbol| matches!(symbol, '|' | ':' | ']')) {
+            bar_line.push(symbol);
+        }
+        let voice = self.voice();
+        voice.accidentals.clear();
+        if bar_line.starts_with(':') {
+            voice.repeat();
+        }
+        if bar_line.len() > 1 && bar_line.ends_with(':') {
+            voice.start_repeat();
+        }
+        if chars.peek().is_some_and(|next| next.is_ascii_digit()) {
+            self.read_ending(chars);
+        }
+    }
+
+    /// Reads the number of an ending, marking where a first ending starts.
+    fn read_ending(&mut self, chars: &mut Peekable<Chars>) {
+        if read_number(chars) == Some(1) {
+            let voice = self.voice();
+            voice.first_ending = Some((voice.notes.len(), voice.time));
+        }
+    }
+
+    /// Reads a tuplet such as "(3" or "(3:2:4". Other brackets are slurs.
+    fn read_tuplet(&mut self, chars: &mut Peekable<Chars>) {
+        let notes = match read_number(chars) {
+            Some(notes) => notes,
+            None => return,
+        };
+        let mut numbers = Vec::new();
+        while chars.next_if_eq(&':').is_some() {
+            numbers.push(read_number(chars));
+        }
+        // Three notes in the time of two, two in the time of three and so on.
+        let default_time = match notes {
+            2 | 4 | 8 => 3,
+            _ => 2,
+        };
+        let time = numbers.first().copied().flatten().unwrap_or(default_time);
+        let count = numbers.get(1).copied().flatten().unwrap_or(notes);
+        if notes > 0 && count > 0 {
+            self.voice().tuplet = Some((time as f64 / notes as f64, count));
+        }
+    }
+
+    fn into_song(self) -> Song {
+        let mut tracks: Vec<SongTrack> = self
+            .voices
+            .into_iter()
+            .filter(|voice| !voice.notes.is_empty())
+            .map(|voice| {
+                let mut notes = voice.notes;
+                notes.sort_by(|a, b| a.start.total_cmp(&b.start));
+                SongTrack {
+                    name: voice.name,
+                    notes,
+                }
+            })
+            .collect();
+        if let [track] = tracks.as_mut_slice() {
+            track.name = self.title;
+        }
+        let duration = tracks
+            .iter()
+            .flat_map(|track| track.notes.iter())
+            .map(|note| note.end)
+            .fold(0.0, f64::max);
+        Song { tracks, duration }
+    }
+}
+
+/// Whether the "[" just read opens an inline field such as "[K:D]".
+fn is_inline_field(chars: &Peekable<Chars>) -> bool {
+    let mut ahead = chars.clone();
+    ahead.next();
+    ahead.next() == Some(':')
+}
+
+fn skip_past(chars: &mut Peekable<Chars>, end: char) {
+    for symbol in chars.by_ref() {
+        if symbol == end {
+            break;
+        }
+    }
+}
+
+fn read_number(chars: &mut Peekable<Chars>) -> Option<u32> {
+    let mut digits = String::new();
+    while let Some(digit) = chars.next_if(|digit| digit.is_ascii_digit()) {
+        digits.push(digit);
+    }
+    digits.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::music_entities::NoteValue;
+    use crate::test_support::{performance, temp_path};
+
+    fn export(notes: &[(f64, f64, &str)], key: Key, grid: NoteValue) -> String {
+        abc(&Score::transcribe(&performance(notes), key, grid))
+    }
+
+    /// The music of voice `number`.
+    fn voice(text: &str, number: usize) -> &str {
+        let header = format!("V:{} ", number);
+        let start = text.find(&header).unwrap();
+        let music = &text[start..];
+        let music = &music[music.find('\n').unwrap() + 1..];
+        match music.find("V:") {
+            Some(end) => &music[..end],
+            None => music,
+        }
+    }
+
+    #[test]
+    fn export_header() {
+        let text = export(
+            &[(0.0, 0.5, "E4")],
+            Key::from_note(Note::D, Mode::Major),
+            NoteValue::new(16, false),
+        );
+        assert!(text.starts_with(
+            "X:1\nT:Performance\nM:4/4\nL:1/8\nQ:1/4=120\n%%score {1 | 2}\nK:D\nV:1 clef=treble\n"
+        ));
+        assert!(text.contains("|]\nV:2 clef=bass\n"));
+        let text = export(
+            &[(0.0, 0.5, "E4")],
+            Key::from_note(Note::FsharpGflat, Mode::Minor),
+            NoteValue::new(16, false),
+        );
+        assert!(text.contains("\nK:F#m\n"));
+    }
+
+    #[test]
+    fn export_octaves_and_accidentals() {
+        let name = |spelled: &str, octave, accidental| {
+            pitch_name(spelled.parse().unwrap(), octave, accidental)
+        };
+        assert_eq!(name("C", 4, None), "C");
+        assert_eq!(name("B", 3, None), "B,");
+        assert_eq!(name("G", 1, None), "G,,,");
+        assert_eq!(name("C", 5, None), "c");
+        assert_eq!(name("E", 7, None), "e''");
+        assert_eq!(name("F#", 4, Some(1)), "^F");
+        assert_eq!(name("Bb", 5, Some(-1)), "_b");
+        assert_eq!(name("F", 6, Some(0)), "=f'");
+        assert_eq!(name("C##", 4, Some(2)), "^^C");
+        assert_eq!(name("Dbb", 3, Some(-2)), "__D,");
+    }
+
+    #[test]
+    fn export_measures_ties_and_rests() {
+        let text = export(
+            &[
+                (0.0, 0.5, "F#4"),
+                (0.5, 1.0, "F#4"),
+                (1.5, 2.5, "C5"),
+                (0.0, 2.0, "C3"),
+            ],
+            Key::from_note(Note::C, Mode::Major),
+            NoteValue::new(16, false),
+        );
+        // Accidentals last to the bar line, and tied notes carry on.
+        assert_eq!(voice(&text, 1), "^F2 F2 z2 c2- | c2 z6 |]\n");
+        assert_eq!(voice(&text, 2), "C,8 | Z |]\n");
+        let text = export(
+            &[(0.0, 0.5, "F#4"), (2.0, 2.25, "F#4"), (2.25, 2.5, "G4")],
+            Key::from_note(Note::C, Mode::Major),
+            NoteValue::new(16, false),
+        );
+        assert_eq!(voice(&text, 1), "^F2 z6 | ^F G z6 |]\n");
+    }
+
+    #[test]
+    fn export_triplet_runs_and_chords() {
+        let third = 1.0 / 6.0;
+        let text = export(
+            &[
+                (0.0, third, "C4"),
+                (third, 2.0 * third, "D4"),
+                (2.0 * third, 0.5, "E4"),
+                (1.0, 1.0 + third, "G4"),
+                (1.0, 1.0 + third, "B4"),
+                (1.0 + third, 1.0 + 2.0 * third, "A4"),
+            ],
+            Key::from_note(Note::C, Mode::Major),
+            NoteValue::new(8, true),
+        );
+        assert_eq!(voice(&text, 1), "(3:2:3C D E z2 (3:2:4[GB] A z2 z2 |]\n");
+    }
+
+    #[test]
+    fn exported_tunes_import_again() {
+        let notes = [
+            (0.0, 0.5, "F#4"),
+            (0.5, 1.0, "F4"),
+            (1.5, 2.5, "C5"),
+            (0.0, 2.0, "C3"),
+        ];
+        let text = export(
+            &notes,
+            Key::from_note(Note::G, Mode::Major),
+            NoteValue::new(16, false),
+        );
+        let song = read_tune(&text);
+        let mut imported: Vec<(f64, f64, String)> = song
+            .tracks
+            .iter()
+            .flat_map(|track| track.notes.iter())
+            .map(|note| (note.start, note.end, note.pitch.to_string()))
+            .collect();
+        imported.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.2.cmp(&b.2)));
+        assert_eq!(
+            imported,
+            [
+                (0.0, 2.0, String::from("C3")),
+                (0.0, 0.5, String::from("F#4")),
+                (0.5, 1.0, String::from("F4")),
+                (1.5, 2.5, String::from("C5")),
+            ]
+        );
+    }
+
+    /// Start, end and pitch of the notes of a single voice tune.
+    fn notes(text: &str) -> Vec<(f64, f64, String)> {
+        let song = read_tune(text);
+        assert_eq!(song.tracks.len(), 1, "{}", text);
+        song.tracks[0]
+            .notes
+            .iter()
+            .map(|note| (note.start, note.end, note.pitch.to_string()))
+            .collect()
+    }
+
+    fn assert_notes(text: &str, expected: &[(f64, f64, &str)]) {
+        let found = notes(text);
+        assert_eq!(found.len(), expected.len(), "{}: {:?}", text, found);
+        for (found, expected) in found.iter().zip(expected) {
+            assert!(
+                (found.0 - expected.0).abs() < 1e-9
+                    && (found.1 - expected.1).abs() < 1e-9
+                    && found.2 == expected.2,
+                "{}: {:?} isn't {:?}",
+                text,
+                found,
+                expected
+            );
+        }
+    }
+
+    fn pitches(text: &str) -> Vec<String> {
+        notes(text).into_iter().map(|(_, _, pitch)| pitch).collect()
+    }
+
+    #[test]
+    fn unit_length_and_tempo_default() {
+        // Eighth notes at 120 quarter notes a minute.
+        assert_notes(
+            "X:1\nT:Scale\nK:C\nCDE c\n",
+            &[
+                (0.0, 0.25, "C4"),
+                (0.25, 0.5, "D4"),
+                (0.5, 0.75, "E4"),
+                (0.75, 1.0, "C5"),
+            ],
+        );
+        // Sixteenths in meters shorter than 3/4.
+        assert_notes(
+            "M:2/4\nK:C\nC2 D",
+            &[(0.0, 0.25, "C4"), (0.25, 0.375, "D4")],
+        );
+        assert_notes(
+            "L:1/4\nQ:1/4=60\nK:C\nC D,",
+            &[(0.0, 1.0, "C4"), (1.0, 2.0, "D3")],
+        );
+        // A tempo in the header waits for the unit length, and one without a
+        // beat counts units.
+        assert_notes("Q:1/4=60\nL:1/8\nK:C\nC", &[(0.0, 0.5, "C4")]);
+        assert_notes("L:1/4\nQ:60\nK:C\nC", &[(0.0, 1.0, "C4")]);
+        assert_eq!(read_tune("T:Title\nK:C\nC").tracks[0].name, "Title");
+    }
+
+    #[test]
+    fn key_signatures_and_accidentals() {
+        assert_eq!(pitches("K:G\nFGf"), ["F#4", "G4", "F#5"]);
+        assert_eq!(pitches("K:Bb\nBEe"), ["A#4", "D#4", "D#5"]);
+        assert_eq!(pitches("K:D dor\nFc"), ["F4", "C5"]);
+        // Accidentals last until the bar line, for that octave only.
+        assert_eq!(pitches("K:C\n^F F f | F"), ["F#4", "F#4", "F5", "F4"]);
+        assert_eq!(pitches("K:G\n=F F | F"), ["F4", "F4", "F#4"]);
+        assert_eq!(pitches("K:C\n[K:D]F"), ["F#4"]);
+    }
+
+    #[test]
+    fn repeats_and_endings_are_played_out() {
+        assert_eq!(pitches("K:C\n|: C D :| E"), ["C4", "D4", "C4", "D4", "E4"]);
+        assert_eq!(pitches("K:C\nC D :| E"), ["C4", "D4", "C4", "D4", "E4"]);
+        assert_eq!(pitches("K:C\n|: C |1 D :|2 E |"), ["C4", "D4", "C4", "E4"]);
+        assert_eq!(
+            pitches("K:C\n|: C [1 D :| [2 E |"),
+            ["C4", "D4", "C4", "E4"]
+        );
+        assert_eq!(pitches("K:C\n|: C :: D :|"), ["C4", "C4", "D4", "D4"]);
+        assert_notes(
+            "K:C\n|: C |1 D :|2 E |",
+            &[
+                (0.0, 0.25, "C4"),
+                (0.25, 0.5, "D4"),
+                (0.5, 0.75, "C4"),
+                (0.75, 1.0, "E4"),
+            ],
+        );
+    }
+
+    #[test]
+    fn endings_before_a_repeat_start_are_ignored() {
+        assert_eq!(
+            pitches("K:C\n|1 AB |2 cd |: ef :|"),
+            ["A4", "B4", "C5", "D5", "E5", "F5", "E5", "F5"]
+        );
+        assert_eq!(
+            pitches("K:C\n|: C |1 D :| E |: F :|"),
+            ["C4", "D4", "C4", "E4", "F4", "F4"]
+        );
+    }
+
+    #[test]
+    fn tuplets_share_the_time_of_fewer_notes() {
+        let third = 0.25 * 2.0 / 3.0;
+        assert_notes(
+            "K:C\n(3CDE F",
+            &[
+                (0.0, third, "C4"),
+                (third, 2.0 * third, "D4"),
+                (2.0 * third, 0.5, "E4"),
+                (0.5, 0.75, "F4"),
+            ],
+        );
+        // Two notes in the time of three.
+        assert_notes(
+            "K:C\n(2CD E",
+            &[(0.0, 0.375, "C4"), (0.375, 0.75, "D4"), (0.75, 1.0, "E4")],
+        );
+        // Three notes in the time of two, for the next four notes.
+        assert_eq!(notes("K:C\n(3:2:4CDEF G")[4].0, 1.0 * 4.0 / 6.0);
+    }
+
+    #[test]
+    fn broken_rhythm_moves_time_between_notes() {
+        assert_notes("K:C\nC>D", &[(0.0, 0.375, "C4"), (0.375, 0.5, "D4")]);
+        assert_notes("K:C\nC<D", &[(0.0, 0.125, "C4"), (0.125, 0.5, "D4")]);
+        assert_notes("K:C\nC>>D", &[(0.0, 0.4375, "C4"), (0.4375, 0.5, "D4")]);
+    }
+
+    #[test]
+    fn ties_join_notes_of_the_same_pitch() {
+        assert_notes("K:C\nC2-C2 D", &[(0.0, 1.0, "C4"), (1.0, 1.25, "D4")]);
+        assert_notes("K:C\nC-|C", &[(0.0, 0.5, "C4")]);
+        assert_notes("K:C\nC-D", &[(0.0, 0.25, "C4"), (0.25, 0.5, "D4")]);
+        assert_notes(
+            "K:C\n[CE]-[CG]",
+            &[(0.0, 0.5, "C4"), (0.0, 0.25, "E4"), (0.25, 0.5, "G4")],
+        );
+    }
+
+    #[test]
+    fn chords_rests_and_voices() {
+        assert_notes(
+            "K:C\n[CE]2 z [C/E]3/",
+            &[
+                (0.0, 0.5, "C4"),
+                (0.0, 0.5, "E4"),
+                (0.75, 0.9375, "C4"),
+                (0.75, 0.9375, "E4"),
+            ],
+        );
+        let song = read_tune("K:C\nV:1\nC D\nV:2\nZ E\n");
+        let names: Vec<&str> = song
+            .tracks
+            .iter()
+            .map(|track| track.name.as_str())
+            .collect();
+        assert_eq!(names, ["1", "2"]);
+        assert_eq!(song.tracks[1].notes[0].start, 2.0);
+        assert_eq!(song.duration, 2.25);
+    }
+
+    #[test]
+    fn malformed_music_is_skipped() {
+        // Lengths divided by zero have no length to play.
+        assert_notes("K:C\nA/0 B", &[(0.0, 0.25, "B4")]);
+        assert_notes(
+            "K:C\n[CE]/0 D [C/0E]",
+            &[(0.0, 0.25, "D4"), (0.25, 0.5, "E4")],
+        );
+        assert_notes("K:C\n#$&* C ] % A comment", &[(0.0, 0.25, "C4")]);
+        assert_notes("L:1/0\nQ:fast\nM:none\nK:C\nC", &[(0.0, 0.25, "C4")]);
+        assert_notes("K:C\n[CE", &[(0.0, 0.25, "C4"), (0.0, 0.25, "E4")]);
+        for note in notes("K:C\n|1 :|2 (0 (3:0 C :: :| |1 D").iter() {
+            assert!(note.0.is_finite() && note.1 >= note.0, "{:?}", note);
+        }
+    }
+
+    #[test]
+    fn tunes_without_notes_are_errors() {
+        assert!(read_tune("T:Just a header\nC D E").tracks.is_empty());
+        assert!(read_tune("K:C\nz4 | Z2 |]").tracks.is_empty());
+        let path = temp_path("abc", "empty.abc");
+        fs::write(&path, "X:1\nK:C\n").unwrap();
+        assert!(import_tune(&path).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(import_tune(&path).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use std::{str::FromStr, time::Duration};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    music_entities::{Interval, NoteValue, Pitch},
+    note_generator::HIGHEST_SAMPLE,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ArpPattern {
+    Up,
+    Down,
+    UpDown,
+    Random,
+    AsPlayed,
+}
+
+impl ArpPattern {
+    pub fn next(&self) -> ArpPattern {
+        match self {
+            ArpPattern::Up => ArpPattern::Down,
+            ArpPattern::Down => ArpPattern::UpDown,
+            ArpPattern::UpDown => ArpPattern::Random,
+            ArpPattern::Random => ArpPattern::AsPlayed,
+            ArpPattern::AsPlayed => ArpPattern::Up,
+        }
+    }
+}
+
+impl FromStr for ArpPattern {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().as_str() {
+            "up" => Ok(ArpPattern::Up),
+            "down" => Ok(ArpPattern::Down),
+            "up-down" => Ok(ArpPattern::UpDown),
+            "random" => Ok(ArpPattern::Random),
+            "as-played" => Ok(ArpPattern::AsPlayed),
+            _ => Err(format!("unknown arpeggiator pattern: {}", name)),
+        }
+    }
+}
+
+/// A note played by the arpeggiator, `length` being the gated part of its step.
+#[derive(Debug, Clone, Copy)]
+pub struct ArpNote {
+    pub pitch: Pitch,
+    pub length: Duration,
+}
+
+/// Plays the held notes one at a time in a pattern, one note per step of
+/// `rate` on the beats of the transport's clock.
+pub struct Arpeggiator {
+    pub pattern: ArpPattern,
+    pub rate: NoteValue,
+    /// How many octaves the held notes are repeated over, at least 1.
+    pub octaves: u8,
+    /// Portion of each step the note sounds for, 0.0 to 1.0.
+    pub gate: f32,
+    enabled: bool,
+    latch: bool,
+    /// The notes being arpeggiated in the order they were pressed.
+    notes: Vec<Pitch>,
+    held: Vec<Pitch>,
+    step: usize,
+    /// Steps of `rate` from beat 0 to the one played last.
+    last_step: Option<i64>,
+}
+
+impl Arpeggiator {
+    pub fn new(pattern: ArpPattern, rate: NoteValue, octaves: u8, gate: f32) -> Self {
+        Arpeggiator {
+            pattern,
+            rate,
+            octaves: octaves.max(1),
+            gate: gate.clamp(0.0, 1.0),
+            enabled: false,
+            latch: false,
+            notes: Vec::new(),
+            held: Vec::new(),
+            step: 0,
+            last_step: None,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// With latch on the notes keep playing after they are released, until
+    /// a new set of notes is pressed.
+    pub fn set_latch(&mut self, latch: bool) {
+        self.latch = latch;
+        if !latch {
+            self.notes = self.held.clone();
+        }
+    }
+
+    pub fn get_latch(&self) -> bool {
+        self.latch
+    }
+
+    /// Updates the notes that are held down, in the order they were pressed.
+    pub fn set_held(&mut self, held: Vec<Pitch>) {
+        // A press after everything was released starts a new latched set.
+        if !self.latch || (self.held.is_empty() && !held.is_empty()) {
+            self.notes = held.clone();
+        } else {
+            for pitch in held.iter() {
+                if !self.notes.contains(pitch) {
+                    self.notes.push(*pitch);
+                }
+            }
+        }
+        self.held = held;
+    }
+
+    /// The held notes repeated over the octave range in pattern order. Random
+    /// picks from the ascending order.
+    fn sequence(&self) -> Vec<Pitch> {
+        let mut ordered = self.notes.clone();
+        if self.pattern != ArpPattern::AsPlayed {
+            ordered.sort();
+        }
+        let mut sequence: Vec<Pitch> = (0..self.octaves as i32)
+            .flat_map(|octave| {
+                ordered
+                    .iter()
+                    .map(move |pitch| *pitch + Interval(12 * octave))
+            })
+            .filter(|pitch| *pitch <= HIGHEST_SAMPLE)
+            .collect();
+        match self.pattern {
+            ArpPattern::Down => sequence.reverse(),
+            ArpPattern::UpDown if sequence.len() > 2 => {
+                let descending: Vec<Pitch> = sequence[1..sequence.len() - 1]
+                    .iter()
+                    .rev()
+                    .copied()
+                    .collect();
+                sequence.extend(descending);
+            }
+            _ => {}
+        }
+        sequence
+    }
+
+    /// The note due at the clock position `beats`, if any. The first note
+    /// plays straight away and the rest whenever `beats` crosses into the
+    /// next step of `rate`.
+    pub fn poll(&mut self, beats: f64, bpm: f32) -> Option<ArpNote> {
+        let sequence = self.sequence();
+        if !self.enabled || sequence.is_empty() {
+            self.step = 0;
+            self.last_step = None;
+            return None;
+        }
+        let step = (beats / self.rate.beats()).floor() as i64;
+        if self.last_step == Some(step) {
+            return None;
+        }
+        self.last_step = Some(step);
+        let pitch = match self.pattern {
+            ArpPattern::Random => sequence[rand::thread_rng().gen_range(0..sequence.len())],
+            _ => sequence[self.step % sequence.len()],
+        };
+        self.step += 1;
+        Some(ArpNote {
+            pitch,
+            length: self.rate.duration(bpm).mul_f32(self.gate),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::music_entities::Note;
+
+    fn pitches(names: &[&str]) -> Vec<Pitch> {
+        names.iter().map(|name| name.parse().unwrap()).collect()
+    }
+
+    fn arpeggiator(pattern: ArpPattern, octaves: u8, held: &[&str]) -> Arpeggiator {
+        let mut arpeggiator = Arpeggiator::new(pattern, NoteValue::new(16, false), octaves, 0.5);
+        arpeggiator.set_enabled(true);
+        arpeggiator.set_held(pitches(held));
+        arpeggiator
+    }
+
+    #[test]
+    fn patterns_order_the_held_notes() {
+        let held = ["E4", "C4", "G4"];
+        let sequence = |pattern| arpeggiator(pattern, 1, &held).sequence();
+        assert_eq!(sequence(ArpPattern::Up), pitches(&["C4", "E4", "G4"]));
+        assert_eq!(sequence(ArpPattern::Down), pitches(&["G4", "E4", "C4"]));
+        assert_eq!(
+            sequence(ArpPattern::UpDown),
+            pitches(&["C4", "E4", "G4", "E4"])
+        );
+        assert_eq!(sequence(ArpPattern::AsPlayed), pitches(&held));
+        assert_eq!(sequence(ArpPattern::Random), pitches(&["C4", "E4", "G4"]));
+        // Two notes go up and down without repeating either end.
+        let two = arpeggiator(ArpPattern::UpDown, 1, &["C4", "G4"]).sequence();
+        assert_eq!(two, pitches(&["C4", "G4"]));
+    }
+
+    #[test]
+    fn octaves_repeat_up_to_the_highest_sample() {
+        let up = arpeggiator(ArpPattern::Up, 3, &["C4", "E4"]).sequence();
+        assert_eq!(up, pitches(&["C4", "E4", "C5", "E5", "C6"]));
+        let as_played = arpeggiator(ArpPattern::AsPlayed, 2, &["G4", "C4"]).sequence();
+        assert_eq!(as_played, pitches(&["G4", "C4", "G5", "C5"]));
+        // Octaves can't be set below 1.
+        let mut arp = Arpeggiator::new(ArpPattern::Up, NoteValue::new(8, false), 0, 2.0);
+        arp.set_held(pitches(&["D4"]));
+        assert_eq!(arp.octaves, 1);
+        assert_eq!(arp.gate, 1.0);
+        assert_eq!(arp.sequence(), pitches(&["D4"]));
+    }
+
+    #[test]
+    fn steps_follow_the_clock() {
+        let mut arp = arpeggiator(ArpPattern::Up, 1, &["C4", "E4", "G4"]);
+        // The first note plays straight away, a 1/16 step in.
+        let first = arp.poll(0.6, 120.0).unwrap();
+        assert_eq!(first.pitch, Pitch::new(Note::C, 4));
+        // Half of a 125 ms step.
+        assert_eq!(first.length, Duration::from_micros(62_500));
+        assert!(arp.poll(0.7, 120.0).is_none());
+        assert_eq!(arp.poll(0.75, 120.0).unwrap().pitch, Pitch::new(Note::E, 4));
+        assert!(arp.poll(0.99, 120.0).is_none());
+        assert_eq!(arp.poll(1.0, 120.0).unwrap().pitch, Pitch::new(Note::G, 4));
+        assert_eq!(arp.poll(1.3, 120.0).unwrap().pitch, Pitch::new(Note::C, 4));
+        // A restarted clock steps on from its new position.
+        assert_eq!(arp.poll(-4.0, 120.0).unwrap().pitch, Pitch::new(Note::E, 4));
+        // Triplet eighths are a third of a beat.
+        arp.rate = NoteValue::new(8, true);
+        assert!(arp.poll(-3.9, 120.0).is_some());
+        assert!(arp.poll(-3.7, 120.0).is_none());
+        assert!(arp.poll(-3.6, 120.0).is_some());
+    }
+
+    #[test]
+    fn nothing_plays_when_disabled_or_released() {
+        let mut arp = arpeggiator(ArpPattern::Up, 1, &["C4", "E4"]);
+        arp.set_enabled(false);
+        assert!(arp.poll(0.0, 120.0).is_none());
+        arp.set_enabled(true);
+        arp.set_held(Vec::new());
+        assert!(arp.poll(0.0, 120.0).is_none());
+        // Released notes start over from the first step.
+        arp.set_held(pitches(&["E4", "C4"]));
+        assert_eq!(arp.poll(0.0, 120.0).unwrap().pitch, Pitch::new(Note::C, 4));
+    }
+
+    #[test]
+    fn latched_notes_keep_playing() {
+        let mut arp = arpeggiator(ArpPattern::AsPlayed, 1, &["C4"]);
+        arp.set_latch(true);
+        arp.set_held(pitches(&["C4", "E4"]));
+        arp.set_held(Vec::new());
+        assert_eq!(arp.sequence(), pitches(&["C4", "E4"]));
+        // A new press after letting go starts a new set.
+        arp.set_held(pitches(&["G4"]));
+        assert_eq!(arp.sequence(), pitches(&["G4"]));
+        arp.set_latch(false);
+        arp.set_held(Vec::new());
+        assert!(arp.sequence().is_empty());
+    }
+}
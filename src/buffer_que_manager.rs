@@ -1,162 +1,295 @@
-use std::{
-    sync::{Arc, Mutex},
-    thread,
-    time::Duration,
-};
+use std::sync::{Arc, Mutex};
 
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    BuildStreamError, SampleFormat, Stream,
+    SampleFormat, Stream,
 };
 
+use crate::{looper::Looper, transport::Clock, tuning};
+
+// Frames are added as 44.1 kHz interleaved stereo, like the mp3 samples, and
+// converted to the output stream's config.
+pub const SAMPLE_RATE: f32 = 44_100.0;
+pub const CHANNELS: usize = 2;
+
 pub trait BufferQueManager {
     fn add_frames_to_que(&mut self, frames: Vec<f32>);
     fn pause_all_streams(&self);
     fn clear_all(&mut self);
+    /// Drops every frame added while muted.
+    fn set_muted(&mut self, muted: bool);
+}
+
+/// Frames of a note still being played.
+struct Voice {
+    frames: Vec<f32>,
+    position: usize,
+}
+
+/// State shared with the output stream's audio callback.
+struct Mixer {
+    voices: Vec<Voice>,
+    looper: Option<Arc<Mutex<Looper>>>,
+    clock: Option<Clock>,
+    sample_rate: u32,
+    channels: usize,
+}
+
+impl Mixer {
+    /// Fills `data` with every voice added together, followed by the loop and
+    /// then the metronome, which isn't recorded into the loop.
+    fn fill(&mut self, data: &mut [f32]) {
+        data.fill(0.0);
+        for voice in self.voices.iter_mut() {
+            let frames = &voice.frames[voice.position..];
+            for (sample, frame) in data.iter_mut().zip(frames) {
+                *sample += frame;
+            }
+            voice.position += frames.len().min(data.len());
+        }
+        self.voices
+            .retain(|voice| voice.position < voice.frames.len());
+        if let Some(looper) = self.looper.as_ref() {
+            if let Ok(mut looper) = looper.lock() {
+                looper.process(data);
+            }
+        }
+        if let Some(clock) = self.clock.as_ref() {
+            clock.process(data, self.channels);
+        }
+    }
 }
 
+/// Plays every note on a single output stream, mixing them as they are
+/// added.
 pub struct DefaultBufferQueManager {
-    buffer_ques: Vec<Arc<Mutex<Vec<f32>>>>,
-    streams: Vec<Stream>,
+    mixer: Arc<Mutex<Mixer>>,
+    stream: Option<Stream>,
+    muted: bool,
 }
 
 impl DefaultBufferQueManager {
     pub fn new() -> DefaultBufferQueManager {
+        let mixer = Arc::new(Mutex::new(Mixer {
+            voices: Vec::new(),
+            looper: None,
+            clock: None,
+            sample_rate: SAMPLE_RATE as u32,
+            channels: CHANNELS,
+        }));
+        let stream = match setup_audio_out_put_stream(Arc::clone(&mixer)) {
+            Ok(stream) => Some(stream),
+            Err(err) => {
+                eprintln!("couldn't start the audio output: {}", err);
+                None
+            }
+        };
         DefaultBufferQueManager {
-            streams: Vec::new(),
-            buffer_ques: Vec::new(),
+            mixer,
+            stream,
+            muted: false,
         }
     }
 
-    fn create_stream_and_add_to_que(&mut self, frames: Vec<f32>) {
-        let mut frame_vector: Vec<f32> = Vec::new();
-        frame_vector.extend(frames);
-        let buffer_que = Arc::new(Mutex::new(frame_vector));
-        let stream = setup_audio_out_put_stream(Arc::clone(&buffer_que));
-
-        match stream {
-            Ok(stream) => {
-                stream.play().unwrap();
-                self.streams.push(stream);
-                self.buffer_ques.push(buffer_que);
-                // pause main thread to play audio from background thread.
-                thread::sleep(Duration::from_secs_f32(0.001))
-            }
-            Err(_) => todo!(), //handle error, (retry?),
+    /// Mixes the loop of `looper` into the output, and records what is
+    /// played into it.
+    pub fn set_looper(&mut self, looper: Arc<Mutex<Looper>>) {
+        if let Ok(mut mixer) = self.mixer.lock() {
+            mixer.looper = Some(looper);
         }
     }
-    fn find_first_available_que(&self) -> Option<Arc<Mutex<Vec<f32>>>> {
-        for (index, buffer_que) in self.buffer_ques.iter().enumerate() {
-            if let Ok(que) = buffer_que.try_lock() {
-                if que.is_empty() {
-                    Some(Arc::clone(&self.buffer_ques[index]));
-                }
-            }
-        }
-        None
-    }
-
-    fn add_stream_to_shortest_que(&mut self, frames: Vec<f32>) {
-        let mut current_index = 0;
-        let mut current_length = 0;
-        for (index, buffer_que) in self.buffer_ques.iter().enumerate() {
-            if let Ok(que) = buffer_que.try_lock() {
-                if index == 0 {
-                    current_length = que.len();
-                }
-                if que.len() < current_length {
-                    current_length = que.len();
-                    current_index = index;
-                }
-            }
-        }
-        match self.buffer_ques[current_index].lock() {
-            Ok(mut que) => que.extend(frames),
-            Err(_) => todo!(), // handle error (retry?),
+
+    /// Drives the transport from the output stream, so beats, the metronome
+    /// and the loop all count the same frames.
+    pub fn set_clock(&mut self, clock: Clock) {
+        if let Ok(mut mixer) = self.mixer.lock() {
+            clock.set_sample_rate(mixer.sample_rate);
+            mixer.clock = Some(clock);
         }
     }
+
+    /// Frames per second of the output stream.
+    pub fn sample_rate(&self) -> u32 {
+        self.mixer
+            .lock()
+            .map(|mixer| mixer.sample_rate)
+            .unwrap_or(SAMPLE_RATE as u32)
+    }
+
+    /// Interleaved channels of the output stream.
+    pub fn channels(&self) -> usize {
+        self.mixer
+            .lock()
+            .map(|mixer| mixer.channels)
+            .unwrap_or(CHANNELS)
+    }
 }
 
 impl BufferQueManager for DefaultBufferQueManager {
     fn add_frames_to_que(&mut self, frames: Vec<f32>) {
-        let max_allowed_ques = 7;
-
-        if self.streams.is_empty() {
-            self.create_stream_and_add_to_que(frames);
+        if self.muted || frames.is_empty() {
             return;
         }
-
-        if let Some(buffer_que) = self.find_first_available_que() {
-            let que = buffer_que.lock();
-            match que {
-                Ok(mut que) => que.extend(frames),
-                Err(_) => todo!(), //add error handling.
-            }
-        } else {
-            if self.streams.len() <= max_allowed_ques {
-                self.create_stream_and_add_to_que(frames)
-            } else {
-                self.add_stream_to_shortest_que(frames)
-            }
+        // Converted before locking, so the audio callback isn't kept waiting.
+        let frames = to_output_format(frames, self.sample_rate(), self.channels());
+        if let Ok(mut mixer) = self.mixer.lock() {
+            mixer.voices.push(Voice {
+                frames,
+                position: 0,
+            });
         }
     }
 
     fn pause_all_streams(&self) {
-        for stream in self.streams.iter() {
-            stream.pause().unwrap();
+        if let Some(stream) = self.stream.as_ref() {
+            if let Err(err) = stream.pause() {
+                eprintln!("couldn't pause the audio output: {}", err);
+            }
         }
     }
 
     fn clear_all(&mut self) {
-        self.buffer_ques = Vec::new();
-        self.streams = Vec::new();
+        if let Ok(mut mixer) = self.mixer.lock() {
+            mixer.voices.clear();
+        }
+    }
+
+    fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
     }
 }
 
-fn setup_audio_out_put_stream(buffer: Arc<Mutex<Vec<f32>>>) -> Result<Stream, BuildStreamError> {
+/// Resamples 44.1 kHz stereo `frames` to `sample_rate` and spreads them over
+/// `channels`, left and right going to the first two and both to a single one.
+fn to_output_format(frames: Vec<f32>, sample_rate: u32, channels: usize) -> Vec<f32> {
+    let frames = match sample_rate as f32 == SAMPLE_RATE {
+        true => frames,
+        false => tuning::repitch(&frames, CHANNELS, SAMPLE_RATE / sample_rate as f32),
+    };
+    if channels == CHANNELS {
+        return frames;
+    }
+    let mut output = Vec::with_capacity(frames.len() / CHANNELS * channels);
+    for frame in frames.chunks_exact(CHANNELS) {
+        match channels {
+            1 => output.push(frame.iter().sum::<f32>() / CHANNELS as f32),
+            _ => output
+                .extend((0..channels).map(|channel| frame.get(channel).copied().unwrap_or(0.0))),
+        }
+    }
+    output
+}
+
+fn setup_audio_out_put_stream(mixer: Arc<Mutex<Mixer>>) -> Result<Stream, String> {
     let host = cpal::default_host();
     let device = host
         .default_output_device()
-        .expect("No output device found");
-    let mut supported_configs_range = device
+        .ok_or("no output device found")?;
+    let supported_config = device
         .supported_output_configs()
-        .expect("error while querying configs");
-    let supported_config = supported_configs_range
+        .map_err(|err| err.to_string())?
         .next()
-        .expect("no supported config")
+        .ok_or("no supported config")?
         .with_max_sample_rate();
-
-    let sample_format = supported_config.sample_format();
-    let config = supported_config.into();
+    if supported_config.sample_format() != SampleFormat::F32 {
+        return Err(format!(
+            "unsupported sample format {:?}",
+            supported_config.sample_format()
+        ));
+    }
+    if let Ok(mut mixer) = mixer.lock() {
+        mixer.sample_rate = supported_config.sample_rate().0;
+        mixer.channels = supported_config.channels() as usize;
+    }
     let err_fn = |err| eprintln!("an error occurred on the output audio stream: {}", err);
-
-    let mut next_frame = move |frame_size: usize| -> Vec<f32> {
-        let mut que = buffer.as_ref().try_lock().unwrap();
-        if que.len() > frame_size {
-            que.drain(0..frame_size).collect()
-        } else {
-            que.drain(0..).collect()
-        }
-    };
-
-    let stream = match sample_format {
-        SampleFormat::F32 => device.build_output_stream(
-            &config,
-            move |data, _| {
-                write_audio(data, &mut next_frame);
+    let stream = device
+        .build_output_stream(
+            &supported_config.into(),
+            move |data: &mut [f32], _| match mixer.lock() {
+                Ok(mut mixer) => mixer.fill(data),
+                Err(_) => data.fill(0.0),
             },
             err_fn,
             None,
-        ),
-        sample_format => panic!("Unsupported sample format {:?}", sample_format),
-    };
+        )
+        .map_err(|err| err.to_string())?;
+    stream.play().map_err(|err| err.to_string())?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mixer() -> Mixer {
+        Mixer {
+            voices: Vec::new(),
+            looper: None,
+            clock: None,
+            sample_rate: SAMPLE_RATE as u32,
+            channels: CHANNELS,
+        }
+    }
+
+    #[test]
+    fn frames_are_converted_to_the_output_format() {
+        // A second of stereo with the left channel at 0.5 and the right at 1.
+        let frames: Vec<f32> = [0.5, 1.0].repeat(44_100);
+        assert_eq!(to_output_format(frames.clone(), 44_100, 2), frames);
+        let resampled = to_output_format(frames.clone(), 48_000, 2);
+        assert_eq!(resampled.len(), 48_000 * 2);
+        assert_eq!(&resampled[..4], &[0.5, 1.0, 0.5, 1.0]);
+        assert_eq!(
+            to_output_format(frames.clone(), 22_050, 2).len(),
+            22_050 * 2
+        );
+        let mono = to_output_format(frames.clone(), 44_100, 1);
+        assert_eq!(mono.len(), 44_100);
+        assert!(mono.iter().all(|sample| *sample == 0.75));
+        let surround = to_output_format(frames, 48_000, 6);
+        assert_eq!(surround.len(), 48_000 * 6);
+        assert_eq!(&surround[..6], &[0.5, 1.0, 0.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn voices_are_mixed_until_they_end() {
+        let mut mixer = mixer();
+        mixer.voices.push(Voice {
+            frames: vec![1.0; 6],
+            position: 0,
+        });
+        mixer.voices.push(Voice {
+            frames: vec![0.5; 2],
+            position: 0,
+        });
+        let mut data = vec![9.0; 4];
+        mixer.fill(&mut data);
+        assert_eq!(data, [1.5, 1.5, 1.0, 1.0]);
+        assert_eq!(mixer.voices.len(), 1);
+        mixer.fill(&mut data);
+        assert_eq!(data, [1.0, 1.0, 0.0, 0.0]);
+        assert!(mixer.voices.is_empty());
+    }
 
-    fn write_audio(data: &mut [f32], next_frame: &mut dyn FnMut(usize) -> Vec<f32>) {
-        let buffer_size = data.len();
-        let frame = next_frame(buffer_size);
-        for (i, data) in data.iter_mut().enumerate() {
-            *data = *frame.get(i).unwrap_or(&0.0)
+    #[test]
+    fn the_looper_records_the_mixed_voices() {
+        let looper = Arc::new(Mutex::new(Looper::new(1)));
+        if let Ok(mut looper) = looper.lock() {
+            looper.arm();
+            looper.start(4);
         }
+        let mut mixer = Mixer {
+            looper: Some(Arc::clone(&looper)),
+            ..mixer()
+        };
+        mixer.voices.push(Voice {
+            frames: vec![0.25; 4],
+            position: 0,
+        });
+        let mut data = vec![0.0; 4];
+        mixer.fill(&mut data);
+        // The recorded pass plays back once the voice has ended.
+        mixer.fill(&mut data);
+        assert_eq!(data, [0.25; 4]);
     }
-    stream
 }
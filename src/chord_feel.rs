@@ -0,0 +1,170 @@
+use std::{str::FromStr, time::Duration};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StrumDirection {
+    /// Lowest note first.
+    Up,
+    /// Highest note first.
+    Down,
+}
+
+impl FromStr for StrumDirection {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().as_str() {
+            "up" => Ok(StrumDirection::Up),
+            "down" => Ok(StrumDirection::Down),
+            _ => Err(format!("unknown strum direction: {}", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Strum {
+    /// Time between the starts of neighbouring notes.
+    pub delay: Duration,
+    pub direction: StrumDirection,
+}
+
+/// Random variation added to every note of a chord.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Humanize {
+    /// Longest random delay before a note starts.
+    pub timing: Duration,
+    /// Largest random drop in volume, 0.0 to 1.0.
+    pub velocity: f32,
+}
+
+/// When each note of a chord starts and how loud it is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoiceOffset {
+    pub delay: Duration,
+    pub gain: f32,
+}
+
+/// Strum and humanize settings for notes that are played together, so
+/// they don't all start on the same sample at the same volume.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct ChordFeel {
+    pub strum: Option<Strum>,
+    pub humanize: Option<Humanize>,
+}
+
+impl ChordFeel {
+    /// Offsets for `count` notes sorted from lowest to highest.
+    pub fn voice_offsets(&self, count: usize) -> Vec<VoiceOffset> {
+        let mut rng = rand::thread_rng();
+        (0..count)
+            .map(|index| {
+                let mut offset = VoiceOffset {
+                    delay: Duration::ZERO,
+                    gain: 1.0,
+                };
+                if let Some(strum) = self.strum {
+                    let position = match strum.direction {
+                        StrumDirection::Up => index,
+                        StrumDirection::Down => count - 1 - index,
+                    };
+                    offset.delay += strum.delay * position as u32;
+                }
+                if let Some(humanize) = self.humanize {
+                    offset.delay += humanize.timing.mul_f32(rng.gen());
+                    offset.gain -= humanize.velocity.clamp(0.0, 1.0) * rng.gen::<f32>();
+                }
+                offset
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delays(offsets: &[VoiceOffset]) -> Vec<Duration> {
+        offsets.iter().map(|offset| offset.delay).collect()
+    }
+
+    fn strum(direction: StrumDirection) -> ChordFeel {
+        ChordFeel {
+            strum: Some(Strum {
+                delay: Duration::from_millis(20),
+                direction,
+            }),
+            humanize: None,
+        }
+    }
+
+    #[test]
+    fn no_feel_plays_every_note_at_once() {
+        let offsets = ChordFeel::default().voice_offsets(3);
+        assert!(offsets.iter().all(|offset| *offset
+            == VoiceOffset {
+                delay: Duration::ZERO,
+                gain: 1.0,
+            }));
+        assert!(ChordFeel::default().voice_offsets(0).is_empty());
+    }
+
+    #[test]
+    fn strums_are_spaced_by_their_delay() {
+        let ms = Duration::from_millis;
+        let up = strum(StrumDirection::Up).voice_offsets(4);
+        assert_eq!(delays(&up), [ms(0), ms(20), ms(40), ms(60)]);
+        let down = strum(StrumDirection::Down).voice_offsets(4);
+        assert_eq!(delays(&down), [ms(60), ms(40), ms(20), ms(0)]);
+        assert!(up
+            .iter()
+            .chain(down.iter())
+            .all(|offset| offset.gain == 1.0));
+        assert_eq!(
+            delays(&strum(StrumDirection::Down).voice_offsets(1)),
+            [ms(0)]
+        );
+    }
+
+    #[test]
+    fn humanize_stays_within_its_bounds() {
+        let timing = Duration::from_millis(15);
+        let feel = ChordFeel {
+            strum: Some(Strum {
+                delay: Duration::from_millis(30),
+                direction: StrumDirection::Up,
+            }),
+            humanize: Some(Humanize {
+                timing,
+                velocity: 0.25,
+            }),
+        };
+        for _ in 0..100 {
+            for (index, offset) in feel.voice_offsets(3).into_iter().enumerate() {
+                let strummed = Duration::from_millis(30) * index as u32;
+                assert!(offset.delay >= strummed && offset.delay <= strummed + timing);
+                assert!((0.75..=1.0).contains(&offset.gain));
+            }
+        }
+        // Drops of more than the whole volume are cut to it.
+        let loud = ChordFeel {
+            strum: None,
+            humanize: Some(Humanize {
+                timing: Duration::ZERO,
+                velocity: 3.0,
+            }),
+        };
+        for offset in loud.voice_offsets(100) {
+            assert_eq!(offset.delay, Duration::ZERO);
+            assert!((0.0..=1.0).contains(&offset.gain));
+        }
+    }
+
+    #[test]
+    fn strum_directions_parse() {
+        assert_eq!("Up".parse(), Ok(StrumDirection::Up));
+        assert_eq!("down".parse(), Ok(StrumDirection::Down));
+        assert!("sideways".parse::<StrumDirection>().is_err());
+    }
+}
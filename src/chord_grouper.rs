@@ -0,0 +1,111 @@
+use std::time::{Duration, Instant};
+
+use crate::music_entities::TimedNote;
+
+/// Groups played notes into chords by their event timestamps.
+///
+/// A group is opened by the first note and collects every note that arrives
+/// within `window` of it. Nothing here sleeps or reads the clock, the caller
+/// passes in the current time when polling.
+pub struct ChordGrouper {
+    window: Duration,
+    pending: Vec<TimedNote>,
+}
+
+impl ChordGrouper {
+    pub fn new(window: Duration) -> Self {
+        ChordGrouper {
+            window,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Adds notes to the open group and returns every group that was closed
+    /// by a note arriving after the window.
+    pub fn add_notes(&mut self, notes: &[TimedNote]) -> Vec<Vec<TimedNote>> {
+        let mut closed_groups = Vec::new();
+        for note in notes {
+            if let Some(first) = self.pending.first() {
+                if note.timestamp.saturating_duration_since(first.timestamp) > self.window {
+                    closed_groups.push(self.take_group());
+                }
+            }
+            self.pending.push(*note);
+        }
+        closed_groups
+    }
+
+    /// Closes the open group if its window has passed at `now`.
+    pub fn poll(&mut self, now: Instant) -> Option<Vec<TimedNote>> {
+        let first = self.pending.first()?;
+        if now.saturating_duration_since(first.timestamp) > self.window {
+            Some(self.take_group())
+        } else {
+            None
+        }
+    }
+
+    fn take_group(&mut self) -> Vec<TimedNote> {
+        self.pending.drain(0..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::music_entities::{Note, Pitch};
+
+    const WINDOW: Duration = Duration::from_millis(100);
+
+    fn note(note: Note, start: Instant, millis: u64) -> TimedNote {
+        TimedNote {
+            pitch: Pitch::new(note, 4),
+            timestamp: start + Duration::from_millis(millis),
+        }
+    }
+
+    fn pitches(group: &[TimedNote]) -> Vec<Note> {
+        group.iter().map(|timed| timed.pitch.note).collect()
+    }
+
+    #[test]
+    fn poll_without_notes_returns_nothing() {
+        let mut grouper = ChordGrouper::new(WINDOW);
+        assert!(grouper.poll(Instant::now()).is_none());
+    }
+
+    #[test]
+    fn group_closes_only_after_the_window() {
+        let start = Instant::now();
+        let mut grouper = ChordGrouper::new(WINDOW);
+        let closed = grouper.add_notes(&[note(Note::C, start, 0), note(Note::E, start, 50)]);
+        assert!(closed.is_empty());
+        // A note exactly at the window's end still belongs to the group.
+        assert!(grouper.add_notes(&[note(Note::G, start, 100)]).is_empty());
+        assert!(grouper.poll(start + WINDOW).is_none());
+        let group = grouper.poll(start + WINDOW + Duration::from_millis(1));
+        assert_eq!(
+            group.as_deref().map(pitches),
+            Some(vec![Note::C, Note::E, Note::G])
+        );
+        assert!(grouper.poll(start + WINDOW * 10).is_none());
+    }
+
+    #[test]
+    fn late_note_starts_a_new_group() {
+        let start = Instant::now();
+        let mut grouper = ChordGrouper::new(WINDOW);
+        let closed = grouper.add_notes(&[
+            note(Note::C, start, 0),
+            note(Note::E, start, 20),
+            note(Note::F, start, 150),
+            note(Note::A, start, 200),
+        ]);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(pitches(&closed[0]), vec![Note::C, Note::E]);
+        // The second group's window runs from its own first note.
+        assert!(grouper.poll(start + Duration::from_millis(250)).is_none());
+        let group = grouper.poll(start + Duration::from_millis(251));
+        assert_eq!(group.as_deref().map(pitches), Some(vec![Note::F, Note::A]));
+    }
+}
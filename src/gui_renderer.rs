@@ -1,12 +1,201 @@
 use rand::Rng;
-use wgpu::{self, core::device::queue, Backends};
-use winit::{self, event::WindowEvent, window::Window};
+use wgpu::{self, util::DeviceExt, Backends};
+use winit::{self, window::Window};
+
+use crate::{music_entities::Note, sequencer::Sequencer};
+
+/// A filled rectangle in window coordinates from (0, 0) top left to (1, 1)
+/// bottom right.
+#[derive(Debug, Clone, Copy)]
+pub struct Quad {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub color: [f32; 4],
+}
+
+// Steps drawn per row of the sequencer grid.
+const STEPS_PER_ROW: usize = 16;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct Vertex {
+    position: [f32; 2],
+    color: [f32; 4],
+}
+
+impl Vertex {
+    const ATTRIBUTES: [wgpu::VertexAttribute; 2] =
+        wgpu::vertex_attr_array![0 => Float32x2, 1 => Float32x4];
+
+    fn layout() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+}
+
+fn quads_to_vertices(quads: &[Quad]) -> Vec<Vertex> {
+    let mut vertices = Vec::with_capacity(quads.len() * 6);
+    for quad in quads {
+        // Convert to clip space where y points up.
+        let left = quad.x * 2.0 - 1.0;
+        let right = (quad.x + quad.width) * 2.0 - 1.0;
+        let top = 1.0 - quad.y * 2.0;
+        let bottom = 1.0 - (quad.y + quad.height) * 2.0;
+        for position in [
+            [left, top],
+            [left, bottom],
+            [right, bottom],
+            [left, top],
+            [right, bottom],
+            [right, top],
+        ] {
+            vertices.push(Vertex {
+                position,
+                color: quad.color,
+            });
+        }
+    }
+    vertices
+}
+
+/// Quads for a one octave keyboard from C to B along the bottom of the
+/// window, with the `highlighted` keys drawn in a brighter color.
+pub fn keyboard_quads(highlighted: &[Note]) -> Vec<Quad> {
+    const WHITE_KEYS: [Note; 7] = [
+        Note::C,
+        Note::D,
+        Note::E,
+        Note::F,
+        Note::G,
+        Note::A,
+        Note::B,
+    ];
+    // Black keys and the white key they sit to the right of.
+    const BLACK_KEYS: [(Note, usize); 5] = [
+        (Note::CsharpDflat, 0),
+        (Note::DsharpEflat, 1),
+        (Note::FsharpGflat, 3),
+        (Note::GsharpAflat, 4),
+        (Note::ASharpBFlat, 5),
+    ];
+    let white_width = 1.0 / WHITE_KEYS.len() as f32;
+    let mut quads = Vec::new();
+
+    for (index, note) in WHITE_KEYS.iter().enumerate() {
+        let color = if highlighted.contains(note) {
+            [0.55, 0.85, 0.55, 1.0]
+        } else {
+            [0.95, 0.95, 0.95, 1.0]
+        };
+        quads.push(Quad {
+            x: index as f32 * white_width + 0.004,
+            y: 0.6,
+            width: white_width - 0.008,
+            height: 0.38,
+            color,
+        });
+    }
+    for (note, white_index) in BLACK_KEYS.iter() {
+        let color = if highlighted.contains(note) {
+            [0.15, 0.5, 0.15, 1.0]
+        } else {
+            [0.05, 0.05, 0.05, 1.0]
+        };
+        quads.push(Quad {
+            x: (*white_index as f32 + 0.7) * white_width,
+            y: 0.6,
+            width: white_width * 0.6,
+            height: 0.23,
+            color,
+        });
+    }
+    quads
+}
+
+/// Quads for the sequencer above the keyboard: a row of patterns, then the
+/// steps of the selected pattern. Steps that are on fill up to their
+/// velocity and across to their gate, fainter the lower their probability.
+pub fn sequencer_quads(sequencer: &Sequencer) -> Vec<Quad> {
+    let sequence = sequencer.get_sequence();
+    let selected_pattern = sequencer.get_selected_pattern();
+    let playing_step = sequencer.get_playing_step();
+    let mut quads = Vec::new();
+
+    let pattern_width = 1.0 / sequence.patterns.len().max(STEPS_PER_ROW) as f32;
+    for index in 0..sequence.patterns.len() {
+        let color = match (index == selected_pattern, playing_step) {
+            (true, _) => [0.9, 0.8, 0.3, 1.0],
+            (false, Some((playing, _))) if playing == index => [0.3, 0.6, 0.9, 1.0],
+            _ => [0.35, 0.35, 0.35, 1.0],
+        };
+        quads.push(Quad {
+            x: index as f32 * pattern_width + 0.004,
+            y: 0.03,
+            width: pattern_width - 0.008,
+            height: 0.04,
+            color,
+        });
+    }
+
+    let steps = &sequence.patterns[selected_pattern].steps;
+    let step_width = 1.0 / STEPS_PER_ROW as f32;
+    let row_height = 0.22;
+    for (index, step) in steps.iter().enumerate() {
+        let x = (index % STEPS_PER_ROW) as f32 * step_width + 0.004;
+        let y = 0.1 + (index / STEPS_PER_ROW) as f32 * (row_height + 0.02);
+        let width = step_width - 0.008;
+        if index == sequencer.get_selected_step() {
+            // Frame around the cursor, red while entering notes.
+            quads.push(Quad {
+                x: x - 0.003,
+                y: y - 0.006,
+                width: width + 0.006,
+                height: row_height + 0.012,
+                color: match sequencer.is_step_edit() {
+                    true => [0.9, 0.3, 0.3, 1.0],
+                    false => [0.9, 0.8, 0.3, 1.0],
+                },
+            });
+        }
+        let playing = playing_step == Some((selected_pattern, index));
+        quads.push(Quad {
+            x,
+            y,
+            width,
+            height: row_height,
+            color: match playing {
+                true => [0.4, 0.4, 0.4, 1.0],
+                false => [0.2, 0.2, 0.2, 1.0],
+            },
+        });
+        if step.active {
+            let fill = step.velocity as f32 / 127.0 * row_height;
+            let strength = 0.3 + 0.7 * step.probability.clamp(0.0, 1.0);
+            quads.push(Quad {
+                x,
+                y: y + row_height - fill,
+                width: width * step.gate.clamp(0.1, 1.0),
+                height: fill,
+                color: [0.3 * strength, 0.6 * strength, 0.95 * strength, 1.0],
+            });
+        }
+    }
+    quads
+}
 
 pub struct State<'a> {
     surface: wgpu::Surface<'a>,
     device: wgpu::Device,
     queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
+    render_pipeline: wgpu::RenderPipeline,
+    quads: Vec<Quad>,
+    clear_color: wgpu::Color,
     pub size: winit::dpi::PhysicalSize<u32>,
 }
 
@@ -19,7 +208,7 @@ impl<'a> State<'a> {
             ..Default::default()
         });
 
-        let surface = unsafe { instance.create_surface(window) }.unwrap();
+        let surface = instance.create_surface(window).unwrap();
         let adapter = instance
             .request_adapter(&wgpu::RequestAdapterOptions {
                 power_preference: wgpu::PowerPreference::default(),
@@ -66,15 +255,63 @@ impl<'a> State<'a> {
             desired_maximum_frame_latency: 2,
         };
 
+        let shader = device.create_shader_module(wgpu::include_wgsl!("shader.wgsl"));
+        let render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Render Pipeline Layout"),
+                bind_group_layouts: &[],
+                push_constant_ranges: &[],
+            });
+        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Render Pipeline"),
+            layout: Some(&render_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[Vertex::layout()],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: config.format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                ..Default::default()
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
         Self {
             surface,
             device,
             queue,
             config,
+            render_pipeline,
+            quads: Vec::new(),
+            clear_color: wgpu::Color {
+                r: 0.1,
+                g: 0.2,
+                b: 0.3,
+                a: 1.0,
+            },
             size,
         }
     }
 
+    /// Replaces the rectangles drawn on top of the background.
+    pub fn set_quads(&mut self, quads: Vec<Quad>) {
+        self.quads = quads;
+    }
+
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
             self.size = new_size;
@@ -84,10 +321,6 @@ impl<'a> State<'a> {
         }
     }
 
-    pub fn input(&mut self, event: &WindowEvent) -> bool {
-        todo!()
-    }
-
     pub fn update(&mut self) {}
 
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
@@ -101,20 +334,23 @@ impl<'a> State<'a> {
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("Render Encoder"),
             });
+        let vertices = quads_to_vertices(&self.quads);
+        let vertex_buffer = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Vertex Buffer"),
+                contents: bytemuck::cast_slice(&vertices),
+                usage: wgpu::BufferUsages::VERTEX,
+            });
 
         {
-            let _render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
                     view: &view,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
-                            a: 1.0,
-                        }),
+                        load: wgpu::LoadOp::Clear(self.clear_color),
                         store: wgpu::StoreOp::Store,
                     },
                 })],
@@ -122,6 +358,11 @@ impl<'a> State<'a> {
                 occlusion_query_set: None,
                 timestamp_writes: None,
             });
+            if !vertices.is_empty() {
+                render_pass.set_pipeline(&self.render_pipeline);
+                render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+                render_pass.draw(0..vertices.len() as u32, 0..1);
+            }
         }
 
         // submit will accept anything that implements IntoIter
@@ -132,47 +373,16 @@ impl<'a> State<'a> {
     }
 
     pub fn render_random_color(&mut self) -> Result<(), wgpu::SurfaceError> {
-        let output = self.surface.get_current_texture()?;
-        let view = output
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
-        // Create command buffer to send to gpu.
-        let mut encoder = self
-            .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: Some("Render Encoder"),
-            });
-
-        {
-            let mut rng = rand::thread_rng();
-            let random_r: f64 = rng.gen();
-            let random_g: f64 = rng.gen();
-            let random_b: f64 = rng.gen();
-            let _render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Random"),
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: random_r,
-                            g: random_b,
-                            b: random_g,
-                            a: 1.0,
-                        }),
-                        store: wgpu::StoreOp::Store,
-                    },
-                })],
-                depth_stencil_attachment: None,
-                occlusion_query_set: None,
-                timestamp_writes: None,
-            });
-        }
-
-        // submit will accept anything that implements IntoIter
-        self.queue.submit(std::iter::once(encoder.finish()));
-        output.present();
-
-        Ok(())
+        let mut rng = rand::thread_rng();
+        let random_r: f64 = rng.gen();
+        let random_g: f64 = rng.gen();
+        let random_b: f64 = rng.gen();
+        self.clear_color = wgpu::Color {
+            r: random_r,
+            g: random_b,
+            b: random_g,
+            a: 1.0,
+        };
+        self.render()
     }
 }
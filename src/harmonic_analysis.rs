@@ -0,0 +1,453 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Write},
+    time::Instant,
+};
+
+use crate::music_entities::{
+    Chord, ChordEvent, ChordQuality, Extension, Inversion, Key, Mode, NamingSystem, Note,
+};
+
+/// A chord's function relative to a key, e.g. "ii", "V7", "bVI" or "V/V".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomanNumeral {
+    /// Scale degree of the root, 1 to 7.
+    pub degree: u8,
+    /// How far the root is raised or lowered from the scale degree.
+    pub accidental: i8,
+    pub quality: ChordQuality,
+    pub extensions: Vec<Extension>,
+    pub inversion: Option<Inversion>,
+    /// For secondary chords, the numeral of the chord they lead to.
+    pub secondary_of: Option<Box<RomanNumeral>>,
+    /// Borrowed from the parallel major or minor key.
+    pub borrowed: bool,
+}
+
+impl RomanNumeral {
+    /// Labels `chord` by its function in `key`. Chords outside the key are
+    /// read as secondary dominants or leading-tone chords when they resolve
+    /// to a major or minor scale degree, then as borrowed from the parallel
+    /// key, and otherwise as chromatic chords on an altered degree.
+    pub fn analyze(chord: &Chord, key: &Key) -> RomanNumeral {
+        let (degree, accidental) = scale_degree(chord.root, key, key);
+        let mut numeral = RomanNumeral {
+            degree,
+            accidental,
+            quality: chord.quality,
+            extensions: chord.extensions.clone(),
+            inversion: chord.inversion(),
+            secondary_of: None,
+            borrowed: false,
+        };
+        if is_diatonic(chord, key) {
+            return numeral;
+        }
+        if let Some(target) = secondary_target(chord, key) {
+            // Secondary chords are named as the dominant or leading tone of
+            // the chord they resolve to.
+            numeral.degree = match chord.root.transpose(5) == target.root {
+                true => 5,
+                false => 7,
+            };
+            numeral.accidental = 0;
+            numeral.secondary_of = Some(Box::new(target.numeral));
+            return numeral;
+        }
+        let parallel_key = Key::new(
+            key.tonic,
+            match key.mode {
+                Mode::Major => Mode::Minor,
+                Mode::Minor => Mode::Major,
+            },
+        );
+        if is_diatonic(chord, &parallel_key) {
+            // Spell the root as it is in the key it's borrowed from, so Ab
+            // major in C is bVI rather than #V.
+            (numeral.degree, numeral.accidental) = scale_degree(chord.root, key, &parallel_key);
+            numeral.borrowed = true;
+        }
+        numeral
+    }
+
+    fn is_minor_quality(&self) -> bool {
+        matches!(
+            self.quality,
+            ChordQuality::Minor
+                | ChordQuality::Minor6
+                | ChordQuality::Minor7
+                | ChordQuality::Diminished
+                | ChordQuality::HalfDiminished7
+                | ChordQuality::Diminished7
+        )
+    }
+
+    fn has_seventh(&self) -> bool {
+        matches!(
+            self.quality,
+            ChordQuality::Dominant7
+                | ChordQuality::Major7
+                | ChordQuality::Minor7
+                | ChordQuality::HalfDiminished7
+                | ChordQuality::Diminished7
+        )
+    }
+
+    /// The quality written between the numeral and the figure. Major and
+    /// minor show in the numeral's case and dominant sevenths by the figure.
+    fn quality_symbol(&self) -> &'static str {
+        match self.quality {
+            ChordQuality::Diminished | ChordQuality::Diminished7 => "°",
+            ChordQuality::HalfDiminished7 => "ø",
+            ChordQuality::Augmented => "+",
+            ChordQuality::Major7 => "M",
+            _ => "",
+        }
+    }
+
+    /// The inversion figure, or 7 or 9 for seventh and ninth chords in root
+    /// position.
+    fn figure(&self) -> &'static str {
+        match (self.inversion_figure(), self.has_seventh()) {
+            ("", true) if self.has_ninth() => "9",
+            ("", true) => "7",
+            (figure, _) => figure,
+        }
+    }
+
+    /// Tones the quality and the figure don't show, written after them.
+    fn added_tones(&self) -> String {
+        let mut added = match self.quality {
+            ChordQuality::Sus2 => "sus2",
+            ChordQuality::Sus4 => "sus4",
+            ChordQuality::Major6 | ChordQuality::Minor6 => "add6",
+            _ => "",
+        }
+        .to_string();
+        if self.has_ninth() && self.figure() != "9" {
+            added.push_str("add9");
+        }
+        added
+    }
+
+    fn has_ninth(&self) -> bool {
+        self.extensions.contains(&Extension::Ninth)
+    }
+
+    /// Figured bass for inversions, "6" and "64" for triads and "65", "43"
+    /// and "42" for seventh chords.
+    fn inversion_figure(&self) -> &'static str {
+        match (self.inversion, self.has_seventh()) {
+            (Some(Inversion::First), false) => "6",
+            (Some(Inversion::Second), false) => "64",
+            (Some(Inversion::First), true) => "65",
+            (Some(Inversion::Second), true) => "43",
+            (Some(Inversion::Third), true) => "42",
+            _ => "",
+        }
+    }
+}
+
+impl fmt::Display for RomanNumeral {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const NUMERALS: [&str; 7] = ["I", "II", "III", "IV", "V", "VI", "VII"];
+        let accidental = if self.accidental >= 0 {
+            "#".repeat(self.accidental as usize)
+        } else {
+            "b".repeat(-self.accidental as usize)
+        };
+        let numeral = NUMERALS[(self.degree - 1) as usize];
+        let numeral = match self.is_minor_quality() {
+            true => numeral.to_lowercase(),
+            false => numeral.to_string(),
+        };
+        write!(
+            f,
+            "{}{}{}{}{}",
+            accidental,
+            numeral,
+            self.quality_symbol(),
+            self.figure(),
+            self.added_tones()
+        )?;
+        if let Some(target) = &self.secondary_of {
+            write!(f, "/{}", target)?;
+        }
+        if self.borrowed {
+            write!(f, " (borrowed)")?;
+        }
+        Ok(())
+    }
+}
+
+/// The scale degree, 1 to 7, of `note` and how far it is raised or lowered
+/// from the key signature, with the note spelled as in `spelling_key`. The
+/// raised sixth and seventh of minor keys count as diatonic.
+fn scale_degree(note: Note, key: &Key, spelling_key: &Key) -> (u8, i8) {
+    let spelled = spelling_key.spell(note);
+    let scale = key.scale();
+    let degree = (spelled.letter.index() + 7 - key.tonic.letter.index()) % 7;
+    let mut accidental = spelled.accidental - scale[degree as usize].accidental;
+    if key.mode == Mode::Minor && (degree == 5 || degree == 6) && accidental == 1 {
+        accidental = 0;
+    }
+    (degree + 1, accidental)
+}
+
+fn is_diatonic(chord: &Chord, key: &Key) -> bool {
+    chord
+        .notes()
+        .into_iter()
+        .all(|note| scale_degree(note, key, key).1 == 0)
+}
+
+struct SecondaryTarget {
+    root: Note,
+    numeral: RomanNumeral,
+}
+
+/// The chord a secondary dominant or leading-tone chord resolves to, if it
+/// is a major or minor triad on a scale degree other than the tonic.
+fn secondary_target(chord: &Chord, key: &Key) -> Option<SecondaryTarget> {
+    let target_root = match chord.quality {
+        ChordQuality::Major | ChordQuality::Dominant7 => chord.root.transpose(5),
+        ChordQuality::Diminished | ChordQuality::Diminished7 | ChordQuality::HalfDiminished7 => {
+            chord.root.transpose(1)
+        }
+        _ => return None,
+    };
+    let (degree, accidental) = scale_degree(target_root, key, key);
+    if degree == 1 || accidental != 0 {
+        return None;
+    }
+    let target_quality = key.diatonic_chord(degree, false)?.quality;
+    if !matches!(target_quality, ChordQuality::Major | ChordQuality::Minor) {
+        return None;
+    }
+    Some(SecondaryTarget {
+        root: target_root,
+        numeral: RomanNumeral {
+            degree,
+            accidental: 0,
+            quality: target_quality,
+            extensions: Vec::new(),
+            inversion: None,
+            secondary_of: None,
+            borrowed: false,
+        },
+    })
+}
+
+pub struct ProgressionEntry {
+    pub seconds: f32,
+    pub chord: Chord,
+    pub key: Key,
+    pub numeral: RomanNumeral,
+}
+
+/// Every chord recognized since the app started, with its function in the
+/// key it was played in.
+pub struct ProgressionHistory {
+    start: Instant,
+    entries: Vec<ProgressionEntry>,
+}
+
+impl ProgressionHistory {
+    pub fn new(start: Instant) -> Self {
+        ProgressionHistory {
+            start,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Analyzes the best candidate of a chord event and adds it to the
+    /// history.
+    pub fn add_chord_event(
+        &mut self,
+        chord_event: &ChordEvent,
+        key: &Key,
+    ) -> Option<&ProgressionEntry> {
+        let chord = chord_event.best()?.chord.clone();
+        let played_at = chord_event
+            .notes
+            .iter()
+            .map(|timed| timed.timestamp)
+            .min()?;
+        self.entries.push(ProgressionEntry {
+            seconds: played_at
+                .saturating_duration_since(self.start)
+                .as_secs_f32(),
+            numeral: RomanNumeral::analyze(&chord, key),
+            chord,
+            key: *key,
+        });
+        self.entries.last()
+    }
+
+    /// The last `count` chords of the progression as numerals, e.g.
+    /// "... - vi - ii65 - V7", so it stays short however long the session.
+    pub fn summary(&self, count: usize) -> String {
+        let skipped = self.entries.len().saturating_sub(count);
+        let mut numerals: Vec<String> = self.entries[skipped..]
+            .iter()
+            .map(|entry| entry.numeral.to_string())
+            .collect();
+        if skipped > 0 {
+            numerals.insert(0, String::from("..."));
+        }
+        numerals.join(" - ")
+    }
+
+    /// Writes one line per chord with its time, key, chord name and numeral,
+    /// separated by tabs.
+    pub fn export(&self, path: &str, naming_system: NamingSystem) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        writeln!(file, "time\tkey\tchord\tnumeral")?;
+        for entry in self.entries.iter() {
+            writeln!(
+                file,
+                "{:.2}\t{}\t{}\t{}",
+                entry.seconds,
+                entry.key,
+                entry.key.name_chord(&entry.chord, naming_system),
+                entry.numeral
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::music_entities::{Pitch, TimedNote};
+
+    /// The chord in root position, each note stacked above the one before.
+    fn chord_event(notes: [Note; 3], timestamp: Instant) -> ChordEvent {
+        let mut pitches: Vec<Pitch> = Vec::new();
+        for note in notes {
+            let mut pitch = Pitch::new(note, 4);
+            if pitches.last().is_some_and(|last| pitch < *last) {
+                pitch = Pitch::new(note, 5);
+            }
+            pitches.push(pitch);
+        }
+        let notes = pitches
+            .into_iter()
+            .map(|pitch| TimedNote { pitch, timestamp })
+            .collect();
+        ChordEvent::recognize(notes).unwrap()
+    }
+
+    #[test]
+    fn summary_shows_only_the_last_chords() {
+        let start = Instant::now();
+        let key = Key::from_note(Note::C, Mode::Major);
+        let mut progression = ProgressionHistory::new(start);
+        assert_eq!(progression.summary(2), "");
+        let chords = [
+            [Note::C, Note::E, Note::G],
+            [Note::A, Note::C, Note::E],
+            [Note::F, Note::A, Note::C],
+            [Note::G, Note::B, Note::D],
+        ];
+        for chord in chords {
+            progression.add_chord_event(&chord_event(chord, start), &key);
+        }
+        assert_eq!(progression.summary(4), "I - vi - IV - V");
+        assert_eq!(progression.summary(2), "... - IV - V");
+    }
+
+    fn numeral(chord: Chord, key: &Key) -> String {
+        RomanNumeral::analyze(&chord, key).to_string()
+    }
+
+    fn chord(root: Note, quality: ChordQuality) -> Chord {
+        Chord::new(root, quality, Vec::new())
+    }
+
+    #[test]
+    fn inversions_show_their_figure() {
+        let key = Key::from_note(Note::C, Mode::Major);
+        let c = chord(Note::C, ChordQuality::Major);
+        assert_eq!(numeral(c.clone(), &key), "I");
+        assert_eq!(numeral(c.clone().with_bass(Note::E), &key), "I6");
+        assert_eq!(numeral(c.with_bass(Note::G), &key), "I64");
+        let g7 = chord(Note::G, ChordQuality::Dominant7);
+        assert_eq!(numeral(g7.clone(), &key), "V7");
+        assert_eq!(numeral(g7.clone().with_bass(Note::B), &key), "V65");
+        assert_eq!(numeral(g7.clone().with_bass(Note::D), &key), "V43");
+        assert_eq!(numeral(g7.with_bass(Note::F), &key), "V42");
+        let cmaj7 = chord(Note::C, ChordQuality::Major7);
+        assert_eq!(numeral(cmaj7.clone(), &key), "IM7");
+        assert_eq!(numeral(cmaj7.with_bass(Note::E), &key), "IM65");
+        let bm7b5 = chord(Note::B, ChordQuality::HalfDiminished7);
+        assert_eq!(numeral(bm7b5.clone(), &key), "viiø7");
+        assert_eq!(numeral(bm7b5.with_bass(Note::D), &key), "viiø65");
+        let bdim = chord(Note::B, ChordQuality::Diminished);
+        assert_eq!(numeral(bdim.with_bass(Note::D), &key), "vii°6");
+        assert_eq!(numeral(chord(Note::G, ChordQuality::Sus4), &key), "Vsus4");
+        assert_eq!(
+            numeral(chord(Note::F, ChordQuality::Major6), &key),
+            "IVadd6"
+        );
+    }
+
+    #[test]
+    fn ninths_replace_the_seventh_in_root_position() {
+        let key = Key::from_note(Note::C, Mode::Major);
+        let g9 = Chord::new(Note::G, ChordQuality::Dominant7, vec![Extension::Ninth]);
+        assert_eq!(numeral(g9.clone(), &key), "V9");
+        assert_eq!(numeral(g9.with_bass(Note::B), &key), "V65add9");
+        let dm_add9 = Chord::new(Note::D, ChordQuality::Minor, vec![Extension::Ninth]);
+        assert_eq!(numeral(dm_add9.clone(), &key), "iiadd9");
+        assert_eq!(numeral(dm_add9.with_bass(Note::F), &key), "ii6add9");
+    }
+
+    #[test]
+    fn secondary_chords_name_their_target() {
+        let key = Key::from_note(Note::C, Mode::Major);
+        assert_eq!(numeral(chord(Note::D, ChordQuality::Major), &key), "V/V");
+        assert_eq!(
+            numeral(chord(Note::D, ChordQuality::Dominant7), &key),
+            "V7/V"
+        );
+        assert_eq!(
+            numeral(chord(Note::FsharpGflat, ChordQuality::Diminished), &key),
+            "vii°/V"
+        );
+        assert_eq!(
+            numeral(chord(Note::FsharpGflat, ChordQuality::Diminished7), &key),
+            "vii°7/V"
+        );
+        assert_eq!(
+            numeral(chord(Note::A, ChordQuality::Dominant7), &key),
+            "V7/ii"
+        );
+        assert_eq!(numeral(chord(Note::E, ChordQuality::Major), &key), "V/vi");
+        let e7 = chord(Note::E, ChordQuality::Dominant7).with_bass(Note::GsharpAflat);
+        assert_eq!(numeral(e7, &key), "V65/vi");
+    }
+
+    #[test]
+    fn borrowed_chords_are_spelled_from_the_parallel_key() {
+        let key = Key::from_note(Note::C, Mode::Major);
+        assert_eq!(
+            numeral(chord(Note::GsharpAflat, ChordQuality::Major), &key),
+            "bVI (borrowed)"
+        );
+        assert_eq!(
+            numeral(chord(Note::ASharpBFlat, ChordQuality::Major), &key),
+            "bVII (borrowed)"
+        );
+        assert_eq!(
+            numeral(chord(Note::F, ChordQuality::Minor), &key),
+            "iv (borrowed)"
+        );
+        // The raised seventh of a minor key is part of it.
+        let minor = Key::from_note(Note::A, Mode::Minor);
+        assert_eq!(numeral(chord(Note::E, ChordQuality::Major), &minor), "V");
+        assert_eq!(numeral(chord(Note::C, ChordQuality::Major), &minor), "III");
+    }
+}
@@ -1,44 +1,211 @@
 use std::{
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
-    },
-    time::Duration,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use winit::{
+    event::KeyEvent,
+    keyboard::{Key, ModifiersState, NamedKey},
 };
 
-use winit::{event::KeyEvent, keyboard::Key};
+use crate::{midi_input::MidiInputEvent, music_entities::Pitch};
+
+#[derive(Clone, Debug)]
+pub struct TimedKeyEvent {
+    pub event: KeyEvent,
+    pub timestamp: Instant,
+    /// Modifiers held when the key was pressed.
+    pub modifiers: ModifiersState,
+}
+
+/// A note played on a MIDI controller.
+#[derive(Clone, Copy, Debug)]
+pub struct MidiNote {
+    pub pitch: Pitch,
+    pub velocity: u8,
+    pub timestamp: Instant,
+}
+
+/// Everything played on the keyboard or a MIDI controller, in the order it
+/// happened, for recording performances.
+#[derive(Clone, Debug)]
+pub enum InputEvent {
+    NotePressed {
+        key_event: TimedKeyEvent,
+        octave: u8,
+    },
+    NoteReleased(TimedKeyEvent),
+    OctaveChanged {
+        octave: u8,
+        timestamp: Instant,
+    },
+    Pedal {
+        down: bool,
+        timestamp: Instant,
+    },
+    MidiNotePressed(MidiNote),
+    MidiNoteReleased {
+        pitch: Pitch,
+        timestamp: Instant,
+    },
+    PitchBend {
+        bend: i16,
+        timestamp: Instant,
+    },
+}
+
+/// Actions triggered by keys that don't play notes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    ToggleScaleLock,
+    ToggleScaleHighlight,
+    ExportProgression,
+    CycleChordMode,
+    ToggleArpeggiator,
+    CycleArpPattern,
+    ToggleArpLatch,
+    ToggleTransport,
+    ToggleMetronome,
+    TapTempo,
+    ToggleRecording,
+    ArmRecording,
+    ExportMidi,
+    TogglePlayback,
+    SeekBackward,
+    SeekForward,
+    MarkLoopPoint,
+    SlowDown,
+    SpeedUp,
+    SelectTrack,
+    ToggleTrackMute,
+    ToggleTrackSolo,
+    SaveSession,
+    LoadSession,
+    QuantizeTake,
+    UndoQuantize,
+    ToggleLooper,
+    UndoLayer,
+    ClearLoop,
+    SelectLayer,
+    ToggleLayerMute,
+    ToggleSequencer,
+    ToggleStepEdit,
+    PreviousStep,
+    NextStep,
+    IncreaseStepValue,
+    DecreaseStepValue,
+    ToggleStep,
+    CycleStepParameter,
+    PreviousPattern,
+    NextPattern,
+    ChainPattern,
+    ResetChain,
+    ToggleStepCount,
+    ExportMusicXml,
+    ExportLilyPond,
+    ExportAbc,
+}
 
 #[derive(Clone)]
 pub struct InputHandler {
     accepted_note_keys: [&'static str; 12],
     accepted_octave_keys: [&'static str; 3],
-    mediator_key_storage: Arc<Mutex<Vec<KeyEvent>>>,
-    key_storage: Arc<Mutex<Vec<KeyEvent>>>,
+    accepted_command_keys: &'static [(&'static str, Command)],
+    /// Commands on keys without text, like the arrow keys.
+    accepted_named_keys: &'static [(NamedKey, Command)],
+    key_storage: Arc<Mutex<Vec<TimedKeyEvent>>>,
+    held_keys: Vec<TimedKeyEvent>,
+    input_events: Vec<InputEvent>,
+    command_storage: Vec<Command>,
     number_input_storage: Option<u8>,
+    modifiers: ModifiersState,
+    midi_note_storage: Vec<MidiNote>,
+    held_midi_notes: Vec<MidiNote>,
+    pitch_bend: i16,
 }
 
 impl InputHandler {
     pub fn new(
         accepted_note_keys: [&'static str; 12],
         accepted_octave_keys: [&'static str; 3],
+        accepted_command_keys: &'static [(&'static str, Command)],
+        accepted_named_keys: &'static [(NamedKey, Command)],
     ) -> InputHandler {
         InputHandler {
             accepted_note_keys,
             accepted_octave_keys,
-            mediator_key_storage: Arc::new(Mutex::new(Vec::new())),
+            accepted_command_keys,
+            accepted_named_keys,
             key_storage: Arc::new(Mutex::new(Vec::new())),
+            held_keys: Vec::new(),
+            input_events: Vec::new(),
+            command_storage: Vec::new(),
             number_input_storage: Some(3),
+            modifiers: ModifiersState::empty(),
+            midi_note_storage: Vec::new(),
+            held_midi_notes: Vec::new(),
+            pitch_bend: 0,
         }
     }
-    pub fn add_input_to_mediator(&mut self, event: KeyEvent) {
-        if self.validate_input(&event.logical_key) && !event.repeat && event.state.is_pressed() {
-            match self
-                .accepted_note_keys
-                .contains(&event.logical_key.to_text().unwrap())
+
+    /// Stores a key press together with the time it was received, so notes
+    /// can be released right away and grouped into chords later on.
+    pub fn add_input(&mut self, event: KeyEvent, timestamp: Instant) {
+        // Space is the sustain pedal.
+        if event.logical_key == Key::Named(NamedKey::Space) && !event.repeat {
+            self.input_events.push(InputEvent::Pedal {
+                down: event.state.is_pressed(),
+                timestamp,
+            });
+            return;
+        }
+        // Held named keys repeat, e.g. to move through steps.
+        if let Key::Named(named_key) = event.logical_key {
+            if let Some(command) = self.find_named_command(named_key) {
+                if event.state.is_pressed() {
+                    self.command_storage.push(command);
+                }
+                return;
+            }
+        }
+        if self.validate_input(&event.logical_key) && !event.state.is_pressed() {
+            let key_text = event.logical_key.to_text();
+            if self
+                .held_keys
+                .iter()
+                .any(|held| held.event.logical_key.to_text() == key_text)
             {
+                self.held_keys
+                    .retain(|held| held.event.logical_key.to_text() != key_text);
+                self.input_events
+                    .push(InputEvent::NoteReleased(TimedKeyEvent {
+                        event,
+                        timestamp,
+                        modifiers: self.modifiers,
+                    }));
+            }
+            return;
+        }
+        if self.validate_input(&event.logical_key) && !event.repeat && event.state.is_pressed() {
+            let key_text = event.logical_key.to_text().unwrap();
+            if let Some(command) = self.find_command(key_text) {
+                self.command_storage.push(command);
+                return;
+            }
+            match self.accepted_note_keys.contains(&key_text) {
                 true => {
-                    if let Ok(mut mediator) = self.mediator_key_storage.lock() {
-                        mediator.push(event)
+                    let timed_event = TimedKeyEvent {
+                        event,
+                        timestamp,
+                        modifiers: self.modifiers,
+                    };
+                    self.held_keys.push(timed_event.clone());
+                    self.input_events.push(InputEvent::NotePressed {
+                        key_event: timed_event.clone(),
+                        octave: self.get_selected_octave(),
+                    });
+                    if let Ok(mut key_storage) = self.key_storage.lock() {
+                        key_storage.push(timed_event)
                     }
                 }
                 false => {
@@ -46,45 +213,83 @@ impl InputHandler {
                     if let Some(string) = event.logical_key.to_text() {
                         if let Ok(parsed_string) = string.parse::<u8>() {
                             self.number_input_storage = Some(parsed_string);
+                            self.input_events.push(InputEvent::OctaveChanged {
+                                octave: parsed_string,
+                                timestamp,
+                            });
                         }
                     }
                 }
             }
         }
     }
-    pub fn move_input_from_mediator_to_storage(
-        input_handler: Arc<Mutex<InputHandler>>,
-        delay: Duration,
-    ) {
-        static IS_RUNNING: AtomicBool = AtomicBool::new(false);
-        {
-            match IS_RUNNING.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst) {
-                Ok(_) => {}
-                Err(_) => return,
-            }
-            tokio::task::block_in_place(|| std::thread::sleep(delay));
 
-            IS_RUNNING.store(false, Ordering::SeqCst);
-            if let Ok(input_handler) = input_handler.lock() {
-                if let Ok(mut key_storage) = input_handler.key_storage.lock() {
-                    if let Ok(mut mediator) = input_handler.mediator_key_storage.lock() {
-                        key_storage.extend(mediator.drain(0..));
-                    }
+    /// Stores what a MIDI controller played alongside the keyboard input.
+    pub fn add_midi_input(&mut self, event: MidiInputEvent, timestamp: Instant) {
+        match event {
+            MidiInputEvent::NoteOn { pitch, velocity } => {
+                let midi_note = MidiNote {
+                    pitch,
+                    velocity,
+                    timestamp,
+                };
+                self.held_midi_notes.push(midi_note);
+                self.midi_note_storage.push(midi_note);
+                self.input_events
+                    .push(InputEvent::MidiNotePressed(midi_note));
+            }
+            MidiInputEvent::NoteOff { pitch } => {
+                if self.held_midi_notes.iter().any(|held| held.pitch == pitch) {
+                    self.held_midi_notes.retain(|held| held.pitch != pitch);
+                    self.input_events
+                        .push(InputEvent::MidiNoteReleased { pitch, timestamp });
                 }
             }
+            MidiInputEvent::Sustain { down } => self
+                .input_events
+                .push(InputEvent::Pedal { down, timestamp }),
+            MidiInputEvent::PitchBend(bend) => {
+                self.pitch_bend = bend;
+                self.input_events
+                    .push(InputEvent::PitchBend { bend, timestamp });
+            }
         }
     }
 
+    pub fn set_modifiers(&mut self, modifiers: ModifiersState) {
+        self.modifiers = modifiers;
+    }
+
+    fn find_command(&self, key_text: &str) -> Option<Command> {
+        self.accepted_command_keys
+            .iter()
+            .find(|(command_key, _)| *command_key == key_text)
+            .map(|(_, command)| *command)
+    }
+
+    fn find_named_command(&self, named_key: NamedKey) -> Option<Command> {
+        self.accepted_named_keys
+            .iter()
+            .find(|(command_key, _)| *command_key == named_key)
+            .map(|(_, command)| *command)
+    }
+
     fn validate_input(&self, key: &Key) -> bool {
         match key.to_text() {
             Some(char) => {
-                self.accepted_note_keys.contains(&char) || self.accepted_octave_keys.contains(&char)
+                self.accepted_note_keys.contains(&char)
+                    || self.accepted_octave_keys.contains(&char)
+                    || self.find_command(char).is_some()
             }
             None => false,
         }
     }
 
-    pub fn get_inputs(&mut self) -> Vec<KeyEvent> {
+    pub fn get_commands(&mut self) -> Vec<Command> {
+        self.command_storage.drain(0..).collect()
+    }
+
+    pub fn get_inputs(&mut self) -> Vec<TimedKeyEvent> {
         if let Ok(mut key_storage) = self.key_storage.lock() {
             key_storage.drain(0..).collect()
         } else {
@@ -92,11 +297,34 @@ impl InputHandler {
         }
     }
 
-    pub fn get_selected_octave(&mut self) -> u8 {
-        if let Some(number) = self.number_input_storage {
-            number
-        } else {
-            3
-        }
+    /// MIDI notes pressed since the last call.
+    pub fn get_midi_notes(&mut self) -> Vec<MidiNote> {
+        self.midi_note_storage.drain(0..).collect()
+    }
+
+    /// MIDI notes that are held down right now, in the order they were pressed.
+    pub fn get_held_midi_notes(&self) -> Vec<MidiNote> {
+        self.held_midi_notes.clone()
+    }
+
+    pub fn get_pitch_bend(&self) -> i16 {
+        self.pitch_bend
+    }
+
+    pub fn get_input_events(&mut self) -> Vec<InputEvent> {
+        self.input_events.drain(0..).collect()
+    }
+
+    /// Note keys that are pressed right now, in the order they were pressed.
+    pub fn get_held_keys(&self) -> Vec<TimedKeyEvent> {
+        self.held_keys.clone()
+    }
+
+    pub fn set_selected_octave(&mut self, octave: u8) {
+        self.number_input_storage = Some(octave);
+    }
+
+    pub fn get_selected_octave(&self) -> u8 {
+        self.number_input_storage.unwrap_or(3)
     }
 }
@@ -0,0 +1,170 @@
+use std::collections::VecDeque;
+
+use crate::music_entities::{Key, Mode, Note, TimedNote};
+
+// Krumhansl-Kessler key profiles, starting on the tonic.
+const MAJOR_PROFILE: [f32; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f32; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyEstimate {
+    pub key: Key,
+    /// Correlation between the played notes and the key profile, -1.0 to 1.0.
+    pub correlation: f32,
+}
+
+/// Estimates the key from the most recently played notes by correlating how
+/// often each pitch class was played with the Krumhansl-Kessler profiles of
+/// all 24 major and minor keys.
+pub struct KeyDetector {
+    history: VecDeque<Note>,
+    history_size: usize,
+    min_notes: usize,
+}
+
+impl KeyDetector {
+    pub fn new(history_size: usize, min_notes: usize) -> Self {
+        KeyDetector {
+            history: VecDeque::with_capacity(history_size),
+            history_size,
+            min_notes,
+        }
+    }
+
+    pub fn add_notes(&mut self, notes: &[TimedNote]) {
+        for timed in notes {
+            if self.history.len() == self.history_size {
+                self.history.pop_front();
+            }
+            self.history.push_back(timed.pitch.note);
+        }
+    }
+
+    /// The best matching key, `None` until enough notes have been played.
+    pub fn estimate(&self) -> Option<KeyEstimate> {
+        if self.history.len() < self.min_notes {
+            return None;
+        }
+        let mut distribution = [0.0f32; 12];
+        for note in self.history.iter() {
+            distribution[note.semitone() as usize] += 1.0;
+        }
+
+        let mut best: Option<KeyEstimate> = None;
+        for tonic in Note::CHROMATIC {
+            for (mode, profile) in [(Mode::Major, MAJOR_PROFILE), (Mode::Minor, MINOR_PROFILE)] {
+                let mut rotated = [0.0f32; 12];
+                for (interval, weight) in profile.iter().enumerate() {
+                    rotated[tonic.transpose(interval as u8).semitone() as usize] = *weight;
+                }
+                let correlation = correlate(&distribution, &rotated);
+                if best.is_none_or(|best| correlation > best.correlation) {
+                    best = Some(KeyEstimate {
+                        key: Key::from_note(tonic, mode),
+                        correlation,
+                    });
+                }
+            }
+        }
+        best
+    }
+}
+
+/// Pearson correlation coefficient of two series.
+fn correlate(a: &[f32; 12], b: &[f32; 12]) -> f32 {
+    let mean_a = a.iter().sum::<f32>() / 12.0;
+    let mean_b = b.iter().sum::<f32>() / 12.0;
+    let mut covariance = 0.0;
+    let mut variance_a = 0.0;
+    let mut variance_b = 0.0;
+    for (x, y) in a.iter().zip(b.iter()) {
+        covariance += (x - mean_a) * (y - mean_b);
+        variance_a += (x - mean_a).powi(2);
+        variance_b += (y - mean_b).powi(2);
+    }
+    if variance_a == 0.0 || variance_b == 0.0 {
+        return 0.0;
+    }
+    covariance / (variance_a * variance_b).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn timed(names: &[&str]) -> Vec<TimedNote> {
+        let timestamp = Instant::now();
+        names
+            .iter()
+            .map(|name| TimedNote {
+                pitch: name.parse().unwrap(),
+                timestamp,
+            })
+            .collect()
+    }
+
+    fn detect(names: &[&str]) -> Option<Key> {
+        let mut detector = KeyDetector::new(64, 8);
+        detector.add_notes(&timed(names));
+        detector.estimate().map(|estimate| estimate.key)
+    }
+
+    #[test]
+    fn major_melodies() {
+        let c_major = [
+            "C4", "D4", "E4", "F4", "G4", "A4", "B4", "C5", "G4", "E4", "C4",
+        ];
+        assert_eq!(detect(&c_major), Some(Key::from_note(Note::C, Mode::Major)));
+        let e_flat_major = [
+            "Eb4", "F4", "G4", "Ab4", "Bb4", "C5", "D5", "Eb5", "Bb4", "G4", "Eb4",
+        ];
+        assert_eq!(
+            detect(&e_flat_major),
+            Some(Key::from_note(Note::DsharpEflat, Mode::Major))
+        );
+    }
+
+    #[test]
+    fn minor_melodies() {
+        let a_minor = [
+            "A3", "B3", "C4", "D4", "E4", "F4", "G#4", "A4", "E4", "C4", "A3",
+        ];
+        assert_eq!(detect(&a_minor), Some(Key::from_note(Note::A, Mode::Minor)));
+        let c_sharp_minor = [
+            "C#4", "D#4", "E4", "F#4", "G#4", "A4", "B#4", "C#5", "G#4", "E4", "C#4",
+        ];
+        assert_eq!(
+            detect(&c_sharp_minor),
+            Some(Key::from_note(Note::CsharpDflat, Mode::Minor))
+        );
+    }
+
+    #[test]
+    fn waits_for_the_minimum_number_of_notes() {
+        let mut detector = KeyDetector::new(64, 4);
+        detector.add_notes(&timed(&["C4", "E4", "G4"]));
+        assert_eq!(detector.estimate(), None);
+        detector.add_notes(&timed(&["C5"]));
+        let estimate = detector.estimate().unwrap();
+        assert_eq!(estimate.key, Key::from_note(Note::C, Mode::Major));
+        assert!((-1.0..=1.0).contains(&estimate.correlation));
+    }
+
+    #[test]
+    fn old_notes_leave_the_history() {
+        let mut detector = KeyDetector::new(8, 8);
+        detector.add_notes(&timed(&["C4", "E4", "G4", "C5", "G4", "E4", "C4", "G4"]));
+        detector.add_notes(&timed(&[
+            "F#4", "A#4", "C#5", "F#5", "C#5", "A#4", "F#4", "C#5",
+        ]));
+        assert_eq!(
+            detector.estimate().map(|estimate| estimate.key),
+            Some(Key::from_note(Note::FsharpGflat, Mode::Major))
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use std::{fmt::Write, fs};
+
+use crate::{
+    music_entities::{Key, Mode, Pitch, SpelledNote},
+    notation::{Clef, NotationEvent, Score, WrittenValue},
+};
+
+const VERSION: &str = "2.24.0";
+
+/// Writes `score` as a LilyPond piano staff. Pitches are written absolute
+/// and LilyPond works out which accidentals to show.
+pub fn export_lilypond(score: &Score, path: &str) -> Result<(), String> {
+    fs::write(path, lilypond(score)).map_err(|err| format!("couldn't write {}: {}", path, err))
+}
+
+fn lilypond(score: &Score) -> String {
+    let mut text = String::new();
+    let _ = writeln!(text, "\\version \"{}\"", VERSION);
+    text.push('\n');
+    text.push_str("\\score {\n");
+    text.push_str("  \\new PianoStaff <<\n");
+    for (index, staff) in score.staves.iter().enumerate() {
+        text.push_str("    \\new Staff {\n");
+        let clef = match staff.clef {
+            Clef::Treble => "treble",
+            Clef::Bass => "bass",
+        };
+        let _ = write!(
+            text,
+            "      \\clef {} {} \\time {}/{}",
+            clef,
+            key_signature(score.key),
+            score.time_signature.beats_per_bar,
+            score.time_signature.beat_value
+        );
+        if index == 0 {
+            let _ = write!(
+                text,
+                " \\tempo {} = {:.0}",
+                score.time_signature.beat_value, score.bpm
+            );
+        }
+        text.push('\n');
+        for measure in staff.measures.iter() {
+            text.push_str("     ");
+            let mut in_tuplet = false;
+            for event in measure.events.iter() {
+                // Neighbouring triplets share a bracket.
+                if event.value.triplet != in_tuplet {
+                    match in_tuplet {
+                        true => text.push_str(" }"),
+                        false => text.push_str(" \\tuplet 3/2 {"),
+                    }
+                    in_tuplet = event.value.triplet;
+                }
+                text.push(' ');
+                write_event(&mut text, score, event);
+            }
+            if in_tuplet {
+                text.push_str(" }");
+            }
+            text.push_str(" |\n");
+        }
+        text.push_str("      \\bar \"|.\"\n");
+        text.push_str("    }\n");
+    }
+    text.push_str("  >>\n");
+    text.push_str("  \\layout { }\n");
+    text.push_str("}\n");
+    text
+}
+
+fn key_signature(key: Key) -> String {
+    let mode = match key.mode {
+        Mode::Major => "major",
+        Mode::Minor => "minor",
+    };
+    format!("\\key {} \\{}", note_name(key.tonic), mode)
+}
+
+fn write_event(text: &mut String, score: &Score, event: &NotationEvent) {
+    if event.is_rest() {
+        // Whole measure rests are centred in the measure.
+        match event.value.ticks() == score.measure_ticks() {
+            true => text.push('R'),
+            false => text.push('r'),
+        }
+        text.push_str(&duration(event.value));
+        return;
+    }
+    match event.pitches.as_slice() {
+        [pitch] => text.push_str(&pitch_name(score.key, *pitch)),
+        pitches => {
+            let names: Vec<String> = pitches
+                .iter()
+                .map(|pitch| pitch_name(score.key, *pitch))
+                .collect();
+            let _ = write!(text, "<{}>", names.join(" "));
+        }
+    }
+    text.push_str(&duration(event.value));
+    if event.tie_start {
+        text.push('~');
+    }
+}
+
+/// The spelled pitch with its octave marks, c' being middle C.
+fn pitch_name(key: Key, pitch: Pitch) -> String {
+    let (spelled, octave) = key.spell_pitch(pitch);
+    let marks = match octave >= 3 {
+        true => "'".repeat((octave - 3) as usize),
+        false => ",".repeat((3 - octave) as usize),
+    };
+    format!("{}{}", note_name(spelled), marks)
+}
+
+/// Names a note the Dutch way LilyPond reads by default, e.g. fis or bes.
+fn note_name(spelled: SpelledNote) -> String {
+    let suffix = match spelled.accidental {
+        accidental if accidental >= 0 => "is".repeat(accidental as usize),
+        accidental => "es".repeat(-accidental as usize),
+    };
+    format!("{:?}{}", spelled.letter, suffix).to_lowercase()
+}
+
+/// The duration of a value, written inside a tuplet for triplets.
+fn duration(value: WrittenValue) -> String {
+    format!("{}{}", value.division, ".".repeat(value.dots as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        music_entities::{Note, NoteValue},
+        test_support::performance,
+    };
+
+    fn export(notes: &[(f64, f64, &str)], key: Key, grid: NoteValue) -> String {
+        lilypond(&Score::transcribe(&performance(notes), key, grid))
+    }
+
+    /// The lines of the staff numbered from 0.
+    fn staff(text: &str, index: usize) -> Vec<&str> {
+        let body = text.split("\\new Staff {\n").nth(index + 1).unwrap();
+        body.lines()
+            .take_while(|line| !line.contains("\\bar"))
+            .collect()
+    }
+
+    #[test]
+    fn octave_marks_and_note_names() {
+        let c_major = Key::from_note(Note::C, Mode::Major);
+        let name = |pitch: &str| pitch_name(c_major, pitch.parse().unwrap());
+        assert_eq!(name("C4"), "c'");
+        assert_eq!(name("B3"), "b");
+        assert_eq!(name("C3"), "c");
+        assert_eq!(name("A0"), "a,,,");
+        assert_eq!(name("C5"), "c''");
+        assert_eq!(name("F#4"), "fis'");
+        let f_major = Key::from_note(Note::F, Mode::Major);
+        assert_eq!(pitch_name(f_major, "A#2".parse().unwrap()), "bes,");
+        let note = |spelled: &str| note_name(spelled.parse().unwrap());
+        assert_eq!(note("E#"), "eis");
+        assert_eq!(note("Cb"), "ces");
+        assert_eq!(note("F##"), "fisis");
+        assert_eq!(note("Bbb"), "beses");
+        assert_eq!(key_signature(f_major), "\\key f \\major");
+        let e_flat_minor = Key::from_note(Note::DsharpEflat, Mode::Minor);
+        assert_eq!(key_signature(e_flat_minor), "\\key ees \\minor");
+    }
+
+    #[test]
+    fn staves_ties_and_rests() {
+        let text = export(
+            &[
+                (0.0, 0.5, "F#4"),
+                (0.5, 1.0, "F#4"),
+                (1.5, 2.5, "C5"),
+                (0.0, 2.0, "C3"),
+                (0.0, 2.0, "E3"),
+            ],
+            Key::from_note(Note::C, Mode::Major),
+            NoteValue::new(16, false),
+        );
+        assert!(text.starts_with("\\version \"2.24.0\"\n"));
+        assert_eq!(
+            staff(&text, 0),
+            [
+                "      \\clef treble \\key c \\major \\time 4/4 \\tempo 4 = 120",
+                "      fis'4 fis'4 r4 c''4~ |",
+                "      c''4 r2. |",
+            ]
+        );
+        // Only the first staff carries the tempo.
+        assert_eq!(
+            staff(&text, 1),
+            [
+                "      \\clef bass \\key c \\major \\time 4/4",
+                "      <c e>1 |",
+                "      R1 |",
+            ]
+        );
+    }
+
+    #[test]
+    fn neighbouring_triplets_share_a_tuplet() {
+        let third = 1.0 / 6.0;
+        let text = export(
+            &[
+                (0.0, third, "C4"),
+                (third, 2.0 * third, "D4"),
+                (2.0 * third, 0.5, "E4"),
+                (1.0 + 2.0 * third, 1.5, "G4"),
+            ],
+            Key::from_note(Note::C, Mode::Major),
+            NoteValue::new(8, true),
+        );
+        assert_eq!(
+            staff(&text, 0)[1],
+            "      \\tuplet 3/2 { c'8 d'8 e'8 r4 r4 r8 g'8 } r4 |"
+        );
+    }
+}
@@ -0,0 +1,304 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LooperState {
+    Empty,
+    /// Waiting for the next bar to start recording.
+    Armed,
+    Recording,
+    Playing,
+    Overdubbing,
+}
+
+/// One pass recorded over the loop.
+struct Layer {
+    frames: Vec<f32>,
+    muted: bool,
+}
+
+impl Layer {
+    fn new(length: usize) -> Self {
+        Layer {
+            frames: vec![0.0; length],
+            muted: false,
+        }
+    }
+
+    fn is_silent(&self) -> bool {
+        self.frames.iter().all(|frame| *frame == 0.0)
+    }
+}
+
+/// Records a phrase of a number of bars and plays it back in a loop, with
+/// every pass overdubbed on top kept as a layer of its own.
+pub struct Looper {
+    state: LooperState,
+    bars: u32,
+    /// Frames in one pass of the loop.
+    length: usize,
+    position: usize,
+    layers: Vec<Layer>,
+    selected_layer: usize,
+}
+
+impl Looper {
+    pub fn new(bars: u32) -> Self {
+        Looper {
+            state: LooperState::Empty,
+            bars: bars.max(1),
+            length: 0,
+            position: 0,
+            layers: Vec::new(),
+            selected_layer: 0,
+        }
+    }
+
+    pub fn get_state(&self) -> LooperState {
+        self.state
+    }
+
+    pub fn get_bars(&self) -> u32 {
+        self.bars
+    }
+
+    /// Takes effect from the next recording.
+    pub fn set_bars(&mut self, bars: u32) {
+        self.bars = bars.max(1);
+    }
+
+    /// Gets ready to record once the next bar starts.
+    pub fn arm(&mut self) {
+        if self.state == LooperState::Empty {
+            self.state = LooperState::Armed;
+        }
+    }
+
+    /// Starts recording the first pass, `length` frames long.
+    pub fn start(&mut self, length: usize) {
+        if self.state == LooperState::Armed && length > 0 {
+            self.length = length;
+            self.position = 0;
+            self.layers = vec![Layer::new(length)];
+            self.state = LooperState::Recording;
+        }
+    }
+
+    /// Starts or stops recording new layers over the loop.
+    pub fn toggle_overdub(&mut self) {
+        match self.state {
+            LooperState::Playing => {
+                self.layers.push(Layer::new(self.length));
+                self.state = LooperState::Overdubbing;
+            }
+            LooperState::Overdubbing => {
+                if self.layers.last().is_some_and(|layer| layer.is_silent()) {
+                    self.layers.pop();
+                }
+                self.state = LooperState::Playing;
+            }
+            _ => {}
+        }
+    }
+
+    /// Removes the newest layer, or the pass being recorded. Undoing the
+    /// first pass empties the looper.
+    pub fn undo(&mut self) -> bool {
+        if self.layers.pop().is_none() {
+            return false;
+        }
+        match self.layers.is_empty() {
+            true => self.clear(),
+            false => {
+                self.state = LooperState::Playing;
+                self.selected_layer = self.selected_layer.min(self.layers.len() - 1);
+            }
+        }
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.layers.clear();
+        self.state = LooperState::Empty;
+        self.selected_layer = 0;
+    }
+
+    pub fn layer_count(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Selects the next layer for muting and returns its index.
+    pub fn select_next_layer(&mut self) -> usize {
+        if !self.layers.is_empty() {
+            self.selected_layer = (self.selected_layer + 1) % self.layers.len();
+        }
+        self.selected_layer
+    }
+
+    pub fn get_selected_layer(&self) -> usize {
+        self.selected_layer
+    }
+
+    /// Mutes or unmutes the selected layer and returns whether it is muted.
+    pub fn toggle_layer_mute(&mut self) -> Option<bool> {
+        let layer = self.layers.get_mut(self.selected_layer)?;
+        layer.muted = !layer.muted;
+        Some(layer.muted)
+    }
+
+    /// Mixes the loop into `frames`, recording what they held before, i.e.
+    /// what is being played live, into the pass being recorded.
+    pub fn process(&mut self, frames: &mut [f32]) {
+        for frame in frames.iter_mut() {
+            let recording = match self.state {
+                LooperState::Recording | LooperState::Overdubbing => true,
+                LooperState::Playing => false,
+                LooperState::Empty | LooperState::Armed => return,
+            };
+            let live = *frame;
+            // The pass being recorded is heard live, not from the loop.
+            let played = self.layers.len() - recording as usize;
+            for layer in self.layers[..played].iter().filter(|layer| !layer.muted) {
+                *frame += layer.frames[self.position];
+            }
+            if let Some(layer) = self.layers.last_mut().filter(|_| recording) {
+                layer.frames[self.position] += live;
+            }
+            self.position += 1;
+            if self.position == self.length {
+                self.position = 0;
+                self.next_pass();
+            }
+        }
+    }
+
+    fn next_pass(&mut self) {
+        match self.state {
+            LooperState::Recording => self.state = LooperState::Playing,
+            // Passes with nothing played in them are reused.
+            LooperState::Overdubbing if !self.layers.last().is_some_and(Layer::is_silent) => {
+                self.layers.push(Layer::new(self.length))
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A looper that has started recording a pass of `length` frames.
+    fn recording(length: usize) -> Looper {
+        let mut looper = Looper::new(1);
+        looper.arm();
+        looper.start(length);
+        looper
+    }
+
+    /// Runs `input` through the looper and returns what it plays.
+    fn play(looper: &mut Looper, input: &[f32]) -> Vec<f32> {
+        let mut frames = input.to_vec();
+        looper.process(&mut frames);
+        frames
+    }
+
+    #[test]
+    fn recording_waits_for_the_arm_and_start() {
+        let mut looper = Looper::new(0);
+        assert_eq!(looper.get_bars(), 1);
+        looper.start(4);
+        assert_eq!(looper.get_state(), LooperState::Empty);
+        assert_eq!(play(&mut looper, &[1.0, 2.0]), [1.0, 2.0]);
+        looper.arm();
+        assert_eq!(looper.get_state(), LooperState::Armed);
+        looper.start(0);
+        assert_eq!(looper.get_state(), LooperState::Armed);
+        looper.start(4);
+        assert_eq!(looper.get_state(), LooperState::Recording);
+    }
+
+    #[test]
+    fn the_first_pass_plays_back() {
+        let mut looper = recording(4);
+        // The pass being recorded is only heard live.
+        assert_eq!(
+            play(&mut looper, &[1.0, 2.0, 3.0, 4.0]),
+            [1.0, 2.0, 3.0, 4.0]
+        );
+        assert_eq!(looper.get_state(), LooperState::Playing);
+        assert_eq!(play(&mut looper, &[0.0; 6]), [1.0, 2.0, 3.0, 4.0, 1.0, 2.0]);
+        // Live input is mixed over the loop without being recorded.
+        assert_eq!(play(&mut looper, &[1.0; 2]), [4.0, 5.0]);
+        assert_eq!(play(&mut looper, &[0.0; 4]), [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(looper.layer_count(), 1);
+    }
+
+    #[test]
+    fn overdubs_are_layered() {
+        let mut looper = recording(2);
+        play(&mut looper, &[1.0, 2.0]);
+        looper.toggle_overdub();
+        assert_eq!(looper.get_state(), LooperState::Overdubbing);
+        assert_eq!(play(&mut looper, &[10.0, 20.0]), [11.0, 22.0]);
+        assert_eq!(looper.layer_count(), 3);
+        looper.toggle_overdub();
+        // The empty pass started after the overdub is dropped.
+        assert_eq!(looper.get_state(), LooperState::Playing);
+        assert_eq!(looper.layer_count(), 2);
+        assert_eq!(play(&mut looper, &[0.0; 2]), [11.0, 22.0]);
+    }
+
+    #[test]
+    fn silent_passes_are_reused() {
+        let mut looper = recording(2);
+        play(&mut looper, &[1.0, 1.0]);
+        looper.toggle_overdub();
+        play(&mut looper, &[0.0; 6]);
+        assert_eq!(looper.layer_count(), 2);
+        play(&mut looper, &[0.0, 3.0]);
+        play(&mut looper, &[0.0; 2]);
+        assert_eq!(looper.layer_count(), 3);
+        looper.toggle_overdub();
+        assert_eq!(looper.layer_count(), 2);
+        assert_eq!(play(&mut looper, &[0.0; 2]), [1.0, 4.0]);
+    }
+
+    #[test]
+    fn undo_removes_layers_down_to_empty() {
+        let mut looper = recording(2);
+        play(&mut looper, &[1.0, 1.0]);
+        looper.toggle_overdub();
+        play(&mut looper, &[2.0, 2.0]);
+        looper.toggle_overdub();
+        assert_eq!(looper.layer_count(), 2);
+        assert!(looper.undo());
+        assert_eq!(looper.get_state(), LooperState::Playing);
+        assert_eq!(play(&mut looper, &[0.0; 2]), [1.0, 1.0]);
+        assert!(looper.undo());
+        assert_eq!(looper.get_state(), LooperState::Empty);
+        assert!(!looper.undo());
+        assert_eq!(play(&mut looper, &[0.5; 2]), [0.5, 0.5]);
+        // Undoing the pass being recorded empties the looper too.
+        let mut looper = recording(4);
+        play(&mut looper, &[1.0; 2]);
+        assert!(looper.undo());
+        assert_eq!(looper.get_state(), LooperState::Empty);
+    }
+
+    #[test]
+    fn muted_layers_are_not_heard() {
+        let mut looper = recording(2);
+        play(&mut looper, &[1.0, 1.0]);
+        looper.toggle_overdub();
+        play(&mut looper, &[2.0, 2.0]);
+        looper.toggle_overdub();
+        assert_eq!(looper.select_next_layer(), 1);
+        assert_eq!(looper.toggle_layer_mute(), Some(true));
+        assert_eq!(play(&mut looper, &[0.0; 2]), [1.0, 1.0]);
+        assert_eq!(looper.select_next_layer(), 0);
+        assert_eq!(looper.toggle_layer_mute(), Some(true));
+        assert_eq!(play(&mut looper, &[0.0; 2]), [0.0, 0.0]);
+        assert_eq!(looper.toggle_layer_mute(), Some(false));
+        assert_eq!(play(&mut looper, &[0.0; 2]), [1.0, 1.0]);
+        looper.clear();
+        assert_eq!(looper.toggle_layer_mute(), None);
+    }
+}
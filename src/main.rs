@@ -1,51 +1,328 @@
+mod abc;
+mod arpeggiator;
 mod buffer_que_manager;
+mod chord_feel;
+mod chord_grouper;
 mod gui_renderer;
+mod harmonic_analysis;
 mod input_handler;
+mod key_detector;
+mod lilypond;
+mod looper;
+mod midi_file;
+mod midi_input;
+mod midi_output;
+mod midi_player;
 mod music_entities;
+mod musicxml;
+mod notation;
 mod note_generator;
+mod note_tracker;
+mod quantize;
+mod recorder;
+mod sequencer;
+mod session;
+#[cfg(test)]
+mod test_support;
+mod transport;
+mod tuning;
 
 use core::f32;
 
 use std::{
+    collections::HashMap,
     fs::File,
     hash::Hash,
     io::BufReader,
-    sync::{Arc, Mutex},
-    time::Duration,
+    path::Path,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
 };
 
-use buffer_que_manager::DefaultBufferQueManager;
+use arpeggiator::Arpeggiator;
+use buffer_que_manager::{DefaultBufferQueManager, CHANNELS, SAMPLE_RATE};
+use chord_feel::{ChordFeel, Humanize, Strum, StrumDirection, VoiceOffset};
+use chord_grouper::ChordGrouper;
+use harmonic_analysis::ProgressionHistory;
+use key_detector::KeyDetector;
+use looper::{Looper, LooperState};
 
+use midi_file::MidiFileFormat;
+use midi_input::MidiInput;
+use midi_output::MidiOutput;
+use midi_player::{MidiPlayer, PlayedNote};
 use minimp3::Decoder;
-use music_entities::Note;
-use note_generator::NoteGenerator;
+use music_entities::{
+    ChordEvent, Interval, Key, Mode, NamingSystem, Note, NoteValue, Pitch, TimedNote,
+};
+use notation::{NotationFormat, Score};
+use note_generator::{NoteGenerator, HIGHEST_SAMPLE, LOWEST_SAMPLE};
+use note_tracker::NoteTracker;
+use quantize::{Quantize, QuantizeHistory};
+use recorder::{Performance, PerformanceEventKind, Recorder, RecorderState, DEFAULT_VELOCITY};
+use sequencer::Sequencer;
+use session::{ArpSettings, Session, Settings};
+use transport::{TapTempo, TimeSignature, Transport, TransportEvent};
+use tuning::{KeyboardMapping, ScalaScale, Temperament, Tuning};
 
 use winit::{
-    event::{DeviceEvent, Event, WindowEvent},
+    event::{Event, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
+    keyboard::NamedKey,
     window::WindowBuilder,
 };
 
-use crate::{buffer_que_manager::BufferQueManager, input_handler::InputHandler};
+use crate::{
+    buffer_que_manager::BufferQueManager,
+    gui_renderer::{keyboard_quads, sequencer_quads, Quad},
+    input_handler::{Command, InputHandler},
+};
 #[tokio::main]
 async fn main() {
     const ACCEPTED_NOTE_KEYS: [&str; 12] =
         ["a", "s", "d", "f", "g", "h", "j", "k", "l", "ö", "ä", "'"];
     const ACCEPTED_OCTAVE_KEYS: [&str; 3] = ["3", "4", "5"];
+    const ACCEPTED_COMMAND_KEYS: [(&str, Command); 31] = [
+        ("z", Command::ToggleScaleLock),
+        ("x", Command::ToggleScaleHighlight),
+        ("p", Command::ExportProgression),
+        ("c", Command::CycleChordMode),
+        ("v", Command::ToggleArpeggiator),
+        ("b", Command::CycleArpPattern),
+        ("n", Command::ToggleArpLatch),
+        ("q", Command::ToggleTransport),
+        ("w", Command::ToggleMetronome),
+        ("t", Command::TapTempo),
+        ("r", Command::ToggleRecording),
+        ("e", Command::ArmRecording),
+        ("m", Command::ExportMidi),
+        ("i", Command::TogglePlayback),
+        ("u", Command::SeekBackward),
+        ("o", Command::SeekForward),
+        ("y", Command::MarkLoopPoint),
+        (",", Command::SlowDown),
+        (".", Command::SpeedUp),
+        ("-", Command::SelectTrack),
+        ("<", Command::ToggleTrackMute),
+        (">", Command::ToggleTrackSolo),
+        ("1", Command::SaveSession),
+        ("2", Command::LoadSession),
+        ("6", Command::QuantizeTake),
+        ("7", Command::UndoQuantize),
+        ("8", Command::ToggleLooper),
+        ("9", Command::UndoLayer),
+        ("0", Command::ClearLoop),
+        ("+", Command::SelectLayer),
+        ("*", Command::ToggleLayerMute),
+    ];
+    const ACCEPTED_NAMED_KEYS: [(NamedKey, Command); 16] = [
+        (NamedKey::Enter, Command::ToggleSequencer),
+        (NamedKey::Tab, Command::ToggleStepEdit),
+        (NamedKey::ArrowLeft, Command::PreviousStep),
+        (NamedKey::ArrowRight, Command::NextStep),
+        (NamedKey::ArrowUp, Command::IncreaseStepValue),
+        (NamedKey::ArrowDown, Command::DecreaseStepValue),
+        (NamedKey::Backspace, Command::ToggleStep),
+        (NamedKey::End, Command::CycleStepParameter),
+        (NamedKey::PageUp, Command::PreviousPattern),
+        (NamedKey::PageDown, Command::NextPattern),
+        (NamedKey::Insert, Command::ChainPattern),
+        (NamedKey::Home, Command::ResetChain),
+        (NamedKey::Delete, Command::ToggleStepCount),
+        (NamedKey::F5, Command::ExportMusicXml),
+        (NamedKey::F6, Command::ExportLilyPond),
+        (NamedKey::F7, Command::ExportAbc),
+    ];
+    const PROGRESSION_EXPORT_PATH: &str = "progression.txt";
+    // Every finished recording is saved here so it can be exported later.
+    const TAKE_PATH: &str = "take.txt";
+    const MIDI_EXPORT_PATH: &str = "performance.mid";
+    const MUSICXML_EXPORT_PATH: &str = "performance.musicxml";
+    const LILYPOND_EXPORT_PATH: &str = "performance.ly";
+    const ABC_EXPORT_PATH: &str = "performance.abc";
+    const SESSION_PATH: &str = "session.json";
+    // Notes pressed within this window of each other are grouped into a chord.
+    const CHORD_WINDOW: Duration = Duration::from_millis(240);
+    // The key is estimated from this many of the most recent notes.
+    const KEY_HISTORY_SIZE: usize = 48;
+    const KEY_MIN_NOTES: usize = 8;
+    const SEEK_STEP: f64 = 5.0;
+    const TEMPO_SCALE_STEP: f32 = 0.1;
     // TODO:
     // Add octave switching.
     // Remove copying of instances where possible.
 
+    // e.g. `--midi-format 0`, format 1 is the default.
+    let midi_format: MidiFileFormat = get_arg_value("--midi-format")
+        .and_then(|format| format.parse().ok())
+        .unwrap_or(MidiFileFormat::MultiTrack);
+    // Exports a take of a saved session without opening a window, e.g. `--export-midi
+    // session.json --take 2 --out take.mid`, quantized first when `--quantize` is given. The
+    // latest take is exported without `--take`, and a take saved as text works too.
+    if let Some(take_path) = get_arg_value("--export-midi") {
+        let out_path = get_arg_value("--out").unwrap_or(MIDI_EXPORT_PATH.to_string());
+        let quantize = get_arg_value("--quantize").map(|_| get_quantize(Quantize::default()));
+        match load_take(&take_path, get_arg_value("--take")).and_then(|performance| {
+            let performance = match quantize {
+                Some(quantize) => quantize.apply(&performance),
+                None => performance,
+            };
+            midi_file::export_performance(&performance, midi_format, &out_path)
+        }) {
+            Ok(_) => println!("exported {} to {}", take_path, out_path),
+            Err(err) => eprintln!("{}", err),
+        }
+        return;
+    }
+    // Writes a take of a saved session out as a score, e.g. `--export-musicxml session.json
+    // --out take.musicxml --quantize 1/8`, or likewise with `--export-lilypond` and
+    // `--export-abc`, spelled in `--key` or else the key detected from the take. Takes are
+    // chosen with `--take` as for `--export-midi`.
+    let notation_exports = [
+        (
+            "--export-musicxml",
+            NotationFormat::MusicXml,
+            MUSICXML_EXPORT_PATH,
+        ),
+        (
+            "--export-lilypond",
+            NotationFormat::LilyPond,
+            LILYPOND_EXPORT_PATH,
+        ),
+        ("--export-abc", NotationFormat::Abc, ABC_EXPORT_PATH),
+    ];
+    for (flag, format, default_path) in notation_exports {
+        let take_path = match get_arg_value(flag) {
+            Some(take_path) => take_path,
+            None => continue,
+        };
+        let out_path = get_arg_value("--out").unwrap_or(default_path.to_string());
+        let grid = get_quantize(Quantize::default()).grid;
+        match load_take(&take_path, get_arg_value("--take")).and_then(|performance| {
+            let key = get_arg_value("--key")
+                .and_then(|key| key.parse().ok())
+                .or_else(|| {
+                    detect_key(
+                        &performance,
+                        KeyDetector::new(KEY_HISTORY_SIZE, KEY_MIN_NOTES),
+                    )
+                })
+                .unwrap_or(Key::from_note(Note::C, Mode::Major));
+            let score = Score::transcribe(&performance, key, grid);
+            format.export(&score, &out_path)
+        }) {
+            Ok(_) => println!("exported {} to {}", take_path, out_path),
+            Err(err) => eprintln!("{}", err),
+        }
+        return;
+    }
+
     let event_loop = EventLoop::new().unwrap();
     let window = WindowBuilder::new().build(&event_loop).unwrap();
     let mut state = gui_renderer::State::new(&window).await;
 
     let note_generator = Arc::new(Mutex::new(NoteGenerator::new()));
     let mut buffer_que_manager = DefaultBufferQueManager::new();
+    // Leaves the sound to the instrument on MIDI out.
+    buffer_que_manager.set_muted(std::env::args().any(|arg| arg == "--mute-audio"));
     let input_handler = Arc::new(Mutex::new(InputHandler::new(
         ACCEPTED_NOTE_KEYS,
         ACCEPTED_OCTAVE_KEYS,
+        &ACCEPTED_COMMAND_KEYS,
+        &ACCEPTED_NAMED_KEYS,
     )));
+    let mut chord_grouper = ChordGrouper::new(CHORD_WINDOW);
+    // The last session is picked up again, e.g. `--session song.json`, with
+    // anything given on the command line taking precedence.
+    let session_path = get_arg_value("--session").unwrap_or(SESSION_PATH.to_string());
+    let session = match Path::new(&session_path).exists() {
+        true => Session::load(&session_path).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            Session::default()
+        }),
+        false => Session::default(),
+    };
+    let mut settings = session.settings;
+    // Note and chord names are spelled for a key, e.g. `--key "F major" --naming german`.
+    // Without a key it follows the key detected from playing.
+    settings.key = get_arg_value("--key")
+        .and_then(|key| key.parse().ok())
+        .or(settings.key);
+    let mut key = settings.key.unwrap_or(Key::from_note(Note::C, Mode::Major));
+    let mut key_detector = KeyDetector::new(KEY_HISTORY_SIZE, KEY_MIN_NOTES);
+    let mut progression = ProgressionHistory::new(Instant::now());
+    settings.naming_system = get_arg_value("--naming")
+        .and_then(|naming| naming.parse().ok())
+        .unwrap_or(settings.naming_system);
+    // Scale used by scale lock and highlighting, e.g. `--scale "D dorian"`.
+    settings.scale = get_arg_value("--scale")
+        .and_then(|scale| scale.parse().ok())
+        .unwrap_or(settings.scale);
+    settings.tuning = get_tuning(settings.tuning);
+    let mut chord_feel = get_chord_feel(session.effects);
+    // e.g. `--bpm 96 --time-signature 3/4`.
+    settings.bpm = get_arg_value("--bpm")
+        .and_then(|bpm| bpm.parse().ok())
+        .unwrap_or(settings.bpm);
+    settings.time_signature = get_arg_value("--time-signature")
+        .and_then(|time_signature| time_signature.parse().ok())
+        .unwrap_or(settings.time_signature);
+    let transport = Transport::new(settings.bpm, settings.time_signature);
+    buffer_que_manager.set_clock(transport.clock());
+    let transport_events = transport.subscribe();
+    let mut tap_tempo = TapTempo::new();
+    let mut recorder = Recorder::new();
+    let mut takes = session.takes;
+    // Undo steps for quantizing the latest take.
+    let mut quantize_history = QuantizeHistory::new();
+    settings.quantize = get_quantize(settings.quantize);
+    let mut note_tracker = NoteTracker::new();
+    // e.g. `--arp-pattern up-down --arp-rate 1/8t --arp-octaves 2 --arp-gate 0.5`.
+    let arp_settings = &mut settings.arpeggiator;
+    arp_settings.pattern = get_arg_value("--arp-pattern")
+        .and_then(|pattern| pattern.parse().ok())
+        .unwrap_or(arp_settings.pattern);
+    arp_settings.rate = get_arg_value("--arp-rate")
+        .and_then(|rate| rate.parse().ok())
+        .unwrap_or(arp_settings.rate);
+    arp_settings.octaves = get_arg_value("--arp-octaves")
+        .and_then(|octaves| octaves.parse().ok())
+        .unwrap_or(arp_settings.octaves);
+    arp_settings.gate = get_arg_value("--arp-gate")
+        .and_then(|gate| gate.parse().ok())
+        .unwrap_or(arp_settings.gate);
+    let mut arpeggiator = Arpeggiator::new(
+        arp_settings.pattern,
+        arp_settings.rate,
+        arp_settings.octaves,
+        arp_settings.gate,
+    );
+    // Length of the phrase the looper records, e.g. `--loop-bars 2`.
+    settings.loop_bars = get_arg_value("--loop-bars")
+        .and_then(|bars| bars.parse().ok())
+        .unwrap_or(settings.loop_bars);
+    let looper = Arc::new(Mutex::new(Looper::new(settings.loop_bars)));
+    buffer_que_manager.set_looper(Arc::clone(&looper));
+    apply_settings(
+        &settings,
+        &note_generator,
+        &input_handler,
+        &transport,
+        &mut arpeggiator,
+        &looper,
+    );
+    let mut midi_input = get_midi_input();
+    let mut midi_output = get_midi_output();
+    if let Some(midi_output) = midi_output.as_mut() {
+        midi_output.send(PerformanceEventKind::InstrumentChange {
+            program: session.instrument,
+        });
+    }
+    let mut midi_player = get_midi_player();
+    let mut sequencer = Sequencer::new(session.sequence);
+    // Track that mute and solo apply to.
+    let mut selected_track = 0;
+    state.set_quads(window_quads(&settings, &sequencer));
 
     event_loop.set_control_flow(ControlFlow::Poll);
 
@@ -70,151 +347,1202 @@ async fn main() {
             ..
         } => {
             println!("The close button was pressed; stopping");
+            buffer_que_manager.pause_all_streams();
+            capture_settings(
+                &mut settings,
+                &note_generator,
+                &input_handler,
+                &transport,
+                &arpeggiator,
+                &looper,
+            );
+            let session = Session::new(
+                settings.clone(),
+                chord_feel,
+                takes.clone(),
+                sequencer.get_sequence().clone(),
+            );
+            match session.save(&session_path) {
+                Ok(_) => println!("session saved to {}", session_path),
+                Err(err) => eprintln!("couldn't save session: {}", err),
+            }
             elwt.exit();
         }
         Event::WindowEvent {
             event: WindowEvent::KeyboardInput { event, .. },
             ..
         } => {
-            state.render_random_color();
+            let mut commands = Vec::new();
             {
                 // Store input and drop lock.
                 if let Ok(mut input_handler) = input_handler.lock() {
-                    input_handler.add_input_to_mediator(event);
+                    input_handler.add_input(event, Instant::now());
+                    commands = input_handler.get_commands();
+                }
+            }
+            for command in commands {
+                match command {
+                    Command::ToggleScaleLock => {
+                        if let Ok(mut note_generator) = note_generator.lock() {
+                            let scale_lock = match note_generator.get_scale_lock() {
+                                Some(_) => None,
+                                None => Some(settings.scale),
+                            };
+                            println!("scale lock: {:?}", scale_lock);
+                            note_generator.set_scale_lock(scale_lock);
+                        }
+                    }
+                    Command::ToggleScaleHighlight => {
+                        settings.highlight_scale = !settings.highlight_scale
+                    }
+                    Command::ToggleArpeggiator => {
+                        arpeggiator.set_enabled(!arpeggiator.is_enabled());
+                        println!("arpeggiator: {}", arpeggiator.is_enabled());
+                    }
+                    Command::CycleArpPattern => {
+                        arpeggiator.pattern = arpeggiator.pattern.next();
+                        println!("arpeggiator pattern: {:?}", arpeggiator.pattern);
+                    }
+                    Command::ToggleArpLatch => {
+                        arpeggiator.set_latch(!arpeggiator.get_latch());
+                        println!("arpeggiator latch: {}", arpeggiator.get_latch());
+                    }
+                    Command::ToggleTransport => match transport.is_running() {
+                        true => transport.stop(),
+                        // Count in a bar when the metronome is on.
+                        false => transport.start(transport.metronome()),
+                    },
+                    Command::ToggleMetronome => {
+                        transport.set_metronome(!transport.metronome());
+                        println!("metronome: {}", transport.metronome());
+                    }
+                    Command::TapTempo => {
+                        if let Some(bpm) = tap_tempo.tap(Instant::now()) {
+                            transport.set_bpm(bpm);
+                            recorder.record_tempo(Instant::now(), transport.bpm());
+                            println!("tempo: {:.0} bpm", transport.bpm());
+                        }
+                    }
+                    Command::ToggleRecording => match recorder.get_state() {
+                        RecorderState::Recording => {
+                            if let Some(performance) = recorder.stop(Instant::now()) {
+                                println!(
+                                    "recorded {} notes in {:.1}s",
+                                    performance.note_count(),
+                                    performance.duration().as_secs_f32()
+                                );
+                                if let Err(err) = performance.save(TAKE_PATH) {
+                                    eprintln!("couldn't save take: {}", err);
+                                }
+                                takes.push(performance.clone());
+                                quantize_history.clear();
+                            }
+                        }
+                        _ => {
+                            recorder.start(
+                                Instant::now(),
+                                transport.bpm(),
+                                transport.time_signature(),
+                            );
+                            println!("recording");
+                        }
+                    },
+                    Command::ExportMidi => match takes.last() {
+                        Some(performance) => {
+                            match midi_file::export_performance(
+                                performance,
+                                midi_format,
+                                MIDI_EXPORT_PATH,
+                            ) {
+                                Ok(_) => println!("performance exported to {}", MIDI_EXPORT_PATH),
+                                Err(err) => eprintln!("{}", err),
+                            }
+                        }
+                        None => println!("nothing recorded to export"),
+                    },
+                    Command::QuantizeTake => match takes.last_mut() {
+                        Some(performance) => {
+                            quantize_history.quantize(performance, &settings.quantize);
+                            if let Err(err) = performance.save(TAKE_PATH) {
+                                eprintln!("couldn't save take: {}", err);
+                            }
+                            println!("take quantized to {}", settings.quantize.grid);
+                        }
+                        None => println!("nothing recorded to quantize"),
+                    },
+                    Command::UndoQuantize => match (takes.last_mut(), quantize_history.undo()) {
+                        (Some(performance), Some(previous)) => {
+                            *performance = previous;
+                            if let Err(err) = performance.save(TAKE_PATH) {
+                                eprintln!("couldn't save take: {}", err);
+                            }
+                            println!("quantize undone");
+                        }
+                        _ => println!("nothing to undo"),
+                    },
+                    Command::ToggleLooper => {
+                        if let Ok(mut looper) = looper.lock() {
+                            match looper.get_state() {
+                                LooperState::Empty => {
+                                    looper.arm();
+                                    // Records against the metronome, counting in a bar when
+                                    // the transport isn't running yet.
+                                    transport.set_metronome(true);
+                                    if !transport.is_running() {
+                                        transport.start(true);
+                                    }
+                                }
+                                LooperState::Armed => looper.clear(),
+                                LooperState::Recording => {}
+                                LooperState::Playing | LooperState::Overdubbing => {
+                                    looper.toggle_overdub()
+                                }
+                            }
+                            println!("looper: {:?}", looper.get_state());
+                        }
+                    }
+                    Command::UndoLayer => {
+                        if let Ok(mut looper) = looper.lock() {
+                            match looper.undo() {
+                                true => println!("looper: {} layers", looper.layer_count()),
+                                false => println!("no loop layers to undo"),
+                            }
+                        }
+                    }
+                    Command::ClearLoop => {
+                        if let Ok(mut looper) = looper.lock() {
+                            looper.clear();
+                            // Also cuts off notes still ringing, so everything falls silent.
+                            buffer_que_manager.clear_all();
+                            println!("loop cleared");
+                        }
+                    }
+                    Command::SelectLayer => {
+                        if let Ok(mut looper) = looper.lock() {
+                            println!(
+                                "loop layer {} of {}",
+                                looper.select_next_layer() + 1,
+                                looper.layer_count()
+                            );
+                        }
+                    }
+                    Command::ToggleLayerMute => {
+                        if let Ok(mut looper) = looper.lock() {
+                            if let Some(muted) = looper.toggle_layer_mute() {
+                                println!(
+                                    "loop layer {} muted: {}",
+                                    looper.get_selected_layer() + 1,
+                                    muted
+                                );
+                            }
+                        }
+                    }
+                    Command::ToggleSequencer => {
+                        sequencer.set_playing(!sequencer.is_playing());
+                        println!("sequencer playing: {}", sequencer.is_playing());
+                    }
+                    Command::ToggleStepEdit => {
+                        sequencer.set_step_edit(!sequencer.is_step_edit());
+                        println!("step entry: {}", sequencer.is_step_edit());
+                    }
+                    Command::PreviousStep => print_step(sequencer.move_cursor(-1), &sequencer),
+                    Command::NextStep => print_step(sequencer.move_cursor(1), &sequencer),
+                    Command::IncreaseStepValue => {
+                        sequencer.adjust_step(true);
+                        print_step(sequencer.get_selected_step(), &sequencer);
+                    }
+                    Command::DecreaseStepValue => {
+                        sequencer.adjust_step(false);
+                        print_step(sequencer.get_selected_step(), &sequencer);
+                    }
+                    Command::ToggleStep => {
+                        sequencer.toggle_step();
+                        print_step(sequencer.get_selected_step(), &sequencer);
+                    }
+                    Command::CycleStepParameter => {
+                        println!("editing step {:?}", sequencer.cycle_parameter())
+                    }
+                    Command::PreviousPattern => {
+                        println!("pattern {}", sequencer.previous_pattern() + 1)
+                    }
+                    Command::NextPattern => println!("pattern {}", sequencer.next_pattern() + 1),
+                    Command::ChainPattern => {
+                        sequencer.chain_pattern();
+                        print_chain(&sequencer);
+                    }
+                    Command::ResetChain => {
+                        sequencer.reset_chain();
+                        print_chain(&sequencer);
+                    }
+                    Command::ToggleStepCount => {
+                        println!("pattern steps: {}", sequencer.toggle_step_count())
+                    }
+                    // Spelled in the chosen or detected key, on the quantize grid.
+                    Command::ExportMusicXml => export_score(
+                        takes.last(),
+                        key,
+                        settings.quantize.grid,
+                        NotationFormat::MusicXml,
+                        MUSICXML_EXPORT_PATH,
+                    ),
+                    Command::ExportLilyPond => export_score(
+                        takes.last(),
+                        key,
+                        settings.quantize.grid,
+                        NotationFormat::LilyPond,
+                        LILYPOND_EXPORT_PATH,
+                    ),
+                    Command::ExportAbc => export_score(
+                        takes.last(),
+                        key,
+                        settings.quantize.grid,
+                        NotationFormat::Abc,
+                        ABC_EXPORT_PATH,
+                    ),
+                    Command::ArmRecording => {
+                        recorder.arm(transport.bpm(), transport.time_signature());
+                        println!("recorder: {:?}", recorder.get_state());
+                    }
+                    Command::CycleChordMode => {
+                        if let Ok(mut note_generator) = note_generator.lock() {
+                            let chord_mode = note_generator.get_chord_mode().next();
+                            println!("chord mode: {:?}", chord_mode);
+                            note_generator.set_chord_mode(chord_mode);
+                        }
+                    }
+                    Command::TogglePlayback => match midi_player.as_mut() {
+                        Some(player) if player.is_playing() => {
+                            player.pause();
+                            println!("paused at {:.1}s", player.get_position());
+                        }
+                        Some(player) => {
+                            player.play();
+                            println!("playing from {:.1}s", player.get_position());
+                        }
+                        None => println!("no midi file loaded, use --midi-file"),
+                    },
+                    Command::SeekBackward | Command::SeekForward => {
+                        if let Some(player) = midi_player.as_mut() {
+                            let step = match command {
+                                Command::SeekBackward => -SEEK_STEP,
+                                _ => SEEK_STEP,
+                            };
+                            player.seek(player.get_position() + step);
+                            println!("position: {:.1}s", player.get_position());
+                        }
+                    }
+                    Command::MarkLoopPoint => {
+                        if let Some(player) = midi_player.as_mut() {
+                            player.mark_loop_point();
+                            match player.get_loop_region() {
+                                Some((start, end)) => println!("loop: {:.1}s-{:.1}s", start, end),
+                                None => println!("loop point: {:.1}s", player.get_position()),
+                            }
+                        }
+                    }
+                    Command::SlowDown | Command::SpeedUp => {
+                        if let Some(player) = midi_player.as_mut() {
+                            let step = match command {
+                                Command::SlowDown => -TEMPO_SCALE_STEP,
+                                _ => TEMPO_SCALE_STEP,
+                            };
+                            player.set_tempo_scale(player.get_tempo_scale() + step);
+                            println!("tempo scale: {:.0}%", player.get_tempo_scale() * 100.0);
+                        }
+                    }
+                    Command::SelectTrack => {
+                        if let Some(player) = midi_player.as_ref() {
+                            let tracks = &player.get_song().tracks;
+                            if !tracks.is_empty() {
+                                selected_track = (selected_track + 1) % tracks.len();
+                                println!(
+                                    "selected track {}: {}",
+                                    selected_track + 1,
+                                    tracks[selected_track].name
+                                );
+                            }
+                        }
+                    }
+                    Command::ToggleTrackMute => {
+                        if let Some(player) = midi_player.as_mut() {
+                            player.set_muted(selected_track, !player.is_muted(selected_track));
+                            println!(
+                                "track {} muted: {}",
+                                selected_track + 1,
+                                player.is_muted(selected_track)
+                            );
+                        }
+                    }
+                    Command::ToggleTrackSolo => {
+                        if let Some(player) = midi_player.as_mut() {
+                            player.set_soloed(selected_track, !player.is_soloed(selected_track));
+                            println!(
+                                "track {} soloed: {}",
+                                selected_track + 1,
+                                player.is_soloed(selected_track)
+                            );
+                        }
+                    }
+                    Command::SaveSession => {
+                        capture_settings(
+                            &mut settings,
+                            &note_generator,
+                            &input_handler,
+                            &transport,
+                            &arpeggiator,
+                            &looper,
+                        );
+                        let session = Session::new(
+                            settings.clone(),
+                            chord_feel,
+                            takes.clone(),
+                            sequencer.get_sequence().clone(),
+                        );
+                        match session.save(&session_path) {
+                            Ok(_) => println!("session saved to {}", session_path),
+                            Err(err) => eprintln!("couldn't save session: {}", err),
+                        }
+                    }
+                    Command::LoadSession => match Session::load(&session_path) {
+                        Ok(session) => {
+                            settings = session.settings;
+                            chord_feel = session.effects;
+                            takes = session.takes;
+                            quantize_history.clear();
+                            sequencer.set_sequence(session.sequence);
+                            key = settings.key.unwrap_or(key);
+                            apply_settings(
+                                &settings,
+                                &note_generator,
+                                &input_handler,
+                                &transport,
+                                &mut arpeggiator,
+                                &looper,
+                            );
+                            println!(
+                                "session loaded from {} with {} takes",
+                                session_path,
+                                takes.len()
+                            );
+                        }
+                        Err(err) => eprintln!("{}", err),
+                    },
+                    Command::ExportProgression => {
+                        match progression.export(PROGRESSION_EXPORT_PATH, settings.naming_system) {
+                            Ok(_) => {
+                                println!("progression exported to {}", PROGRESSION_EXPORT_PATH)
+                            }
+                            Err(err) => eprintln!("couldn't export progression: {}", err),
+                        }
+                    }
                 }
             }
-            // Debounce input handling and then move input to storage.
-            let input_handler_clone = Arc::clone(&input_handler);
-            tokio::task::spawn(async {
-                InputHandler::move_input_from_mediator_to_storage(
-                    input_handler_clone,
-                    Duration::from_secs_f32(0.24),
-                )
-            });
-            // add notes to buffer que on detected input.
-            add_notes_to_buffer_que(&input_handler, &note_generator, &mut buffer_que_manager);
+            state.set_quads(window_quads(&settings, &sequencer));
+            let _ = state.render_random_color();
+            // add notes to buffer que on detected input, they sound right away.
+            let notes = add_notes_to_buffer_que(
+                &input_handler,
+                &note_generator,
+                &mut buffer_que_manager,
+                &settings.tuning,
+                &chord_feel,
+                &key,
+                arpeggiator.is_enabled(),
+            );
+            if enter_step_notes(&mut sequencer, &notes) {
+                state.set_quads(window_quads(&settings, &sequencer));
+                let _ = state.render();
+            }
+            key_detector.add_notes(&notes);
+            if settings.key.is_none() {
+                update_detected_key(&key_detector, &mut key);
+            }
+            for chord_notes in chord_grouper.add_notes(&notes) {
+                handle_chord_group(chord_notes, &key, settings.naming_system, &mut progression);
+            }
+        }
+        Event::WindowEvent {
+            event: WindowEvent::ModifiersChanged(modifiers),
+            ..
+        } => {
+            if let Ok(mut input_handler) = input_handler.lock() {
+                input_handler.set_modifiers(modifiers.state());
+            }
         }
         Event::WindowEvent {
             event: WindowEvent::Resized(physical_size),
             ..
         } => state.resize(physical_size),
         _ => {
+            if let Some(midi_input) = midi_input.as_mut() {
+                if let Ok(mut input_handler) = input_handler.lock() {
+                    for (midi_event, timestamp) in midi_input.poll() {
+                        input_handler.add_midi_input(midi_event, timestamp);
+                    }
+                }
+            }
             // add notes to buffer que on poll loop.
-            add_notes_to_buffer_que(&input_handler, &note_generator, &mut buffer_que_manager);
+            let notes = add_notes_to_buffer_que(
+                &input_handler,
+                &note_generator,
+                &mut buffer_que_manager,
+                &settings.tuning,
+                &chord_feel,
+                &key,
+                arpeggiator.is_enabled(),
+            );
+            if enter_step_notes(&mut sequencer, &notes) {
+                state.set_quads(window_quads(&settings, &sequencer));
+                let _ = state.render();
+            }
+            key_detector.add_notes(&notes);
+            if settings.key.is_none() {
+                update_detected_key(&key_detector, &mut key);
+            }
+            for chord_notes in chord_grouper.add_notes(&notes) {
+                handle_chord_group(chord_notes, &key, settings.naming_system, &mut progression);
+            }
+            if let Some(chord_notes) = chord_grouper.poll(Instant::now()) {
+                handle_chord_group(chord_notes, &key, settings.naming_system, &mut progression);
+            }
+            update_arpeggiator(&input_handler, &note_generator, &mut arpeggiator, &key);
+            play_arpeggiator(
+                &mut arpeggiator,
+                &mut buffer_que_manager,
+                &mut midi_output,
+                &settings.tuning,
+                transport.beats(),
+                transport.bpm(),
+            );
+            handle_input_events(
+                &input_handler,
+                &note_generator,
+                &mut note_tracker,
+                &mut recorder,
+                &mut midi_output,
+                &key,
+                arpeggiator.is_enabled(),
+            );
+            let playing_step = sequencer.get_playing_step();
+            if let Some(note) = sequencer.poll(transport.beats(), transport.bpm()) {
+                play_notes(
+                    vec![note],
+                    &mut buffer_que_manager,
+                    &mut midi_output,
+                    &settings.tuning,
+                );
+            }
+            if sequencer.get_playing_step() != playing_step {
+                state.set_quads(window_quads(&settings, &sequencer));
+                let _ = state.render();
+            }
+            if let Some(player) = midi_player.as_mut() {
+                play_midi_file(
+                    player,
+                    &mut buffer_que_manager,
+                    &mut midi_output,
+                    &settings.tuning,
+                );
+            }
+            if let Some(midi_output) = midi_output.as_mut() {
+                midi_output.poll(Instant::now());
+            }
+            for transport_event in transport_events.try_iter() {
+                // An armed recorder starts on the first beat after the count-in.
+                if let TransportEvent::Beat(position) = transport_event {
+                    if position.bar == 0 && position.beat == 0 {
+                        recorder.start_if_armed(Instant::now());
+                    }
+                    // An armed looper starts on the next bar.
+                    if position.beat == 0 && !position.is_count_in() {
+                        if let Ok(mut looper) = looper.lock() {
+                            let length = loop_length(
+                                looper.get_bars(),
+                                transport.bpm(),
+                                transport.time_signature(),
+                                buffer_que_manager.sample_rate(),
+                                buffer_que_manager.channels(),
+                            );
+                            looper.start(length);
+                        }
+                    }
+                }
+                match transport_event {
+                    TransportEvent::Started { bpm } => {
+                        println!(
+                            "transport started at {:.0} bpm in {}",
+                            bpm,
+                            transport.time_signature()
+                        )
+                    }
+                    TransportEvent::Beat(position)
+                        if position.beat == 0 || position.is_count_in() =>
+                    {
+                        println!("{}", position)
+                    }
+                    TransportEvent::Beat(_) => {}
+                    TransportEvent::Stopped => println!("transport stopped"),
+                }
+            }
         }
     });
 }
 
-fn get_frames_from_note(note: Note, selected_octave: u8) -> Vec<f32> {
-    match note {
-        Note::A => {
-            AudioFile::new(&format!("a{}.mp3", selected_octave.to_string()), Note::A)
-                .f32_parsed_audio
+fn get_frames_from_note(pitch: Pitch, tuning: &Tuning) -> Vec<f32> {
+    // Pitches without a sample of their own are played from the same note
+    // in the nearest octave that has one.
+    let mut sample_pitch = pitch;
+    while sample_pitch < LOWEST_SAMPLE {
+        sample_pitch = sample_pitch + Interval(12);
+    }
+    while sample_pitch > HIGHEST_SAMPLE {
+        sample_pitch = sample_pitch - Interval(12);
+    }
+    let sample = AudioFile::load(sample_pitch);
+    // Samples are in 12-TET at A4 = 440 Hz, so they're resampled to the tuning.
+    match tuning.playback_rate(pitch, sample_pitch) {
+        Some(rate) => tuning::repitch(&sample.f32_parsed_audio, CHANNELS, rate),
+        None => Vec::new(),
+    }
+}
+
+fn add_notes_to_buffer_que(
+    input_handler: &Arc<Mutex<InputHandler>>,
+    note_generator: &Arc<Mutex<NoteGenerator>>,
+    buffer_que_manager: &mut DefaultBufferQueManager,
+    tuning: &Tuning,
+    chord_feel: &ChordFeel,
+    key: &Key,
+    arpeggiating: bool,
+) -> Vec<TimedNote> {
+    if let Ok(mut input_handler) = input_handler.lock() {
+        let input = input_handler.get_inputs();
+        let selected_octave = input_handler.get_selected_octave();
+        let midi_notes = input_handler.get_midi_notes();
+        let bend_ratio = midi_input::bend_ratio(input_handler.get_pitch_bend());
+        if let Ok(mut note_generator) = note_generator.lock() {
+            let mut timed_notes = note_generator.get_notes_from_keys(input, selected_octave, key);
+            let pitches: Vec<Pitch> = timed_notes.iter().map(|timed| timed.pitch).collect();
+            timed_notes.extend(midi_notes.iter().map(|midi_note| TimedNote {
+                pitch: midi_note.pitch,
+                timestamp: midi_note.timestamp,
+            }));
+
+            // The arpeggiator plays held notes itself.
+            if arpeggiating {
+                return timed_notes;
+            }
+            if pitches.len() >= 2 {
+                println!("multi");
+                let frames = mix_notes(pitches, tuning, chord_feel);
+                buffer_que_manager
+                    .add_frames_to_que(tuning::repitch(&frames, CHANNELS, bend_ratio));
+            } else if pitches.len() == 1 {
+                let pitch = pitches[0];
+                match (pitch.to_midi(), tuning.frequency(pitch)) {
+                    (Some(midi_note), Some(frequency)) => println!(
+                        "single: {} (MIDI {}, {:.2} Hz)",
+                        pitch, midi_note, frequency
+                    ),
+                    _ => println!("single: {}", pitch),
+                }
+                let frames = get_frames_from_note(pitch, tuning);
+                buffer_que_manager
+                    .add_frames_to_que(tuning::repitch(&frames, CHANNELS, bend_ratio));
+            }
+            // Notes from a MIDI controller are played as they come, at their velocity.
+            if !midi_notes.is_empty() {
+                let voices = midi_notes
+                    .iter()
+                    .map(|midi_note| {
+                        let offset = VoiceOffset {
+                            delay: Duration::ZERO,
+                            gain: midi_note.velocity as f32 / 127.0,
+                        };
+                        (get_frames_from_note(midi_note.pitch, tuning), offset)
+                    })
+                    .collect();
+                let frames = mix_frames(voices);
+                buffer_que_manager
+                    .add_frames_to_que(tuning::repitch(&frames, CHANNELS, bend_ratio));
+            }
+            return timed_notes;
         }
-        Note::ASharpBFlat => {
-            AudioFile::new(
-                &format!("a-{}.mp3", selected_octave.to_string()),
-                Note::ASharpBFlat,
-            )
-            .f32_parsed_audio
+    }
+    Vec::new()
+}
+
+/// Hands the notes held on the keyboard and a MIDI controller to the arpeggiator.
+fn update_arpeggiator(
+    input_handler: &Arc<Mutex<InputHandler>>,
+    note_generator: &Arc<Mutex<NoteGenerator>>,
+    arpeggiator: &mut Arpeggiator,
+    key: &Key,
+) {
+    if !arpeggiator.is_enabled() {
+        return;
+    }
+    if let Ok(input_handler) = input_handler.lock() {
+        let held_keys = input_handler.get_held_keys();
+        let selected_octave = input_handler.get_selected_octave();
+        if let Ok(mut note_generator) = note_generator.lock() {
+            let held = note_generator.get_notes_from_keys(held_keys, selected_octave, key);
+            let held_midi = input_handler.get_held_midi_notes();
+            arpeggiator.set_held(
+                held.iter()
+                    .map(|timed| timed.pitch)
+                    .chain(held_midi.iter().map(|midi_note| midi_note.pitch))
+                    .collect(),
+            );
         }
-        Note::B => {
-            AudioFile::new(&format!("b{}.mp3", selected_octave.to_string()), Note::B)
-                .f32_parsed_audio
+    }
+}
+
+fn play_arpeggiator(
+    arpeggiator: &mut Arpeggiator,
+    buffer_que_manager: &mut DefaultBufferQueManager,
+    midi_output: &mut Option<MidiOutput>,
+    tuning: &Tuning,
+    beats: f64,
+    bpm: f32,
+) {
+    if !arpeggiator.is_enabled() {
+        return;
+    }
+    if let Some(arp_note) = arpeggiator.poll(beats, bpm) {
+        if let Some(midi_output) = midi_output.as_mut() {
+            midi_output.play_note(
+                arp_note.pitch,
+                DEFAULT_VELOCITY,
+                arp_note.length,
+                Instant::now(),
+            );
         }
-        Note::C => {
-            AudioFile::new(&format!("c{}.mp3", selected_octave.to_string()), Note::C)
-                .f32_parsed_audio
+        let frames = get_frames_from_note(arp_note.pitch, tuning);
+        buffer_que_manager.add_frames_to_que(gate_frames(frames, arp_note.length));
+    }
+}
+
+/// Plays the notes the midi player has reached.
+fn play_midi_file(
+    midi_player: &mut MidiPlayer,
+    buffer_que_manager: &mut DefaultBufferQueManager,
+    midi_output: &mut Option<MidiOutput>,
+    tuning: &Tuning,
+) {
+    let notes = midi_player.poll(Instant::now());
+    play_notes(notes, buffer_que_manager, midi_output, tuning);
+}
+
+/// Plays notes of a known length, mixed into one buffer with each note cut
+/// to its length and scaled by its velocity.
+fn play_notes(
+    notes: Vec<PlayedNote>,
+    buffer_que_manager: &mut DefaultBufferQueManager,
+    midi_output: &mut Option<MidiOutput>,
+    tuning: &Tuning,
+) {
+    if notes.is_empty() {
+        return;
+    }
+    if let Some(midi_output) = midi_output.as_mut() {
+        for note in notes.iter() {
+            midi_output.play_note(note.pitch, note.velocity, note.length, Instant::now());
         }
-        Note::CsharpDflat => {
-            AudioFile::new(
-                &format!("c-{}.mp3", selected_octave.to_string()),
-                Note::CsharpDflat,
-            )
-            .f32_parsed_audio
+    }
+    let voices = notes
+        .into_iter()
+        .map(|note| {
+            let frames = gate_frames(get_frames_from_note(note.pitch, tuning), note.length);
+            let offset = VoiceOffset {
+                delay: Duration::ZERO,
+                gain: note.velocity as f32 / 127.0,
+            };
+            (frames, offset)
+        })
+        .collect();
+    buffer_que_manager.add_frames_to_que(mix_frames(voices));
+}
+
+/// Turns what was played into note events for the recorder and MIDI out.
+/// While arpeggiating only the arpeggiator's notes go out, but note offs
+/// still do so notes held from before don't hang.
+fn handle_input_events(
+    input_handler: &Arc<Mutex<InputHandler>>,
+    note_generator: &Arc<Mutex<NoteGenerator>>,
+    note_tracker: &mut NoteTracker,
+    recorder: &mut Recorder,
+    midi_output: &mut Option<MidiOutput>,
+    key: &Key,
+    arpeggiating: bool,
+) {
+    let mut note_events = Vec::new();
+    if let Ok(mut input_handler) = input_handler.lock() {
+        let input_events = input_handler.get_input_events();
+        if let Ok(mut note_generator) = note_generator.lock() {
+            note_events = note_tracker.track(input_events, &mut note_generator, key);
         }
-        Note::D => {
-            AudioFile::new(&format!("d{}.mp3", selected_octave.to_string()), Note::D)
-                .f32_parsed_audio
+    }
+    recorder.add_note_events(&note_events);
+    if let Some(midi_output) = midi_output.as_mut() {
+        for (_, kind) in note_events {
+            if !(arpeggiating && matches!(kind, PerformanceEventKind::NoteOn { .. })) {
+                midi_output.send(kind);
+            }
         }
-        Note::DsharpEflat => {
-            AudioFile::new(
-                &format!("d-{}.mp3", selected_octave.to_string()),
-                Note::DsharpEflat,
-            )
-            .f32_parsed_audio
+    }
+}
+
+/// Sets up everything that keeps its own settings as saved in `settings`.
+fn apply_settings(
+    settings: &Settings,
+    note_generator: &Arc<Mutex<NoteGenerator>>,
+    input_handler: &Arc<Mutex<InputHandler>>,
+    transport: &Transport,
+    arpeggiator: &mut Arpeggiator,
+    looper: &Mutex<Looper>,
+) {
+    if let Ok(mut note_generator) = note_generator.lock() {
+        note_generator.set_scale_lock(settings.scale_lock.then_some(settings.scale));
+        note_generator.set_chord_mode(settings.chord_mode);
+    }
+    if let Ok(mut input_handler) = input_handler.lock() {
+        input_handler.set_selected_octave(settings.octave);
+    }
+    transport.set_bpm(settings.bpm);
+    transport.set_time_signature(settings.time_signature);
+    transport.set_metronome(settings.metronome);
+    let arp_settings = &settings.arpeggiator;
+    arpeggiator.pattern = arp_settings.pattern;
+    arpeggiator.rate = arp_settings.rate;
+    arpeggiator.octaves = arp_settings.octaves.max(1);
+    arpeggiator.gate = arp_settings.gate.clamp(0.0, 1.0);
+    arpeggiator.set_enabled(arp_settings.enabled);
+    arpeggiator.set_latch(arp_settings.latch);
+    if let Ok(mut looper) = looper.lock() {
+        looper.set_bars(settings.loop_bars);
+    }
+}
+
+/// Reads the settings kept elsewhere back into `settings` before saving.
+fn capture_settings(
+    settings: &mut Settings,
+    note_generator: &Arc<Mutex<NoteGenerator>>,
+    input_handler: &Arc<Mutex<InputHandler>>,
+    transport: &Transport,
+    arpeggiator: &Arpeggiator,
+    looper: &Mutex<Looper>,
+) {
+    if let Ok(note_generator) = note_generator.lock() {
+        settings.scale_lock = note_generator.get_scale_lock().is_some();
+        settings.chord_mode = note_generator.get_chord_mode();
+    }
+    if let Ok(input_handler) = input_handler.lock() {
+        settings.octave = input_handler.get_selected_octave();
+    }
+    settings.bpm = transport.bpm();
+    settings.time_signature = transport.time_signature();
+    settings.metronome = transport.metronome();
+    settings.arpeggiator = ArpSettings {
+        enabled: arpeggiator.is_enabled(),
+        latch: arpeggiator.get_latch(),
+        pattern: arpeggiator.pattern,
+        rate: arpeggiator.rate,
+        octaves: arpeggiator.octaves,
+        gate: arpeggiator.gate,
+    };
+    if let Ok(looper) = looper.lock() {
+        settings.loop_bars = looper.get_bars();
+    }
+}
+
+/// Returns the value following `flag` on the command line.
+fn get_arg_value(flag: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != flag).nth(1)
+}
+
+/// Loads take number `take`, counted from 1, or else the latest take, from the
+/// session or take file at `path`.
+fn load_take(path: &str, take: Option<String>) -> Result<Performance, String> {
+    let mut takes = Session::load(path)?.takes;
+    match take {
+        Some(take) => {
+            let index = take
+                .parse::<usize>()
+                .ok()
+                .filter(|index| (1..=takes.len()).contains(index))
+                .ok_or(format!("{} has no take {}", path, take))?;
+            Ok(takes.swap_remove(index - 1))
         }
-        Note::E => {
-            AudioFile::new(&format!("e{}.mp3", selected_octave.to_string()), Note::E)
-                .f32_parsed_audio
+        None => takes.pop().ok_or(format!("{} has no takes", path)),
+    }
+}
+
+/// Builds the tuning from the command line, e.g. `--reference 415 --temperament
+/// "just D"`, or `--scl file.scl` with an optional `--kbm file.kbm`, keeping
+/// whatever isn't given from `default`.
+fn get_tuning(default: Tuning) -> Tuning {
+    let reference: f32 = get_arg_value("--reference")
+        .and_then(|reference| reference.parse().ok())
+        .filter(|reference| tuning::is_valid_reference(*reference as f64))
+        .unwrap_or(default.reference);
+    let temperament_arg = get_arg_value("--temperament").unwrap_or_default();
+    let mut words = temperament_arg.split_whitespace();
+    let mut temperament: Temperament = words
+        .next()
+        .and_then(|temperament| temperament.parse().ok())
+        .unwrap_or(default.temperament);
+    let tonic: Note = words
+        .next()
+        .and_then(|tonic| tonic.parse().ok())
+        .unwrap_or(default.tonic);
+    if let Some(path) = get_arg_value("--scl") {
+        match ScalaScale::from_file(&path) {
+            Ok(scale) => temperament = Temperament::Scala(scale),
+            Err(err) => eprintln!("{}", err),
         }
-        Note::F => {
-            AudioFile::new(&format!("f{}.mp3", selected_octave.to_string()), Note::F)
-                .f32_parsed_audio
+    }
+    let mut tuning = match Tuning::new(reference, temperament, tonic) {
+        Ok(tuning) => tuning,
+        Err(err) => {
+            eprintln!("{}", err);
+            return Tuning::default();
         }
-        Note::FsharpGflat => {
-            AudioFile::new(
-                &format!("f-{}.mp3", selected_octave.to_string()),
-                Note::FsharpGflat,
-            )
-            .f32_parsed_audio
+    };
+    tuning.keyboard_mapping = default.keyboard_mapping;
+    if let Some(path) = get_arg_value("--kbm") {
+        match KeyboardMapping::from_file(&path) {
+            Ok(keyboard_mapping) => tuning = tuning.with_keyboard_mapping(keyboard_mapping),
+            Err(err) => eprintln!("{}", err),
         }
-        Note::G => {
-            AudioFile::new(&format!("g{}.mp3", selected_octave.to_string()), Note::G)
-                .f32_parsed_audio
+    }
+    tuning
+}
+
+/// Opens MIDI input from the command line, either a port with `--midi-in
+/// "port name"` or `--midi-in virtual`, or a recorded byte stream with
+/// `--midi-replay bytes.txt`.
+fn get_midi_input() -> Option<MidiInput> {
+    let midi_input = match (get_arg_value("--midi-in"), get_arg_value("--midi-replay")) {
+        (_, Some(path)) => MidiInput::replay(&path),
+        (Some(port), None) => MidiInput::connect(&port),
+        (None, None) => return None,
+    };
+    match midi_input {
+        Ok(midi_input) => Some(midi_input),
+        Err(err) => {
+            eprintln!("couldn't open midi input: {}", err);
+            None
         }
-        Note::GsharpAflat => {
-            AudioFile::new(
-                &format!("g-{}.mp3", selected_octave.to_string()),
-                Note::GsharpAflat,
-            )
-            .f32_parsed_audio
+    }
+}
+
+/// Opens MIDI out from the command line, e.g. `--midi-out "port name"
+/// --midi-channel 2` or `--midi-out virtual`, on channel 1 by default.
+fn get_midi_output() -> Option<MidiOutput> {
+    let port = get_arg_value("--midi-out")?;
+    let channel: u8 = get_arg_value("--midi-channel")
+        .and_then(|channel| channel.parse().ok())
+        .unwrap_or(1);
+    match MidiOutput::connect(&port, channel) {
+        Ok(midi_output) => Some(midi_output),
+        Err(err) => {
+            eprintln!("couldn't open midi output: {}", err);
+            None
         }
     }
 }
 
-fn add_notes_to_buffer_que(
-    input_handler: &Arc<Mutex<InputHandler>>,
-    note_generator: &Arc<Mutex<NoteGenerator>>,
-    buffer_que_manager: &mut DefaultBufferQueManager,
-) {
-    if let Ok(mut input_handler) = input_handler.lock() {
-        let input = input_handler.get_inputs();
-        let selected_octave = input_handler.get_selected_octave();
-        if let Ok(mut note_generator) = note_generator.lock() {
-            let notes = note_generator.get_notes_from_keys(input);
+/// Loads the file given with `--midi-file`, e.g. `--midi-file piece.mid
+/// --tempo-scale 0.5 --loop 8-16 --mute 2 --solo 1,3`, with the loop in
+/// seconds and tracks numbered from 1.
+fn get_midi_player() -> Option<MidiPlayer> {
+    // ABC tunes, e.g. `--abc-file tune.abc`, play the same way as MIDI files.
+    let (path, song) = match (get_arg_value("--midi-file"), get_arg_value("--abc-file")) {
+        (Some(path), _) => {
+            let song = midi_file::import_song(&path);
+            (path, song)
+        }
+        (None, Some(path)) => {
+            let song = abc::import_tune(&path);
+            (path, song)
+        }
+        (None, None) => return None,
+    };
+    let song = match song {
+        Ok(song) => song,
+        Err(err) => {
+            eprintln!("{}", err);
+            return None;
+        }
+    };
+    println!("loaded {} ({:.1}s)", path, song.duration);
+    for (index, track) in song.tracks.iter().enumerate() {
+        println!(
+            "track {}: {} ({} notes)",
+            index + 1,
+            track.name,
+            track.notes.len()
+        );
+    }
+    let mut player = MidiPlayer::new(song);
+    if let Some(tempo_scale) = get_arg_value("--tempo-scale").and_then(|scale| scale.parse().ok()) {
+        player.set_tempo_scale(tempo_scale);
+    }
+    let loop_region = get_arg_value("--loop").and_then(|region| {
+        let (start, end) = region.split_once('-')?;
+        Some((start.parse().ok()?, end.parse().ok()?))
+    });
+    player.set_loop_region(loop_region);
+    let track_numbers = |flag: &str| -> Vec<usize> {
+        get_arg_value(flag)
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|track| track.trim().parse::<usize>().ok())
+            .filter(|track| *track > 0)
+            .map(|track| track - 1)
+            .collect()
+    };
+    for track in track_numbers("--mute") {
+        player.set_muted(track, true);
+    }
+    for track in track_numbers("--solo") {
+        player.set_soloed(track, true);
+    }
+    Some(player)
+}
 
-            if notes.len() >= 2 {
-                println!("multi");
-                buffer_que_manager.add_frames_to_que(mix_notes(notes, selected_octave));
-            } else if notes.len() == 1 {
-                println!("single");
-                buffer_que_manager
-                    .add_frames_to_que(get_frames_from_note(notes[0], selected_octave));
+/// Writes `performance` out as a score spelled in `key`, on the `grid`.
+fn export_score(
+    performance: Option<&Performance>,
+    key: Key,
+    grid: NoteValue,
+    format: NotationFormat,
+    path: &str,
+) {
+    match performance {
+        Some(performance) => {
+            let score = Score::transcribe(performance, key, grid);
+            match format.export(&score, path) {
+                Ok(_) => println!("score exported to {}", path),
+                Err(err) => eprintln!("{}", err),
             }
         }
+        None => println!("nothing recorded to export"),
+    }
+}
+
+/// Builds the strum and humanize settings from the command line, e.g.
+/// `--strum 30 --strum-direction down --humanize 15 --humanize-velocity 0.2`,
+/// with times in milliseconds, keeping `default` for what isn't given.
+fn get_chord_feel(default: ChordFeel) -> ChordFeel {
+    let strum = get_arg_value("--strum")
+        .and_then(|delay| delay.parse().ok())
+        .map(|delay| Strum {
+            delay: Duration::from_millis(delay),
+            direction: get_arg_value("--strum-direction")
+                .and_then(|direction| direction.parse().ok())
+                .unwrap_or(StrumDirection::Up),
+        })
+        .or(default.strum);
+    let humanize = get_arg_value("--humanize")
+        .and_then(|timing| timing.parse().ok())
+        .map(|timing| Humanize {
+            timing: Duration::from_millis(timing),
+            velocity: get_arg_value("--humanize-velocity")
+                .and_then(|velocity| velocity.parse().ok())
+                .unwrap_or(0.1),
+        })
+        .or(default.humanize);
+    ChordFeel { strum, humanize }
+}
+
+/// Reads the quantize settings from the command line, e.g. `--quantize 1/8t
+/// --swing 60 --strength 80 --quantize-ends`, with swing and strength in percent.
+fn get_quantize(default: Quantize) -> Quantize {
+    Quantize {
+        grid: get_arg_value("--quantize")
+            .and_then(|grid| grid.parse().ok())
+            .unwrap_or(default.grid),
+        swing: get_arg_value("--swing")
+            .and_then(|swing| swing.parse::<f32>().ok())
+            .map(|swing| swing / 100.0)
+            .unwrap_or(default.swing),
+        strength: get_arg_value("--strength")
+            .and_then(|strength| strength.parse::<f32>().ok())
+            .map(|strength| strength / 100.0)
+            .unwrap_or(default.strength),
+        ends: default.ends || std::env::args().any(|arg| arg == "--quantize-ends"),
+    }
+}
+
+/// Writes the lowest of the notes just played into the selected step while
+/// entering steps, returning whether a step was written.
+fn enter_step_notes(sequencer: &mut Sequencer, notes: &[TimedNote]) -> bool {
+    let lowest = notes.iter().map(|note| note.pitch).min();
+    match lowest.filter(|_| sequencer.is_step_edit()) {
+        Some(pitch) => {
+            sequencer.enter_note(pitch);
+            println!("step entered: {}", pitch);
+            true
+        }
+        None => false,
+    }
+}
+
+fn print_step(index: usize, sequencer: &Sequencer) {
+    let step = sequencer.get_step();
+    println!(
+        "step {}: {} {} velocity {} gate {:.1} probability {:.0}%",
+        index + 1,
+        if step.active { "on" } else { "off" },
+        step.pitch,
+        step.velocity,
+        step.gate,
+        step.probability * 100.0
+    );
+}
+
+fn print_chain(sequencer: &Sequencer) {
+    let chain: Vec<String> = sequencer
+        .get_sequence()
+        .chain
+        .iter()
+        .map(|pattern| (pattern + 1).to_string())
+        .collect();
+    println!("pattern chain: {}", chain.join(" "));
+}
+
+/// The keyboard, with the scale highlighted if asked for, below the
+/// sequencer.
+fn window_quads(settings: &Settings, sequencer: &Sequencer) -> Vec<Quad> {
+    let highlighted = match settings.highlight_scale {
+        true => settings.scale.notes(),
+        false => Vec::new(),
+    };
+    let mut quads = sequencer_quads(sequencer);
+    quads.extend(keyboard_quads(&highlighted));
+    quads
+}
+
+/// The key detected from the notes of a whole performance.
+fn detect_key(performance: &Performance, mut key_detector: KeyDetector) -> Option<Key> {
+    let now = Instant::now();
+    let notes: Vec<TimedNote> = performance
+        .events
+        .iter()
+        .filter_map(|event| match event.kind {
+            PerformanceEventKind::NoteOn { pitch, .. } => Some(TimedNote {
+                pitch,
+                timestamp: now + event.time,
+            }),
+            _ => None,
+        })
+        .collect();
+    key_detector.add_notes(&notes);
+    key_detector.estimate().map(|estimate| estimate.key)
+}
+
+fn update_detected_key(key_detector: &KeyDetector, key: &mut Key) {
+    if let Some(estimate) = key_detector.estimate() {
+        if estimate.key != *key {
+            println!("key: {} ({:.2})", estimate.key, estimate.correlation);
+            *key = estimate.key;
+        }
+    }
+}
+
+// Chords of the progression printed after each recognized chord.
+const PROGRESSION_SUMMARY_LENGTH: usize = 8;
+
+fn handle_chord_group(
+    notes: Vec<TimedNote>,
+    key: &Key,
+    naming_system: NamingSystem,
+    progression: &mut ProgressionHistory,
+) {
+    if let Some(chord_event) = ChordEvent::recognize(notes) {
+        match chord_event.best() {
+            Some(candidate) => println!(
+                "chord pressed: {} ({:.0}%) from {}",
+                candidate.chord.description(key, naming_system),
+                candidate.confidence * 100.0,
+                chord_event
+                    .notes
+                    .iter()
+                    .map(|timed| key.name_pitch(timed.pitch, naming_system))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            ),
+            None => println!("nothing"),
+        }
+        if progression.add_chord_event(&chord_event, key).is_some() {
+            println!(
+                "progression in {}: {}",
+                key,
+                progression.summary(PROGRESSION_SUMMARY_LENGTH)
+            );
+        }
     }
 }
 
+/// Samples in `bars` bars at the given tempo on an output stream of
+/// `sample_rate` and `channels`.
+fn loop_length(
+    bars: u32,
+    bpm: f32,
+    time_signature: TimeSignature,
+    sample_rate: u32,
+    channels: usize,
+) -> usize {
+    let beats = bars as f64 * time_signature.beats_per_bar as f64;
+    (beats * 60.0 / bpm as f64 * sample_rate as f64) as usize * channels
+}
+const GATE_FADE_OUT: Duration = Duration::from_millis(5);
+
 struct AudioFile {
-    note: Note,
+    pitch: Pitch,
     f32_parsed_audio: Vec<f32>,
 }
 
 impl Hash for AudioFile {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.note.hash(state);
+        self.pitch.hash(state);
     }
 }
 
+// Samples decoded so far by pitch, so each file is only read and decoded
+// the first time its note is played.
+static SAMPLES: OnceLock<Mutex<HashMap<Pitch, Arc<AudioFile>>>> = OnceLock::new();
+
 impl AudioFile {
-    fn new(file_path: &str, note: Note) -> Self {
+    /// The decoded sample of `pitch`, which must be one of the sampled pitches.
+    fn load(pitch: Pitch) -> Arc<AudioFile> {
+        AudioFile::cached(pitch, || {
+            // Samples are named like "c4.mp3", with sharps written as "c-4.mp3".
+            let note_name = pitch.note.to_string().to_lowercase().replace('#', "-");
+            AudioFile::new(&format!("{}{}.mp3", note_name, pitch.octave), pitch)
+        })
+    }
+
+    /// The sample of `pitch` from the cache, decoded with `decode` the first
+    /// time.
+    fn cached(pitch: Pitch, decode: impl FnOnce() -> AudioFile) -> Arc<AudioFile> {
+        match SAMPLES.get_or_init(Default::default).lock() {
+            Ok(mut samples) => {
+                Arc::clone(samples.entry(pitch).or_insert_with(|| Arc::new(decode())))
+            }
+            Err(_) => Arc::new(decode()),
+        }
+    }
+
+    fn new(file_path: &str, pitch: Pitch) -> Self {
         let folder = "./src/audio_files/";
         let file_path = format!("{}{}", folder, file_path);
         let mp3_file = File::open(file_path).expect("Couldn't find file");
         let f32_parsed_audio = parse_mp3_file_to_f32(mp3_file);
         Self {
-            note,
+            pitch,
             f32_parsed_audio,
         }
     }
@@ -237,32 +1565,107 @@ fn parse_mp3_file_to_f32(mp3: File) -> Vec<f32> {
     samples
 }
 
-fn mix_notes(notes: Vec<Note>, selected_octave: u8) -> Vec<f32> {
-    let mut frames: Vec<Vec<f32>> = Vec::new();
-    for note in notes.into_iter() {
-        frames.push(get_frames_from_note(note, selected_octave));
-    }
-    frames.sort_by(|a, b| a.len().cmp(&b.len()));
+/// Mixes the notes into one buffer, each delayed and scaled as set by
+/// `chord_feel`.
+fn mix_notes(mut pitches: Vec<Pitch>, tuning: &Tuning, chord_feel: &ChordFeel) -> Vec<f32> {
+    pitches.sort();
+    let offsets = chord_feel.voice_offsets(pitches.len());
+    let voices = pitches
+        .into_iter()
+        .map(|pitch| get_frames_from_note(pitch, tuning))
+        .zip(offsets)
+        .collect();
+    mix_frames(voices)
+}
 
-    merge_all_arrays(frames)
+/// Sums the voices into one buffer, long enough for the latest ending one.
+fn mix_frames(voices: Vec<(Vec<f32>, VoiceOffset)>) -> Vec<f32> {
+    let mut mixed: Vec<f32> = Vec::new();
+    for (frames, offset) in voices {
+        let start = (offset.delay.as_secs_f32() * SAMPLE_RATE) as usize * CHANNELS;
+        if mixed.len() < start + frames.len() {
+            mixed.resize(start + frames.len(), 0.0);
+        }
+        for (mixed_sample, sample) in mixed[start..].iter_mut().zip(frames) {
+            *mixed_sample += sample * offset.gain;
+        }
+    }
+    mixed
 }
 
-fn merge_all_arrays(mut arrays: Vec<Vec<f32>>) -> Vec<f32> {
-    if arrays.len() == 1 {
-        arrays.pop().unwrap()
-    } else {
-        let shortest = arrays.remove(0);
-        let longest = arrays.remove(0);
-        let merged = merge_arrays(shortest, longest);
-        arrays.insert(0, merged);
-        merge_all_arrays(arrays)
+/// Cuts frames down to `length`, fading out the end so the cut doesn't click.
+fn gate_frames(mut frames: Vec<f32>, length: Duration) -> Vec<f32> {
+    frames.truncate((length.as_secs_f32() * SAMPLE_RATE) as usize * CHANNELS);
+    let fade_length =
+        ((GATE_FADE_OUT.as_secs_f32() * SAMPLE_RATE) as usize * CHANNELS).min(frames.len());
+    let fade_start = frames.len() - fade_length;
+    for (index, sample) in frames[fade_start..].iter_mut().enumerate() {
+        *sample *= 1.0 - index as f32 / fade_length as f32;
     }
+    frames
 }
 
-fn merge_arrays(shortest: Vec<f32>, longest: Vec<f32>) -> Vec<f32> {
-    shortest
-        .iter()
-        .zip(longest.iter())
-        .map(|(a, b)| a + b)
-        .collect()
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{sequencer::Sequence, test_support::temp_path};
+
+    #[test]
+    fn takes_are_exported_from_sessions_and_take_files() {
+        let take = |bpm: f32| Performance::new(bpm, TimeSignature::new(4, 4));
+        let session = Session::new(
+            Settings::default(),
+            ChordFeel::default(),
+            vec![take(90.0), take(100.0)],
+            Sequence::default(),
+        );
+        let path = temp_path("main", "session.json");
+        session.save(&path).unwrap();
+        let bpm =
+            |take: Option<&str>| load_take(&path, take.map(String::from)).map(|take| take.bpm);
+        assert_eq!(bpm(None), Ok(100.0));
+        assert_eq!(bpm(Some("1")), Ok(90.0));
+        assert_eq!(bpm(Some("2")), Ok(100.0));
+        for take in ["0", "3", "last"] {
+            assert!(bpm(Some(take)).is_err(), "take {} loaded", take);
+        }
+        Session::default().save(&path).unwrap();
+        assert!(bpm(None).is_err());
+        take(80.0).save(&path).unwrap();
+        assert_eq!(bpm(None), Ok(80.0));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn loop_length_follows_the_output_stream() {
+        let four_four = TimeSignature::new(4, 4);
+        // Two bars of 4/4 at 120 bpm last four seconds.
+        assert_eq!(loop_length(2, 120.0, four_four, 44_100, 2), 352_800);
+        assert_eq!(loop_length(2, 120.0, four_four, 48_000, 2), 384_000);
+        assert_eq!(loop_length(2, 120.0, four_four, 48_000, 6), 1_152_000);
+        assert_eq!(
+            loop_length(1, 90.0, TimeSignature::new(3, 4), 48_000, 1),
+            96_000
+        );
+    }
+
+    #[test]
+    fn samples_are_decoded_once() {
+        // A pitch without a sample file, so nothing else caches it.
+        let pitch = Pitch::new(Note::C, -1);
+        let mut decoded = 0;
+        let mut decode = || {
+            decoded += 1;
+            AudioFile {
+                pitch,
+                f32_parsed_audio: vec![0.5; 4],
+            }
+        };
+        let first = AudioFile::cached(pitch, &mut decode);
+        let second = AudioFile::cached(pitch, &mut decode);
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(decoded, 1);
+    }
 }
@@ -0,0 +1,455 @@
+use std::{collections::HashMap, fs, str::FromStr};
+
+use midly::{
+    num::{u15, u28, u4, u7},
+    Format, Header, MetaMessage, MidiMessage, PitchBend, Smf, Timing, TrackEvent, TrackEventKind,
+};
+
+use crate::{
+    music_entities::Pitch,
+    recorder::{Performance, PerformanceEventKind},
+};
+
+pub const TICKS_PER_QUARTER: u16 = 480;
+pub const SUSTAIN_PEDAL: u8 = 64;
+const TRACK_NAME: &[u8] = b"piano_man";
+// General MIDI puts drums on channel 10, which has no place on a piano.
+const DRUM_CHANNEL: u8 = 9;
+
+/// Format 0 keeps everything on one track, format 1 puts the tempo map and
+/// time signature on a track of their own before the notes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiFileFormat {
+    SingleTrack,
+    MultiTrack,
+}
+
+impl FromStr for MidiFileFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "0" => Ok(MidiFileFormat::SingleTrack),
+            "1" => Ok(MidiFileFormat::MultiTrack),
+            _ => Err(format!("unsupported midi file format: {}", format)),
+        }
+    }
+}
+
+/// Microseconds per quarter note, which is how SMF stores tempo. The tempo
+/// counts beats of the time signature, so 6/8 at 120 is 60 quarters a minute.
+fn microseconds_per_quarter(bpm: f32, beat_value: u8) -> u32 {
+    let quarters_per_minute = bpm * 4.0 / beat_value as f32;
+    (60_000_000.0 / quarters_per_minute) as u32
+}
+
+/// Writes `performance` to `path` as a Standard MIDI File on channel 1.
+pub fn export_performance(
+    performance: &Performance,
+    format: MidiFileFormat,
+    path: &str,
+) -> Result<(), String> {
+    let beat_value = performance.time_signature.beat_value;
+    let conductor = vec![
+        (0, TrackEventKind::Meta(MetaMessage::TrackName(TRACK_NAME))),
+        (
+            0,
+            TrackEventKind::Meta(MetaMessage::TimeSignature(
+                performance.time_signature.beats_per_bar,
+                beat_value.trailing_zeros() as u8,
+                24,
+                8,
+            )),
+        ),
+        (
+            0,
+            TrackEventKind::Meta(MetaMessage::Tempo(
+                microseconds_per_quarter(performance.bpm, beat_value).into(),
+            )),
+        ),
+    ];
+    let mut tempo_map = Vec::new();
+    let mut notes = Vec::new();
+
+    // Seconds are turned into ticks piece by piece between tempo changes.
+    let mut bpm = performance.bpm;
+    let mut tick_base = 0.0;
+    let mut seconds_base = 0.0;
+    let ticks_per_second =
+        |bpm: f32| bpm as f64 * 4.0 / beat_value as f64 / 60.0 * TICKS_PER_QUARTER as f64;
+    for event in performance.events.iter() {
+        let seconds = event.time.as_secs_f64();
+        let tick = (tick_base + (seconds - seconds_base) * ticks_per_second(bpm)) as u32;
+        let channel = u4::new(0);
+        match event.kind {
+            PerformanceEventKind::NoteOn { pitch, velocity } => {
+                if let Some(key) = pitch.to_midi() {
+                    notes.push((
+                        tick,
+                        TrackEventKind::Midi {
+                            channel,
+                            message: MidiMessage::NoteOn {
+                                key: u7::new(key),
+                                vel: u7::new(velocity.clamp(1, 127)),
+                            },
+                        },
+                    ));
+                }
+            }
+            PerformanceEventKind::NoteOff { pitch } => {
+                if let Some(key) = pitch.to_midi() {
+                    notes.push((
+                        tick,
+                        TrackEventKind::Midi {
+                            channel,
+                            message: MidiMessage::NoteOff {
+                                key: u7::new(key),
+                                vel: u7::new(0),
+                            },
+                        },
+                    ));
+                }
+            }
+            PerformanceEventKind::Pedal { down } => notes.push((
+                tick,
+                TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::Controller {
+                        controller: u7::new(SUSTAIN_PEDAL),
+                        value: u7::new(if down { 127 } else { 0 }),
+                    },
+                },
+            )),
+            PerformanceEventKind::PitchBend { bend } => notes.push((
+                tick,
+                TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::PitchBend {
+                        bend: PitchBend::from_int(bend),
+                    },
+                },
+            )),
+            PerformanceEventKind::InstrumentChange { program } => notes.push((
+                tick,
+                TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::ProgramChange {
+                        program: u7::new(program.min(127)),
+                    },
+                },
+            )),
+            PerformanceEventKind::TempoChange { bpm: new_bpm } => {
+                tick_base += (seconds - seconds_base) * ticks_per_second(bpm);
+                seconds_base = seconds;
+                bpm = new_bpm;
+                tempo_map.push((
+                    tick,
+                    TrackEventKind::Meta(MetaMessage::Tempo(
+                        microseconds_per_quarter(bpm, beat_value).into(),
+                    )),
+                ));
+            }
+            // The selected octave is already part of every pitch.
+            PerformanceEventKind::OctaveChange(_) => {}
+        }
+    }
+
+    let conductor: Vec<(u32, TrackEventKind)> = conductor.into_iter().chain(tempo_map).collect();
+    let (midly_format, tracks) = match format {
+        MidiFileFormat::SingleTrack => {
+            let mut events: Vec<(u32, TrackEventKind)> =
+                conductor.into_iter().chain(notes).collect();
+            // Stable, so events on the same tick keep the order they were played in.
+            events.sort_by_key(|(tick, _)| *tick);
+            (Format::SingleTrack, vec![to_track(events)])
+        }
+        MidiFileFormat::MultiTrack => {
+            (Format::Parallel, vec![to_track(conductor), to_track(notes)])
+        }
+    };
+    let mut smf = Smf::new(Header::new(
+        midly_format,
+        Timing::Metrical(u15::new(TICKS_PER_QUARTER)),
+    ));
+    smf.tracks = tracks;
+    smf.save(path)
+        .map_err(|err| format!("couldn't write {}: {}", path, err))
+}
+
+/// Turns absolute ticks into delta times and ends the track.
+fn to_track(events: Vec<(u32, TrackEventKind)>) -> Vec<TrackEvent> {
+    let mut previous_tick = 0;
+    let mut track: Vec<TrackEvent> = events
+        .into_iter()
+        .map(|(tick, kind)| {
+            let delta = tick.saturating_sub(previous_tick);
+            previous_tick = previous_tick.max(tick);
+            TrackEvent {
+                delta: u28::new(delta),
+                kind,
+            }
+        })
+        .collect();
+    track.push(TrackEvent {
+        delta: u28::new(0),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+    track
+}
+
+/// A note of an imported file, timed in seconds from its start.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SongNote {
+    pub start: f64,
+    pub end: f64,
+    pub pitch: Pitch,
+    pub velocity: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SongTrack {
+    pub name: String,
+    /// Sorted by start.
+    pub notes: Vec<SongNote>,
+}
+
+/// The notes of a Standard MIDI File, with the tempo map already applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Song {
+    pub tracks: Vec<SongTrack>,
+    /// Seconds until the last note ends.
+    pub duration: f64,
+}
+
+/// Turns ticks into seconds, following every tempo change in the file.
+struct TempoMap {
+    /// Tick, seconds at that tick and seconds per tick from there on.
+    segments: Vec<(u64, f64, f64)>,
+}
+
+impl TempoMap {
+    fn new(smf: &Smf) -> Self {
+        let ticks_per_quarter = match smf.header.timing {
+            Timing::Metrical(ticks) => ticks.as_int() as f64,
+            // Timecode files count ticks in real time, so tempo doesn't apply.
+            Timing::Timecode(fps, subframes) => {
+                let seconds_per_tick = 1.0 / (fps.as_f32() as f64 * subframes as f64);
+                return TempoMap {
+                    segments: vec![(0, 0.0, seconds_per_tick)],
+                };
+            }
+        };
+        let mut tempos: Vec<(u64, u32)> = Vec::new();
+        for track in smf.tracks.iter() {
+            let mut tick = 0;
+            for event in track.iter() {
+                tick += event.delta.as_int() as u64;
+                if let TrackEventKind::Meta(MetaMessage::Tempo(tempo)) = event.kind {
+                    tempos.push((tick, tempo.as_int()));
+                }
+            }
+        }
+        tempos.sort_by_key(|(tick, _)| *tick);
+        // 120 bpm until the first tempo event.
+        let mut segments = vec![(0, 0.0, 0.5 / ticks_per_quarter)];
+        for (tick, microseconds) in tempos {
+            let (last_tick, last_seconds, seconds_per_tick) = segments[segments.len() - 1];
+            let seconds = last_seconds + (tick - last_tick) as f64 * seconds_per_tick;
+            let seconds_per_tick = microseconds as f64 / 1_000_000.0 / ticks_per_quarter;
+            match segments.last_mut() {
+                Some(last) if last.0 == tick => *last = (tick, seconds, seconds_per_tick),
+                _ => segments.push((tick, seconds, seconds_per_tick)),
+            }
+        }
+        TempoMap { segments }
+    }
+
+    fn seconds(&self, tick: u64) -> f64 {
+        let index = self
+            .segments
+            .partition_point(|(start, _, _)| *start <= tick)
+            .saturating_sub(1);
+        let (start, seconds, seconds_per_tick) = self.segments[index];
+        seconds + (tick - start) as f64 * seconds_per_tick
+    }
+}
+
+/// Reads the notes of every track in the Standard MIDI File at `path`.
+/// Tracks without notes, like the tempo track of a format 1 file, are left out.
+pub fn import_song(path: &str) -> Result<Song, String> {
+    let bytes = fs::read(path).map_err(|err| format!("couldn't read {}: {}", path, err))?;
+    let smf = Smf::parse(&bytes).map_err(|err| format!("couldn't parse {}: {}", path, err))?;
+    let tempo_map = TempoMap::new(&smf);
+    let mut tracks = Vec::new();
+    for (index, track) in smf.tracks.iter().enumerate() {
+        let mut name = format!("track {}", index + 1);
+        let mut notes = Vec::new();
+        // Start tick and velocity of the sounding notes by channel and key.
+        let mut sounding: HashMap<(u8, u8), (u64, u8)> = HashMap::new();
+        let mut tick = 0;
+        for event in track.iter() {
+            tick += event.delta.as_int() as u64;
+            let (channel, message) = match event.kind {
+                TrackEventKind::Meta(MetaMessage::TrackName(track_name)) => {
+                    name = String::from_utf8_lossy(track_name).trim().to_string();
+                    continue;
+                }
+                TrackEventKind::Midi { channel, message } if channel.as_int() != DRUM_CHANNEL => {
+                    (channel.as_int(), message)
+                }
+                _ => continue,
+            };
+            match message {
+                MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                    sounding.insert((channel, key.as_int()), (tick, vel.as_int()));
+                }
+                // A note on with velocity 0 is a note off.
+                MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                    if let Some((start, velocity)) = sounding.remove(&(channel, key.as_int())) {
+                        notes.push(SongNote {
+                            start: tempo_map.seconds(start),
+                            end: tempo_map.seconds(tick),
+                            pitch: Pitch::from_midi(key.as_int()),
+                            velocity,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Notes that are never released end with the track.
+        for ((_, key), (start, velocity)) in sounding {
+            notes.push(SongNote {
+                start: tempo_map.seconds(start),
+                end: tempo_map.seconds(tick),
+                pitch: Pitch::from_midi(key),
+                velocity,
+            });
+        }
+        if notes.is_empty() {
+            continue;
+        }
+        notes.sort_by(|a, b| a.start.total_cmp(&b.start));
+        tracks.push(SongTrack { name, notes });
+    }
+    let duration = tracks
+        .iter()
+        .flat_map(|track| track.notes.iter())
+        .map(|note| note.end)
+        .fold(0.0, f64::max);
+    Ok(Song { tracks, duration })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        music_entities::Note, recorder::PerformanceEvent, test_support::temp_path,
+        transport::TimeSignature,
+    };
+
+    fn event(millis: u64, kind: PerformanceEventKind) -> PerformanceEvent {
+        PerformanceEvent {
+            time: Duration::from_millis(millis),
+            kind,
+        }
+    }
+
+    /// C4 and E4 at the starting tempo, then G4 after a change to half the
+    /// speed.
+    fn performance(time_signature: TimeSignature) -> Performance {
+        let mut performance = Performance::new(120.0, time_signature);
+        let c = Pitch::new(Note::C, 4);
+        let e = Pitch::new(Note::E, 4);
+        let g = Pitch::new(Note::G, 4);
+        performance.events = vec![
+            event(
+                0,
+                PerformanceEventKind::NoteOn {
+                    pitch: c,
+                    velocity: 90,
+                },
+            ),
+            event(250, PerformanceEventKind::Pedal { down: true }),
+            event(500, PerformanceEventKind::NoteOff { pitch: c }),
+            event(
+                500,
+                PerformanceEventKind::NoteOn {
+                    pitch: e,
+                    velocity: 60,
+                },
+            ),
+            event(1000, PerformanceEventKind::NoteOff { pitch: e }),
+            event(1000, PerformanceEventKind::TempoChange { bpm: 60.0 }),
+            event(
+                1000,
+                PerformanceEventKind::NoteOn {
+                    pitch: g,
+                    velocity: 127,
+                },
+            ),
+            event(2000, PerformanceEventKind::NoteOff { pitch: g }),
+        ];
+        performance
+    }
+
+    fn round_trip(performance: &Performance, format: MidiFileFormat, name: &str) -> Song {
+        let path = temp_path("midi", name);
+        export_performance(performance, format, &path).unwrap();
+        let song = import_song(&path);
+        fs::remove_file(&path).unwrap();
+        song.unwrap()
+    }
+
+    fn assert_notes(song: &Song) {
+        assert_eq!(song.tracks.len(), 1);
+        let notes = &song.tracks[0].notes;
+        let expected = [
+            (0.0, 0.5, Note::C, 90),
+            (0.5, 1.0, Note::E, 60),
+            (1.0, 2.0, Note::G, 127),
+        ];
+        assert_eq!(notes.len(), expected.len());
+        for (note, (start, end, pitch, velocity)) in notes.iter().zip(expected) {
+            // Ticks round to well under a millisecond.
+            assert!((note.start - start).abs() < 1e-3, "{:?}", note);
+            assert!((note.end - end).abs() < 1e-3, "{:?}", note);
+            assert_eq!(note.pitch, Pitch::new(pitch, 4));
+            assert_eq!(note.velocity, velocity);
+        }
+        assert!((song.duration - 2.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn both_formats_round_trip_through_tempo_changes() {
+        let performance = performance(TimeSignature::new(4, 4));
+        let single = round_trip(&performance, MidiFileFormat::SingleTrack, "format_0.mid");
+        assert_notes(&single);
+        let multi = round_trip(&performance, MidiFileFormat::MultiTrack, "format_1.mid");
+        assert_notes(&multi);
+        // The name is on the conductor track, which has no notes.
+        assert_eq!(multi.tracks[0].name, "track 2");
+    }
+
+    #[test]
+    fn tempo_counts_beats_of_the_time_signature() {
+        let performance = performance(TimeSignature::new(6, 8));
+        assert_notes(&round_trip(
+            &performance,
+            MidiFileFormat::MultiTrack,
+            "compound.mid",
+        ));
+    }
+
+    #[test]
+    fn unreadable_files_are_errors() {
+        assert!(import_song(&temp_path("midi", "missing.mid")).is_err());
+        let path = temp_path("midi", "garbage.mid");
+        fs::write(&path, b"MThd but not really").unwrap();
+        let song = import_song(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(song.unwrap_err().starts_with("couldn't parse"));
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    fs,
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+use midir::{Ignore, MidiInputConnection};
+use midly::{live::LiveEvent, stream::MidiStream, MidiMessage};
+
+use crate::{midi_file::SUSTAIN_PEDAL, music_entities::Pitch};
+
+const CLIENT_NAME: &str = "piano_man";
+/// Semitones the pitch wheel bends at either end.
+pub const PITCH_BEND_RANGE: f32 = 2.0;
+
+/// What a MIDI controller played, on any channel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MidiInputEvent {
+    NoteOn {
+        pitch: Pitch,
+        velocity: u8,
+    },
+    NoteOff {
+        pitch: Pitch,
+    },
+    Sustain {
+        down: bool,
+    },
+    /// -8192 to 8191, 0 being the wheel at rest.
+    PitchBend(i16),
+}
+
+/// How much higher a note sounds with the pitch wheel at `bend`.
+pub fn bend_ratio(bend: i16) -> f32 {
+    2f32.powf(bend as f32 / 8192.0 * PITCH_BEND_RANGE / 12.0)
+}
+
+/// Raw MIDI bytes from a port or a replayed file, parsed as they are polled.
+pub struct MidiInput {
+    receiver: Receiver<(Instant, Vec<u8>)>,
+    stream: MidiStream,
+    _connection: Option<MidiInputConnection<()>>,
+}
+
+impl MidiInput {
+    /// Connects to the first input port whose name contains `port`, or opens
+    /// a virtual port other programs can send to when `port` is "virtual".
+    pub fn connect(port: &str) -> Result<Self, String> {
+        let (sender, receiver) = mpsc::channel();
+        let mut input = midir::MidiInput::new(CLIENT_NAME).map_err(|err| err.to_string())?;
+        input.ignore(Ignore::All);
+        let callback = move |_: u64, bytes: &[u8], _: &mut ()| {
+            let _ = sender.send((Instant::now(), bytes.to_vec()));
+        };
+        let connection = match port {
+            "virtual" => create_virtual(input, callback)?,
+            _ => {
+                let found = input
+                    .ports()
+                    .into_iter()
+                    .find(|found| {
+                        input
+                            .port_name(found)
+                            .map(|name| name.contains(port))
+                            .unwrap_or(false)
+                    })
+                    .ok_or(format!("no midi input port matching {}", port))?;
+                input
+                    .connect(&found, CLIENT_NAME, callback, ())
+                    .map_err(|err| err.to_string())?
+            }
+        };
+        Ok(MidiInput {
+            receiver,
+            stream: MidiStream::new(),
+            _connection: Some(connection),
+        })
+    }
+
+    /// Replays a file of MIDI bytes as if they came from a port. Each line is
+    /// the seconds since the start followed by bytes in hex, e.g.
+    /// `0.5 90 3c 64`, and may rely on running status.
+    pub fn replay(path: &str) -> Result<Self, String> {
+        let text =
+            fs::read_to_string(path).map_err(|err| format!("couldn't read {}: {}", path, err))?;
+        let mut messages = Vec::new();
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let invalid = || format!("invalid line in {}: {}", path, line);
+            let mut fields = line.split_whitespace();
+            let seconds = fields
+                .next()
+                .and_then(|seconds| seconds.parse::<f64>().ok())
+                .filter(|seconds| seconds.is_finite())
+                .ok_or_else(invalid)?;
+            let bytes = fields
+                .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| invalid()))
+                .collect::<Result<Vec<u8>, String>>()?;
+            messages.push((Duration::from_secs_f64(seconds.max(0.0)), bytes));
+        }
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let start = Instant::now();
+            for (time, bytes) in messages {
+                thread::sleep((start + time).saturating_duration_since(Instant::now()));
+                if sender.send((Instant::now(), bytes)).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(MidiInput {
+            receiver,
+            stream: MidiStream::new(),
+            _connection: None,
+        })
+    }
+
+    /// Events received since the last poll, with the time they arrived.
+    pub fn poll(&mut self) -> Vec<(MidiInputEvent, Instant)> {
+        let mut events = Vec::new();
+        for (timestamp, bytes) in self.receiver.try_iter() {
+            self.stream.feed(&bytes, |event| {
+                if let Some(event) = to_input_event(event) {
+                    events.push((event, timestamp));
+                }
+            });
+        }
+        events
+    }
+}
+
+#[cfg(unix)]
+fn create_virtual<F>(
+    input: midir::MidiInput,
+    callback: F,
+) -> Result<MidiInputConnection<()>, String>
+where
+    F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+{
+    use midir::os::unix::VirtualInput;
+    input
+        .create_virtual(CLIENT_NAME, callback, ())
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(not(unix))]
+fn create_virtual<F>(_: midir::MidiInput, _: F) -> Result<MidiInputConnection<()>, String>
+where
+    F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+{
+    Err("virtual midi ports aren't supported on this platform".to_string())
+}
+
+fn to_input_event(event: LiveEvent) -> Option<MidiInputEvent> {
+    let LiveEvent::Midi { message, .. } = event else {
+        return None;
+    };
+    match message {
+        // A note on with velocity 0 is a note off.
+        MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => Some(MidiInputEvent::NoteOn {
+            pitch: Pitch::from_midi(key.as_int()),
+            velocity: vel.as_int(),
+        }),
+        MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+            Some(MidiInputEvent::NoteOff {
+                pitch: Pitch::from_midi(key.as_int()),
+            })
+        }
+        // Half pedal and above counts as down.
+        MidiMessage::Controller { controller, value } if controller.as_int() == SUSTAIN_PEDAL => {
+            Some(MidiInputEvent::Sustain {
+                down: value.as_int() >= 64,
+            })
+        }
+        MidiMessage::PitchBend { bend } => Some(MidiInputEvent::PitchBend(bend.as_int())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{music_entities::Note, test_support::temp_path};
+
+    fn replay_text(name: &str, text: &str) -> Result<MidiInput, String> {
+        let path = temp_path("midi_input", name);
+        fs::write(&path, text).unwrap();
+        let input = MidiInput::replay(&path);
+        fs::remove_file(&path).unwrap();
+        input
+    }
+
+    #[test]
+    fn replayed_bytes_become_input_events() {
+        let text =
+            "0 90 3c 64\n\n0 3e 50\n0.02 80 3c 00\n0.02 b0 40 7f\n0.02 e0 00 60\n0.02 90 3e 00\n";
+        let mut input = replay_text("bytes.txt", text).unwrap();
+        let mut events = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while events.len() < 6 && Instant::now() < deadline {
+            events.extend(input.poll().into_iter().map(|(event, _)| event));
+            thread::sleep(Duration::from_millis(5));
+        }
+        let c = Pitch::new(Note::C, 4);
+        let d = Pitch::new(Note::D, 4);
+        assert_eq!(
+            events,
+            vec![
+                MidiInputEvent::NoteOn {
+                    pitch: c,
+                    velocity: 100
+                },
+                // Running status reuses the note on status byte.
+                MidiInputEvent::NoteOn {
+                    pitch: d,
+                    velocity: 80
+                },
+                MidiInputEvent::NoteOff { pitch: c },
+                MidiInputEvent::Sustain { down: true },
+                MidiInputEvent::PitchBend(4096),
+                MidiInputEvent::NoteOff { pitch: d },
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_lines_are_errors() {
+        assert!(replay_text("seconds.txt", "soon 90 3c 64\n").is_err());
+        assert!(replay_text("infinite.txt", "inf 90 3c 64\n").is_err());
+        assert!(replay_text("nan.txt", "NaN 90 3c 64\n").is_err());
+        assert!(replay_text("hex.txt", "0 90 zz 64\n").is_err());
+        assert!(MidiInput::replay(&temp_path("midi_input", "missing.txt")).is_err());
+    }
+
+    #[test]
+    fn pitch_bend_range_is_two_semitones() {
+        assert_eq!(bend_ratio(0), 1.0);
+        assert!((bend_ratio(8192) - 2f32.powf(2.0 / 12.0)).abs() < 1e-6);
+        assert!((bend_ratio(-8192) - 2f32.powf(-2.0 / 12.0)).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,233 @@
+use std::time::{Duration, Instant};
+
+use midir::MidiOutputConnection;
+use midly::{
+    live::LiveEvent,
+    num::{u4, u7},
+    MidiMessage, PitchBend,
+};
+
+use crate::{midi_file::SUSTAIN_PEDAL, music_entities::Pitch, recorder::PerformanceEventKind};
+
+const CLIENT_NAME: &str = "piano_man";
+const ALL_NOTES_OFF: u8 = 123;
+
+/// Sends what is played to another instrument on one MIDI channel.
+pub struct MidiOutput {
+    connection: MidiOutputConnection,
+    channel: u4,
+    /// Note offs for notes of a known length, sent once they are due.
+    pending_offs: Vec<(Instant, Pitch)>,
+}
+
+impl MidiOutput {
+    /// Connects to the first output port whose name contains `port`, or
+    /// opens a virtual port when `port` is "virtual". Channels are 1 to 16.
+    pub fn connect(port: &str, channel: u8) -> Result<Self, String> {
+        if !(1..=16).contains(&channel) {
+            return Err(format!("midi channel must be 1 to 16, not {}", channel));
+        }
+        let output = midir::MidiOutput::new(CLIENT_NAME).map_err(|err| err.to_string())?;
+        let connection = match port {
+            "virtual" => create_virtual(output)?,
+            _ => {
+                let found = output
+                    .ports()
+                    .into_iter()
+                    .find(|found| {
+                        output
+                            .port_name(found)
+                            .map(|name| name.contains(port))
+                            .unwrap_or(false)
+                    })
+                    .ok_or(format!("no midi output port matching {}", port))?;
+                output
+                    .connect(&found, CLIENT_NAME)
+                    .map_err(|err| err.to_string())?
+            }
+        };
+        Ok(MidiOutput {
+            connection,
+            channel: u4::new(channel - 1),
+            pending_offs: Vec::new(),
+        })
+    }
+
+    /// Sends a note event from `NoteTracker`. Events MIDI has no message
+    /// for, like octave changes, are left out.
+    pub fn send(&mut self, kind: PerformanceEventKind) {
+        if let Some(message) = midi_message(kind) {
+            self.send_message(message);
+        }
+    }
+
+    /// Sends a note on now and its note off after `length`, for notes that
+    /// aren't held on a key, like those of the arpeggiator.
+    pub fn play_note(&mut self, pitch: Pitch, velocity: u8, length: Duration, now: Instant) {
+        self.send(PerformanceEventKind::NoteOn { pitch, velocity });
+        self.pending_offs.push((now + length, pitch));
+    }
+
+    /// Sends the note offs that are due.
+    pub fn poll(&mut self, now: Instant) {
+        for pitch in take_due(&mut self.pending_offs, now) {
+            self.send(PerformanceEventKind::NoteOff { pitch });
+        }
+    }
+
+    fn send_message(&mut self, message: MidiMessage) {
+        if let Some(bytes) = message_bytes(self.channel, message) {
+            if let Err(err) = self.connection.send(&bytes) {
+                eprintln!("couldn't send midi: {}", err);
+            }
+        }
+    }
+}
+
+/// The message for a performance event, `None` for events MIDI has no
+/// message for and pitches outside of its range.
+fn midi_message(kind: PerformanceEventKind) -> Option<MidiMessage> {
+    let message = match kind {
+        PerformanceEventKind::NoteOn { pitch, velocity } => MidiMessage::NoteOn {
+            key: u7::new(pitch.to_midi()?),
+            vel: u7::new(velocity.clamp(1, 127)),
+        },
+        PerformanceEventKind::NoteOff { pitch } => MidiMessage::NoteOff {
+            key: u7::new(pitch.to_midi()?),
+            vel: u7::new(0),
+        },
+        PerformanceEventKind::Pedal { down } => MidiMessage::Controller {
+            controller: u7::new(SUSTAIN_PEDAL),
+            value: u7::new(if down { 127 } else { 0 }),
+        },
+        PerformanceEventKind::PitchBend { bend } => MidiMessage::PitchBend {
+            bend: PitchBend::from_int(bend),
+        },
+        PerformanceEventKind::InstrumentChange { program } => MidiMessage::ProgramChange {
+            program: u7::new(program.min(127)),
+        },
+        PerformanceEventKind::OctaveChange(_) | PerformanceEventKind::TempoChange { .. } => {
+            return None
+        }
+    };
+    Some(message)
+}
+
+/// The bytes sent for `message` on `channel`.
+fn message_bytes(channel: u4, message: MidiMessage) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let event = LiveEvent::Midi { channel, message };
+    event.write_std(&mut bytes).ok().map(|_| bytes)
+}
+
+/// Removes the note offs due at `now` and returns their pitches.
+fn take_due(pending_offs: &mut Vec<(Instant, Pitch)>, now: Instant) -> Vec<Pitch> {
+    let mut due = Vec::new();
+    pending_offs.retain(|(time, pitch)| {
+        if *time <= now {
+            due.push(*pitch);
+        }
+        *time > now
+    });
+    due
+}
+
+// Leaves no notes hanging on the other instrument.
+impl Drop for MidiOutput {
+    fn drop(&mut self) {
+        for (_, pitch) in std::mem::take(&mut self.pending_offs) {
+            self.send(PerformanceEventKind::NoteOff { pitch });
+        }
+        self.send_message(MidiMessage::Controller {
+            controller: u7::new(ALL_NOTES_OFF),
+            value: u7::new(0),
+        });
+    }
+}
+
+#[cfg(unix)]
+fn create_virtual(output: midir::MidiOutput) -> Result<MidiOutputConnection, String> {
+    use midir::os::unix::VirtualOutput;
+    output
+        .create_virtual(CLIENT_NAME)
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(not(unix))]
+fn create_virtual(_: midir::MidiOutput) -> Result<MidiOutputConnection, String> {
+    Err("virtual midi ports aren't supported on this platform".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::music_entities::Note;
+
+    fn bytes(channel: u8, kind: PerformanceEventKind) -> Option<Vec<u8>> {
+        message_bytes(u4::new(channel), midi_message(kind)?)
+    }
+
+    #[test]
+    fn note_messages() {
+        let middle_c = Pitch::new(Note::C, 4);
+        let note_on = |velocity| PerformanceEventKind::NoteOn {
+            pitch: middle_c,
+            velocity,
+        };
+        assert_eq!(bytes(0, note_on(100)), Some(vec![0x90, 60, 100]));
+        assert_eq!(bytes(9, note_on(100)), Some(vec![0x99, 60, 100]));
+        // A velocity of 0 would be read as a note off.
+        assert_eq!(bytes(0, note_on(0)), Some(vec![0x90, 60, 1]));
+        assert_eq!(bytes(15, note_on(200)), Some(vec![0x9F, 60, 127]));
+        let note_off = PerformanceEventKind::NoteOff { pitch: middle_c };
+        assert_eq!(bytes(2, note_off), Some(vec![0x82, 60, 0]));
+        let too_high = PerformanceEventKind::NoteOn {
+            pitch: Pitch::new(Note::A, 9),
+            velocity: 100,
+        };
+        assert_eq!(bytes(0, too_high), None);
+    }
+
+    #[test]
+    fn controller_messages() {
+        let pedal = |down| PerformanceEventKind::Pedal { down };
+        assert_eq!(bytes(0, pedal(true)), Some(vec![0xB0, 64, 127]));
+        assert_eq!(bytes(0, pedal(false)), Some(vec![0xB0, 64, 0]));
+        let bend = |bend| PerformanceEventKind::PitchBend { bend };
+        assert_eq!(bytes(0, bend(0)), Some(vec![0xE0, 0x00, 0x40]));
+        assert_eq!(bytes(1, bend(-8192)), Some(vec![0xE1, 0x00, 0x00]));
+        assert_eq!(bytes(0, bend(8191)), Some(vec![0xE0, 0x7F, 0x7F]));
+        let program = |program| PerformanceEventKind::InstrumentChange { program };
+        assert_eq!(bytes(0, program(5)), Some(vec![0xC0, 5]));
+        assert_eq!(bytes(0, program(200)), Some(vec![0xC0, 127]));
+        assert_eq!(bytes(0, PerformanceEventKind::OctaveChange(5)), None);
+        assert_eq!(
+            bytes(0, PerformanceEventKind::TempoChange { bpm: 90.0 }),
+            None
+        );
+    }
+
+    #[test]
+    fn note_offs_are_sent_once_due() {
+        let now = Instant::now();
+        let c = Pitch::new(Note::C, 4);
+        let e = Pitch::new(Note::E, 4);
+        let g = Pitch::new(Note::G, 4);
+        let mut pending_offs = vec![
+            (now + Duration::from_millis(200), c),
+            (now + Duration::from_millis(100), e),
+            (now + Duration::from_millis(300), g),
+        ];
+        assert!(take_due(&mut pending_offs, now).is_empty());
+        assert_eq!(
+            take_due(&mut pending_offs, now + Duration::from_millis(200)),
+            [c, e]
+        );
+        assert_eq!(pending_offs, [(now + Duration::from_millis(300), g)]);
+        assert_eq!(
+            take_due(&mut pending_offs, now + Duration::from_secs(1)),
+            [g]
+        );
+        assert!(take_due(&mut pending_offs, now + Duration::from_secs(2)).is_empty());
+    }
+}
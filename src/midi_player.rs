@@ -0,0 +1,366 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    midi_file::{Song, SongNote},
+    music_entities::Pitch,
+};
+
+const MIN_TEMPO_SCALE: f32 = 0.25;
+const MAX_TEMPO_SCALE: f32 = 4.0;
+
+/// A note the player has reached, with its length at the current tempo scale.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlayedNote {
+    pub pitch: Pitch,
+    pub velocity: u8,
+    pub length: Duration,
+}
+
+/// Plays back an imported `Song`. It is polled from the event loop and
+/// returns the notes that start between polls.
+pub struct MidiPlayer {
+    song: Song,
+    /// Seconds into the song.
+    position: f64,
+    playing: bool,
+    last_poll: Option<Instant>,
+    tempo_scale: f32,
+    loop_region: Option<(f64, f64)>,
+    /// Start of a loop region waiting for its end to be marked.
+    loop_start: Option<f64>,
+    muted: Vec<bool>,
+    soloed: Vec<bool>,
+}
+
+impl MidiPlayer {
+    pub fn new(song: Song) -> Self {
+        let track_count = song.tracks.len();
+        MidiPlayer {
+            song,
+            position: 0.0,
+            playing: false,
+            last_poll: None,
+            tempo_scale: 1.0,
+            loop_region: None,
+            loop_start: None,
+            muted: vec![false; track_count],
+            soloed: vec![false; track_count],
+        }
+    }
+
+    pub fn get_song(&self) -> &Song {
+        &self.song
+    }
+
+    pub fn play(&mut self) {
+        if self.position >= self.song.duration {
+            self.position = 0.0;
+        }
+        self.playing = true;
+    }
+
+    pub fn pause(&mut self) {
+        self.playing = false;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn get_position(&self) -> f64 {
+        self.position
+    }
+
+    /// Moves to `seconds` into the song. Notes already sounding carry on.
+    pub fn seek(&mut self, seconds: f64) {
+        self.position = seconds.clamp(0.0, self.song.duration);
+    }
+
+    pub fn set_tempo_scale(&mut self, tempo_scale: f32) {
+        self.tempo_scale = tempo_scale.clamp(MIN_TEMPO_SCALE, MAX_TEMPO_SCALE);
+    }
+
+    pub fn get_tempo_scale(&self) -> f32 {
+        self.tempo_scale
+    }
+
+    /// Loops between `start` and `end` seconds, or plays through with `None`.
+    pub fn set_loop_region(&mut self, loop_region: Option<(f64, f64)>) {
+        self.loop_start = None;
+        self.loop_region = loop_region.filter(|(start, end)| end > start);
+    }
+
+    pub fn get_loop_region(&self) -> Option<(f64, f64)> {
+        self.loop_region
+    }
+
+    /// Marks the start of a loop region at the current position, then its
+    /// end, and clears the region on the next call.
+    pub fn mark_loop_point(&mut self) {
+        match (self.loop_region, self.loop_start) {
+            (Some(_), _) => self.set_loop_region(None),
+            (None, None) => self.loop_start = Some(self.position),
+            (None, Some(start)) => {
+                self.set_loop_region(Some((start.min(self.position), start.max(self.position))))
+            }
+        }
+    }
+
+    pub fn set_muted(&mut self, track: usize, muted: bool) {
+        if let Some(track_muted) = self.muted.get_mut(track) {
+            *track_muted = muted;
+        }
+    }
+
+    pub fn is_muted(&self, track: usize) -> bool {
+        self.muted.get(track).copied().unwrap_or(false)
+    }
+
+    pub fn set_soloed(&mut self, track: usize, soloed: bool) {
+        if let Some(track_soloed) = self.soloed.get_mut(track) {
+            *track_soloed = soloed;
+        }
+    }
+
+    pub fn is_soloed(&self, track: usize) -> bool {
+        self.soloed.get(track).copied().unwrap_or(false)
+    }
+
+    /// Whether a track is heard: soloed tracks silence all others, otherwise
+    /// every track that isn't muted plays.
+    pub fn is_audible(&self, track: usize) -> bool {
+        match self.soloed.contains(&true) {
+            true => self.is_soloed(track),
+            false => !self.is_muted(track),
+        }
+    }
+
+    /// Advances the position by the time since the last poll and returns
+    /// the notes of audible tracks that start in between.
+    pub fn poll(&mut self, now: Instant) -> Vec<PlayedNote> {
+        if !self.playing {
+            self.last_poll = None;
+            return Vec::new();
+        }
+        let elapsed = self
+            .last_poll
+            .map(|last_poll| now.saturating_duration_since(last_poll).as_secs_f64())
+            .unwrap_or(0.0);
+        self.last_poll = Some(now);
+        let from = self.position;
+        let to = from + elapsed * self.tempo_scale as f64;
+
+        match self.loop_region {
+            // Positions past the region, e.g. from seeking, jump back into it
+            // once they are reached.
+            Some((start, end)) if from < end && to >= end => {
+                let wrapped = start + (to - end) % (end - start);
+                let mut notes = self.notes_between(from, end);
+                notes.extend(self.notes_between(start, wrapped));
+                self.position = wrapped;
+                notes
+            }
+            _ if to >= self.song.duration => {
+                let notes = self.notes_between(from, f64::INFINITY);
+                self.position = self.song.duration;
+                self.playing = false;
+                notes
+            }
+            _ => {
+                self.position = to;
+                self.notes_between(from, to)
+            }
+        }
+    }
+
+    fn notes_between(&self, from: f64, to: f64) -> Vec<PlayedNote> {
+        let mut notes = Vec::new();
+        for (index, track) in self.song.tracks.iter().enumerate() {
+            if !self.is_audible(index) {
+                continue;
+            }
+            let first = track.notes.partition_point(|note| note.start < from);
+            let last = track.notes.partition_point(|note| note.start < to);
+            notes.extend(
+                track.notes[first..last]
+                    .iter()
+                    .map(|note| self.played_note(note)),
+            );
+        }
+        notes
+    }
+
+    fn played_note(&self, note: &SongNote) -> PlayedNote {
+        PlayedNote {
+            pitch: note.pitch,
+            velocity: note.velocity,
+            // Lengths that can't be played, such as a note ending before it
+            // starts, play as nothing.
+            length: Duration::try_from_secs_f64((note.end - note.start) / self.tempo_scale as f64)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{midi_file::SongTrack, music_entities::Note};
+
+    fn note(start: f64, end: f64, note: Note) -> SongNote {
+        SongNote {
+            start,
+            end,
+            pitch: Pitch::new(note, 4),
+            velocity: 100,
+        }
+    }
+
+    /// C, D, E and F a second apart, and a second track with a G at 0.5 s.
+    fn player() -> MidiPlayer {
+        MidiPlayer::new(Song {
+            tracks: vec![
+                SongTrack {
+                    name: String::from("melody"),
+                    notes: [Note::C, Note::D, Note::E, Note::F]
+                        .into_iter()
+                        .enumerate()
+                        .map(|(index, pitch)| note(index as f64, index as f64 + 1.0, pitch))
+                        .collect(),
+                },
+                SongTrack {
+                    name: String::from("bass"),
+                    notes: vec![note(0.5, 1.0, Note::G)],
+                },
+            ],
+            duration: 4.0,
+        })
+    }
+
+    fn notes(played: Vec<PlayedNote>) -> Vec<Note> {
+        played.iter().map(|note| note.pitch.note).collect()
+    }
+
+    fn seconds(seconds: f64) -> Duration {
+        Duration::from_secs_f64(seconds)
+    }
+
+    #[test]
+    fn polls_return_the_notes_started_since_the_last() {
+        let mut player = player();
+        let start = Instant::now();
+        assert!(player.poll(start).is_empty());
+        player.play();
+        assert!(player.poll(start).is_empty());
+        assert_eq!(notes(player.poll(start + seconds(0.6))), [Note::C, Note::G]);
+        assert_eq!(notes(player.poll(start + seconds(1.6))), [Note::D]);
+        assert!((player.get_position() - 1.6).abs() < 1e-9);
+        player.pause();
+        assert!(player.poll(start + seconds(3.0)).is_empty());
+        assert!((player.get_position() - 1.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn playing_stops_at_the_end_and_starts_over() {
+        let mut player = player();
+        let start = Instant::now();
+        player.seek(2.5);
+        player.play();
+        player.poll(start);
+        assert_eq!(notes(player.poll(start + seconds(10.0))), [Note::F]);
+        assert!(!player.is_playing());
+        assert_eq!(player.get_position(), 4.0);
+        player.play();
+        assert_eq!(player.get_position(), 0.0);
+        player.seek(-1.0);
+        assert_eq!(player.get_position(), 0.0);
+        player.seek(10.0);
+        assert_eq!(player.get_position(), 4.0);
+    }
+
+    #[test]
+    fn loop_regions_wrap_around() {
+        let mut player = player();
+        let start = Instant::now();
+        player.seek(1.5);
+        player.set_loop_region(Some((1.0, 2.0)));
+        player.play();
+        player.poll(start);
+        // From 1.5 to the end of the region, then 0.5 s from its start.
+        assert_eq!(notes(player.poll(start + seconds(1.0))), [Note::D]);
+        assert!((player.get_position() - 1.5).abs() < 1e-9);
+        player.set_loop_region(Some((2.0, 1.0)));
+        assert_eq!(player.get_loop_region(), None);
+    }
+
+    #[test]
+    fn loop_points_are_marked_in_either_order() {
+        let mut player = player();
+        player.seek(3.0);
+        player.mark_loop_point();
+        assert_eq!(player.get_loop_region(), None);
+        player.seek(1.0);
+        player.mark_loop_point();
+        assert_eq!(player.get_loop_region(), Some((1.0, 3.0)));
+        player.mark_loop_point();
+        assert_eq!(player.get_loop_region(), None);
+    }
+
+    #[test]
+    fn tempo_scale_speeds_up_position_and_lengths() {
+        let mut player = player();
+        let start = Instant::now();
+        player.set_tempo_scale(2.0);
+        player.play();
+        player.poll(start);
+        let played = player.poll(start + seconds(0.5));
+        assert_eq!(notes(played.clone()), [Note::C, Note::G]);
+        assert_eq!(played[0].length, seconds(0.5));
+        assert!((player.get_position() - 1.0).abs() < 1e-9);
+        player.set_tempo_scale(100.0);
+        assert_eq!(player.get_tempo_scale(), MAX_TEMPO_SCALE);
+        player.set_tempo_scale(0.0);
+        assert_eq!(player.get_tempo_scale(), MIN_TEMPO_SCALE);
+    }
+
+    #[test]
+    fn muted_and_soloed_tracks() {
+        let mut player = player();
+        player.set_muted(0, true);
+        assert_eq!(notes(player.notes_between(0.0, 1.0)), [Note::G]);
+        player.set_muted(0, false);
+        player.set_soloed(0, true);
+        assert!(player.is_audible(0));
+        assert!(!player.is_audible(1));
+        assert_eq!(notes(player.notes_between(0.0, 1.0)), [Note::C]);
+        // Soloing wins over muting, and unknown tracks are ignored.
+        player.set_muted(0, true);
+        assert!(player.is_audible(0));
+        player.set_soloed(5, true);
+        assert!(!player.is_soloed(5));
+    }
+
+    #[test]
+    fn unplayable_lengths_play_as_nothing() {
+        let player = MidiPlayer::new(Song {
+            tracks: vec![SongTrack {
+                name: String::from("track"),
+                notes: vec![
+                    note(0.0, 1.0, Note::C),
+                    note(1.0, 0.5, Note::C),
+                    note(2.0, f64::INFINITY, Note::C),
+                ],
+            }],
+            duration: 3.0,
+        });
+        let lengths: Vec<Duration> = player
+            .notes_between(0.0, 3.0)
+            .iter()
+            .map(|note| note.length)
+            .collect();
+        assert_eq!(
+            lengths,
+            [Duration::from_secs(1), Duration::ZERO, Duration::ZERO]
+        );
+    }
+}
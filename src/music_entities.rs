@@ -1,19 +1,11 @@
-use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
-use std::hash::{Hash, Hasher};
+use std::fmt;
+use std::ops::{Add, RangeInclusive, Sub};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use maplit::hashmap;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
-pub enum Octave {
-    C1,
-    C2,
-    C3,
-    C4,
-    C5,
-    C6,
-}
-#[derive(Debug, Eq, Hash, PartialEq, PartialOrd, Ord, Clone, Copy)]
+#[derive(Debug, Eq, Hash, PartialEq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
 pub enum Note {
     A,
     ASharpBFlat,
@@ -28,86 +20,1802 @@ pub enum Note {
     G,
     GsharpAflat,
 }
-#[derive(Debug)]
-pub enum Chord {
+
+impl Note {
+    /// All notes in semitone order starting from C.
+    pub const CHROMATIC: [Note; 12] = [
+        Note::C,
+        Note::CsharpDflat,
+        Note::D,
+        Note::DsharpEflat,
+        Note::E,
+        Note::F,
+        Note::FsharpGflat,
+        Note::G,
+        Note::GsharpAflat,
+        Note::A,
+        Note::ASharpBFlat,
+        Note::B,
+    ];
+
+    /// Semitones above C, 0..=11.
+    pub fn semitone(&self) -> u8 {
+        match self {
+            Note::C => 0,
+            Note::CsharpDflat => 1,
+            Note::D => 2,
+            Note::DsharpEflat => 3,
+            Note::E => 4,
+            Note::F => 5,
+            Note::FsharpGflat => 6,
+            Note::G => 7,
+            Note::GsharpAflat => 8,
+            Note::A => 9,
+            Note::ASharpBFlat => 10,
+            Note::B => 11,
+        }
+    }
+
+    pub fn from_semitone(semitone: u8) -> Note {
+        Note::CHROMATIC[(semitone % 12) as usize]
+    }
+
+    /// Moves the note up by `semitones`, wrapping around the octave.
+    pub fn transpose(&self, semitones: u8) -> Note {
+        Note::from_semitone(self.semitone() + semitones % 12)
+    }
+}
+
+impl fmt::Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Note::C => "C",
+            Note::CsharpDflat => "C#",
+            Note::D => "D",
+            Note::DsharpEflat => "D#",
+            Note::E => "E",
+            Note::F => "F",
+            Note::FsharpGflat => "F#",
+            Note::G => "G",
+            Note::GsharpAflat => "G#",
+            Note::A => "A",
+            Note::ASharpBFlat => "A#",
+            Note::B => "B",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Note {
+    type Err = String;
+
+    /// Parses a note name such as "C", "C#" or "Db".
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let mut chars = name.chars();
+        let natural = match chars.next().map(|letter| letter.to_ascii_uppercase()) {
+            Some('C') => Note::C,
+            Some('D') => Note::D,
+            Some('E') => Note::E,
+            Some('F') => Note::F,
+            Some('G') => Note::G,
+            Some('A') => Note::A,
+            Some('B') => Note::B,
+            _ => return Err(format!("invalid note name: {}", name)),
+        };
+        let mut semitone = natural.semitone() as i32;
+        for accidental in chars {
+            match accidental {
+                '#' => semitone += 1,
+                'b' => semitone -= 1,
+                _ => return Err(format!("invalid note name: {}", name)),
+            }
+        }
+        Ok(Note::from_semitone(semitone.rem_euclid(12) as u8))
+    }
+}
+
+/// A distance between two pitches counted in semitones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Interval(pub i32);
+
+impl Interval {
+    pub fn semitones(&self) -> i32 {
+        self.0
+    }
+}
+
+/// A note in a specific octave, using scientific pitch notation where C4 is
+/// middle C and MIDI note 60.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Pitch {
+    pub note: Note,
+    pub octave: i8,
+}
+
+impl Pitch {
+    pub fn new(note: Note, octave: i8) -> Self {
+        Pitch { note, octave }
+    }
+
+    /// Semitones above C-1, which lines up with MIDI note numbers but is not
+    /// limited to their range.
+    pub fn index(&self) -> i32 {
+        (self.octave as i32 + 1) * 12 + self.note.semitone() as i32
+    }
+
+    fn from_index(index: i32) -> Self {
+        Pitch {
+            note: Note::from_semitone(index.rem_euclid(12) as u8),
+            octave: (index.div_euclid(12) - 1) as i8,
+        }
+    }
+
+    pub fn from_midi(midi_note: u8) -> Self {
+        Pitch::from_index(midi_note as i32)
+    }
+
+    /// The MIDI note number, `None` when the pitch is outside 0..=127.
+    pub fn to_midi(self) -> Option<u8> {
+        u8::try_from(self.index())
+            .ok()
+            .filter(|midi_note| *midi_note <= 127)
+    }
+
+    /// Frequency in Hz in twelve-tone equal temperament with A4 at 440 Hz.
+    pub fn frequency(&self) -> f32 {
+        440.0 * 2f32.powf((self.index() - 69) as f32 / 12.0)
+    }
+
+    pub fn transpose(&self, interval: Interval) -> Self {
+        Pitch::from_index(self.index() + interval.semitones())
+    }
+}
+
+impl Ord for Pitch {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.index().cmp(&other.index())
+    }
+}
+
+impl PartialOrd for Pitch {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Add<Interval> for Pitch {
+    type Output = Pitch;
+
+    fn add(self, interval: Interval) -> Pitch {
+        self.transpose(interval)
+    }
+}
+
+impl Sub<Interval> for Pitch {
+    type Output = Pitch;
+
+    fn sub(self, interval: Interval) -> Pitch {
+        self.transpose(Interval(-interval.semitones()))
+    }
+}
+
+impl Sub<Pitch> for Pitch {
+    type Output = Interval;
+
+    fn sub(self, other: Pitch) -> Interval {
+        Interval(self.index() - other.index())
+    }
+}
+
+impl fmt::Display for Pitch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.note, self.octave)
+    }
+}
+
+impl FromStr for Pitch {
+    type Err = String;
+
+    /// Parses scientific pitch names such as "C4", "C#4", "Bb3" or "A-1".
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let octave_start = name
+            .find(|c: char| c.is_ascii_digit() || c == '-')
+            .ok_or_else(|| format!("missing octave in pitch: {}", name))?;
+        let (note_name, octave) = name.split_at(octave_start);
+        let octave = octave
+            .parse::<i8>()
+            .map_err(|_| format!("invalid octave in pitch: {}", name))?;
+        let note = note_name.parse::<Note>()?;
+        // Cb and B# cross the octave boundary.
+        let natural_index = Pitch::new(note_name[..1].parse::<Note>()?, octave).index();
+        let mut index = Pitch::new(note, octave).index();
+        if index - natural_index > 6 {
+            index -= 12;
+        } else if natural_index - index > 6 {
+            index += 12;
+        }
+        Ok(Pitch::from_index(index))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Letter {
     C,
-    Cm,
     D,
-    Dm,
     E,
-    Em,
     F,
-    Fm,
     G,
-    Gm,
     A,
-    Am,
     B,
-    Bm,
 }
-#[derive(Debug, Eq, PartialEq)]
-struct ThreeNoteChord(u64);
 
-impl Hash for ThreeNoteChord {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.0.hash(state)
+impl Letter {
+    pub const ALL: [Letter; 7] = [
+        Letter::C,
+        Letter::D,
+        Letter::E,
+        Letter::F,
+        Letter::G,
+        Letter::A,
+        Letter::B,
+    ];
+
+    pub fn index(&self) -> u8 {
+        *self as u8
+    }
+
+    fn from_index(index: u8) -> Letter {
+        Letter::ALL[(index % 7) as usize]
+    }
+
+    pub fn natural(&self) -> Note {
+        match self {
+            Letter::C => Note::C,
+            Letter::D => Note::D,
+            Letter::E => Note::E,
+            Letter::F => Note::F,
+            Letter::G => Note::G,
+            Letter::A => Note::A,
+            Letter::B => Note::B,
+        }
+    }
+
+    /// Position on the circle of fifths counted from C, F being -1.
+    fn fifths(&self) -> i8 {
+        match self {
+            Letter::F => -1,
+            Letter::C => 0,
+            Letter::G => 1,
+            Letter::D => 2,
+            Letter::A => 3,
+            Letter::E => 4,
+            Letter::B => 5,
+        }
+    }
+
+    /// The accidental a key signature of `fifths` sharps, negative for
+    /// flats, puts on this letter.
+    pub fn in_signature(&self, fifths: i8) -> i8 {
+        // Sharps are added from F upwards in fifths, flats from B downwards.
+        let sharp_order = self.fifths() + 1;
+        let flat_order = 5 - self.fifths();
+        if fifths > sharp_order {
+            1 + (fifths - sharp_order - 1) / 7
+        } else if -fifths > flat_order {
+            -1 - (-fifths - flat_order - 1) / 7
+        } else {
+            0
+        }
+    }
+}
+
+/// A note written with a letter and an accidental, so A# and Bb are
+/// different spellings of the same `Note`. Positive accidentals are sharps,
+/// negative ones flats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SpelledNote {
+    pub letter: Letter,
+    pub accidental: i8,
+}
+
+impl SpelledNote {
+    pub fn new(letter: Letter, accidental: i8) -> Self {
+        SpelledNote { letter, accidental }
+    }
+
+    pub fn note(&self) -> Note {
+        let semitone = self.letter.natural().semitone() as i8 + self.accidental;
+        Note::from_semitone(semitone.rem_euclid(12) as u8)
+    }
+
+    /// Spells `note` on `letter` with whichever accidental reaches it.
+    pub fn on_letter(note: Note, letter: Letter) -> SpelledNote {
+        let distance = (note.semitone() as i8 - letter.natural().semitone() as i8).rem_euclid(12);
+        let accidental = if distance > 6 {
+            distance - 12
+        } else {
+            distance
+        };
+        SpelledNote { letter, accidental }
+    }
+
+    /// The note `semitones` above this one, spelled `letter_steps` letters up.
+    pub fn up(&self, letter_steps: u8, semitones: u8) -> SpelledNote {
+        SpelledNote::on_letter(
+            self.note().transpose(semitones),
+            Letter::from_index(self.letter.index() + letter_steps),
+        )
+    }
+
+    pub fn name(&self, naming: NamingSystem, key: &Key) -> String {
+        match naming {
+            NamingSystem::English => self.to_string(),
+            NamingSystem::German => self.german_name("is", "es", "s", "Heses"),
+            NamingSystem::Nordic => self.german_name("iss", "ess", "ss", "Bess"),
+            NamingSystem::FixedSolfege => {
+                let syllable =
+                    ["Do", "Re", "Mi", "Fa", "Sol", "La", "Si"][self.letter.index() as usize];
+                format!("{}{}", syllable, accidental_symbols(self.accidental))
+            }
+            NamingSystem::MovableSolfege => self.movable_solfege_name(key),
+        }
+    }
+
+    /// German style names, where B is H and Bb is B, and accidentals become
+    /// suffixes such as Cis, Es or As.
+    fn german_name(
+        &self,
+        sharp: &str,
+        flat: &str,
+        vowel_flat: &str,
+        b_double_flat: &str,
+    ) -> String {
+        let letter = format!("{:?}", self.letter);
+        match (self.letter, self.accidental) {
+            (Letter::B, 0) => "H".to_string(),
+            (Letter::B, -1) => "B".to_string(),
+            (Letter::B, accidental) if accidental < -1 => {
+                format!(
+                    "{}{}",
+                    b_double_flat,
+                    flat.repeat((-accidental - 2) as usize)
+                )
+            }
+            (Letter::B, accidental) => format!("H{}", sharp.repeat(accidental as usize)),
+            (Letter::E | Letter::A, accidental) if accidental < 0 => format!(
+                "{}{}{}",
+                letter,
+                vowel_flat,
+                flat.repeat((-accidental - 1) as usize)
+            ),
+            (_, accidental) if accidental < 0 => {
+                format!("{}{}", letter, flat.repeat(-accidental as usize))
+            }
+            (_, accidental) => format!("{}{}", letter, sharp.repeat(accidental as usize)),
+        }
+    }
+
+    /// Movable do solfège, with do on the major tonic and la on the minor
+    /// tonic. Chromatic notes use the raised and lowered syllables.
+    fn movable_solfege_name(&self, key: &Key) -> String {
+        const SYLLABLES: [&str; 7] = ["Do", "Re", "Mi", "Fa", "Sol", "La", "Ti"];
+        const RAISED: [&str; 7] = ["Di", "Ri", "My", "Fi", "Si", "Li", "Ty"];
+        const LOWERED: [&str; 7] = ["De", "Ra", "Me", "Fe", "Se", "Le", "Te"];
+
+        let d = key.relative_major_tonic();
+        let degree = (self.letter.index() + 7 - d.letter.index()) % 7;
+        let expected = d.up(degree, MAJOR_SCALE[degree as usize]);
+        let alteration = self.accidental - expected.accidental;
+        let degree = degree as usize;
+        match alteration {
+            0 => SYLLABLES[degree].to_string(),
+            1 => RAISED[degree].to_string(),
+            -1 => LOWERED[degree].to_string(),
+            _ => format!("{}{}", SYLLABLES[degree], accidental_symbols(alteration)),
+        }
+    }
+}
+
+fn accidental_symbols(accidental: i8) -> String {
+    if accidental >= 0 {
+        "#".repeat(accidental as usize)
+    } else {
+        "b".repeat(-accidental as usize)
+    }
+}
+
+impl fmt::Display for SpelledNote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?}{}",
+            self.letter,
+            accidental_symbols(self.accidental)
+        )
+    }
+}
+
+impl FromStr for SpelledNote {
+    type Err = String;
+
+    /// Parses spellings such as "C", "F#", "Bb" or "Ebb".
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let mut chars = name.chars();
+        let letter = match chars.next().map(|letter| letter.to_ascii_uppercase()) {
+            Some('C') => Letter::C,
+            Some('D') => Letter::D,
+            Some('E') => Letter::E,
+            Some('F') => Letter::F,
+            Some('G') => Letter::G,
+            Some('A') => Letter::A,
+            Some('B') => Letter::B,
+            _ => return Err(format!("invalid note name: {}", name)),
+        };
+        let mut accidental = 0;
+        for symbol in chars {
+            match symbol {
+                '#' => accidental += 1,
+                'b' => accidental -= 1,
+                _ => return Err(format!("invalid note name: {}", name)),
+            }
+        }
+        Ok(SpelledNote::new(letter, accidental))
+    }
+}
+
+/// Ways of naming notes that are in use among our users.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum NamingSystem {
+    /// C, C#, Db, ... B.
+    English,
+    /// C, Cis, Des, ... B for Bb and H for B.
+    German,
+    /// Like German but with the Scandinavian Ciss, Dess, Ess and Ass.
+    Nordic,
+    /// Do, Re, Mi on fixed pitches, Do always being C.
+    FixedSolfege,
+    /// Do, Re, Mi relative to the tonic of the current key.
+    MovableSolfege,
+}
+
+impl FromStr for NamingSystem {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().as_str() {
+            "english" => Ok(NamingSystem::English),
+            "german" => Ok(NamingSystem::German),
+            "nordic" => Ok(NamingSystem::Nordic),
+            "solfege" | "fixed-solfege" => Ok(NamingSystem::FixedSolfege),
+            "movable-solfege" => Ok(NamingSystem::MovableSolfege),
+            _ => Err(format!("unknown naming system: {}", name)),
+        }
+    }
+}
+
+const MAJOR_SCALE: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+const NATURAL_MINOR_SCALE: [u8; 7] = [0, 2, 3, 5, 7, 8, 10];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ScaleKind {
+    Major,
+    NaturalMinor,
+    HarmonicMinor,
+    MelodicMinor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues,
+    WholeTone,
+    /// Octatonic scale starting with a half step.
+    HalfWholeDiminished,
+    /// Octatonic scale starting with a whole step.
+    WholeHalfDiminished,
+}
+
+impl ScaleKind {
+    /// Semitones above the root of every scale degree.
+    pub fn intervals(&self) -> &'static [u8] {
+        match self {
+            ScaleKind::Major => &MAJOR_SCALE,
+            ScaleKind::NaturalMinor => &NATURAL_MINOR_SCALE,
+            ScaleKind::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            ScaleKind::MelodicMinor => &[0, 2, 3, 5, 7, 9, 11],
+            ScaleKind::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            ScaleKind::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+            ScaleKind::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+            ScaleKind::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            ScaleKind::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+            ScaleKind::MajorPentatonic => &[0, 2, 4, 7, 9],
+            ScaleKind::MinorPentatonic => &[0, 3, 5, 7, 10],
+            ScaleKind::Blues => &[0, 3, 5, 6, 7, 10],
+            ScaleKind::WholeTone => &[0, 2, 4, 6, 8, 10],
+            ScaleKind::HalfWholeDiminished => &[0, 1, 3, 4, 6, 7, 9, 10],
+            ScaleKind::WholeHalfDiminished => &[0, 2, 3, 5, 6, 8, 9, 11],
+        }
+    }
+}
+
+impl FromStr for ScaleKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().as_str() {
+            "major" | "ionian" => Ok(ScaleKind::Major),
+            "minor" | "natural-minor" | "aeolian" => Ok(ScaleKind::NaturalMinor),
+            "harmonic-minor" => Ok(ScaleKind::HarmonicMinor),
+            "melodic-minor" => Ok(ScaleKind::MelodicMinor),
+            "dorian" => Ok(ScaleKind::Dorian),
+            "phrygian" => Ok(ScaleKind::Phrygian),
+            "lydian" => Ok(ScaleKind::Lydian),
+            "mixolydian" => Ok(ScaleKind::Mixolydian),
+            "locrian" => Ok(ScaleKind::Locrian),
+            "major-pentatonic" | "pentatonic" => Ok(ScaleKind::MajorPentatonic),
+            "minor-pentatonic" => Ok(ScaleKind::MinorPentatonic),
+            "blues" => Ok(ScaleKind::Blues),
+            "whole-tone" => Ok(ScaleKind::WholeTone),
+            "half-whole-diminished" | "diminished" => Ok(ScaleKind::HalfWholeDiminished),
+            "whole-half-diminished" => Ok(ScaleKind::WholeHalfDiminished),
+            _ => Err(format!("unknown scale: {}", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Scale {
+    pub root: Note,
+    pub kind: ScaleKind,
+}
+
+impl Scale {
+    pub fn new(root: Note, kind: ScaleKind) -> Self {
+        Scale { root, kind }
+    }
+
+    pub fn notes(&self) -> Vec<Note> {
+        self.kind
+            .intervals()
+            .iter()
+            .map(|interval| self.root.transpose(*interval))
+            .collect()
+    }
+
+    pub fn contains(&self, note: Note) -> bool {
+        self.notes().contains(&note)
+    }
+
+    /// Moves a pitch to the closest scale degree inside `range`, preferring
+    /// the degree below when two are equally close.
+    pub fn snap(&self, pitch: Pitch, range: RangeInclusive<Pitch>) -> Pitch {
+        (0..=6)
+            .flat_map(|distance| [-distance, distance])
+            .map(|semitones| pitch.transpose(Interval(semitones)))
+            .find(|candidate| range.contains(candidate) && self.contains(candidate.note))
+            .unwrap_or(pitch)
+    }
+}
+
+impl FromStr for Scale {
+    type Err = String;
+
+    /// Parses scales written as "D dorian" or "A minor-pentatonic".
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let mut words = name.split_whitespace();
+        let (Some(root), Some(kind), None) = (words.next(), words.next(), words.next()) else {
+            return Err(format!("invalid scale: {}", name));
+        };
+        Ok(Scale::new(root.parse()?, kind.parse()?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Mode {
+    Major,
+    Minor,
+}
+
+/// A key gives the context needed to spell notes, e.g. Bb in F major but
+/// A# in B major.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Key {
+    pub tonic: SpelledNote,
+    pub mode: Mode,
+}
+
+impl Key {
+    pub fn new(tonic: SpelledNote, mode: Mode) -> Self {
+        Key { tonic, mode }
+    }
+
+    /// The key on `note` with its conventional spelling, Eb major rather
+    /// than D# major, but C# minor rather than Db minor.
+    pub fn from_note(note: Note, mode: Mode) -> Self {
+        let name = match (mode, note) {
+            (Mode::Major, Note::CsharpDflat) => "Db",
+            (Mode::Major, Note::DsharpEflat) => "Eb",
+            (Mode::Major, Note::FsharpGflat) => "F#",
+            (Mode::Major, Note::GsharpAflat) => "Ab",
+            (Mode::Major, Note::ASharpBFlat) => "Bb",
+            (Mode::Minor, Note::CsharpDflat) => "C#",
+            (Mode::Minor, Note::DsharpEflat) => "Eb",
+            (Mode::Minor, Note::FsharpGflat) => "F#",
+            (Mode::Minor, Note::GsharpAflat) => "G#",
+            (Mode::Minor, Note::ASharpBFlat) => "Bb",
+            _ => "",
+        };
+        let tonic = name
+            .parse()
+            .unwrap_or_else(|_| SpelledNote::on_letter(note, natural_letter(note)));
+        Key::new(tonic, mode)
+    }
+
+    /// Sharps in the key signature, negative for flats.
+    pub fn fifths(&self) -> i8 {
+        let major_fifths = self.tonic.letter.fifths() + 7 * self.tonic.accidental;
+        match self.mode {
+            Mode::Major => major_fifths,
+            Mode::Minor => major_fifths - 3,
+        }
+    }
+
+    fn relative_major_tonic(&self) -> SpelledNote {
+        match self.mode {
+            Mode::Major => self.tonic,
+            Mode::Minor => self.tonic.up(2, 3),
+        }
+    }
+
+    /// The seven spelled degrees of the major or natural minor scale.
+    pub fn scale(&self) -> [SpelledNote; 7] {
+        let intervals = match self.mode {
+            Mode::Major => MAJOR_SCALE,
+            Mode::Minor => NATURAL_MINOR_SCALE,
+        };
+        let mut scale = [self.tonic; 7];
+        for (degree, interval) in intervals.into_iter().enumerate() {
+            scale[degree] = self.tonic.up(degree as u8, interval);
+        }
+        scale
+    }
+
+    /// The scale degree of `note`, 1 to 7, if it is in the scale.
+    pub fn degree_of(&self, note: Note) -> Option<u8> {
+        self.scale()
+            .iter()
+            .position(|spelled| spelled.note() == note)
+            .map(|position| position as u8 + 1)
+    }
+
+    /// The chord stacked in thirds from the scale on `degree`, 1 to 7, as a
+    /// triad or with its seventh. `None` for any other degree.
+    pub fn diatonic_chord(&self, degree: u8, seventh: bool) -> Option<Chord> {
+        if !(1..=7).contains(&degree) {
+            return None;
+        }
+        let scale = self.scale();
+        let root = scale[(degree - 1) as usize].note();
+        let tones = if seventh { 4 } else { 3 };
+        let intervals: Vec<u8> = (0..tones)
+            .map(|tone| {
+                let note = scale[((degree - 1 + 2 * tone) % 7) as usize].note();
+                (note.semitone() + 12 - root.semitone()) % 12
+            })
+            .collect();
+        let quality = ChordQuality::ALL
+            .into_iter()
+            .find(|quality| quality.intervals() == intervals.as_slice())
+            .unwrap_or(ChordQuality::Major);
+        Some(Chord::new(root, quality, Vec::new()))
+    }
+
+    /// Spells a note for this key. Scale notes use the key signature and the
+    /// raised sixth and seventh of minor keys stay on their letters. Other
+    /// chromatic notes get the spelling with the fewest accidentals, sharps
+    /// in sharp keys and flats in flat keys when both are equally simple.
+    pub fn spell(&self, note: Note) -> SpelledNote {
+        let scale = self.scale();
+        if let Some(spelled) = scale.iter().find(|spelled| spelled.note() == note) {
+            return *spelled;
+        }
+        if self.mode == Mode::Minor {
+            for degree in [5, 6] {
+                let raised = SpelledNote::new(scale[degree].letter, scale[degree].accidental + 1);
+                if raised.note() == note {
+                    return raised;
+                }
+            }
+        }
+        let raised = scale
+            .iter()
+            .find(|spelled| spelled.note().transpose(1) == note)
+            .map(|spelled| SpelledNote::new(spelled.letter, spelled.accidental + 1));
+        let lowered = scale
+            .iter()
+            .find(|spelled| spelled.note() == note.transpose(1))
+            .map(|spelled| SpelledNote::new(spelled.letter, spelled.accidental - 1));
+        let chromatic = match (raised, lowered) {
+            (Some(raised), Some(lowered)) => {
+                if raised.accidental.abs() < lowered.accidental.abs() {
+                    Some(raised)
+                } else if lowered.accidental.abs() < raised.accidental.abs() {
+                    Some(lowered)
+                } else if self.fifths() >= 0 {
+                    Some(raised)
+                } else {
+                    Some(lowered)
+                }
+            }
+            (raised, lowered) => raised.or(lowered),
+        };
+        chromatic.unwrap_or_else(|| SpelledNote::on_letter(note, natural_letter(note)))
+    }
+
+    /// Spells a pitch for this key together with its written octave, which
+    /// follows the letter, so B#3 and C4 are the same pitch.
+    pub fn spell_pitch(&self, pitch: Pitch) -> (SpelledNote, i8) {
+        let spelled = self.spell(pitch.note);
+        let natural_index = pitch.note.semitone() as i8 - spelled.accidental;
+        (spelled, pitch.octave + natural_index.div_euclid(12))
+    }
+
+    /// Names a pitch with its written octave.
+    pub fn name_pitch(&self, pitch: Pitch, naming: NamingSystem) -> String {
+        let (spelled, octave) = self.spell_pitch(pitch);
+        format!("{}{}", spelled.name(naming, self), octave)
+    }
+
+    pub fn name_chord(&self, chord: &Chord, naming: NamingSystem) -> String {
+        let root = self.spell(chord.root);
+        let mut name = format!("{}{}", root.name(naming, self), chord.suffix());
+        if chord.bass != chord.root {
+            let bass = chord
+                .spelled_notes(root)
+                .into_iter()
+                .find(|spelled| spelled.note() == chord.bass)
+                .unwrap_or_else(|| self.spell(chord.bass));
+            name = format!("{}/{}", name, bass.name(naming, self));
+        }
+        name
+    }
+}
+
+/// The letter a note is written on when spelled with sharps.
+fn natural_letter(note: Note) -> Letter {
+    match note {
+        Note::C | Note::CsharpDflat => Letter::C,
+        Note::D | Note::DsharpEflat => Letter::D,
+        Note::E => Letter::E,
+        Note::F | Note::FsharpGflat => Letter::F,
+        Note::G | Note::GsharpAflat => Letter::G,
+        Note::A | Note::ASharpBFlat => Letter::A,
+        Note::B => Letter::B,
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mode {
+            Mode::Major => write!(f, "{} major", self.tonic),
+            Mode::Minor => write!(f, "{} minor", self.tonic),
+        }
+    }
+}
+
+impl FromStr for Key {
+    type Err = String;
+
+    /// Parses keys written as "F major", "Bb minor", "Bb" or "Bbm".
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let mut words = name.split_whitespace();
+        let tonic = words
+            .next()
+            .ok_or_else(|| format!("invalid key: {}", name))?;
+        let (tonic, mode) = match words.next().map(|mode| mode.to_lowercase()) {
+            Some(mode) if mode == "major" => (tonic, Mode::Major),
+            Some(mode) if mode == "minor" => (tonic, Mode::Minor),
+            Some(_) => return Err(format!("invalid key: {}", name)),
+            None => match tonic.strip_suffix('m') {
+                Some(tonic) => (tonic, Mode::Minor),
+                None => (tonic, Mode::Major),
+            },
+        };
+        Ok(Key::new(tonic.parse()?, mode))
+    }
+}
+
+/// A note length as a fraction of a whole note, e.g. 1/16 or an eighth
+/// note triplet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "NoteValueFields")]
+pub struct NoteValue {
+    pub division: u32,
+    pub triplet: bool,
+}
+
+/// A `NoteValue` as saved, checked before it is used.
+#[derive(Deserialize)]
+struct NoteValueFields {
+    division: u32,
+    triplet: bool,
+}
+
+impl TryFrom<NoteValueFields> for NoteValue {
+    type Error = String;
+
+    fn try_from(fields: NoteValueFields) -> Result<Self, Self::Error> {
+        NoteValue::checked(fields.division, fields.triplet)
+    }
+}
+
+impl NoteValue {
+    pub fn new(division: u32, triplet: bool) -> Self {
+        NoteValue { division, triplet }
+    }
+
+    /// A value from a quarter to a 32nd note.
+    pub fn checked(division: u32, triplet: bool) -> Result<Self, String> {
+        match division {
+            4 | 8 | 16 | 32 => Ok(NoteValue::new(division, triplet)),
+            _ => Err(format!("invalid note value: 1/{}", division)),
+        }
+    }
+
+    /// The length in quarter note beats.
+    pub fn beats(&self) -> f64 {
+        let quarters = 4.0 / self.division as f64;
+        match self.triplet {
+            true => quarters * 2.0 / 3.0,
+            false => quarters,
+        }
+    }
+
+    pub fn duration(&self, bpm: f32) -> Duration {
+        // Tempos that aren't above zero give no time at all.
+        let whole_note = Duration::try_from_secs_f32(240.0 / bpm).unwrap_or_default();
+        let duration = whole_note / self.division;
+        match self.triplet {
+            true => duration * 2 / 3,
+            false => duration,
+        }
+    }
+}
+
+impl fmt::Display for NoteValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "1/{}", self.division)?;
+        if self.triplet {
+            write!(f, "t")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for NoteValue {
+    type Err = String;
+
+    /// Parses values from "1/4" to "1/32", with a trailing "t" for triplets.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (value, triplet) = match value.strip_suffix('t') {
+            Some(value) => (value, true),
+            None => (value, false),
+        };
+        let invalid = || format!("invalid note value: {}", value);
+        let division = value
+            .strip_prefix("1/")
+            .and_then(|division| division.parse().ok())
+            .ok_or_else(invalid)?;
+        NoteValue::checked(division, triplet).map_err(|_| invalid())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimedNote {
+    pub pitch: Pitch,
+    pub timestamp: Instant,
+}
+
+impl TimedNote {
+    /// Returns the lowest sounding note.
+    pub fn lowest(notes: &[TimedNote]) -> Option<&TimedNote> {
+        notes.iter().min_by_key(|timed| timed.pitch)
+    }
+}
+/// The set of pitch classes in a group of notes, one bit per semitone above C.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct PitchClassSet(u16);
+
+impl PitchClassSet {
+    pub fn from_notes(notes: &[Note]) -> Self {
+        let mut set = PitchClassSet::default();
+        for note in notes {
+            set.insert(*note);
+        }
+        set
+    }
+
+    pub fn insert(&mut self, note: Note) {
+        self.0 |= 1 << note.semitone();
+    }
+
+    pub fn contains(&self, note: Note) -> bool {
+        self.0 & (1 << note.semitone()) != 0
+    }
+
+    pub fn len(&self) -> u32 {
+        self.0.count_ones()
+    }
+
+    pub fn intersection(&self, other: &PitchClassSet) -> PitchClassSet {
+        PitchClassSet(self.0 & other.0)
+    }
+
+    pub fn union(&self, other: &PitchClassSet) -> PitchClassSet {
+        PitchClassSet(self.0 | other.0)
+    }
+
+    pub fn is_subset_of(&self, other: &PitchClassSet) -> bool {
+        self.0 & !other.0 == 0
+    }
+
+    pub fn notes(&self) -> Vec<Note> {
+        Note::CHROMATIC
+            .into_iter()
+            .filter(|note| self.contains(*note))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChordQuality {
+    Major,
+    Minor,
+    Diminished,
+    Augmented,
+    Sus2,
+    Sus4,
+    Major6,
+    Minor6,
+    Dominant7,
+    Major7,
+    Minor7,
+    HalfDiminished7,
+    Diminished7,
+}
+
+impl ChordQuality {
+    pub const ALL: [ChordQuality; 13] = [
+        ChordQuality::Major,
+        ChordQuality::Minor,
+        ChordQuality::Diminished,
+        ChordQuality::Augmented,
+        ChordQuality::Sus2,
+        ChordQuality::Sus4,
+        ChordQuality::Major6,
+        ChordQuality::Minor6,
+        ChordQuality::Dominant7,
+        ChordQuality::Major7,
+        ChordQuality::Minor7,
+        ChordQuality::HalfDiminished7,
+        ChordQuality::Diminished7,
+    ];
+
+    /// Semitones above the root, including the root itself.
+    pub fn intervals(&self) -> &'static [u8] {
+        match self {
+            ChordQuality::Major => &[0, 4, 7],
+            ChordQuality::Minor => &[0, 3, 7],
+            ChordQuality::Diminished => &[0, 3, 6],
+            ChordQuality::Augmented => &[0, 4, 8],
+            ChordQuality::Sus2 => &[0, 2, 7],
+            ChordQuality::Sus4 => &[0, 5, 7],
+            ChordQuality::Major6 => &[0, 4, 7, 9],
+            ChordQuality::Minor6 => &[0, 3, 7, 9],
+            ChordQuality::Dominant7 => &[0, 4, 7, 10],
+            ChordQuality::Major7 => &[0, 4, 7, 11],
+            ChordQuality::Minor7 => &[0, 3, 7, 10],
+            ChordQuality::HalfDiminished7 => &[0, 3, 6, 10],
+            ChordQuality::Diminished7 => &[0, 3, 6, 9],
+        }
+    }
+
+    /// How many letter names above the root each interval is spelled, in the
+    /// same order as `intervals`. A diminished seventh is spelled on the
+    /// seventh letter even though it sounds like a sixth.
+    fn letter_steps(&self) -> &'static [u8] {
+        match self {
+            ChordQuality::Sus2 => &[0, 1, 4],
+            ChordQuality::Sus4 => &[0, 3, 4],
+            ChordQuality::Major6 | ChordQuality::Minor6 => &[0, 2, 4, 5],
+            ChordQuality::Dominant7
+            | ChordQuality::Major7
+            | ChordQuality::Minor7
+            | ChordQuality::HalfDiminished7
+            | ChordQuality::Diminished7 => &[0, 2, 4, 6],
+            _ => &[0, 2, 4],
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            ChordQuality::Major => "",
+            ChordQuality::Minor => "m",
+            ChordQuality::Diminished => "dim",
+            ChordQuality::Augmented => "aug",
+            ChordQuality::Sus2 => "sus2",
+            ChordQuality::Sus4 => "sus4",
+            ChordQuality::Major6 => "6",
+            ChordQuality::Minor6 => "m6",
+            ChordQuality::Dominant7 => "7",
+            ChordQuality::Major7 => "maj7",
+            ChordQuality::Minor7 => "m7",
+            ChordQuality::HalfDiminished7 => "m7b5",
+            ChordQuality::Diminished7 => "dim7",
+        }
+    }
+
+    fn has_seventh(&self) -> bool {
+        matches!(
+            self,
+            ChordQuality::Dominant7 | ChordQuality::Major7 | ChordQuality::Minor7
+        )
+    }
+}
+
+/// Tones stacked on top of the chord quality. On a seventh chord a ninth
+/// turns it into a ninth chord, on anything else it is an added ninth.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Extension {
+    Ninth,
+}
+
+impl Extension {
+    /// Semitones above the root, folded into one octave.
+    pub fn interval(&self) -> u8 {
+        match self {
+            Extension::Ninth => 2,
+        }
+    }
+
+    fn letter_step(&self) -> u8 {
+        match self {
+            Extension::Ninth => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Inversion {
+    Root,
+    First,
+    Second,
+    Third,
+}
+
+impl fmt::Display for Inversion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Inversion::Root => "root position",
+            Inversion::First => "first inversion",
+            Inversion::Second => "second inversion",
+            Inversion::Third => "third inversion",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Chord {
+    pub root: Note,
+    pub quality: ChordQuality,
+    pub extensions: Vec<Extension>,
+    /// The lowest sounding note, the root unless the chord is inverted or
+    /// played over a foreign bass note.
+    pub bass: Note,
+}
+
+impl Chord {
+    pub fn new(root: Note, quality: ChordQuality, extensions: Vec<Extension>) -> Self {
+        Chord {
+            root,
+            quality,
+            extensions,
+            bass: root,
+        }
+    }
+
+    pub fn with_bass(mut self, bass: Note) -> Self {
+        self.bass = bass;
+        self
+    }
+
+    /// Which chord tone is in the bass. `None` means the bass is not part of
+    /// the triad or seventh, which makes this a slash chord.
+    pub fn inversion(&self) -> Option<Inversion> {
+        let bass_interval = (self.bass.semitone() + 12 - self.root.semitone()) % 12;
+        let position = self
+            .quality
+            .intervals()
+            .iter()
+            .position(|interval| *interval == bass_interval)?;
+        match position {
+            0 => Some(Inversion::Root),
+            1 => Some(Inversion::First),
+            2 => Some(Inversion::Second),
+            _ => Some(Inversion::Third),
+        }
+    }
+
+    /// Sorts root position first, then the inversions, then slash chords.
+    fn voicing_rank(&self) -> u8 {
+        self.inversion()
+            .map_or(u8::MAX, |inversion| inversion as u8)
+    }
+
+    /// Human readable name including the voicing spelled for `key`,
+    /// e.g. "C/E, first inversion".
+    pub fn description(&self, key: &Key, naming: NamingSystem) -> String {
+        let name = key.name_chord(self, naming);
+        match self.inversion() {
+            Some(inversion) => format!("{}, {}", name, inversion),
+            None => format!("{}, slash chord", name),
+        }
+    }
+
+    /// The chord symbol without the root, e.g. "m7" or "add9".
+    pub fn suffix(&self) -> String {
+        let symbol = self.quality.symbol();
+        if self.extensions.contains(&Extension::Ninth) {
+            if self.quality.has_seventh() {
+                symbol.replace('7', "9")
+            } else {
+                format!("{}add9", symbol)
+            }
+        } else {
+            symbol.to_string()
+        }
+    }
+
+    /// Spells every chord tone by stacking letters on a spelled root, so
+    /// the third of D is F# and the third of Gb is Bb.
+    pub fn spelled_notes(&self, root: SpelledNote) -> Vec<SpelledNote> {
+        self.quality
+            .intervals()
+            .iter()
+            .zip(self.quality.letter_steps())
+            .map(|(interval, letter_step)| (*interval, *letter_step))
+            .chain(
+                self.extensions
+                    .iter()
+                    .map(|extension| (extension.interval(), extension.letter_step())),
+            )
+            .map(|(interval, letter_step)| root.up(letter_step, interval))
+            .collect()
+    }
+
+    pub fn notes(&self) -> Vec<Note> {
+        self.quality
+            .intervals()
+            .iter()
+            .copied()
+            .chain(self.extensions.iter().map(|extension| extension.interval()))
+            .map(|interval| self.root.transpose(interval))
+            .collect()
+    }
+
+    /// The chord tones stacked upwards in close position from the root at
+    /// `root`.
+    pub fn pitches(&self, root: Pitch) -> Vec<Pitch> {
+        self.quality
+            .intervals()
+            .iter()
+            .copied()
+            .chain(
+                self.extensions
+                    .iter()
+                    .map(|extension| extension.interval() + 12),
+            )
+            .map(|interval| root.transpose(Interval(interval as i32)))
+            .collect()
+    }
+
+    pub fn pitch_classes(&self) -> PitchClassSet {
+        PitchClassSet::from_notes(&self.notes())
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.root, self.suffix())?;
+        if self.bass != self.root {
+            write!(f, "/{}", self.bass)?;
+        }
+        Ok(())
+    }
+}
+
+/// Every quality and extension combination the recognizer knows about.
+fn chord_templates() -> Vec<(ChordQuality, Vec<Extension>)> {
+    let mut templates: Vec<(ChordQuality, Vec<Extension>)> = ChordQuality::ALL
+        .into_iter()
+        .map(|quality| (quality, Vec::new()))
+        .collect();
+    for quality in [
+        ChordQuality::Major,
+        ChordQuality::Minor,
+        ChordQuality::Dominant7,
+        ChordQuality::Major7,
+        ChordQuality::Minor7,
+    ] {
+        templates.push((quality, vec![Extension::Ninth]));
+    }
+    templates
+}
+
+/// Returns every chord whose pitch classes are exactly the ones played.
+/// Symmetric chords such as C6 and Am7 give more than one result.
+pub fn recognize_chords(notes: &[Note]) -> Vec<Chord> {
+    let played = PitchClassSet::from_notes(notes);
+    let templates = chord_templates();
+    let mut chords = Vec::new();
+
+    for root in played.notes() {
+        for (quality, extensions) in templates.iter() {
+            let chord = Chord::new(root, *quality, extensions.clone());
+            if chord.pitch_classes() == played {
+                chords.push(chord);
+            }
+        }
     }
+    chords
 }
 
-impl ThreeNoteChord {
-    fn new(notes: [&Note; 3]) -> Self {
-        let mut hahser = DefaultHasher::new();
-        let mut sorted_notes = notes;
-        sorted_notes.sort();
-        sorted_notes.iter().for_each(|note| note.hash(&mut hahser));
-        ThreeNoteChord(hahser.finish())
+/// Recognizes chords from notes that carry their octave, so the lowest note
+/// decides the inversion. If the notes only form a chord once the bass is
+/// left out, the result is named as a slash chord over that bass.
+pub fn recognize_voicing(notes: &[TimedNote]) -> Vec<Chord> {
+    let Some(bass) = TimedNote::lowest(notes).map(|timed| timed.pitch.note) else {
+        return Vec::new();
+    };
+    let pitch_classes: Vec<Note> = notes.iter().map(|timed| timed.pitch.note).collect();
+
+    let mut chords: Vec<Chord> = recognize_chords(&pitch_classes)
+        .into_iter()
+        .map(|chord| chord.with_bass(bass))
+        .collect();
+    // Prefer the reading that puts the chord in root position.
+    chords.sort_by_key(Chord::voicing_rank);
+
+    if chords.is_empty() {
+        let upper_notes: Vec<Note> = pitch_classes
+            .into_iter()
+            .filter(|note| *note != bass)
+            .collect();
+        chords = recognize_chords(&upper_notes)
+            .into_iter()
+            .map(|chord| chord.with_bass(bass))
+            .collect();
     }
+    chords
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MatchKind {
+    /// The played notes are exactly the chord tones.
+    Exact,
+    /// Every played note is a chord tone, but a chord tone is missing.
+    Subset,
+    /// Every chord tone is played, together with notes outside the chord.
+    ExtraNotes,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChordCandidate {
+    pub chord: Chord,
+    pub match_kind: MatchKind,
+    /// Share of the played and chord notes that overlap, 1.0 for an exact match.
+    pub confidence: f32,
 }
 
-fn generate_chord_map() -> HashMap<ThreeNoteChord, Chord> {
-    hashmap! {
-        ThreeNoteChord::new([&Note::C, &Note::E, &Note::G]) => Chord::C,
-        ThreeNoteChord::new([&Note::C, &Note::DsharpEflat, &Note::G]) => Chord::Cm,
-        ThreeNoteChord::new([&Note::D, &Note::FsharpGflat, &Note::A]) => Chord::D,
-        ThreeNoteChord::new([&Note::D, &Note::F, &Note::A]) => Chord::Dm,
-        ThreeNoteChord::new([&Note::E, &Note::GsharpAflat, &Note::B]) => Chord::E,
-        ThreeNoteChord::new([&Note::E, &Note::G, &Note::B]) => Chord::Em,
-        ThreeNoteChord::new([&Note::F, &Note::A, &Note::C]) => Chord::F,
-        ThreeNoteChord::new([&Note::F, &Note::GsharpAflat, &Note::C]) => Chord::Fm,
-        ThreeNoteChord::new([&Note::G, &Note::B, &Note::D]) => Chord::G,
-        ThreeNoteChord::new([&Note::G, &Note::ASharpBFlat, &Note::D]) => Chord::Gm,
-        ThreeNoteChord::new([&Note::A, &Note::CsharpDflat, &Note::E]) => Chord::A,
-        ThreeNoteChord::new([&Note::A, &Note::C, &Note::E]) => Chord::Am,
-        ThreeNoteChord::new([&Note::B, &Note::DsharpEflat, &Note::FsharpGflat]) => Chord::B,
-        ThreeNoteChord::new([&Note::B, &Note::D, &Note::FsharpGflat]) => Chord::Bm
+/// Ranks every chord that could describe the played notes, best match first.
+///
+/// Exact matches, including slash chords, score 1.0. Chords rooted on a played
+/// note that miss one chord tone or leave notes over are scored by how many
+/// notes the chord and the played notes share.
+pub fn get_chords_from_notes(notes: Vec<TimedNote>) -> Vec<ChordCandidate> {
+    let Some(bass) = TimedNote::lowest(&notes).map(|timed| timed.pitch.note) else {
+        return Vec::new();
+    };
+    let pitch_classes: Vec<Note> = notes.iter().map(|timed| timed.pitch.note).collect();
+    let played = PitchClassSet::from_notes(&pitch_classes);
 
+    let mut candidates: Vec<ChordCandidate> = recognize_voicing(&notes)
+        .into_iter()
+        .map(|chord| ChordCandidate {
+            chord,
+            match_kind: MatchKind::Exact,
+            confidence: 1.0,
+        })
+        .collect();
+
+    for root in played.notes() {
+        for (quality, extensions) in chord_templates() {
+            let chord = Chord::new(root, quality, extensions).with_bass(bass);
+            // Slash chords over the bass are already listed as exact matches.
+            if candidates.iter().any(|candidate| candidate.chord == chord) {
+                continue;
+            }
+            let chord_tones = chord.pitch_classes();
+            let match_kind = if chord_tones == played {
+                continue;
+            } else if chord_tones.is_subset_of(&played) {
+                MatchKind::ExtraNotes
+            } else if played.is_subset_of(&chord_tones) && chord_tones.len() - played.len() == 1 {
+                MatchKind::Subset
+            } else {
+                continue;
+            };
+            let confidence = chord_tones.intersection(&played).len() as f32
+                / chord_tones.union(&played).len() as f32;
+            candidates.push(ChordCandidate {
+                chord,
+                match_kind,
+                confidence,
+            });
+        }
     }
+
+    // Highest confidence first, then root position before inversions and
+    // inversions before slash chords.
+    candidates.sort_by(|a, b| {
+        b.confidence
+            .total_cmp(&a.confidence)
+            .then_with(|| a.chord.voicing_rank().cmp(&b.chord.voicing_rank()))
+    });
+    candidates
 }
 
-pub fn get_chords_from_notes(notes: Vec<Note>) {
-    let chords = generate_chord_map();
-    let note_combinations = get_note_combinations(&notes);
+/// The result of recognizing one group of notes played together.
+#[derive(Debug, Clone)]
+pub struct ChordEvent {
+    pub notes: Vec<TimedNote>,
+    pub candidates: Vec<ChordCandidate>,
+}
 
-    for combination in note_combinations {
-        match chords.get(&combination) {
-            Some(chord) => println!("chord pressed: {:?}", chord),
-            _ => println!("nothing"),
+impl ChordEvent {
+    /// Builds an event for a group of notes, `None` when fewer than two notes
+    /// were played since a single note is not a chord.
+    pub fn recognize(notes: Vec<TimedNote>) -> Option<ChordEvent> {
+        if notes.len() < 2 {
+            return None;
         }
+        let candidates = get_chords_from_notes(notes.clone());
+        Some(ChordEvent { notes, candidates })
+    }
+
+    pub fn best(&self) -> Option<&ChordCandidate> {
+        self.candidates.first()
     }
 }
 
-fn get_note_combinations<'a>(notes: &'a Vec<Note>) -> Vec<ThreeNoteChord> {
-    let mut vec: Vec<[&Note; 3]> = vec![];
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    for i in 0..notes.len() {
-        for j in i + 1..notes.len() {
-            for k in j + 1..notes.len() {
-                vec.push([&notes[i], &notes[j], &notes[k]]);
+    fn timed(pitches: &[(Note, i8)]) -> Vec<TimedNote> {
+        let timestamp = Instant::now();
+        pitches
+            .iter()
+            .map(|(note, octave)| TimedNote {
+                pitch: Pitch::new(*note, *octave),
+                timestamp,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn exact_triad_ranks_first_in_root_position() {
+        let candidates = get_chords_from_notes(timed(&[(Note::C, 4), (Note::E, 4), (Note::G, 4)]));
+        let best = &candidates[0];
+        assert_eq!(
+            best.chord,
+            Chord::new(Note::C, ChordQuality::Major, Vec::new())
+        );
+        assert_eq!(best.match_kind, MatchKind::Exact);
+        assert_eq!(best.confidence, 1.0);
+        assert_eq!(best.chord.inversion(), Some(Inversion::Root));
+    }
+
+    #[test]
+    fn lowest_note_gives_the_inversion() {
+        let candidates = get_chords_from_notes(timed(&[(Note::E, 3), (Note::G, 4), (Note::C, 5)]));
+        let best = &candidates[0];
+        assert_eq!(best.chord.root, Note::C);
+        assert_eq!(best.chord.bass, Note::E);
+        assert_eq!(best.chord.inversion(), Some(Inversion::First));
+    }
+
+    #[test]
+    fn missing_chord_tone_is_a_subset_match() {
+        let candidates = get_chords_from_notes(timed(&[(Note::C, 4), (Note::E, 4)]));
+        assert!(!candidates.is_empty());
+        assert!(candidates
+            .iter()
+            .all(|candidate| candidate.match_kind == MatchKind::Subset));
+        let major = candidates
+            .iter()
+            .find(|candidate| {
+                candidate.chord == Chord::new(Note::C, ChordQuality::Major, Vec::new())
+            })
+            .unwrap();
+        assert!((major.confidence - 2.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn slash_chord_is_exact_and_not_listed_again() {
+        let candidates = get_chords_from_notes(timed(&[
+            (Note::C, 4),
+            (Note::CsharpDflat, 4),
+            (Note::E, 4),
+            (Note::G, 4),
+        ]));
+        let slash =
+            Chord::new(Note::CsharpDflat, ChordQuality::Diminished, Vec::new()).with_bass(Note::C);
+        assert_eq!(candidates[0].chord, slash);
+        assert_eq!(candidates[0].match_kind, MatchKind::Exact);
+        assert_eq!(candidates[0].chord.inversion(), None);
+        assert_eq!(
+            candidates
+                .iter()
+                .filter(|candidate| candidate.chord == slash)
+                .count(),
+            1
+        );
+        let major = &candidates[1];
+        assert_eq!(
+            major.chord,
+            Chord::new(Note::C, ChordQuality::Major, Vec::new())
+        );
+        assert_eq!(major.match_kind, MatchKind::ExtraNotes);
+        assert_eq!(major.confidence, 0.75);
+    }
+
+    #[test]
+    fn ties_rank_inversions_before_slash_chords() {
+        let candidates = get_chords_from_notes(timed(&[
+            (Note::C, 3),
+            (Note::D, 4),
+            (Note::F, 4),
+            (Note::A, 4),
+        ]));
+        let names: Vec<String> = candidates.iter().map(|c| c.chord.to_string()).collect();
+        let position = |name: &str| names.iter().position(|found| found == name).unwrap();
+        // Second inversion before third, then inversions before slash chords.
+        assert!(position("F6/C") < position("Dm7/C"));
+        assert!(position("F/C") < position("Dm/C"));
+    }
+
+    /// Every set of three to five notes above a C4 bass ranks by confidence,
+    /// then voicing, and lists each chord once.
+    #[test]
+    fn candidates_are_ranked_and_unique() {
+        for mask in 0u16..1 << 11 {
+            let mut set = vec![(Note::C, 3)];
+            set.extend(
+                (0..11)
+                    .filter(|bit| mask & (1 << bit) != 0)
+                    .map(|bit| (Note::from_semitone(bit as u8 + 1), 4)),
+            );
+            if !(3..=5).contains(&set.len()) {
+                continue;
+            }
+            let candidates = get_chords_from_notes(timed(&set));
+            for pair in candidates.windows(2) {
+                assert!(pair[0].confidence >= pair[1].confidence);
+                if pair[0].confidence == pair[1].confidence {
+                    assert!(pair[0].chord.voicing_rank() <= pair[1].chord.voicing_rank());
+                }
+            }
+            for (index, candidate) in candidates.iter().enumerate() {
+                assert!(!candidates[..index]
+                    .iter()
+                    .any(|earlier| earlier.chord == candidate.chord));
             }
         }
     }
 
-    vec.iter()
-        .map(|notes| ThreeNoteChord::new(*notes))
-        .collect()
+    #[test]
+    fn fewer_than_two_notes_are_not_a_chord() {
+        assert!(ChordEvent::recognize(Vec::new()).is_none());
+        assert!(ChordEvent::recognize(timed(&[(Note::C, 4)])).is_none());
+        assert!(get_chords_from_notes(Vec::new()).is_empty());
+        let event =
+            ChordEvent::recognize(timed(&[(Note::A, 3), (Note::C, 4), (Note::E, 4)])).unwrap();
+        assert_eq!(event.notes.len(), 3);
+        assert_eq!(
+            event.best().map(|best| best.chord.clone()),
+            Some(Chord::new(Note::A, ChordQuality::Minor, Vec::new()))
+        );
+    }
+
+    /// The best chord for the pitches, the lowest being played first.
+    fn best(names: &[&str]) -> Chord {
+        let timestamp = Instant::now();
+        let notes = names
+            .iter()
+            .map(|name| TimedNote {
+                pitch: name.parse().unwrap(),
+                timestamp,
+            })
+            .collect();
+        get_chords_from_notes(notes).remove(0).chord
+    }
+
+    #[test]
+    fn recognizes_every_quality_and_the_ninth() {
+        let key = Key::from_note(Note::C, Mode::Major);
+        let name = |names: &[&str]| key.name_chord(&best(names), NamingSystem::English);
+        assert_eq!(name(&["B3", "D4", "F4"]), "Bdim");
+        assert_eq!(name(&["C4", "E4", "G#4"]), "Caug");
+        assert_eq!(name(&["E4", "G#4", "C5"]), "Eaug");
+        assert_eq!(name(&["C4", "D4", "G4"]), "Csus2");
+        assert_eq!(name(&["G3", "C4", "D4"]), "Gsus4");
+        assert_eq!(name(&["B3", "D4", "F4", "G#4"]), "Bdim7");
+        assert_eq!(name(&["D4", "F4", "G#4", "B4"]), "Ddim7");
+        assert_eq!(name(&["B3", "D4", "F4", "A4"]), "Bm7b5");
+        assert_eq!(name(&["A3", "C4", "E4", "F#4"]), "Am6");
+        assert_eq!(name(&["C4", "E4", "G4", "D5"]), "Cadd9");
+        assert_eq!(name(&["C4", "Eb4", "G4", "D5"]), "Cmadd9");
+        assert_eq!(name(&["G3", "B3", "D4", "F4", "A4"]), "G9");
+        assert_eq!(name(&["C4", "E4", "G4", "B4", "D5"]), "Cmaj9");
+        assert_eq!(name(&["D4", "F4", "A4", "C5", "E5"]), "Dm9");
+    }
+
+    #[test]
+    fn sharp_roots_are_spelled_for_the_key() {
+        let chord = best(&["F#3", "A3", "C#4"]);
+        assert_eq!(
+            chord,
+            Chord::new(Note::FsharpGflat, ChordQuality::Minor, Vec::new())
+        );
+        let e_major = Key::from_note(Note::E, Mode::Major);
+        assert_eq!(e_major.name_chord(&chord, NamingSystem::English), "F#m");
+        let c_sharp = best(&["C#4", "E#4", "G#4", "B4"]);
+        assert_eq!(e_major.name_chord(&c_sharp, NamingSystem::English), "C#7");
+        let g_sharp = best(&["B3", "D4", "G#4"]);
+        let a_minor = Key::from_note(Note::A, Mode::Minor);
+        assert_eq!(
+            a_minor.name_chord(&g_sharp, NamingSystem::English),
+            "G#dim/B"
+        );
+        let d_flat = Key::from_note(Note::CsharpDflat, Mode::Major);
+        assert_eq!(d_flat.name_chord(&c_sharp, NamingSystem::English), "Db7");
+    }
+
+    fn pitch(name: &str) -> Pitch {
+        name.parse().unwrap()
+    }
+
+    #[test]
+    fn pitches_round_trip_through_midi_and_names() {
+        for midi_note in 0..=127 {
+            let pitch = Pitch::from_midi(midi_note);
+            assert_eq!(pitch.to_midi(), Some(midi_note));
+            assert_eq!(pitch.to_string().parse::<Pitch>(), Ok(pitch));
+        }
+        assert_eq!(pitch("C4").to_midi(), Some(60));
+        assert_eq!(pitch("C-1").to_midi(), Some(0));
+        assert_eq!(pitch("G9").to_midi(), Some(127));
+        assert_eq!(pitch("G#9").to_midi(), None);
+        assert_eq!(pitch("B-2").to_midi(), None);
+    }
+
+    #[test]
+    fn pitch_frequencies() {
+        assert_eq!(pitch("A4").frequency(), 440.0);
+        assert_eq!(pitch("A0").frequency(), 27.5);
+        assert_eq!(pitch("A5").frequency(), 880.0);
+        assert!((pitch("C4").frequency() - 261.626).abs() < 0.001);
+    }
+
+    #[test]
+    fn pitch_names_parse_across_octave_boundaries() {
+        assert_eq!(pitch("Cb4"), pitch("B3"));
+        assert_eq!(pitch("B#3"), pitch("C4"));
+        assert_eq!(pitch("E#4"), pitch("F4"));
+        assert_eq!(pitch("Fb4"), pitch("E4"));
+        assert_eq!(pitch("A-1"), Pitch::new(Note::A, -1));
+        assert_eq!(pitch("A-1").to_midi(), Some(9));
+        assert_eq!(pitch("Bb3"), Pitch::new(Note::ASharpBFlat, 3));
+        assert!("C".parse::<Pitch>().is_err());
+        assert!("4".parse::<Pitch>().is_err());
+        assert!("H4".parse::<Pitch>().is_err());
+        assert!("C#x".parse::<Pitch>().is_err());
+        assert!("C200".parse::<Pitch>().is_err());
+    }
+
+    fn spelled(name: &str) -> SpelledNote {
+        name.parse().unwrap()
+    }
+
+    #[test]
+    fn keys_spell_scale_and_chromatic_notes() {
+        let spell = |key: Key, note| key.spell(note).to_string();
+        let d_major = Key::from_note(Note::D, Mode::Major);
+        assert_eq!(spell(d_major, Note::FsharpGflat), "F#");
+        assert_eq!(spell(d_major, Note::CsharpDflat), "C#");
+        assert_eq!(spell(d_major, Note::GsharpAflat), "G#");
+        let f_major = Key::from_note(Note::F, Mode::Major);
+        assert_eq!(spell(f_major, Note::ASharpBFlat), "Bb");
+        assert_eq!(spell(f_major, Note::DsharpEflat), "Eb");
+        // The raised sixth and seventh of minor keys stay on their letters.
+        let a_minor = Key::from_note(Note::A, Mode::Minor);
+        assert_eq!(spell(a_minor, Note::GsharpAflat), "G#");
+        assert_eq!(spell(a_minor, Note::FsharpGflat), "F#");
+        let c_sharp_minor = Key::from_note(Note::CsharpDflat, Mode::Minor);
+        assert_eq!(spell(c_sharp_minor, Note::C), "B#");
+        let g_flat_major = Key::new(spelled("Gb"), Mode::Major);
+        assert_eq!(spell(g_flat_major, Note::B), "Cb");
+        // Cb is written an octave above the B it sounds as.
+        assert_eq!(g_flat_major.spell_pitch(pitch("B3")), (spelled("Cb"), 4));
+    }
+
+    #[test]
+    fn key_signatures_put_accidentals_on_letters() {
+        assert_eq!(Letter::F.in_signature(1), 1);
+        assert_eq!(Letter::C.in_signature(1), 0);
+        assert_eq!(Letter::C.in_signature(2), 1);
+        assert_eq!(Letter::B.in_signature(7), 1);
+        assert_eq!(Letter::B.in_signature(-1), -1);
+        assert_eq!(Letter::E.in_signature(-1), 0);
+        assert_eq!(Letter::F.in_signature(-7), -1);
+        // Past seven the accidentals double, F## in the key of G#.
+        assert_eq!(Letter::F.in_signature(8), 2);
+        assert_eq!(Letter::B.in_signature(-8), -2);
+        assert_eq!(Key::new(spelled("G#"), Mode::Major).fifths(), 8);
+    }
+
+    #[test]
+    fn notes_are_named_in_every_naming_system() {
+        let c_major = Key::from_note(Note::C, Mode::Major);
+        let name = |note: &str, naming| spelled(note).name(naming, &c_major);
+        let german: Vec<String> = ["B", "Bb", "Bbb", "B#", "Eb", "Ab", "Ebb", "C#", "Db", "F##"]
+            .iter()
+            .map(|note| name(note, NamingSystem::German))
+            .collect();
+        assert_eq!(
+            german,
+            ["H", "B", "Heses", "His", "Es", "As", "Eses", "Cis", "Des", "Fisis"]
+        );
+        let nordic: Vec<String> = ["B", "Bb", "Bbb", "C#", "Db", "Eb", "Ab", "Gb"]
+            .iter()
+            .map(|note| name(note, NamingSystem::Nordic))
+            .collect();
+        assert_eq!(
+            nordic,
+            ["H", "B", "Bess", "Ciss", "Dess", "Ess", "Ass", "Gess"]
+        );
+        assert_eq!(name("C", NamingSystem::FixedSolfege), "Do");
+        assert_eq!(name("G", NamingSystem::FixedSolfege), "Sol");
+        assert_eq!(name("F#", NamingSystem::FixedSolfege), "Fa#");
+        assert_eq!(name("Bb", NamingSystem::FixedSolfege), "Sib");
+        assert_eq!(name("Db", NamingSystem::English), "Db");
+    }
+
+    #[test]
+    fn movable_solfege_follows_the_key() {
+        let name = |note: &str, key: Key| spelled(note).name(NamingSystem::MovableSolfege, &key);
+        let d_major = Key::from_note(Note::D, Mode::Major);
+        assert_eq!(name("D", d_major), "Do");
+        assert_eq!(name("F#", d_major), "Mi");
+        assert_eq!(name("G#", d_major), "Fi");
+        assert_eq!(name("C", d_major), "Te");
+        assert_eq!(name("C##", d_major), "Ty");
+        assert_eq!(name("Cb", d_major), "Tibb");
+        // Minor keys are la based.
+        let a_minor = Key::from_note(Note::A, Mode::Minor);
+        assert_eq!(name("A", a_minor), "La");
+        assert_eq!(name("C", a_minor), "Do");
+        assert_eq!(name("G#", a_minor), "Si");
+        let e_flat_major = Key::from_note(Note::DsharpEflat, Mode::Major);
+        assert_eq!(name("Eb", e_flat_major), "Do");
+        assert_eq!(name("Cb", e_flat_major), "Le");
+    }
+
+    #[test]
+    fn scales_parse_from_root_and_kind() {
+        let scale: Scale = "D dorian".parse().unwrap();
+        assert_eq!(scale, Scale::new(Note::D, ScaleKind::Dorian));
+        assert_eq!(
+            scale.notes(),
+            [
+                Note::D,
+                Note::E,
+                Note::F,
+                Note::G,
+                Note::A,
+                Note::B,
+                Note::C
+            ]
+        );
+        assert_eq!(
+            "Bb Minor-Pentatonic".parse::<Scale>(),
+            Ok(Scale::new(Note::ASharpBFlat, ScaleKind::MinorPentatonic))
+        );
+        assert_eq!(
+            "A aeolian".parse::<Scale>(),
+            Ok(Scale::new(Note::A, ScaleKind::NaturalMinor))
+        );
+        assert!("D".parse::<Scale>().is_err());
+        assert!("D dorian mode".parse::<Scale>().is_err());
+        assert!("D lydian-dominant".parse::<Scale>().is_err());
+        assert!("H dorian".parse::<Scale>().is_err());
+    }
+
+    #[test]
+    fn snap_moves_to_the_closest_degree() {
+        let everywhere = pitch("C-1")..=pitch("G9");
+        let c_major = Scale::new(Note::C, ScaleKind::Major);
+        assert_eq!(c_major.snap(pitch("E4"), everywhere.clone()), pitch("E4"));
+        // Equally close degrees snap down.
+        assert_eq!(c_major.snap(pitch("F#4"), everywhere.clone()), pitch("F4"));
+        assert_eq!(c_major.snap(pitch("C#4"), everywhere.clone()), pitch("C4"));
+        let blues = Scale::new(Note::A, ScaleKind::Blues);
+        assert_eq!(blues.snap(pitch("B4"), everywhere.clone()), pitch("C5"));
+        assert_eq!(blues.snap(pitch("G#4"), everywhere), pitch("G4"));
+    }
+
+    #[test]
+    fn snap_stays_inside_the_range() {
+        let range = pitch("C3")..=pitch("C6");
+        let b_major = Scale::new(Note::B, ScaleKind::Major);
+        // B2 is closer but below the range.
+        assert_eq!(b_major.snap(pitch("C3"), range.clone()), pitch("C#3"));
+        let d_major = Scale::new(Note::D, ScaleKind::Major);
+        assert_eq!(d_major.snap(pitch("C6"), range.clone()), pitch("B5"));
+        let pentatonic = Scale::new(Note::D, ScaleKind::MajorPentatonic);
+        assert_eq!(pentatonic.snap(pitch("C3"), range.clone()), pitch("D3"));
+        assert_eq!(pentatonic.snap(pitch("C6"), range), pitch("B5"));
+    }
+
+    #[test]
+    fn diatonic_chords_follow_the_key() {
+        let qualities = |key: Key, seventh| -> Vec<(Note, ChordQuality)> {
+            (1..=7)
+                .map(|degree| key.diatonic_chord(degree, seventh).unwrap())
+                .map(|chord| (chord.root, chord.quality))
+                .collect()
+        };
+        let c_major = Key::from_note(Note::C, Mode::Major);
+        assert_eq!(
+            qualities(c_major, false),
+            [
+                (Note::C, ChordQuality::Major),
+                (Note::D, ChordQuality::Minor),
+                (Note::E, ChordQuality::Minor),
+                (Note::F, ChordQuality::Major),
+                (Note::G, ChordQuality::Major),
+                (Note::A, ChordQuality::Minor),
+                (Note::B, ChordQuality::Diminished),
+            ]
+        );
+        let a_minor = Key::from_note(Note::A, Mode::Minor);
+        assert_eq!(
+            qualities(a_minor, true),
+            [
+                (Note::A, ChordQuality::Minor7),
+                (Note::B, ChordQuality::HalfDiminished7),
+                (Note::C, ChordQuality::Major7),
+                (Note::D, ChordQuality::Minor7),
+                (Note::E, ChordQuality::Minor7),
+                (Note::F, ChordQuality::Major7),
+                (Note::G, ChordQuality::Dominant7),
+            ]
+        );
+        assert_eq!(c_major.diatonic_chord(0, false), None);
+        assert_eq!(c_major.diatonic_chord(8, true), None);
+    }
 }
@@ -0,0 +1,364 @@
+use std::{fmt::Write, fs};
+
+use crate::{
+    music_entities::{Mode, Pitch},
+    notation::{Clef, MeasureAccidentals, NotationEvent, Score, WrittenValue, DIVISIONS},
+};
+
+const HEADER: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>
+<!DOCTYPE score-partwise PUBLIC "-//Recordare//DTD MusicXML 4.0 Partwise//EN" "http://www.musicxml.org/dtds/partwise.dtd">
+"#;
+
+/// Writes `score` as a MusicXML piano part with a treble and a bass staff.
+pub fn export_musicxml(score: &Score, path: &str) -> Result<(), String> {
+    fs::write(path, musicxml(score)).map_err(|err| format!("couldn't write {}: {}", path, err))
+}
+
+fn musicxml(score: &Score) -> String {
+    let mut xml = String::from(HEADER);
+    xml.push_str("<score-partwise version=\"4.0\">\n");
+    xml.push_str("  <part-list>\n");
+    xml.push_str("    <score-part id=\"P1\"><part-name>Piano</part-name></score-part>\n");
+    xml.push_str("  </part-list>\n");
+    xml.push_str("  <part id=\"P1\">\n");
+    let measure_count = score
+        .staves
+        .iter()
+        .map(|staff| staff.measures.len())
+        .max()
+        .unwrap_or(0);
+    for index in 0..measure_count {
+        let _ = writeln!(xml, "    <measure number=\"{}\">", index + 1);
+        if index == 0 {
+            write_attributes(&mut xml, score);
+        }
+        for (staff_index, staff) in score.staves.iter().enumerate() {
+            if staff_index > 0 {
+                let _ = writeln!(
+                    xml,
+                    "      <backup><duration>{}</duration></backup>",
+                    score.measure_ticks()
+                );
+            }
+            let mut accidentals = MeasureAccidentals::new(score.key);
+            for event in staff.measures[index].events.iter() {
+                write_event(&mut xml, score, event, staff_index + 1, &mut accidentals);
+            }
+        }
+        xml.push_str("    </measure>\n");
+    }
+    xml.push_str("  </part>\n");
+    xml.push_str("</score-partwise>\n");
+    xml
+}
+
+fn write_attributes(xml: &mut String, score: &Score) {
+    let mode = match score.key.mode {
+        Mode::Major => "major",
+        Mode::Minor => "minor",
+    };
+    let time_signature = score.time_signature;
+    xml.push_str("      <attributes>\n");
+    let _ = writeln!(xml, "        <divisions>{}</divisions>", DIVISIONS);
+    let _ = writeln!(
+        xml,
+        "        <key><fifths>{}</fifths><mode>{}</mode></key>",
+        score.key.fifths(),
+        mode
+    );
+    let _ = writeln!(
+        xml,
+        "        <time><beats>{}</beats><beat-type>{}</beat-type></time>",
+        time_signature.beats_per_bar, time_signature.beat_value
+    );
+    let _ = writeln!(xml, "        <staves>{}</staves>", score.staves.len());
+    for (index, staff) in score.staves.iter().enumerate() {
+        let (sign, line) = match staff.clef {
+            Clef::Treble => ("G", 2),
+            Clef::Bass => ("F", 4),
+        };
+        let _ = writeln!(
+            xml,
+            "        <clef number=\"{}\"><sign>{}</sign><line>{}</line></clef>",
+            index + 1,
+            sign,
+            line
+        );
+    }
+    xml.push_str("      </attributes>\n");
+    // The tempo counts beats of the time signature, sound tempo quarters.
+    let quarters_per_minute = score.bpm * 4.0 / time_signature.beat_value as f32;
+    let _ = writeln!(
+        xml,
+        "      <direction placement=\"above\"><direction-type><metronome><beat-unit>{}</beat-unit><per-minute>{:.0}</per-minute></metronome></direction-type><sound tempo=\"{:.0}\"/></direction>",
+        type_name(time_signature.beat_value as u32),
+        score.bpm,
+        quarters_per_minute
+    );
+}
+
+fn write_event(
+    xml: &mut String,
+    score: &Score,
+    event: &NotationEvent,
+    staff: usize,
+    accidentals: &mut MeasureAccidentals,
+) {
+    if event.is_rest() {
+        xml.push_str("      <note>");
+        match event.value.ticks() == score.measure_ticks() {
+            true => xml.push_str("<rest measure=\"yes\"/>"),
+            false => xml.push_str("<rest/>"),
+        }
+        let _ = write!(xml, "<duration>{}</duration>", event.value.ticks());
+        let _ = write!(xml, "<voice>{}</voice>", staff);
+        write_value(
+            xml,
+            event.value,
+            event.value.ticks() == score.measure_ticks(),
+        );
+        let _ = writeln!(xml, "<staff>{}</staff></note>", staff);
+        return;
+    }
+    for (index, pitch) in event.pitches.iter().enumerate() {
+        xml.push_str("      <note>");
+        if index > 0 {
+            xml.push_str("<chord/>");
+        }
+        let accidental = write_pitch(xml, score, *pitch, event.tie_stop, accidentals);
+        let _ = write!(xml, "<duration>{}</duration>", event.value.ticks());
+        if event.tie_stop {
+            xml.push_str("<tie type=\"stop\"/>");
+        }
+        if event.tie_start {
+            xml.push_str("<tie type=\"start\"/>");
+        }
+        let _ = write!(xml, "<voice>{}</voice>", staff);
+        write_value(xml, event.value, false);
+        if let Some(accidental) = accidental {
+            let _ = write!(xml, "<accidental>{}</accidental>", accidental);
+        }
+        if event.value.triplet {
+            xml.push_str("<time-modification><actual-notes>3</actual-notes><normal-notes>2</normal-notes></time-modification>");
+        }
+        let _ = write!(xml, "<staff>{}</staff>", staff);
+        if event.tie_start || event.tie_stop {
+            xml.push_str("<notations>");
+            if event.tie_stop {
+                xml.push_str("<tied type=\"stop\"/>");
+            }
+            if event.tie_start {
+                xml.push_str("<tied type=\"start\"/>");
+            }
+            xml.push_str("</notations>");
+        }
+        xml.push_str("</note>\n");
+    }
+}
+
+/// Writes the spelled pitch and returns the accidental to show, if any.
+fn write_pitch(
+    xml: &mut String,
+    score: &Score,
+    pitch: Pitch,
+    tied: bool,
+    accidentals: &mut MeasureAccidentals,
+) -> Option<&'static str> {
+    let (spelled, octave) = score.key.spell_pitch(pitch);
+    let _ = write!(xml, "<pitch><step>{:?}</step>", spelled.letter);
+    if spelled.accidental != 0 {
+        let _ = write!(xml, "<alter>{}</alter>", spelled.accidental);
+    }
+    let _ = write!(xml, "<octave>{}</octave></pitch>", octave);
+    accidentals
+        .show(spelled, octave, tied)
+        .map(|accidental| match accidental {
+            2 => "double-sharp",
+            1 => "sharp",
+            0 => "natural",
+            -1 => "flat",
+            _ => "flat-flat",
+        })
+}
+
+/// Writes the note type and dots, which whole measure rests go without.
+fn write_value(xml: &mut String, value: WrittenValue, measure_rest: bool) {
+    if measure_rest {
+        return;
+    }
+    let _ = write!(xml, "<type>{}</type>", type_name(value.division));
+    for _ in 0..value.dots {
+        xml.push_str("<dot/>");
+    }
+}
+
+fn type_name(division: u32) -> &'static str {
+    match division {
+        1 => "whole",
+        2 => "half",
+        4 => "quarter",
+        8 => "eighth",
+        16 => "16th",
+        _ => "32nd",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        music_entities::{Key, Note, NoteValue},
+        test_support::performance,
+    };
+
+    fn musicxml_of(notes: &[(f64, f64, &str)], key: Key, grid: NoteValue) -> String {
+        musicxml(&Score::transcribe(&performance(notes), key, grid))
+    }
+
+    /// The lines of each note, in the order they are written.
+    fn notes(xml: &str) -> Vec<&str> {
+        xml.lines().filter(|line| line.contains("<note>")).collect()
+    }
+
+    /// Panics unless `parts` appear in `text` in order.
+    fn assert_in_order(text: &str, parts: &[&str]) {
+        let mut position = 0;
+        for part in parts {
+            match text[position..].find(part) {
+                Some(found) => position += found + part.len(),
+                None => panic!("{} missing after {} in {}", part, position, text),
+            }
+        }
+    }
+
+    #[test]
+    fn attributes_come_first() {
+        let xml = musicxml_of(
+            &[(0.0, 0.5, "D4")],
+            Key::from_note(Note::D, Mode::Major),
+            NoteValue::new(16, false),
+        );
+        assert!(xml.starts_with(HEADER));
+        assert_in_order(
+            &xml,
+            &[
+                "<score-partwise version=\"4.0\">",
+                "<part-list>",
+                "<part id=\"P1\">",
+                "<measure number=\"1\">",
+                "<attributes>",
+                "<divisions>24</divisions>",
+                "<key><fifths>2</fifths><mode>major</mode></key>",
+                "<time><beats>4</beats><beat-type>4</beat-type></time>",
+                "<staves>2</staves>",
+                "<clef number=\"1\"><sign>G</sign><line>2</line></clef>",
+                "<clef number=\"2\"><sign>F</sign><line>4</line></clef>",
+                "</attributes>",
+                "<beat-unit>quarter</beat-unit><per-minute>120</per-minute>",
+                "<sound tempo=\"120\"/>",
+                "<note>",
+                "</measure>",
+                "</part>",
+                "</score-partwise>",
+            ],
+        );
+        assert_eq!(xml.matches("<attributes>").count(), 1);
+    }
+
+    #[test]
+    fn the_bass_staff_follows_a_backup() {
+        let xml = musicxml_of(
+            &[(0.0, 0.5, "E4"), (0.0, 0.5, "C3"), (2.0, 2.5, "G2")],
+            Key::from_note(Note::C, Mode::Major),
+            NoteValue::new(16, false),
+        );
+        let measures: Vec<&str> = xml.split("<measure number=").skip(1).collect();
+        assert_eq!(measures.len(), 2);
+        for measure in measures {
+            let (treble, bass) = measure
+                .split_once("<backup><duration>96</duration></backup>")
+                .unwrap();
+            assert!(!bass.contains("<backup>"));
+            assert!(treble.contains("<staff>1</staff>") && !treble.contains("<staff>2</staff>"));
+            assert!(bass.contains("<staff>2</staff>") && !bass.contains("<staff>1</staff>"));
+        }
+        // The treble staff of the second measure is a whole measure rest.
+        assert!(xml.contains(
+            "<note><rest measure=\"yes\"/><duration>96</duration><voice>1</voice><staff>1</staff></note>"
+        ));
+    }
+
+    #[test]
+    fn tied_notes_and_accidentals() {
+        let xml = musicxml_of(
+            &[(0.0, 0.5, "F4"), (1.5, 2.5, "F#4")],
+            Key::from_note(Note::D, Mode::Major),
+            NoteValue::new(16, false),
+        );
+        let notes: Vec<&str> = notes(&xml)
+            .into_iter()
+            .filter(|note| note.contains("<pitch>"))
+            .collect();
+        assert_eq!(notes.len(), 3);
+        // F natural against the key, then F sharp against the earlier note.
+        assert!(notes[0].contains("<accidental>natural</accidental>"));
+        assert!(!notes[0].contains("<tie"));
+        assert_in_order(
+            notes[1],
+            &[
+                "<pitch><step>F</step><alter>1</alter><octave>4</octave></pitch>",
+                "<duration>24</duration>",
+                "<tie type=\"start\"/>",
+                "<voice>1</voice>",
+                "<type>quarter</type>",
+                "<accidental>sharp</accidental>",
+                "<staff>1</staff>",
+                "<notations><tied type=\"start\"/></notations>",
+            ],
+        );
+        // The key signature is back in the next measure, and tied notes
+        // never show an accidental.
+        assert!(notes[2].contains("<tie type=\"stop\"/>"));
+        assert!(notes[2].contains("<notations><tied type=\"stop\"/></notations>"));
+        assert!(!notes[2].contains("<accidental>"));
+    }
+
+    #[test]
+    fn chords_share_a_stem() {
+        let xml = musicxml_of(
+            &[(0.0, 1.0, "C4"), (0.0, 1.0, "E4")],
+            Key::from_note(Note::C, Mode::Major),
+            NoteValue::new(16, false),
+        );
+        let notes = notes(&xml);
+        assert!(!notes[0].contains("<chord/>"));
+        assert!(notes[0].contains("<step>C</step>"));
+        assert!(notes[1].starts_with("      <note><chord/><pitch><step>E</step>"));
+        assert!(notes[1].contains("<type>half</type>"));
+    }
+
+    #[test]
+    fn triplets_have_a_time_modification() {
+        let xml = musicxml_of(
+            &[(0.0, 1.0 / 6.0, "C4"), (1.0 / 6.0, 0.5, "D4")],
+            Key::from_note(Note::C, Mode::Major),
+            NoteValue::new(8, true),
+        );
+        let notes = notes(&xml);
+        assert_in_order(
+            notes[0],
+            &[
+                "<duration>8</duration>",
+                "<type>eighth</type>",
+                "<time-modification><actual-notes>3</actual-notes><normal-notes>2</normal-notes></time-modification>",
+                "<staff>1</staff>",
+            ],
+        );
+        assert!(notes[1].contains(
+            "<duration>16</duration><voice>1</voice><type>quarter</type><time-modification>"
+        ));
+        // Rests are straight values.
+        assert!(notes[2].contains("<rest/><duration>72</duration>"));
+        assert!(!notes[2].contains("time-modification"));
+    }
+}
@@ -0,0 +1,538 @@
+use std::collections::HashMap;
+
+use crate::{
+    abc, lilypond,
+    music_entities::{Key, Letter, Note, NoteValue, Pitch, SpelledNote},
+    musicxml,
+    quantize::Grid,
+    recorder::{Performance, PerformanceEventKind},
+    transport::TimeSignature,
+};
+
+/// Ticks per quarter note, enough for 1/32 notes and their triplets.
+pub const DIVISIONS: u32 = 24;
+/// Notes from middle C up go on the treble staff, lower ones on the bass.
+const STAFF_SPLIT: Pitch = Pitch {
+    note: Note::C,
+    octave: 4,
+};
+
+/// A note length as written, e.g. a dotted quarter or an eighth triplet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WrittenValue {
+    /// 1 for a whole note, 4 for a quarter and so on.
+    pub division: u32,
+    pub dots: u8,
+    pub triplet: bool,
+}
+
+impl WrittenValue {
+    const fn new(division: u32, dots: u8, triplet: bool) -> Self {
+        WrittenValue {
+            division,
+            dots,
+            triplet,
+        }
+    }
+
+    pub fn ticks(&self) -> u32 {
+        let mut ticks = DIVISIONS * 4 / self.division;
+        if self.dots == 1 {
+            ticks += ticks / 2;
+        }
+        match self.triplet {
+            true => ticks * 2 / 3,
+            false => ticks,
+        }
+    }
+}
+
+/// Every value a length is written with, longest first.
+const WRITTEN_VALUES: [WrittenValue; 14] = [
+    WrittenValue::new(1, 0, false),
+    WrittenValue::new(2, 1, false),
+    WrittenValue::new(2, 0, false),
+    WrittenValue::new(4, 1, false),
+    WrittenValue::new(4, 0, false),
+    WrittenValue::new(8, 1, false),
+    WrittenValue::new(4, 0, true),
+    WrittenValue::new(8, 0, false),
+    WrittenValue::new(16, 1, false),
+    WrittenValue::new(8, 0, true),
+    WrittenValue::new(16, 0, false),
+    WrittenValue::new(16, 0, true),
+    WrittenValue::new(32, 0, false),
+    WrittenValue::new(32, 0, true),
+];
+
+/// Splits `ticks` into written values, longest first. A length that is one
+/// value is written as it, and anything else with values of the grid's
+/// family, triplets or not, so a triplet grid doesn't leave lone triplets
+/// after straight values. Every length of two ticks or more can be written,
+/// so a value is only skipped when it would leave a single tick over.
+fn written_values(mut ticks: u32, triplet: bool) -> Vec<WrittenValue> {
+    if let Some(value) = WRITTEN_VALUES.iter().find(|value| value.ticks() == ticks) {
+        return vec![*value];
+    }
+    let mut values = Vec::new();
+    // What the grid's family can't write is written with any value.
+    for family_only in [true, false] {
+        while let Some(value) = WRITTEN_VALUES.iter().find(|value| {
+            (!family_only || value.triplet == triplet)
+                && value.ticks() <= ticks
+                && ticks - value.ticks() != 1
+        }) {
+            values.push(*value);
+            ticks -= value.ticks();
+        }
+    }
+    values
+}
+
+/// A chord, single note or rest in a measure.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NotationEvent {
+    /// Lowest first, empty for a rest.
+    pub pitches: Vec<Pitch>,
+    pub value: WrittenValue,
+    /// Tied to the next event, which holds the same pitches.
+    pub tie_start: bool,
+    /// Tied from the previous event.
+    pub tie_stop: bool,
+}
+
+impl NotationEvent {
+    pub fn is_rest(&self) -> bool {
+        self.pitches.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measure {
+    pub events: Vec<NotationEvent>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clef {
+    Treble,
+    Bass,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Staff {
+    pub clef: Clef,
+    pub measures: Vec<Measure>,
+}
+
+/// The accidentals in effect through a measure, starting from the key
+/// signature, for formats that leave showing them to the writer.
+pub struct MeasureAccidentals {
+    fifths: i8,
+    alterations: HashMap<(Letter, i8), i8>,
+}
+
+impl MeasureAccidentals {
+    pub fn new(key: Key) -> Self {
+        MeasureAccidentals {
+            fifths: key.fifths(),
+            alterations: HashMap::new(),
+        }
+    }
+
+    /// The accidental to show on `spelled` in its written `octave`, if it
+    /// differs from what the key signature or an earlier note in the measure
+    /// gives its letter. Notes continuing a tie show none.
+    pub fn show(&mut self, spelled: SpelledNote, octave: i8, tied: bool) -> Option<i8> {
+        let in_effect = self
+            .alterations
+            .insert((spelled.letter, octave), spelled.accidental)
+            .unwrap_or_else(|| spelled.letter.in_signature(self.fifths));
+        (!tied && in_effect != spelled.accidental).then_some(spelled.accidental)
+    }
+}
+
+/// The text formats a score can be written out in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotationFormat {
+    MusicXml,
+    LilyPond,
+    Abc,
+}
+
+impl NotationFormat {
+    pub fn export(&self, score: &Score, path: &str) -> Result<(), String> {
+        match self {
+            NotationFormat::MusicXml => musicxml::export_musicxml(score, path),
+            NotationFormat::LilyPond => lilypond::export_lilypond(score, path),
+            NotationFormat::Abc => abc::export_abc(score, path),
+        }
+    }
+}
+
+/// A performance written out as a piano score with one voice per staff.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Score {
+    pub key: Key,
+    pub time_signature: TimeSignature,
+    pub bpm: f32,
+    /// Treble first, then bass.
+    pub staves: Vec<Staff>,
+}
+
+impl Score {
+    /// Writes out `performance` with notes snapped to `grid`. Notes starting
+    /// together on a staff become a chord, and a chord is cut short where the
+    /// next one on its staff starts.
+    pub fn transcribe(performance: &Performance, key: Key, grid: NoteValue) -> Score {
+        let grid_ticks = WrittenValue::new(grid.division, 0, grid.triplet)
+            .ticks()
+            .max(2);
+        // Steps of a quarter note at the performance's tempo are beats, as the
+        // tempo counts beats of the time signature.
+        let beats = Grid::new(performance, NoteValue::new(4, false));
+        let beat_ticks = DIVISIONS as f64 * 4.0 / performance.time_signature.beat_value as f64;
+        let to_ticks = |seconds: f64| {
+            let ticks = beats.to_steps(seconds) * beat_ticks;
+            (ticks / grid_ticks as f64).round().max(0.0) as u32 * grid_ticks
+        };
+        let mut treble = Vec::new();
+        let mut bass = Vec::new();
+        for (start, end, pitch) in played_notes(performance) {
+            let start = to_ticks(start);
+            let note = (start, to_ticks(end).max(start + grid_ticks), pitch);
+            match pitch >= STAFF_SPLIT {
+                true => treble.push(note),
+                false => bass.push(note),
+            }
+        }
+        let time_signature = performance.time_signature;
+        let measure_ticks = measure_ticks(time_signature);
+        let end = treble.iter().chain(bass.iter()).map(|note| note.1).max();
+        let measure_count = end.unwrap_or(0).div_ceil(measure_ticks).max(1);
+        let staves = [(Clef::Treble, treble), (Clef::Bass, bass)]
+            .into_iter()
+            .map(|(clef, notes)| Staff {
+                clef,
+                measures: write_measures(
+                    &chords(notes),
+                    measure_ticks,
+                    measure_count,
+                    grid.triplet,
+                ),
+            })
+            .collect();
+        Score {
+            key,
+            time_signature,
+            bpm: performance.bpm,
+            staves,
+        }
+    }
+
+    pub fn measure_ticks(&self) -> u32 {
+        measure_ticks(self.time_signature)
+    }
+}
+
+fn measure_ticks(time_signature: TimeSignature) -> u32 {
+    DIVISIONS * 4 * time_signature.beats_per_bar as u32 / time_signature.beat_value as u32
+}
+
+/// Start, end and pitch of every note, with notes that never end lasting to
+/// the end of the performance.
+fn played_notes(performance: &Performance) -> Vec<(f64, f64, Pitch)> {
+    let mut notes = Vec::new();
+    let mut started: HashMap<Pitch, Vec<f64>> = HashMap::new();
+    for event in performance.events.iter() {
+        let time = event.time.as_secs_f64();
+        match event.kind {
+            PerformanceEventKind::NoteOn { pitch, .. } => {
+                started.entry(pitch).or_default().push(time)
+            }
+            PerformanceEventKind::NoteOff { pitch } => {
+                if let Some(start) = started
+                    .get_mut(&pitch)
+                    .filter(|starts| !starts.is_empty())
+                    .map(|starts| starts.remove(0))
+                {
+                    notes.push((start, time, pitch));
+                }
+            }
+            _ => {}
+        }
+    }
+    let end = performance.duration().as_secs_f64();
+    for (pitch, starts) in started {
+        notes.extend(starts.into_iter().map(|start| (start, end, pitch)));
+    }
+    notes
+}
+
+/// Groups notes starting on the same tick into chords of start, end and
+/// pitches, each ending by the time the next starts.
+fn chords(mut notes: Vec<(u32, u32, Pitch)>) -> Vec<(u32, u32, Vec<Pitch>)> {
+    notes.sort_by_key(|(start, _, pitch)| (*start, *pitch));
+    let mut chords: Vec<(u32, u32, Vec<Pitch>)> = Vec::new();
+    for (start, end, pitch) in notes {
+        match chords.last_mut() {
+            Some(chord) if chord.0 == start => {
+                chord.1 = chord.1.max(end);
+                if !chord.2.contains(&pitch) {
+                    chord.2.push(pitch);
+                }
+            }
+            _ => chords.push((start, end, vec![pitch])),
+        }
+    }
+    for index in 1..chords.len() {
+        let next_start = chords[index].0;
+        chords[index - 1].1 = chords[index - 1].1.min(next_start);
+    }
+    chords
+}
+
+/// Fills `measure_count` measures with the chords and the rests between
+/// them, splitting what crosses a bar line or can't be written as a single
+/// value into tied notes, written in triplets on a `triplet` grid.
+fn write_measures(
+    chords: &[(u32, u32, Vec<Pitch>)],
+    measure_ticks: u32,
+    measure_count: u32,
+    triplet: bool,
+) -> Vec<Measure> {
+    let mut spans = Vec::new();
+    let mut position = 0;
+    for (start, end, pitches) in chords {
+        if *start > position {
+            spans.push((position, *start, Vec::new()));
+        }
+        spans.push((*start, *end, pitches.clone()));
+        position = *end;
+    }
+    let total = measure_ticks * measure_count;
+    if position < total {
+        spans.push((position, total, Vec::new()));
+    }
+
+    let mut measures = vec![Measure { events: Vec::new() }; measure_count as usize];
+    for (start, end, pitches) in spans {
+        let mut pieces = Vec::new();
+        let mut position = start;
+        while position < end {
+            let bar_end = (position / measure_ticks + 1) * measure_ticks;
+            let piece_end = end.min(bar_end);
+            for value in written_values(piece_end - position, triplet) {
+                pieces.push((position / measure_ticks, value));
+            }
+            position = piece_end;
+        }
+        let tied = !pitches.is_empty();
+        let last = pieces.len().saturating_sub(1);
+        for (index, (measure, value)) in pieces.into_iter().enumerate() {
+            if let Some(measure) = measures.get_mut(measure as usize) {
+                measure.events.push(NotationEvent {
+                    pitches: pitches.clone(),
+                    value,
+                    tie_start: tied && index < last,
+                    tie_stop: tied && index > 0,
+                });
+            }
+        }
+    }
+    measures
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{music_entities::Mode, test_support::performance};
+
+    const QUARTER: WrittenValue = WrittenValue::new(4, 0, false);
+    const HALF: WrittenValue = WrittenValue::new(2, 0, false);
+    const WHOLE: WrittenValue = WrittenValue::new(1, 0, false);
+
+    fn transcribe(notes: &[(f64, f64, &str)]) -> Score {
+        Score::transcribe(
+            &performance(notes),
+            Key::from_note(Note::C, Mode::Major),
+            NoteValue::new(16, false),
+        )
+    }
+
+    /// Each event of a staff as its measure, pitches, value and ties.
+    fn events(staff: &Staff) -> Vec<(usize, Vec<String>, WrittenValue, bool, bool)> {
+        let mut events = Vec::new();
+        for (index, measure) in staff.measures.iter().enumerate() {
+            for event in measure.events.iter() {
+                let pitches = event.pitches.iter().map(Pitch::to_string).collect();
+                events.push((index, pitches, event.value, event.tie_start, event.tie_stop));
+            }
+        }
+        events
+    }
+
+    fn note(
+        measure: usize,
+        pitches: &[&str],
+        value: WrittenValue,
+        tie_start: bool,
+        tie_stop: bool,
+    ) -> (usize, Vec<String>, WrittenValue, bool, bool) {
+        let pitches = pitches.iter().map(|pitch| pitch.to_string()).collect();
+        (measure, pitches, value, tie_start, tie_stop)
+    }
+
+    fn rest(measure: usize, value: WrittenValue) -> (usize, Vec<String>, WrittenValue, bool, bool) {
+        note(measure, &[], value, false, false)
+    }
+
+    #[test]
+    fn written_values_add_up() {
+        for triplet in [false, true] {
+            for ticks in 2..=DIVISIONS * 8 {
+                let values = written_values(ticks, triplet);
+                let total: u32 = values.iter().map(WrittenValue::ticks).sum();
+                assert_eq!(total, ticks, "{:?}", values);
+            }
+        }
+        assert!(written_values(1, false).is_empty());
+        assert!(written_values(0, true).is_empty());
+    }
+
+    #[test]
+    fn single_values_are_written_as_they_are() {
+        assert_eq!(written_values(24, false), [QUARTER]);
+        assert_eq!(written_values(36, true), [WrittenValue::new(4, 1, false)]);
+        assert_eq!(written_values(8, false), [WrittenValue::new(8, 0, true)]);
+        assert_eq!(written_values(48, true), [HALF]);
+    }
+
+    #[test]
+    fn lengths_are_written_in_the_grid_family() {
+        assert_eq!(
+            written_values(42, false),
+            [
+                WrittenValue::new(4, 1, false),
+                WrittenValue::new(16, 0, false)
+            ]
+        );
+        // Five eighth note triplets.
+        assert_eq!(
+            written_values(40, true),
+            [
+                WrittenValue::new(4, 0, true),
+                WrittenValue::new(4, 0, true),
+                WrittenValue::new(8, 0, true),
+            ]
+        );
+        for ticks in (8..=DIVISIONS * 8).step_by(8) {
+            let values = written_values(ticks, true);
+            assert!(
+                values.len() == 1 || values.iter().all(|value| value.triplet),
+                "{} ticks: {:?}",
+                ticks,
+                values
+            );
+        }
+        for ticks in (6..=DIVISIONS * 8).step_by(6) {
+            let values = written_values(ticks, false);
+            assert!(
+                values.iter().all(|value| !value.triplet),
+                "{} ticks: {:?}",
+                ticks,
+                values
+            );
+        }
+    }
+
+    #[test]
+    fn notes_across_bar_lines_are_tied() {
+        let score = transcribe(&[(1.5, 2.5, "C4")]);
+        assert_eq!(
+            events(&score.staves[0]),
+            [
+                rest(0, WrittenValue::new(2, 1, false)),
+                note(0, &["C4"], QUARTER, true, false),
+                note(1, &["C4"], QUARTER, false, true),
+                rest(1, WrittenValue::new(2, 1, false)),
+            ]
+        );
+        // The bass staff has a whole measure rest in each measure.
+        assert_eq!(events(&score.staves[1]), [rest(0, WHOLE), rest(1, WHOLE)]);
+    }
+
+    #[test]
+    fn lengths_without_a_single_value_are_tied() {
+        // Five sixteenths, from the second beat.
+        let score = transcribe(&[(0.5, 1.125, "E4")]);
+        assert_eq!(
+            events(&score.staves[0]),
+            [
+                rest(0, QUARTER),
+                note(0, &["E4"], QUARTER, true, false),
+                note(0, &["E4"], WrittenValue::new(16, 0, false), false, true),
+                rest(0, WrittenValue::new(4, 1, false)),
+                rest(0, WrittenValue::new(16, 0, false)),
+            ]
+        );
+    }
+
+    #[test]
+    fn notes_are_split_between_the_staves_at_middle_c() {
+        let score = transcribe(&[(0.0, 2.0, "C4"), (0.0, 2.0, "B3")]);
+        assert_eq!(score.staves[0].clef, Clef::Treble);
+        assert_eq!(score.staves[1].clef, Clef::Bass);
+        assert_eq!(
+            events(&score.staves[0]),
+            [note(0, &["C4"], WHOLE, false, false)]
+        );
+        assert_eq!(
+            events(&score.staves[1]),
+            [note(0, &["B3"], WHOLE, false, false)]
+        );
+    }
+
+    #[test]
+    fn chords_are_grouped_and_cut_short() {
+        let score = transcribe(&[
+            (0.0, 1.5, "G4"),
+            (0.02, 1.0, "C4"),
+            (0.0, 1.0, "E4"),
+            (0.5, 1.0, "A4"),
+        ]);
+        assert_eq!(
+            events(&score.staves[0]),
+            [
+                note(0, &["C4", "E4", "G4"], QUARTER, false, false),
+                note(0, &["A4"], QUARTER, false, false),
+                rest(0, HALF),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_takes_have_one_measure() {
+        let score = transcribe(&[]);
+        assert_eq!(score.bpm, 120.0);
+        for staff in score.staves.iter() {
+            assert_eq!(events(staff), [rest(0, WHOLE)]);
+        }
+    }
+
+    #[test]
+    fn measure_accidentals_follow_the_key_and_earlier_notes() {
+        let mut accidentals = MeasureAccidentals::new(Key::from_note(Note::G, Mode::Major));
+        let note = |name: &str| name.parse::<SpelledNote>().unwrap();
+        assert_eq!(accidentals.show(note("F#"), 4, false), None);
+        assert_eq!(accidentals.show(note("F"), 4, false), Some(0));
+        assert_eq!(accidentals.show(note("F"), 4, false), None);
+        assert_eq!(accidentals.show(note("F#"), 4, false), Some(1));
+        // Other octaves keep the key signature.
+        assert_eq!(accidentals.show(note("F"), 5, false), Some(0));
+        assert_eq!(accidentals.show(note("Bb"), 4, false), Some(-1));
+        // Tied notes show nothing but still change what follows.
+        assert_eq!(accidentals.show(note("C#"), 4, true), None);
+        assert_eq!(accidentals.show(note("C#"), 4, false), None);
+        assert_eq!(accidentals.show(note("C"), 4, false), Some(0));
+    }
+}
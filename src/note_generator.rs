@@ -1,13 +1,93 @@
-use winit::event::KeyEvent;
+use serde::{Deserialize, Serialize};
+use winit::keyboard::ModifiersState;
 
-use crate::music_entities::{Note, Octave};
+use crate::{
+    input_handler::TimedKeyEvent,
+    music_entities::{Chord, ChordQuality, Interval, Key, Note, Pitch, Scale, TimedNote},
+};
+
+/// Lowest pitch there is a sample for in audio_files.
+pub const LOWEST_SAMPLE: Pitch = Pitch {
+    note: Note::C,
+    octave: 3,
+};
+/// Highest pitch there is a sample for in audio_files.
+pub const HIGHEST_SAMPLE: Pitch = Pitch {
+    note: Note::C,
+    octave: 6,
+};
+
+/// How a single note key is turned into a chord built on its note.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChordMode {
+    Off,
+    /// The chord on that degree of the key, with Alt adding its seventh.
+    /// Notes outside of the key fall back to the modifier chords.
+    Diatonic,
+    /// Major, minor with Ctrl, dominant seventh with Alt and minor seventh
+    /// with both.
+    Modifier,
+}
+
+impl ChordMode {
+    pub fn next(&self) -> ChordMode {
+        match self {
+            ChordMode::Off => ChordMode::Diatonic,
+            ChordMode::Diatonic => ChordMode::Modifier,
+            ChordMode::Modifier => ChordMode::Off,
+        }
+    }
+}
 
 #[derive(Clone)]
-pub struct NoteGenerator {}
+pub struct NoteGenerator {
+    scale_lock: Option<Scale>,
+    chord_mode: ChordMode,
+}
 
 impl NoteGenerator {
     pub fn new() -> Self {
-        NoteGenerator {}
+        NoteGenerator {
+            scale_lock: None,
+            chord_mode: ChordMode::Off,
+        }
+    }
+
+    /// Locks every played note to the nearest degree of `scale`, so no key
+    /// plays a note outside of it. `None` turns the lock off.
+    pub fn set_scale_lock(&mut self, scale: Option<Scale>) {
+        self.scale_lock = scale;
+    }
+
+    pub fn get_scale_lock(&self) -> Option<Scale> {
+        self.scale_lock
+    }
+
+    pub fn set_chord_mode(&mut self, chord_mode: ChordMode) {
+        self.chord_mode = chord_mode;
+    }
+
+    pub fn get_chord_mode(&self) -> ChordMode {
+        self.chord_mode
+    }
+
+    fn chord_on(&self, root: Note, modifiers: ModifiersState, key: &Key) -> Chord {
+        let seventh = modifiers.alt_key();
+        if self.chord_mode == ChordMode::Diatonic {
+            if let Some(chord) = key
+                .degree_of(root)
+                .and_then(|degree| key.diatonic_chord(degree, seventh))
+            {
+                return chord;
+            }
+        }
+        let quality = match (modifiers.control_key(), seventh) {
+            (false, false) => ChordQuality::Major,
+            (true, false) => ChordQuality::Minor,
+            (false, true) => ChordQuality::Dominant7,
+            (true, true) => ChordQuality::Minor7,
+        };
+        Chord::new(root, quality, Vec::new())
     }
 
     fn map_str_to_note(&self, key: &str) -> Option<Note> {
@@ -28,25 +108,113 @@ impl NoteGenerator {
         }
     }
 
-    fn map_key_code_to_octave(&self, key_pressed: &str) -> Octave {
-        match key_pressed {
-            "1" => Octave::C1,
-            "2" => Octave::C2,
-            "3" => Octave::C3,
-            "4" => Octave::C4,
-            "5" => Octave::C5,
-            "6" => Octave::C6,
-            _ => Octave::C1,
-        }
-    }
-
-    pub fn get_notes_from_keys(&mut self, key_events: Vec<KeyEvent>) -> Vec<Note> {
-        let mut notes_to_return: Vec<Note> = Vec::new();
-        for event in key_events {
+    /// Turns key presses into notes, or into whole chords in chord mode. In
+    /// diatonic chord mode the chords are taken from `key`.
+    pub fn get_notes_from_keys(
+        &mut self,
+        key_events: Vec<TimedKeyEvent>,
+        selected_octave: u8,
+        key: &Key,
+    ) -> Vec<TimedNote> {
+        let mut notes_to_return: Vec<TimedNote> = Vec::new();
+        for TimedKeyEvent {
+            event,
+            timestamp,
+            modifiers,
+        } in key_events
+        {
             if let Some(note) = self.map_str_to_note(event.logical_key.to_text().unwrap()) {
-                notes_to_return.push(note)
+                let mut pitch = Pitch::new(note, selected_octave as i8);
+                if let Some(scale) = self.scale_lock {
+                    pitch = scale.snap(pitch, LOWEST_SAMPLE..=HIGHEST_SAMPLE);
+                }
+                if self.chord_mode == ChordMode::Off {
+                    notes_to_return.push(TimedNote { pitch, timestamp });
+                    continue;
+                }
+                let mut pitches = self.chord_on(pitch.note, modifiers, key).pitches(pitch);
+                // Chords that reach past the samples are played an octave lower.
+                if pitches.iter().any(|pitch| *pitch > HIGHEST_SAMPLE) {
+                    pitches = pitches
+                        .into_iter()
+                        .map(|pitch| pitch - Interval(12))
+                        .collect();
+                }
+                notes_to_return.extend(
+                    pitches
+                        .into_iter()
+                        .map(|pitch| TimedNote { pitch, timestamp }),
+                );
             }
         }
         notes_to_return
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::music_entities::Mode;
+
+    fn chord(mode: ChordMode, root: Note, modifiers: ModifiersState, key: Key) -> Chord {
+        let mut generator = NoteGenerator::new();
+        generator.set_chord_mode(mode);
+        generator.chord_on(root, modifiers, &key)
+    }
+
+    #[test]
+    fn modifier_chords() {
+        let key = Key::from_note(Note::C, Mode::Major);
+        let quality = |modifiers| chord(ChordMode::Modifier, Note::D, modifiers, key).quality;
+        assert_eq!(quality(ModifiersState::empty()), ChordQuality::Major);
+        assert_eq!(quality(ModifiersState::CONTROL), ChordQuality::Minor);
+        assert_eq!(quality(ModifiersState::ALT), ChordQuality::Dominant7);
+        assert_eq!(
+            quality(ModifiersState::CONTROL | ModifiersState::ALT),
+            ChordQuality::Minor7
+        );
+    }
+
+    #[test]
+    fn diatonic_chords_come_from_the_key() {
+        let key = Key::from_note(Note::G, Mode::Major);
+        let diatonic = |root, modifiers| chord(ChordMode::Diatonic, root, modifiers, key);
+        assert_eq!(
+            diatonic(Note::A, ModifiersState::empty()),
+            Chord::new(Note::A, ChordQuality::Minor, Vec::new())
+        );
+        assert_eq!(
+            diatonic(Note::FsharpGflat, ModifiersState::empty()),
+            Chord::new(Note::FsharpGflat, ChordQuality::Diminished, Vec::new())
+        );
+        assert_eq!(
+            diatonic(Note::D, ModifiersState::ALT),
+            Chord::new(Note::D, ChordQuality::Dominant7, Vec::new())
+        );
+        // Ctrl has no say over chords in the key.
+        assert_eq!(
+            diatonic(Note::C, ModifiersState::CONTROL),
+            Chord::new(Note::C, ChordQuality::Major, Vec::new())
+        );
+        // Notes outside of the key fall back to the modifier chords.
+        assert_eq!(
+            diatonic(Note::F, ModifiersState::CONTROL),
+            Chord::new(Note::F, ChordQuality::Minor, Vec::new())
+        );
+    }
+
+    #[test]
+    fn chord_pitches_stack_on_the_played_pitch() {
+        let key = Key::from_note(Note::C, Mode::Major);
+        let chord = chord(ChordMode::Diatonic, Note::B, ModifiersState::ALT, key);
+        assert_eq!(
+            chord.pitches(Pitch::new(Note::B, 3)),
+            [
+                Pitch::new(Note::B, 3),
+                Pitch::new(Note::D, 4),
+                Pitch::new(Note::F, 4),
+                Pitch::new(Note::A, 4),
+            ]
+        );
+    }
+}
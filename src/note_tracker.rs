@@ -0,0 +1,91 @@
+use std::{collections::HashMap, time::Instant};
+
+use crate::{
+    input_handler::InputEvent,
+    music_entities::{Key, Pitch},
+    note_generator::NoteGenerator,
+    recorder::{PerformanceEventKind, DEFAULT_VELOCITY},
+};
+
+/// Turns input events into note events, so everything that follows what is
+/// played, like the recorder and MIDI out, sees the same notes.
+pub struct NoteTracker {
+    /// Pitches started by each held key, so releases end the same notes
+    /// even if the octave or chord mode changed in between.
+    sounding: HashMap<String, Vec<Pitch>>,
+}
+
+impl NoteTracker {
+    pub fn new() -> Self {
+        NoteTracker {
+            sounding: HashMap::new(),
+        }
+    }
+
+    /// Turns keys into pitches the same way `note_generator` does for
+    /// playback. Notes from a MIDI controller are passed on as they are.
+    pub fn track(
+        &mut self,
+        input_events: Vec<InputEvent>,
+        note_generator: &mut NoteGenerator,
+        key: &Key,
+    ) -> Vec<(Instant, PerformanceEventKind)> {
+        let mut note_events = Vec::new();
+        for input_event in input_events {
+            match input_event {
+                InputEvent::NotePressed { key_event, octave } => {
+                    let timestamp = key_event.timestamp;
+                    let key_text = key_text(&key_event.event);
+                    let pitches: Vec<Pitch> = note_generator
+                        .get_notes_from_keys(vec![key_event], octave, key)
+                        .iter()
+                        .map(|timed| timed.pitch)
+                        .collect();
+                    for pitch in pitches.iter() {
+                        note_events.push((
+                            timestamp,
+                            PerformanceEventKind::NoteOn {
+                                pitch: *pitch,
+                                velocity: DEFAULT_VELOCITY,
+                            },
+                        ));
+                    }
+                    self.sounding.insert(key_text, pitches);
+                }
+                InputEvent::NoteReleased(key_event) => {
+                    let Some(pitches) = self.sounding.remove(&key_text(&key_event.event)) else {
+                        continue;
+                    };
+                    for pitch in pitches {
+                        note_events
+                            .push((key_event.timestamp, PerformanceEventKind::NoteOff { pitch }));
+                    }
+                }
+                InputEvent::OctaveChanged { octave, timestamp } => {
+                    note_events.push((timestamp, PerformanceEventKind::OctaveChange(octave)));
+                }
+                InputEvent::Pedal { down, timestamp } => {
+                    note_events.push((timestamp, PerformanceEventKind::Pedal { down }));
+                }
+                InputEvent::MidiNotePressed(midi_note) => note_events.push((
+                    midi_note.timestamp,
+                    PerformanceEventKind::NoteOn {
+                        pitch: midi_note.pitch,
+                        velocity: midi_note.velocity,
+                    },
+                )),
+                InputEvent::MidiNoteReleased { pitch, timestamp } => {
+                    note_events.push((timestamp, PerformanceEventKind::NoteOff { pitch }));
+                }
+                InputEvent::PitchBend { bend, timestamp } => {
+                    note_events.push((timestamp, PerformanceEventKind::PitchBend { bend }));
+                }
+            }
+        }
+        note_events
+    }
+}
+
+fn key_text(event: &winit::event::KeyEvent) -> String {
+    event.logical_key.to_text().unwrap_or_default().to_string()
+}
@@ -0,0 +1,330 @@
+use std::{collections::HashMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    music_entities::{NoteValue, Pitch},
+    recorder::{Performance, PerformanceEventKind},
+};
+
+/// How notes are pulled onto the grid.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Quantize {
+    pub grid: NoteValue,
+    /// Where every second grid line sits between its neighbours, 0.5 being
+    /// straight and 0.75 a heavy shuffle.
+    pub swing: f32,
+    /// How far notes move towards the grid, 1.0 snapping them onto it.
+    pub strength: f32,
+    /// Quantizes note ends too, otherwise notes keep their length.
+    pub ends: bool,
+}
+
+impl Default for Quantize {
+    fn default() -> Self {
+        Quantize {
+            grid: NoteValue::new(16, false),
+            swing: 0.5,
+            strength: 1.0,
+            ends: false,
+        }
+    }
+}
+
+impl Quantize {
+    /// Returns a copy of `performance` with its notes moved towards the grid,
+    /// following the tempo changes in it.
+    pub fn apply(&self, performance: &Performance) -> Performance {
+        let grid = Grid::new(performance, self.grid);
+        let mut events = performance.events.clone();
+        // Offsets and snapped start steps of the notes that have started,
+        // oldest first for each pitch.
+        let mut started: HashMap<Pitch, Vec<(f64, f64)>> = HashMap::new();
+        for event in events.iter_mut() {
+            let seconds = event.time.as_secs_f64();
+            let moved = match event.kind {
+                PerformanceEventKind::NoteOn { pitch, .. } => {
+                    let start = self.snap(grid.to_steps(seconds));
+                    let moved = grid.to_seconds(start);
+                    started
+                        .entry(pitch)
+                        .or_default()
+                        .push((moved - seconds, start));
+                    moved
+                }
+                PerformanceEventKind::NoteOff { pitch } => {
+                    let (offset, start) = started
+                        .get_mut(&pitch)
+                        .filter(|starts| !starts.is_empty())
+                        .map(|starts| starts.remove(0))
+                        .unwrap_or((0.0, f64::MIN));
+                    match self.ends {
+                        // Ends never snap onto or before their start.
+                        true => grid.to_seconds(self.snap(grid.to_steps(seconds)).max(start + 1.0)),
+                        false => seconds + offset,
+                    }
+                }
+                _ => seconds,
+            };
+            // Events the grid can't place, e.g. at a tempo of zero, stay put.
+            if moved.is_finite() {
+                event.time = Duration::from_secs_f64(moved.max(0.0));
+            }
+        }
+        // Stable, so events that end up together keep their order.
+        events.sort_by_key(|event| event.time);
+        Performance {
+            events,
+            ..performance.clone()
+        }
+    }
+
+    /// Moves a position in grid steps towards the nearest swung grid line.
+    fn snap(&self, steps: f64) -> f64 {
+        let pair = (steps / 2.0).floor() * 2.0;
+        let swing = self.swing.clamp(0.5, 0.75) as f64 * 2.0;
+        let nearest = [pair, pair + swing, pair + 2.0]
+            .into_iter()
+            .min_by(|a, b| (a - steps).abs().total_cmp(&(b - steps).abs()))
+            .unwrap_or(steps);
+        steps + (nearest - steps) * self.strength.clamp(0.0, 1.0) as f64
+    }
+}
+
+/// Converts between seconds and grid steps across the tempo changes of a
+/// performance.
+pub struct Grid {
+    /// Seconds and steps where each tempo starts, with the length of a step.
+    segments: Vec<(f64, f64, f64)>,
+}
+
+impl Grid {
+    pub fn new(performance: &Performance, value: NoteValue) -> Self {
+        let step = |bpm: f32| value.duration(bpm).as_secs_f64();
+        let mut segments = vec![(0.0, 0.0, step(performance.bpm))];
+        for event in performance.events.iter() {
+            if let PerformanceEventKind::TempoChange { bpm } = event.kind {
+                let (seconds, steps, length) = segments[segments.len() - 1];
+                let time = event.time.as_secs_f64();
+                segments.push((time, steps + (time - seconds) / length, step(bpm)));
+            }
+        }
+        Grid { segments }
+    }
+
+    pub fn to_steps(&self, seconds: f64) -> f64 {
+        let segment = self
+            .segments
+            .iter()
+            .rev()
+            .find(|(start, _, _)| *start <= seconds)
+            .unwrap_or(&self.segments[0]);
+        segment.1 + (seconds - segment.0) / segment.2
+    }
+
+    fn to_seconds(&self, steps: f64) -> f64 {
+        let segment = self
+            .segments
+            .iter()
+            .rev()
+            .find(|(_, start, _)| *start <= steps)
+            .unwrap_or(&self.segments[0]);
+        segment.0 + (steps - segment.1) * segment.2
+    }
+}
+
+/// Takes as they were before being quantized, so quantizing can be
+/// undone.
+pub struct QuantizeHistory {
+    undo: Vec<Performance>,
+}
+
+impl QuantizeHistory {
+    pub fn new() -> Self {
+        QuantizeHistory { undo: Vec::new() }
+    }
+
+    /// Quantizes `take` in place, remembering how it was.
+    pub fn quantize(&mut self, take: &mut Performance, quantize: &Quantize) {
+        let quantized = quantize.apply(take);
+        self.undo.push(std::mem::replace(take, quantized));
+    }
+
+    /// The take as it was before the last quantize.
+    pub fn undo(&mut self) -> Option<Performance> {
+        self.undo.pop()
+    }
+
+    /// Forgets the undo steps, e.g. once a new take is recorded.
+    pub fn clear(&mut self) {
+        self.undo.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{music_entities::Note, recorder::PerformanceEvent, transport::TimeSignature};
+
+    /// A performance at 120 bpm of notes given as start and end seconds,
+    /// each on its own pitch.
+    fn performance(notes: &[(f64, f64)]) -> Performance {
+        let mut performance = Performance::new(120.0, TimeSignature::new(4, 4));
+        for (index, (start, end)) in notes.iter().enumerate() {
+            let pitch = Pitch::new(Note::C, 4 + index as i8);
+            for (seconds, kind) in [
+                (
+                    start,
+                    PerformanceEventKind::NoteOn {
+                        pitch,
+                        velocity: 100,
+                    },
+                ),
+                (end, PerformanceEventKind::NoteOff { pitch }),
+            ] {
+                performance.events.push(PerformanceEvent {
+                    time: Duration::from_secs_f64(*seconds),
+                    kind,
+                });
+            }
+        }
+        performance.events.sort_by_key(|event| event.time);
+        performance
+    }
+
+    /// Start and end seconds of each note, in the order of their pitches.
+    fn notes(performance: &Performance) -> Vec<(f64, f64)> {
+        let mut notes: Vec<(Pitch, f64, f64)> = Vec::new();
+        for event in performance.events.iter() {
+            let seconds = event.time.as_secs_f64();
+            match event.kind {
+                PerformanceEventKind::NoteOn { pitch, .. } => notes.push((pitch, seconds, 0.0)),
+                PerformanceEventKind::NoteOff { pitch } => {
+                    if let Some(note) = notes.iter_mut().find(|note| note.0 == pitch) {
+                        note.2 = seconds;
+                    }
+                }
+                _ => {}
+            }
+        }
+        notes.sort_by_key(|note| note.0);
+        notes
+            .into_iter()
+            .map(|(_, start, end)| (start, end))
+            .collect()
+    }
+
+    fn assert_close(found: Vec<(f64, f64)>, expected: &[(f64, f64)]) {
+        assert_eq!(found.len(), expected.len());
+        for (found, expected) in found.iter().zip(expected) {
+            assert!(
+                (found.0 - expected.0).abs() < 1e-6 && (found.1 - expected.1).abs() < 1e-6,
+                "{:?} isn't {:?}",
+                found,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn notes_snap_and_keep_their_length() {
+        // A 1/16 step at 120 bpm is 0.125 s.
+        let quantized = Quantize::default().apply(&performance(&[(0.1, 0.3), (0.44, 0.5)]));
+        assert_close(notes(&quantized), &[(0.125, 0.325), (0.5, 0.56)]);
+    }
+
+    #[test]
+    fn quantized_ends_stay_a_step_after_the_start() {
+        let quantize = Quantize {
+            ends: true,
+            ..Quantize::default()
+        };
+        let quantized = quantize.apply(&performance(&[(0.1, 0.3), (0.26, 0.27), (0.6, 1.01)]));
+        assert_close(
+            notes(&quantized),
+            &[(0.125, 0.25), (0.25, 0.375), (0.625, 1.0)],
+        );
+    }
+
+    #[test]
+    fn strength_moves_notes_part_of_the_way() {
+        let quantize = Quantize {
+            strength: 0.5,
+            ..Quantize::default()
+        };
+        let quantized = quantize.apply(&performance(&[(0.1, 0.2)]));
+        assert_close(notes(&quantized), &[(0.1125, 0.2125)]);
+        let untouched = Quantize {
+            strength: 0.0,
+            ..Quantize::default()
+        };
+        let performance = performance(&[(0.1, 0.2)]);
+        assert_eq!(untouched.apply(&performance), performance);
+    }
+
+    #[test]
+    fn swing_delays_every_second_grid_line() {
+        let quantize = Quantize {
+            swing: 0.75,
+            ..Quantize::default()
+        };
+        // Offbeat sixteenths move to three quarters of the way through their
+        // pair, 0.1875 s, while the downbeats stay put.
+        let quantized = quantize.apply(&performance(&[(0.16, 0.2), (0.26, 0.3)]));
+        assert_close(notes(&quantized), &[(0.1875, 0.2275), (0.25, 0.29)]);
+    }
+
+    #[test]
+    fn triplet_grid() {
+        let quantize = Quantize {
+            grid: NoteValue::new(8, true),
+            ends: true,
+            ..Quantize::default()
+        };
+        // An eighth triplet at 120 bpm is a sixth of a second.
+        let quantized = quantize.apply(&performance(&[(0.18, 0.5)]));
+        assert_close(notes(&quantized), &[(1.0 / 6.0, 0.5)]);
+    }
+
+    #[test]
+    fn grid_follows_tempo_changes() {
+        let mut performance = performance(&[(1.1, 1.2)]);
+        performance.events.insert(
+            0,
+            PerformanceEvent {
+                time: Duration::from_secs(1),
+                kind: PerformanceEventKind::TempoChange { bpm: 60.0 },
+            },
+        );
+        // At 60 bpm a step is 0.25 s, counted from the change.
+        let quantized = Quantize::default().apply(&performance);
+        assert_close(notes(&quantized), &[(1.0, 1.1)]);
+    }
+
+    #[test]
+    fn notes_stay_put_at_a_tempo_of_zero() {
+        let mut performance = performance(&[(0.3, 0.6)]);
+        performance.bpm = 0.0;
+        let quantize = Quantize {
+            ends: true,
+            ..Quantize::default()
+        };
+        assert_close(notes(&quantize.apply(&performance)), &[(0.3, 0.6)]);
+    }
+
+    #[test]
+    fn undo_restores_the_take() {
+        let original = performance(&[(0.1, 0.3)]);
+        let mut take = original.clone();
+        let mut history = QuantizeHistory::new();
+        history.quantize(&mut take, &Quantize::default());
+        assert_ne!(take, original);
+        history.quantize(&mut take, &Quantize::default());
+        history.undo();
+        assert_eq!(history.undo(), Some(original));
+        assert_eq!(history.undo(), None);
+        history.quantize(&mut take, &Quantize::default());
+        history.clear();
+        assert_eq!(history.undo(), None);
+    }
+}
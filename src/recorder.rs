@@ -0,0 +1,413 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{music_entities::Pitch, transport::TimeSignature};
+
+/// Computer keyboards can't tell how hard a key was hit, so their notes are
+/// recorded with this velocity.
+pub const DEFAULT_VELOCITY: u8 = 100;
+/// General MIDI program of the piano samples.
+pub const PIANO_PROGRAM: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PerformanceEventKind {
+    NoteOn {
+        pitch: Pitch,
+        velocity: u8,
+    },
+    NoteOff {
+        pitch: Pitch,
+    },
+    OctaveChange(u8),
+    Pedal {
+        down: bool,
+    },
+    /// -8192 to 8191, 0 being the wheel at rest.
+    PitchBend {
+        bend: i16,
+    },
+    InstrumentChange {
+        program: u8,
+    },
+    TempoChange {
+        bpm: f32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PerformanceEvent {
+    /// Time since the recording started.
+    pub time: Duration,
+    pub kind: PerformanceEventKind,
+}
+
+/// A recorded performance with the tempo it was played at.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Performance {
+    pub bpm: f32,
+    pub time_signature: TimeSignature,
+    pub events: Vec<PerformanceEvent>,
+}
+
+impl Performance {
+    pub fn new(bpm: f32, time_signature: TimeSignature) -> Self {
+        Performance {
+            bpm,
+            time_signature,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn note_count(&self) -> usize {
+        self.events
+            .iter()
+            .filter(|event| matches!(event.kind, PerformanceEventKind::NoteOn { .. }))
+            .count()
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.events
+            .last()
+            .map(|event| event.time)
+            .unwrap_or_default()
+    }
+
+    /// Saves the performance as text, one tab separated event per line
+    /// after the tempo and time signature.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        writeln!(file, "bpm\t{}", self.bpm)?;
+        writeln!(file, "time_signature\t{}", self.time_signature)?;
+        for event in self.events.iter() {
+            let seconds = event.time.as_secs_f64();
+            match event.kind {
+                PerformanceEventKind::NoteOn { pitch, velocity } => {
+                    writeln!(file, "{:.6}\tnote_on\t{}\t{}", seconds, pitch, velocity)?
+                }
+                PerformanceEventKind::NoteOff { pitch } => {
+                    writeln!(file, "{:.6}\tnote_off\t{}", seconds, pitch)?
+                }
+                PerformanceEventKind::OctaveChange(octave) => {
+                    writeln!(file, "{:.6}\toctave\t{}", seconds, octave)?
+                }
+                PerformanceEventKind::Pedal { down } => writeln!(
+                    file,
+                    "{:.6}\tpedal\t{}",
+                    seconds,
+                    if down { "down" } else { "up" }
+                )?,
+                PerformanceEventKind::PitchBend { bend } => {
+                    writeln!(file, "{:.6}\tpitch_bend\t{}", seconds, bend)?
+                }
+                PerformanceEventKind::InstrumentChange { program } => {
+                    writeln!(file, "{:.6}\tinstrument\t{}", seconds, program)?
+                }
+                PerformanceEventKind::TempoChange { bpm } => {
+                    writeln!(file, "{:.6}\ttempo\t{}", seconds, bpm)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text =
+            fs::read_to_string(path).map_err(|err| format!("couldn't read {}: {}", path, err))?;
+        let mut performance = Performance::new(120.0, TimeSignature::new(4, 4));
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let invalid = || format!("invalid line in {}: {}", path, line);
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields.as_slice() {
+                ["bpm", bpm] => performance.bpm = parse_bpm(bpm).ok_or_else(invalid)?,
+                ["time_signature", time_signature] => {
+                    performance.time_signature = time_signature.parse()?
+                }
+                [seconds, kind, values @ ..] => {
+                    let seconds = seconds
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|seconds| seconds.is_finite())
+                        .ok_or_else(invalid)?;
+                    let kind = match (*kind, values) {
+                        ("note_on", [pitch, velocity]) => PerformanceEventKind::NoteOn {
+                            pitch: pitch.parse()?,
+                            velocity: velocity.parse().map_err(|_| invalid())?,
+                        },
+                        ("note_off", [pitch]) => PerformanceEventKind::NoteOff {
+                            pitch: pitch.parse()?,
+                        },
+                        ("octave", [octave]) => PerformanceEventKind::OctaveChange(
+                            octave.parse().map_err(|_| invalid())?,
+                        ),
+                        ("pedal", [down]) => PerformanceEventKind::Pedal {
+                            down: *down == "down",
+                        },
+                        ("pitch_bend", [bend]) => PerformanceEventKind::PitchBend {
+                            bend: bend.parse().map_err(|_| invalid())?,
+                        },
+                        ("instrument", [program]) => PerformanceEventKind::InstrumentChange {
+                            program: program.parse().map_err(|_| invalid())?,
+                        },
+                        ("tempo", [bpm]) => PerformanceEventKind::TempoChange {
+                            bpm: parse_bpm(bpm).ok_or_else(invalid)?,
+                        },
+                        _ => return Err(invalid()),
+                    };
+                    performance.events.push(PerformanceEvent {
+                        time: Duration::from_secs_f64(seconds.max(0.0)),
+                        kind,
+                    });
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(performance)
+    }
+}
+
+/// A tempo above zero, which the quantize grid can divide by.
+fn parse_bpm(value: &str) -> Option<f32> {
+    value
+        .parse::<f32>()
+        .ok()
+        .filter(|bpm| bpm.is_finite() && *bpm > 0.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecorderState {
+    Stopped,
+    /// Waiting for the first note or the transport to start recording.
+    Armed,
+    Recording,
+}
+
+/// Records what is played into a `Performance`.
+pub struct Recorder {
+    state: RecorderState,
+    start: Instant,
+    bpm: f32,
+    time_signature: TimeSignature,
+    performance: Option<Performance>,
+    /// Pitches that started while recording and haven't ended yet.
+    sounding: Vec<Pitch>,
+    pedal_down: bool,
+    last_performance: Option<Performance>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Recorder {
+            state: RecorderState::Stopped,
+            start: Instant::now(),
+            bpm: 120.0,
+            time_signature: TimeSignature::new(4, 4),
+            performance: None,
+            sounding: Vec::new(),
+            pedal_down: false,
+            last_performance: None,
+        }
+    }
+
+    pub fn get_state(&self) -> RecorderState {
+        self.state
+    }
+
+    /// Arms the recorder, or disarms it if it already is.
+    pub fn arm(&mut self, bpm: f32, time_signature: TimeSignature) {
+        match self.state {
+            RecorderState::Stopped => {
+                self.bpm = bpm;
+                self.time_signature = time_signature;
+                self.state = RecorderState::Armed;
+            }
+            RecorderState::Armed => self.state = RecorderState::Stopped,
+            RecorderState::Recording => {}
+        }
+    }
+
+    pub fn start(&mut self, now: Instant, bpm: f32, time_signature: TimeSignature) {
+        self.bpm = bpm;
+        self.time_signature = time_signature;
+        self.begin(now);
+    }
+
+    /// Starts recording if the recorder is armed.
+    pub fn start_if_armed(&mut self, now: Instant) {
+        if self.state == RecorderState::Armed {
+            self.begin(now);
+        }
+    }
+
+    fn begin(&mut self, now: Instant) {
+        self.start = now;
+        let mut performance = Performance::new(self.bpm, self.time_signature);
+        performance.events.push(PerformanceEvent {
+            time: Duration::ZERO,
+            kind: PerformanceEventKind::InstrumentChange {
+                program: PIANO_PROGRAM,
+            },
+        });
+        self.performance = Some(performance);
+        self.state = RecorderState::Recording;
+    }
+
+    /// Stops recording, ending notes and the pedal that are still held, and
+    /// returns the finished performance.
+    pub fn stop(&mut self, now: Instant) -> Option<&Performance> {
+        if self.state != RecorderState::Recording {
+            self.state = RecorderState::Stopped;
+            return None;
+        }
+        for pitch in self.sounding.drain(0..) {
+            push_event(
+                &mut self.performance,
+                self.start,
+                now,
+                PerformanceEventKind::NoteOff { pitch },
+            );
+        }
+        if self.pedal_down {
+            push_event(
+                &mut self.performance,
+                self.start,
+                now,
+                PerformanceEventKind::Pedal { down: false },
+            );
+        }
+        self.pedal_down = false;
+        self.state = RecorderState::Stopped;
+        self.last_performance = self.performance.take();
+        self.last_performance.as_ref()
+    }
+
+    /// Records a tempo change, such as from tap tempo, while recording.
+    pub fn record_tempo(&mut self, now: Instant, bpm: f32) {
+        self.push(now, PerformanceEventKind::TempoChange { bpm });
+    }
+
+    /// Records note events from `NoteTracker`. Note offs are only recorded
+    /// for notes that started while recording.
+    pub fn add_note_events(&mut self, note_events: &[(Instant, PerformanceEventKind)]) {
+        for (timestamp, kind) in note_events.iter().copied() {
+            match kind {
+                PerformanceEventKind::NoteOn { pitch, .. } => {
+                    self.start_if_armed(timestamp);
+                    if self.state == RecorderState::Recording {
+                        self.sounding.push(pitch);
+                        self.push(timestamp, kind);
+                    }
+                }
+                PerformanceEventKind::NoteOff { pitch } => {
+                    if let Some(index) = self.sounding.iter().position(|held| *held == pitch) {
+                        self.sounding.remove(index);
+                        self.push(timestamp, kind);
+                    }
+                }
+                PerformanceEventKind::Pedal { down } => {
+                    if self.state == RecorderState::Recording && self.pedal_down != down {
+                        self.pedal_down = down;
+                        self.push(timestamp, kind);
+                    }
+                }
+                _ => self.push(timestamp, kind),
+            }
+        }
+    }
+
+    fn push(&mut self, timestamp: Instant, kind: PerformanceEventKind) {
+        if self.state == RecorderState::Recording {
+            push_event(&mut self.performance, self.start, timestamp, kind);
+        }
+    }
+}
+
+fn push_event(
+    performance: &mut Option<Performance>,
+    start: Instant,
+    timestamp: Instant,
+    kind: PerformanceEventKind,
+) {
+    if let Some(performance) = performance {
+        performance.events.push(PerformanceEvent {
+            time: timestamp.saturating_duration_since(start),
+            kind,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{music_entities::Note, test_support::temp_path};
+
+    fn load_text(name: &str, text: &str) -> Result<Performance, String> {
+        let path = temp_path("recorder", name);
+        fs::write(&path, text).unwrap();
+        let performance = Performance::load(&path);
+        fs::remove_file(&path).unwrap();
+        performance
+    }
+
+    #[test]
+    fn every_event_kind_round_trips() {
+        let pitch = Pitch::new(Note::ASharpBFlat, 3);
+        let kinds = [
+            PerformanceEventKind::NoteOn {
+                pitch,
+                velocity: 64,
+            },
+            PerformanceEventKind::OctaveChange(5),
+            PerformanceEventKind::Pedal { down: true },
+            PerformanceEventKind::PitchBend { bend: -8192 },
+            PerformanceEventKind::InstrumentChange { program: 4 },
+            PerformanceEventKind::TempoChange { bpm: 90.5 },
+            PerformanceEventKind::Pedal { down: false },
+            PerformanceEventKind::NoteOff { pitch },
+        ];
+        let mut performance = Performance::new(140.0, TimeSignature::new(6, 8));
+        for (index, kind) in kinds.into_iter().enumerate() {
+            performance.events.push(PerformanceEvent {
+                time: Duration::from_millis(125 * index as u64),
+                kind,
+            });
+        }
+        let path = temp_path("recorder", "round_trip.txt");
+        performance.save(&path).unwrap();
+        let loaded = Performance::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, Ok(performance));
+    }
+
+    #[test]
+    fn header_defaults_and_blank_lines() {
+        let performance = load_text("defaults.txt", "\n0.5\tnote_on\tBb4\t80\n\n").unwrap();
+        assert_eq!(performance.bpm, 120.0);
+        assert_eq!(performance.time_signature, TimeSignature::new(4, 4));
+        assert_eq!(performance.duration(), Duration::from_millis(500));
+        assert_eq!(performance.note_count(), 1);
+    }
+
+    #[test]
+    fn malformed_lines_are_errors() {
+        for (name, text) in [
+            ("kind.txt", "0.0\tsneeze\n"),
+            ("fields.txt", "0.0\tnote_on\tC4\n"),
+            ("seconds.txt", "soon\tnote_off\tC4\n"),
+            ("infinite.txt", "inf\tnote_off\tC4\n"),
+            ("nan.txt", "NaN\tnote_off\tC4\n"),
+            ("tempo.txt", "0.0\ttempo\t0\n"),
+            ("velocity.txt", "0.0\tnote_on\tC4\tloud\n"),
+            ("pitch.txt", "0.0\tnote_off\tH4\n"),
+            ("bpm.txt", "bpm\tfast\n"),
+            ("negative.txt", "bpm\t-120\n"),
+            ("lone.txt", "bpm\n"),
+        ] {
+            assert!(load_text(name, text).is_err(), "{} loaded", text);
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    midi_player::PlayedNote,
+    music_entities::{Interval, Note, NoteValue, Pitch},
+    recorder::DEFAULT_VELOCITY,
+};
+
+/// Step counts a pattern can have.
+pub const STEP_COUNTS: [usize; 2] = [16, 32];
+const VELOCITY_STEP: u8 = 8;
+const GATE_STEP: f32 = 0.1;
+const PROBABILITY_STEP: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    pub active: bool,
+    pub pitch: Pitch,
+    pub velocity: u8,
+    /// Portion of the step the note sounds for, 0.0 to 1.0.
+    pub gate: f32,
+    /// Chance of the step playing each time it is reached, 0.0 to 1.0.
+    pub probability: f32,
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Step {
+            active: false,
+            pitch: Pitch::new(Note::C, 4),
+            velocity: DEFAULT_VELOCITY,
+            gate: 0.5,
+            probability: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pattern {
+    pub steps: Vec<Step>,
+}
+
+impl Pattern {
+    pub fn new(step_count: usize) -> Self {
+        Pattern {
+            steps: vec![Step::default(); step_count],
+        }
+    }
+}
+
+/// The patterns of the sequencer and the order they are played in.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sequence {
+    pub patterns: Vec<Pattern>,
+    /// Indices into `patterns`, played one after the other and repeated.
+    pub chain: Vec<usize>,
+    pub rate: NoteValue,
+}
+
+impl Default for Sequence {
+    fn default() -> Self {
+        Sequence {
+            patterns: vec![Pattern::new(STEP_COUNTS[0])],
+            chain: vec![0],
+            rate: NoteValue::new(16, false),
+        }
+    }
+}
+
+/// What the up and down keys change on the selected step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepParameter {
+    Pitch,
+    Velocity,
+    Gate,
+    Probability,
+}
+
+impl StepParameter {
+    pub fn next(&self) -> StepParameter {
+        match self {
+            StepParameter::Pitch => StepParameter::Velocity,
+            StepParameter::Velocity => StepParameter::Gate,
+            StepParameter::Gate => StepParameter::Probability,
+            StepParameter::Probability => StepParameter::Pitch,
+        }
+    }
+}
+
+/// Plays the patterns of a `Sequence` in chain order, one step per `rate`
+/// on the beats of the transport's clock, and edits them one step at a time.
+pub struct Sequencer {
+    sequence: Sequence,
+    playing: bool,
+    /// Position in the chain and in its pattern of the next step to play.
+    chain_position: usize,
+    step: usize,
+    /// Steps of `rate` from beat 0 to the one played last.
+    last_step: Option<i64>,
+    /// Pattern and step of the last step played.
+    playing_step: Option<(usize, usize)>,
+    selected_pattern: usize,
+    selected_step: usize,
+    parameter: StepParameter,
+    /// Notes played on the keyboard are written into the selected step.
+    step_edit: bool,
+}
+
+impl Sequencer {
+    pub fn new(sequence: Sequence) -> Self {
+        let mut sequencer = Sequencer {
+            sequence: Sequence::default(),
+            playing: false,
+            chain_position: 0,
+            step: 0,
+            last_step: None,
+            playing_step: None,
+            selected_pattern: 0,
+            selected_step: 0,
+            parameter: StepParameter::Pitch,
+            step_edit: false,
+        };
+        sequencer.set_sequence(sequence);
+        sequencer
+    }
+
+    pub fn get_sequence(&self) -> &Sequence {
+        &self.sequence
+    }
+
+    /// Replaces the patterns, dropping chain entries without a pattern.
+    /// Patterns of other lengths are padded with empty steps to the next
+    /// step count, or cut down to the longest.
+    pub fn set_sequence(&mut self, mut sequence: Sequence) {
+        if sequence.patterns.is_empty() {
+            sequence.patterns.push(Pattern::new(STEP_COUNTS[0]));
+        }
+        for pattern in sequence.patterns.iter_mut() {
+            let step_count = STEP_COUNTS
+                .into_iter()
+                .find(|step_count| pattern.steps.len() <= *step_count)
+                .unwrap_or(STEP_COUNTS[STEP_COUNTS.len() - 1]);
+            pattern.steps.resize(step_count, Step::default());
+        }
+        let pattern_count = sequence.patterns.len();
+        sequence.chain.retain(|index| *index < pattern_count);
+        if sequence.chain.is_empty() {
+            sequence.chain.push(0);
+        }
+        self.sequence = sequence;
+        self.selected_pattern = 0;
+        self.selected_step = 0;
+        self.chain_position = 0;
+        self.step = 0;
+    }
+
+    pub fn set_playing(&mut self, playing: bool) {
+        self.playing = playing;
+        self.chain_position = 0;
+        self.step = 0;
+        self.last_step = None;
+        self.playing_step = None;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// The pattern and step played last.
+    pub fn get_playing_step(&self) -> Option<(usize, usize)> {
+        self.playing_step
+    }
+
+    /// The note due at the clock position `beats`, if any. The first step
+    /// plays straight away and the rest whenever `beats` crosses into the
+    /// next step of `rate`. Steps that are off or lose their roll of the
+    /// dice still take up their time.
+    pub fn poll(&mut self, beats: f64, bpm: f32) -> Option<PlayedNote> {
+        if !self.playing {
+            return None;
+        }
+        let clock_step = (beats / self.sequence.rate.beats()).floor() as i64;
+        if self.last_step == Some(clock_step) {
+            return None;
+        }
+        self.last_step = Some(clock_step);
+        let pattern_index = self.sequence.chain[self.chain_position];
+        let pattern = &self.sequence.patterns[pattern_index];
+        let step = pattern.steps.get(self.step).copied().unwrap_or_default();
+        self.playing_step = Some((pattern_index, self.step));
+        self.step += 1;
+        if self.step >= pattern.steps.len() {
+            self.step = 0;
+            self.chain_position = (self.chain_position + 1) % self.sequence.chain.len();
+        }
+        let plays = step.active && rand::thread_rng().gen::<f32>() < step.probability;
+        plays.then(|| PlayedNote {
+            pitch: step.pitch,
+            velocity: step.velocity,
+            length: self
+                .sequence
+                .rate
+                .duration(bpm)
+                .mul_f32(step.gate.clamp(0.0, 1.0)),
+        })
+    }
+
+    pub fn get_selected_pattern(&self) -> usize {
+        self.selected_pattern
+    }
+
+    /// Selects the next pattern, adding an empty one after the last.
+    pub fn next_pattern(&mut self) -> usize {
+        let step_count = self.selected_steps().len();
+        self.selected_pattern += 1;
+        if self.selected_pattern == self.sequence.patterns.len() {
+            self.sequence.patterns.push(Pattern::new(step_count));
+        }
+        self.clamp_cursor();
+        self.selected_pattern
+    }
+
+    pub fn previous_pattern(&mut self) -> usize {
+        self.selected_pattern = self.selected_pattern.saturating_sub(1);
+        self.clamp_cursor();
+        self.selected_pattern
+    }
+
+    /// Adds the selected pattern to the end of the chain.
+    pub fn chain_pattern(&mut self) {
+        self.sequence.chain.push(self.selected_pattern);
+    }
+
+    /// Starts the chain over with just the selected pattern.
+    pub fn reset_chain(&mut self) {
+        self.sequence.chain = vec![self.selected_pattern];
+        self.chain_position = 0;
+    }
+
+    /// Switches the selected pattern between 16 and 32 steps, keeping the
+    /// steps both lengths have.
+    pub fn toggle_step_count(&mut self) -> usize {
+        let steps = &mut self.sequence.patterns[self.selected_pattern].steps;
+        let step_count = match steps.len() == STEP_COUNTS[0] {
+            true => STEP_COUNTS[1],
+            false => STEP_COUNTS[0],
+        };
+        steps.resize(step_count, Step::default());
+        self.clamp_cursor();
+        step_count
+    }
+
+    pub fn get_selected_step(&self) -> usize {
+        self.selected_step
+    }
+
+    /// Moves the cursor by `offset` steps, wrapping around the pattern.
+    pub fn move_cursor(&mut self, offset: i32) -> usize {
+        let step_count = self.selected_steps().len() as i32;
+        self.selected_step = (self.selected_step as i32 + offset).rem_euclid(step_count) as usize;
+        self.selected_step
+    }
+
+    /// Keeps the cursor inside a shorter pattern.
+    fn clamp_cursor(&mut self) {
+        self.selected_step = self.selected_step.min(self.selected_steps().len() - 1);
+    }
+
+    fn selected_steps(&self) -> &[Step] {
+        &self.sequence.patterns[self.selected_pattern].steps
+    }
+
+    pub fn get_step(&self) -> Step {
+        self.selected_steps()[self.selected_step]
+    }
+
+    fn step_mut(&mut self) -> &mut Step {
+        &mut self.sequence.patterns[self.selected_pattern].steps[self.selected_step]
+    }
+
+    pub fn toggle_step(&mut self) -> bool {
+        let step = self.step_mut();
+        step.active = !step.active;
+        step.active
+    }
+
+    pub fn cycle_parameter(&mut self) -> StepParameter {
+        self.parameter = self.parameter.next();
+        self.parameter
+    }
+
+    /// Raises or lowers the selected parameter of the selected step by one
+    /// increment, a semitone for the pitch.
+    pub fn adjust_step(&mut self, up: bool) -> Step {
+        let parameter = self.parameter;
+        let step = self.step_mut();
+        let sign = if up { 1.0 } else { -1.0 };
+        match parameter {
+            StepParameter::Pitch => step.pitch = step.pitch + Interval(sign as i32),
+            StepParameter::Velocity => {
+                step.velocity = match up {
+                    true => step.velocity.saturating_add(VELOCITY_STEP).min(127),
+                    false => step.velocity.saturating_sub(VELOCITY_STEP).max(1),
+                }
+            }
+            StepParameter::Gate => {
+                step.gate = (step.gate + sign * GATE_STEP).clamp(GATE_STEP, 1.0);
+            }
+            StepParameter::Probability => {
+                step.probability = (step.probability + sign * PROBABILITY_STEP).clamp(0.0, 1.0);
+            }
+        }
+        *step
+    }
+
+    pub fn set_step_edit(&mut self, step_edit: bool) {
+        self.step_edit = step_edit;
+    }
+
+    pub fn is_step_edit(&self) -> bool {
+        self.step_edit
+    }
+
+    /// Writes `pitch` into the selected step, turning it on, and moves to
+    /// the next step.
+    pub fn enter_note(&mut self, pitch: Pitch) {
+        let step = self.step_mut();
+        step.pitch = pitch;
+        step.active = true;
+        self.move_cursor(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence(step_counts: &[usize], chain: Vec<usize>) -> Sequence {
+        Sequence {
+            patterns: step_counts
+                .iter()
+                .map(|count| Pattern::new(*count))
+                .collect(),
+            chain,
+            rate: NoteValue::new(16, false),
+        }
+    }
+
+    #[test]
+    fn patterns_are_fitted_to_a_step_count() {
+        let mut sequencer = Sequencer::new(sequence(&[0, 20, 40, 16], vec![0, 1, 7, 3]));
+        let step_counts: Vec<usize> = sequencer
+            .get_sequence()
+            .patterns
+            .iter()
+            .map(|pattern| pattern.steps.len())
+            .collect();
+        assert_eq!(step_counts, vec![16, 32, 32, 16]);
+        assert_eq!(sequencer.get_sequence().chain, vec![0, 1, 3]);
+        // The cursor moves and wraps in what was an empty pattern.
+        assert_eq!(sequencer.move_cursor(-1), 15);
+        sequencer.previous_pattern();
+        assert!(!sequencer.get_step().active);
+    }
+
+    #[test]
+    fn empty_sequence_gets_a_pattern() {
+        let sequencer = Sequencer::new(sequence(&[], Vec::new()));
+        assert_eq!(sequencer.get_sequence().patterns.len(), 1);
+        assert_eq!(sequencer.get_sequence().chain, vec![0]);
+    }
+
+    #[test]
+    fn steps_play_in_chain_order_on_the_clock() {
+        let mut patterns = sequence(&[16, 16], vec![1, 0]);
+        patterns.patterns[0].steps[0].active = true;
+        patterns.patterns[1].steps[0].active = true;
+        patterns.patterns[1].steps[0].pitch = Pitch::new(Note::E, 4);
+        let mut sequencer = Sequencer::new(patterns);
+        sequencer.set_playing(true);
+        // A 1/16 step is a quarter of a beat, 125 ms at 120 bpm.
+        let first = sequencer.poll(0.1, 120.0).unwrap();
+        assert_eq!(first.pitch, Pitch::new(Note::E, 4));
+        assert_eq!(first.length, std::time::Duration::from_micros(62_500));
+        assert_eq!(sequencer.get_playing_step(), Some((1, 0)));
+        assert!(sequencer.poll(0.2, 120.0).is_none());
+        assert_eq!(sequencer.get_playing_step(), Some((1, 0)));
+        assert!(sequencer.poll(0.25, 120.0).is_none());
+        assert_eq!(sequencer.get_playing_step(), Some((1, 1)));
+        for index in 2..=16 {
+            let note = sequencer.poll(index as f64 * 0.25, 120.0);
+            assert_eq!(note.is_some(), index == 16);
+        }
+        assert_eq!(sequencer.get_playing_step(), Some((0, 0)));
+        // Stopping starts the chain over.
+        sequencer.set_playing(false);
+        assert!(sequencer.poll(4.5, 120.0).is_none());
+        sequencer.set_playing(true);
+        assert!(sequencer.poll(4.5, 120.0).is_some());
+        assert_eq!(sequencer.get_playing_step(), Some((1, 0)));
+    }
+}
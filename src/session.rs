@@ -0,0 +1,347 @@
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    arpeggiator::ArpPattern,
+    chord_feel::ChordFeel,
+    music_entities::{Key, NamingSystem, Note, NoteValue, Scale, ScaleKind},
+    note_generator::ChordMode,
+    quantize::Quantize,
+    recorder::{Performance, PIANO_PROGRAM},
+    sequencer::Sequence,
+    transport::TimeSignature,
+    tuning::Tuning,
+};
+
+/// Bumped whenever the layout of the session changes, together with a new
+/// step in `migrate`.
+pub const SESSION_VERSION: u64 = 2;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArpSettings {
+    pub enabled: bool,
+    pub latch: bool,
+    pub pattern: ArpPattern,
+    pub rate: NoteValue,
+    pub octaves: u8,
+    pub gate: f32,
+}
+
+/// Everything that can be changed while playing. Settings missing from a
+/// session file keep their defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub octave: u8,
+    /// Key notes and chords are spelled in, `None` to follow the detected key.
+    pub key: Option<Key>,
+    pub naming_system: NamingSystem,
+    pub scale: Scale,
+    pub scale_lock: bool,
+    pub highlight_scale: bool,
+    pub chord_mode: ChordMode,
+    pub bpm: f32,
+    pub time_signature: TimeSignature,
+    pub metronome: bool,
+    pub arpeggiator: ArpSettings,
+    pub tuning: Tuning,
+    pub quantize: Quantize,
+    /// Bars the looper records before it starts looping.
+    pub loop_bars: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            octave: 3,
+            key: None,
+            naming_system: NamingSystem::English,
+            scale: Scale::new(Note::C, ScaleKind::Major),
+            scale_lock: false,
+            highlight_scale: false,
+            chord_mode: ChordMode::Off,
+            bpm: 120.0,
+            time_signature: TimeSignature::new(4, 4),
+            metronome: false,
+            arpeggiator: ArpSettings {
+                enabled: false,
+                latch: false,
+                pattern: ArpPattern::Up,
+                rate: NoteValue::new(16, false),
+                octaves: 1,
+                gate: 0.8,
+            },
+            tuning: Tuning::default(),
+            quantize: Quantize::default(),
+            loop_bars: 4,
+        }
+    }
+}
+
+/// Work saved between runs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub version: u64,
+    pub settings: Settings,
+    /// General MIDI program of the selected instrument.
+    pub instrument: u8,
+    /// Strum and humanize are the only effects so far.
+    pub effects: ChordFeel,
+    pub takes: Vec<Performance>,
+    /// Step sequencer patterns.
+    pub sequence: Sequence,
+}
+
+impl Session {
+    pub fn new(
+        settings: Settings,
+        effects: ChordFeel,
+        takes: Vec<Performance>,
+        sequence: Sequence,
+    ) -> Self {
+        Session {
+            version: SESSION_VERSION,
+            settings,
+            instrument: PIANO_PROGRAM,
+            effects,
+            takes,
+            sequence,
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).map_err(|err| err.to_string())?;
+        fs::write(path, json).map_err(|err| format!("couldn't write {}: {}", path, err))
+    }
+
+    /// Loads a session, upgrading it if it was saved by an older version.
+    /// A take saved as text before sessions existed loads as a session
+    /// holding just that take.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text =
+            fs::read_to_string(path).map_err(|err| format!("couldn't read {}: {}", path, err))?;
+        let session: Value = match serde_json::from_str(&text) {
+            Ok(session) => session,
+            Err(err) => {
+                return match Performance::load(path) {
+                    Ok(take) => Ok(Session::new(
+                        Settings::default(),
+                        ChordFeel::default(),
+                        vec![take],
+                        Sequence::default(),
+                    )),
+                    Err(_) => Err(format!("couldn't parse {}: {}", path, err)),
+                }
+            }
+        };
+        let version = session["version"]
+            .as_u64()
+            .ok_or(format!("{} has no session version", path))?;
+        serde_json::from_value(migrate(session, version)?)
+            .map_err(|err| format!("couldn't parse {}: {}", path, err))
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session::new(
+            Settings::default(),
+            ChordFeel::default(),
+            Vec::new(),
+            Sequence::default(),
+        )
+    }
+}
+
+/// Brings a session saved by an older version up to `SESSION_VERSION`,
+/// one version at a time.
+fn migrate(session: Value, version: u64) -> Result<Value, String> {
+    match version {
+        SESSION_VERSION => Ok(session),
+        1 => migrate(version_1_to_2(session)?, 2),
+        version => Err(format!(
+            "unsupported session version {}, this build reads up to version {}",
+            version, SESSION_VERSION
+        )),
+    }
+}
+
+/// Version 2 added the quantize and loop settings and the step sequencer,
+/// which older sessions get the defaults of.
+fn version_1_to_2(mut session: Value) -> Result<Value, String> {
+    let defaults = Settings::default();
+    let quantize = serde_json::to_value(defaults.quantize).map_err(|err| err.to_string())?;
+    let sequence = serde_json::to_value(Sequence::default()).map_err(|err| err.to_string())?;
+    if let Some(settings) = session["settings"].as_object_mut() {
+        settings.entry("quantize").or_insert(quantize);
+        settings
+            .entry("loop_bars")
+            .or_insert(json!(defaults.loop_bars));
+    }
+    session["sequence"] = sequence;
+    session["version"] = json!(2);
+    Ok(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        music_entities::Mode,
+        recorder::{PerformanceEvent, PerformanceEventKind},
+        test_support::temp_path,
+        tuning::KeyboardMapping,
+    };
+
+    #[test]
+    fn session_round_trips() {
+        let path = temp_path("session", "round_trip.json");
+        let settings = Settings {
+            bpm: 96.0,
+            key: Some(Key::from_note(Note::D, Mode::Minor)),
+            loop_bars: 2,
+            ..Settings::default()
+        };
+        let mut take = Performance::new(96.0, TimeSignature::new(3, 4));
+        take.events.push(PerformanceEvent {
+            time: Duration::from_millis(250),
+            kind: PerformanceEventKind::Pedal { down: true },
+        });
+        let session = Session::new(
+            settings,
+            ChordFeel::default(),
+            vec![take],
+            Sequence::default(),
+        );
+        session.save(&path).unwrap();
+        let loaded = Session::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, Ok(session));
+    }
+
+    #[test]
+    fn invalid_values_are_errors() {
+        let take = Performance::new(120.0, TimeSignature::new(4, 4));
+        let session = Session::new(
+            Settings::default(),
+            ChordFeel::default(),
+            vec![take],
+            Sequence::default(),
+        );
+        let session = serde_json::to_value(session).unwrap();
+        let path = temp_path("session", "invalid.json");
+        for (pointer, value) in [
+            ("/settings/time_signature/beats_per_bar", json!(0)),
+            ("/settings/time_signature/beat_value", json!(0)),
+            ("/settings/time_signature/beat_value", json!(3)),
+            ("/takes/0/time_signature/beats_per_bar", json!(0)),
+            ("/settings/arpeggiator/rate/division", json!(0)),
+            ("/settings/quantize/grid/division", json!(0)),
+            ("/sequence/rate/division", json!(0)),
+            ("/sequence/rate/division", json!(12)),
+            ("/settings/tuning/reference", json!(0.0)),
+            ("/settings/tuning/reference", json!(-440.0)),
+        ] {
+            let mut invalid = session.clone();
+            *invalid.pointer_mut(pointer).unwrap() = value.clone();
+            fs::write(&path, invalid.to_string()).unwrap();
+            assert!(
+                Session::load(&path).is_err(),
+                "{} = {} loaded",
+                pointer,
+                value
+            );
+        }
+        let mut invalid = session.clone();
+        invalid["settings"]["tuning"]["keyboard_mapping"] =
+            serde_json::to_value(KeyboardMapping::linear(0.0)).unwrap();
+        fs::write(&path, invalid.to_string()).unwrap();
+        assert!(Session::load(&path).is_err());
+        fs::write(&path, session.to_string()).unwrap();
+        assert!(Session::load(&path).is_ok());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_settings_keep_their_defaults() {
+        let path = temp_path("session", "missing.json");
+        let mut session = serde_json::to_value(Session::default()).unwrap();
+        session["settings"] = json!({ "bpm": 80.0 });
+        fs::write(&path, session.to_string()).unwrap();
+        let loaded = Session::load(&path);
+        fs::remove_file(&path).unwrap();
+        let expected = Settings {
+            bpm: 80.0,
+            ..Settings::default()
+        };
+        assert_eq!(loaded.map(|session| session.settings), Ok(expected));
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let path = temp_path("session", "newer.json");
+        let mut session = serde_json::to_value(Session::default()).unwrap();
+        session["version"] = json!(SESSION_VERSION + 1);
+        fs::write(&path, session.to_string()).unwrap();
+        let loaded = Session::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.unwrap_err().contains("unsupported session version"));
+    }
+
+    #[test]
+    fn take_file_loads_as_a_session() {
+        let path = temp_path("session", "take.txt");
+        fs::write(
+            &path,
+            "bpm\t100\ntime_signature\t4/4\n0.000000\tnote_on\tC4\t90\n0.500000\tnote_off\tC4\n",
+        )
+        .unwrap();
+        let loaded = Session::load(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.settings, Settings::default());
+        assert_eq!(loaded.takes.len(), 1);
+        assert_eq!(loaded.takes[0].bpm, 100.0);
+        assert_eq!(loaded.takes[0].note_count(), 1);
+    }
+
+    #[test]
+    fn unreadable_files_are_errors() {
+        let path = temp_path("session", "garbage.json");
+        fs::write(&path, "{ not json").unwrap();
+        let loaded = Session::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.unwrap_err().starts_with("couldn't parse"));
+        assert!(Session::load(&temp_path("session", "missing_file.json")).is_err());
+        let path = temp_path("session", "no_version.json");
+        fs::write(&path, "{}").unwrap();
+        let loaded = Session::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.unwrap_err().contains("no session version"));
+    }
+
+    #[test]
+    fn version_1_sessions_get_the_new_defaults() {
+        let path = temp_path("session", "version_1.json");
+        let mut session = serde_json::to_value(Session::default()).unwrap();
+        session["version"] = json!(1);
+        session["settings"]["bpm"] = json!(90.0);
+        for field in ["quantize", "loop_bars"] {
+            session["settings"].as_object_mut().unwrap().remove(field);
+        }
+        session.as_object_mut().unwrap().remove("sequence");
+        fs::write(&path, session.to_string()).unwrap();
+        let loaded = Session::load(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.version, SESSION_VERSION);
+        assert_eq!(loaded.settings.bpm, 90.0);
+        assert_eq!(loaded.settings.loop_bars, 4);
+        assert_eq!(loaded.settings.quantize, Quantize::default());
+        assert_eq!(loaded.sequence, Sequence::default());
+    }
+}
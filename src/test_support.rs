@@ -0,0 +1,48 @@
+//! Helpers shared by the unit tests.
+
+use std::time::Duration;
+
+use crate::{
+    music_entities::Pitch,
+    recorder::{Performance, PerformanceEvent, PerformanceEventKind},
+    transport::TimeSignature,
+};
+
+/// A file in the temp directory, unique to the test process and `module`.
+pub fn temp_path(module: &str, name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!(
+            "piano_man_{}_{}_{}",
+            module,
+            std::process::id(),
+            name
+        ))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// A 4/4 take at 120 bpm, so a quarter note lasts half a second, of notes
+/// given as start and end in seconds and a pitch name.
+pub fn performance(notes: &[(f64, f64, &str)]) -> Performance {
+    let mut performance = Performance::new(120.0, TimeSignature::new(4, 4));
+    for (start, end, pitch) in notes {
+        let pitch: Pitch = pitch.parse().unwrap();
+        for (seconds, kind) in [
+            (
+                start,
+                PerformanceEventKind::NoteOn {
+                    pitch,
+                    velocity: 100,
+                },
+            ),
+            (end, PerformanceEventKind::NoteOff { pitch }),
+        ] {
+            performance.events.push(PerformanceEvent {
+                time: Duration::from_secs_f64(*seconds),
+                kind,
+            });
+        }
+    }
+    performance.events.sort_by_key(|event| event.time);
+    performance
+}
@@ -0,0 +1,508 @@
+use std::{
+    collections::VecDeque,
+    f32::consts::TAU,
+    fmt,
+    str::FromStr,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+const CLICK_LENGTH: Duration = Duration::from_millis(30);
+const CLICK_FREQUENCY: f32 = 1000.0;
+const ACCENT_FREQUENCY: f32 = 1500.0;
+const CLICK_VOLUME: f32 = 0.4;
+// Taps further apart than this start a new tempo.
+const TAP_TIMEOUT: Duration = Duration::from_secs(2);
+const TAP_HISTORY: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "TimeSignatureFields")]
+pub struct TimeSignature {
+    pub beats_per_bar: u8,
+    pub beat_value: u8,
+}
+
+impl TimeSignature {
+    pub fn new(beats_per_bar: u8, beat_value: u8) -> Self {
+        TimeSignature {
+            beats_per_bar,
+            beat_value,
+        }
+    }
+
+    /// A signature with at least one beat, of a power of two.
+    pub fn checked(beats_per_bar: u8, beat_value: u8) -> Result<Self, String> {
+        if beats_per_bar == 0 || !beat_value.is_power_of_two() {
+            return Err(format!(
+                "invalid time signature: {}/{}",
+                beats_per_bar, beat_value
+            ));
+        }
+        Ok(TimeSignature::new(beats_per_bar, beat_value))
+    }
+}
+
+/// A `TimeSignature` as saved, checked before it is used.
+#[derive(Deserialize)]
+struct TimeSignatureFields {
+    beats_per_bar: u8,
+    beat_value: u8,
+}
+
+impl TryFrom<TimeSignatureFields> for TimeSignature {
+    type Error = String;
+
+    fn try_from(fields: TimeSignatureFields) -> Result<Self, Self::Error> {
+        TimeSignature::checked(fields.beats_per_bar, fields.beat_value)
+    }
+}
+
+impl fmt::Display for TimeSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.beats_per_bar, self.beat_value)
+    }
+}
+
+impl FromStr for TimeSignature {
+    type Err = String;
+
+    /// Parses time signatures written as "3/4" or "6/8".
+    fn from_str(signature: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid time signature: {}", signature);
+        let (beats_per_bar, beat_value) = signature.split_once('/').ok_or_else(invalid)?;
+        let beats_per_bar: u8 = beats_per_bar.parse().map_err(|_| invalid())?;
+        let beat_value: u8 = beat_value.parse().map_err(|_| invalid())?;
+        TimeSignature::checked(beats_per_bar, beat_value).map_err(|_| invalid())
+    }
+}
+
+/// A beat counted from the start of the transport. Bars and beats start at
+/// zero and bars are negative during the count-in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BeatPosition {
+    pub bar: i64,
+    pub beat: u8,
+    /// Audio frames since bar 0, beat 0.
+    pub frame: i64,
+}
+
+impl BeatPosition {
+    pub fn is_count_in(&self) -> bool {
+        self.bar < 0
+    }
+}
+
+impl fmt::Display for BeatPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.is_count_in() {
+            true => write!(f, "count-in {}", self.beat + 1),
+            false => write!(f, "{}.{}", self.bar + 1, self.beat + 1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransportEvent {
+    Started { bpm: f32 },
+    Beat(BeatPosition),
+    Stopped,
+}
+
+struct Click {
+    frequency: f32,
+    frame: usize,
+}
+
+/// State shared with the audio callback driving the clock.
+struct ClockState {
+    bpm: f32,
+    time_signature: TimeSignature,
+    /// Signature to count in from the next bar line.
+    pending_time_signature: Option<TimeSignature>,
+    sample_rate: u32,
+    running: bool,
+    metronome: bool,
+    /// Frames since bar 0, negative during the count-in.
+    frame: i64,
+    next_beat_frame: f64,
+    beat_number: i64,
+    /// Beats counted since the stream started, or since bar 0 once the
+    /// transport is started. It keeps counting while stopped.
+    beats: f64,
+    /// Beat number and bar of the bar line the time signature has been
+    /// counted from.
+    bar_origin: (i64, i64),
+    click: Option<Click>,
+    subscribers: Vec<Sender<TransportEvent>>,
+}
+
+impl ClockState {
+    fn frames_per_beat(&self) -> f64 {
+        self.sample_rate as f64 * 60.0 / self.bpm as f64
+    }
+
+    fn publish(&mut self, event: TransportEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event).is_ok());
+    }
+
+    /// Advances the clock by one frame and returns the metronome sample.
+    fn next_frame(&mut self) -> f32 {
+        self.beats += 1.0 / self.frames_per_beat();
+        if self.running {
+            if self.frame as f64 >= self.next_beat_frame {
+                let (origin_beat, origin_bar) = self.bar_origin;
+                let beats = self.beat_number - origin_beat;
+                let beats_per_bar = self.time_signature.beats_per_bar as i64;
+                if beats.rem_euclid(beats_per_bar) == 0 {
+                    if let Some(time_signature) = self.pending_time_signature.take() {
+                        let bar = origin_bar + beats.div_euclid(beats_per_bar);
+                        self.bar_origin = (self.beat_number, bar);
+                        self.time_signature = time_signature;
+                    }
+                }
+                let (origin_beat, origin_bar) = self.bar_origin;
+                let beats = self.beat_number - origin_beat;
+                let beats_per_bar = self.time_signature.beats_per_bar as i64;
+                let position = BeatPosition {
+                    bar: origin_bar + beats.div_euclid(beats_per_bar),
+                    beat: beats.rem_euclid(beats_per_bar) as u8,
+                    frame: self.frame,
+                };
+                if self.metronome || position.is_count_in() {
+                    self.click = Some(Click {
+                        frequency: match position.beat {
+                            0 => ACCENT_FREQUENCY,
+                            _ => CLICK_FREQUENCY,
+                        },
+                        frame: 0,
+                    });
+                }
+                self.publish(TransportEvent::Beat(position));
+                self.beat_number += 1;
+                self.next_beat_frame += self.frames_per_beat();
+            }
+            self.frame += 1;
+        }
+        self.next_click_sample()
+    }
+
+    fn next_click_sample(&mut self) -> f32 {
+        let Some(click) = self.click.as_mut() else {
+            return 0.0;
+        };
+        let length = (CLICK_LENGTH.as_secs_f32() * self.sample_rate as f32) as usize;
+        if click.frame >= length {
+            self.click = None;
+            return 0.0;
+        }
+        let time = click.frame as f32 / self.sample_rate as f32;
+        let envelope = 1.0 - click.frame as f32 / length as f32;
+        click.frame += 1;
+        (TAU * click.frequency * time).sin() * envelope * envelope * CLICK_VOLUME
+    }
+}
+
+/// The tempo clock. It is driven by the output stream through a [`Clock`],
+/// so positions are counted in audio frames rather than read from the system
+/// clock, and the metronome plays on that same stream.
+pub struct Transport {
+    state: Arc<Mutex<ClockState>>,
+}
+
+impl Transport {
+    pub fn new(bpm: f32, time_signature: TimeSignature) -> Self {
+        let state = Arc::new(Mutex::new(ClockState {
+            bpm: 120.0,
+            time_signature,
+            pending_time_signature: None,
+            sample_rate: 44_100,
+            running: false,
+            metronome: false,
+            frame: 0,
+            next_beat_frame: 0.0,
+            beat_number: 0,
+            beats: 0.0,
+            bar_origin: (0, 0),
+            click: None,
+            subscribers: Vec::new(),
+        }));
+        let transport = Transport { state };
+        transport.set_bpm(bpm);
+        transport
+    }
+
+    /// The handle an output stream advances the transport with.
+    pub fn clock(&self) -> Clock {
+        Clock {
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    /// Starts from bar 0, after a bar of clicks if `count_in` is set.
+    pub fn start(&self, count_in: bool) {
+        if let Ok(mut state) = self.state.lock() {
+            if let Some(time_signature) = state.pending_time_signature.take() {
+                state.time_signature = time_signature;
+            }
+            let beats_per_bar = state.time_signature.beats_per_bar as i64;
+            state.beat_number = if count_in { -beats_per_bar } else { 0 };
+            state.bar_origin = (state.beat_number, if count_in { -1 } else { 0 });
+            state.frame = (state.beat_number as f64 * state.frames_per_beat()) as i64;
+            state.next_beat_frame = state.frame as f64;
+            state.beats = state.beat_number as f64;
+            state.running = true;
+            let bpm = state.bpm;
+            state.publish(TransportEvent::Started { bpm });
+        }
+    }
+
+    pub fn stop(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.running = false;
+            state.publish(TransportEvent::Stopped);
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state
+            .lock()
+            .map(|state| state.running)
+            .unwrap_or(false)
+    }
+
+    pub fn set_bpm(&self, bpm: f32) {
+        if let Ok(mut state) = self.state.lock() {
+            if bpm.is_finite() {
+                state.bpm = bpm.clamp(20.0, 400.0);
+            }
+        }
+    }
+
+    pub fn bpm(&self) -> f32 {
+        self.state.lock().map(|state| state.bpm).unwrap_or(120.0)
+    }
+
+    /// The clock's position in beats, counted by the output stream whether
+    /// or not the transport is running. Starting the transport sets it to 0
+    /// at bar 0, so anything stepping on it lines up with the bars.
+    pub fn beats(&self) -> f64 {
+        self.state.lock().map(|state| state.beats).unwrap_or(0.0)
+    }
+
+    /// Changes the time signature from the next bar line counted, or
+    /// straight away while stopped.
+    pub fn set_time_signature(&self, time_signature: TimeSignature) {
+        if let Ok(mut state) = self.state.lock() {
+            match state.running {
+                true => state.pending_time_signature = Some(time_signature),
+                false => state.time_signature = time_signature,
+            }
+        }
+    }
+
+    /// The time signature set last, even if it isn't counted until the next
+    /// bar line.
+    pub fn time_signature(&self) -> TimeSignature {
+        self.state
+            .lock()
+            .map(|state| state.pending_time_signature.unwrap_or(state.time_signature))
+            .unwrap_or(TimeSignature::new(4, 4))
+    }
+
+    pub fn set_metronome(&self, metronome: bool) {
+        if let Ok(mut state) = self.state.lock() {
+            state.metronome = metronome;
+        }
+    }
+
+    pub fn metronome(&self) -> bool {
+        self.state
+            .lock()
+            .map(|state| state.metronome)
+            .unwrap_or(false)
+    }
+
+    /// Receives an event on start, stop and every beat.
+    pub fn subscribe(&self) -> Receiver<TransportEvent> {
+        let (sender, receiver) = mpsc::channel();
+        if let Ok(mut state) = self.state.lock() {
+            state.subscribers.push(sender);
+        }
+        receiver
+    }
+}
+
+/// Advances a [`Transport`] from an output stream's audio callback.
+pub struct Clock {
+    state: Arc<Mutex<ClockState>>,
+}
+
+impl Clock {
+    pub fn set_sample_rate(&self, sample_rate: u32) {
+        if let Ok(mut state) = self.state.lock() {
+            state.sample_rate = sample_rate;
+        }
+    }
+
+    /// Advances the clock by every frame of interleaved `data` and adds the
+    /// metronome to it.
+    pub fn process(&self, data: &mut [f32], channels: usize) {
+        if let Ok(mut state) = self.state.lock() {
+            for frame in data.chunks_mut(channels.max(1)) {
+                let sample = state.next_frame();
+                for channel in frame.iter_mut() {
+                    *channel += sample;
+                }
+            }
+        }
+    }
+}
+
+/// Works out a tempo from the average time between the last few taps.
+pub struct TapTempo {
+    taps: VecDeque<Instant>,
+}
+
+impl TapTempo {
+    pub fn new() -> Self {
+        TapTempo {
+            taps: VecDeque::with_capacity(TAP_HISTORY),
+        }
+    }
+
+    /// Registers a tap and returns the tempo once there are two taps in a row.
+    pub fn tap(&mut self, now: Instant) -> Option<f32> {
+        if let Some(last) = self.taps.back() {
+            if now.saturating_duration_since(*last) > TAP_TIMEOUT {
+                self.taps.clear();
+            }
+        }
+        if self.taps.len() == TAP_HISTORY {
+            self.taps.pop_front();
+        }
+        self.taps.push_back(now);
+        let first = self.taps.front()?;
+        let last = self.taps.back()?;
+        let intervals = self.taps.len() as u32 - 1;
+        if intervals == 0 {
+            return None;
+        }
+        let beat = last.saturating_duration_since(*first) / intervals;
+        Some(60.0 / beat.as_secs_f32())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_counts_beats_at_its_sample_rate() {
+        let transport = Transport::new(120.0, TimeSignature::new(3, 4));
+        let events = transport.subscribe();
+        let clock = transport.clock();
+        clock.set_sample_rate(48_000);
+        transport.start(false);
+        // One and a half seconds of stereo.
+        let mut data = vec![0.0; 72_000 * 2];
+        clock.process(&mut data, 2);
+        let beats: Vec<BeatPosition> = events
+            .try_iter()
+            .filter_map(|event| match event {
+                TransportEvent::Beat(position) => Some(position),
+                _ => None,
+            })
+            .collect();
+        let frames: Vec<i64> = beats.iter().map(|position| position.frame).collect();
+        assert_eq!(frames, vec![0, 24_000, 48_000]);
+        assert_eq!((beats[2].bar, beats[2].beat), (0, 2));
+        // The metronome is off, so nothing is added to the output.
+        assert!(data.iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn beats_count_while_stopped_and_restart_at_bar_0() {
+        let transport = Transport::new(120.0, TimeSignature::new(4, 4));
+        let clock = transport.clock();
+        clock.set_sample_rate(48_000);
+        // Half a second of mono is a beat at 120 bpm.
+        clock.process(&mut vec![0.0; 24_000], 1);
+        assert!((transport.beats() - 1.0).abs() < 1e-6);
+        transport.start(true);
+        assert_eq!(transport.beats(), -4.0);
+        clock.process(&mut vec![0.0; 24_000 * 5], 1);
+        assert!((transport.beats() - 1.0).abs() < 1e-6);
+        transport.set_bpm(60.0);
+        clock.process(&mut vec![0.0; 24_000], 1);
+        assert!((transport.beats() - 1.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn time_signatures_change_at_the_next_bar_line() {
+        let transport = Transport::new(60.0, TimeSignature::new(4, 4));
+        let events = transport.subscribe();
+        let clock = transport.clock();
+        // A beat every 100 frames.
+        clock.set_sample_rate(100);
+        transport.start(false);
+        let beats = |count: usize| -> Vec<(i64, u8)> {
+            let mut data = vec![0.0; 100 * count];
+            clock.process(&mut data, 1);
+            events
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Beat(position) => Some((position.bar, position.beat)),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(beats(6).last(), Some(&(1, 1)));
+        transport.set_time_signature(TimeSignature::new(3, 4));
+        assert_eq!(transport.time_signature(), TimeSignature::new(3, 4));
+        assert_eq!(
+            beats(7),
+            [(1, 2), (1, 3), (2, 0), (2, 1), (2, 2), (3, 0), (3, 1)]
+        );
+        // Changes during the count-in wait for bar 0.
+        transport.start(true);
+        beats(1);
+        transport.set_time_signature(TimeSignature::new(2, 4));
+        assert_eq!(beats(6), [(-1, 1), (-1, 2), (0, 0), (0, 1), (1, 0), (1, 1)]);
+    }
+
+    #[test]
+    fn count_in_clicks_on_every_channel() {
+        let transport = Transport::new(120.0, TimeSignature::new(4, 4));
+        let events = transport.subscribe();
+        let clock = transport.clock();
+        clock.set_sample_rate(48_000);
+        transport.start(true);
+        let mut data = vec![0.0; 1_000 * 2];
+        clock.process(&mut data, 2);
+        match events.try_iter().nth(1) {
+            Some(TransportEvent::Beat(position)) => {
+                assert!(position.is_count_in());
+                assert_eq!(position.frame, -96_000);
+            }
+            event => panic!("expected a count-in beat, got {:?}", event),
+        }
+        assert!(data.iter().any(|sample| *sample != 0.0));
+        for frame in data.chunks(2) {
+            assert_eq!(frame[0], frame[1]);
+        }
+    }
+
+    #[test]
+    fn time_signatures_parse() {
+        assert_eq!("6/8".parse(), Ok(TimeSignature::new(6, 8)));
+        assert!("0/4".parse::<TimeSignature>().is_err());
+        assert!("3/5".parse::<TimeSignature>().is_err());
+        assert!("three/4".parse::<TimeSignature>().is_err());
+    }
+}
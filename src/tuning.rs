@@ -0,0 +1,571 @@
+use std::{fs, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::music_entities::{Note, Pitch};
+
+const A4: Pitch = Pitch {
+    note: Note::A,
+    octave: 4,
+};
+
+// Werckmeister III in cents above C.
+const WERCKMEISTER_III: [f64; 12] = [
+    0.0, 90.225, 192.18, 294.135, 390.225, 498.045, 588.27, 696.09, 792.18, 888.27, 996.09, 1092.18,
+];
+// 5-limit just intonation, starting on the tonic.
+const JUST_RATIOS: [(u32, u32); 12] = [
+    (1, 1),
+    (16, 15),
+    (9, 8),
+    (6, 5),
+    (5, 4),
+    (4, 3),
+    (45, 32),
+    (3, 2),
+    (8, 5),
+    (5, 3),
+    (9, 5),
+    (15, 8),
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Temperament {
+    Equal,
+    Pythagorean,
+    /// Quarter-comma meantone.
+    Meantone,
+    WerckmeisterIII,
+    JustIntonation,
+    Scala(ScalaScale),
+}
+
+impl Temperament {
+    /// Cents above the tonic for each of the twelve semitones above it.
+    fn cents(&self) -> [f64; 12] {
+        let mut cents = [0.0; 12];
+        match self {
+            Temperament::Equal | Temperament::Scala(_) => {
+                for (semitone, cent) in cents.iter_mut().enumerate() {
+                    *cent = semitone as f64 * 100.0;
+                }
+            }
+            // Chains of fifths, Db to F# for Pythagorean and Eb to G# for meantone.
+            Temperament::Pythagorean => fill_from_fifths(&mut cents, 1200.0 * 1.5f64.log2(), -5),
+            Temperament::Meantone => fill_from_fifths(&mut cents, 1200.0 * 5f64.log2() / 4.0, -3),
+            Temperament::WerckmeisterIII => cents = WERCKMEISTER_III,
+            Temperament::JustIntonation => {
+                for (cent, (numerator, denominator)) in cents.iter_mut().zip(JUST_RATIOS) {
+                    *cent = ratio_to_cents(numerator as f64 / denominator as f64);
+                }
+            }
+        }
+        cents
+    }
+}
+
+fn fill_from_fifths(cents: &mut [f64; 12], fifth: f64, lowest: i32) {
+    for fifths in lowest..lowest + 12 {
+        let semitone = (fifths * 7).rem_euclid(12) as usize;
+        cents[semitone] = (fifths as f64 * fifth).rem_euclid(1200.0);
+    }
+}
+
+fn ratio_to_cents(ratio: f64) -> f64 {
+    1200.0 * ratio.log2()
+}
+
+impl FromStr for Temperament {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().as_str() {
+            "equal" | "12-tet" => Ok(Temperament::Equal),
+            "pythagorean" => Ok(Temperament::Pythagorean),
+            "meantone" => Ok(Temperament::Meantone),
+            "werckmeister" | "werckmeister-iii" => Ok(Temperament::WerckmeisterIII),
+            "just" | "just-intonation" => Ok(Temperament::JustIntonation),
+            _ => Err(format!("unknown temperament: {}", name)),
+        }
+    }
+}
+
+/// A scale read from a Scala `.scl` file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScalaScale {
+    pub description: String,
+    /// Cents above the first degree for every degree after it, the last one
+    /// being the period, usually an octave.
+    pub degrees: Vec<f64>,
+}
+
+impl ScalaScale {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        fs::read_to_string(path)
+            .map_err(|err| format!("couldn't read {}: {}", path, err))?
+            .parse()
+    }
+
+    /// Cents above degree 0 of any degree, counting whole periods for
+    /// degrees outside of the scale.
+    fn degree_cents(&self, degree: i32) -> f64 {
+        let size = self.degrees.len() as i32;
+        let period = self.degrees[self.degrees.len() - 1];
+        let within = degree.rem_euclid(size);
+        let periods = degree.div_euclid(size) as f64;
+        let cents = match within {
+            0 => 0.0,
+            within => self.degrees[within as usize - 1],
+        };
+        periods * period + cents
+    }
+}
+
+impl FromStr for ScalaScale {
+    type Err = String;
+
+    fn from_str(scl: &str) -> Result<Self, Self::Err> {
+        let mut lines = scala_lines(scl);
+        let description = lines.next().unwrap_or_default().to_string();
+        let count: usize = lines
+            .next()
+            .and_then(|line| line.split_whitespace().next())
+            .and_then(|count| count.parse().ok())
+            .ok_or("missing note count in .scl")?;
+        let degrees = lines
+            .take(count)
+            .map(parse_scala_pitch)
+            .collect::<Result<Vec<f64>, String>>()?;
+        if degrees.len() != count || count == 0 {
+            return Err(format!(
+                "expected {} pitches in .scl, found {}",
+                count,
+                degrees.len()
+            ));
+        }
+        Ok(ScalaScale {
+            description,
+            degrees,
+        })
+    }
+}
+
+/// Lines of a Scala file without its comments. The description line may be
+/// empty, so only comment lines are skipped.
+fn scala_lines(file: &str) -> impl Iterator<Item = &str> {
+    file.lines()
+        .filter(|line| !line.starts_with('!'))
+        .map(|line| line.trim())
+}
+
+/// Pitches with a period are in cents, anything else is a ratio like "3/2" or "2".
+fn parse_scala_pitch(line: &str) -> Result<f64, String> {
+    let value = line.split_whitespace().next().unwrap_or_default();
+    let invalid = || format!("invalid pitch in .scl: {}", line);
+    if value.contains('.') {
+        return value.parse().map_err(|_| invalid());
+    }
+    let (numerator, denominator) = value.split_once('/').unwrap_or((value, "1"));
+    let numerator: f64 = numerator.parse().map_err(|_| invalid())?;
+    let denominator: f64 = denominator.parse().map_err(|_| invalid())?;
+    if numerator <= 0.0 || denominator <= 0.0 {
+        return Err(invalid());
+    }
+    Ok(ratio_to_cents(numerator / denominator))
+}
+
+/// A Scala `.kbm` keyboard mapping, which places a scale on MIDI notes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyboardMapping {
+    /// MIDI note where scale degree 0 sits.
+    pub middle_note: i32,
+    pub reference_note: i32,
+    pub reference_frequency: f64,
+    /// Scale degree that each repetition of the mapping moves by.
+    pub octave_degree: i32,
+    /// Scale degree of each key in one repetition, `None` for unmapped keys.
+    /// Empty maps every key to the next degree.
+    pub map: Vec<Option<i32>>,
+}
+
+impl KeyboardMapping {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        fs::read_to_string(path)
+            .map_err(|err| format!("couldn't read {}: {}", path, err))?
+            .parse()
+    }
+
+    /// The standard mapping: degree 0 on middle C and A4 at `reference`.
+    pub fn linear(reference: f64) -> Self {
+        KeyboardMapping {
+            middle_note: 60,
+            reference_note: 69,
+            reference_frequency: reference,
+            octave_degree: 0,
+            map: Vec::new(),
+        }
+    }
+
+    fn degree(&self, midi_note: i32, scale_size: i32) -> Option<i32> {
+        let offset = midi_note - self.middle_note;
+        if self.map.is_empty() {
+            return Some(offset);
+        }
+        let size = self.map.len() as i32;
+        let octave_degree = match self.octave_degree {
+            0 => scale_size,
+            octave_degree => octave_degree,
+        };
+        let mapped = self.map[offset.rem_euclid(size) as usize]?;
+        Some(offset.div_euclid(size) * octave_degree + mapped)
+    }
+}
+
+impl FromStr for KeyboardMapping {
+    type Err = String;
+
+    fn from_str(kbm: &str) -> Result<Self, Self::Err> {
+        let mut values = scala_lines(kbm)
+            .filter(|line| !line.is_empty())
+            .map(|line| line.split_whitespace().next().unwrap_or_default());
+        let mut next_number = |name: &str| -> Result<f64, String> {
+            values
+                .next()
+                .and_then(|value| value.parse().ok())
+                .ok_or(format!("missing {} in .kbm", name))
+        };
+        let size = next_number("map size")? as usize;
+        next_number("first note")?;
+        next_number("last note")?;
+        let middle_note = next_number("middle note")? as i32;
+        let reference_note = next_number("reference note")? as i32;
+        let reference_frequency = next_number("reference frequency")?;
+        if !is_valid_reference(reference_frequency) {
+            return Err(format!(
+                "invalid reference frequency in .kbm: {}",
+                reference_frequency
+            ));
+        }
+        let octave_degree = next_number("octave degree")? as i32;
+        let map = values
+            .take(size)
+            .map(|value| match value {
+                "x" | "X" => Ok(None),
+                value => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| format!("invalid key in .kbm: {}", value)),
+            })
+            .collect::<Result<Vec<Option<i32>>, String>>()?;
+        Ok(KeyboardMapping {
+            middle_note,
+            reference_note,
+            reference_frequency,
+            octave_degree,
+            map,
+        })
+    }
+}
+
+/// Decides the frequency of every pitch. A4 is held at `reference` and the
+/// temperament is laid out from `tonic`, unless a Scala keyboard mapping
+/// says otherwise.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "TuningFields")]
+pub struct Tuning {
+    pub reference: f32,
+    pub temperament: Temperament,
+    pub tonic: Note,
+    pub keyboard_mapping: Option<KeyboardMapping>,
+}
+
+/// A `Tuning` as saved, checked before it is used.
+#[derive(Deserialize)]
+struct TuningFields {
+    reference: f32,
+    temperament: Temperament,
+    tonic: Note,
+    keyboard_mapping: Option<KeyboardMapping>,
+}
+
+impl TryFrom<TuningFields> for Tuning {
+    type Error = String;
+
+    fn try_from(fields: TuningFields) -> Result<Self, Self::Error> {
+        let tuning = Tuning::new(fields.reference, fields.temperament, fields.tonic)?;
+        match fields.keyboard_mapping {
+            Some(mapping) if !is_valid_reference(mapping.reference_frequency) => Err(format!(
+                "invalid reference frequency in keyboard mapping: {}",
+                mapping.reference_frequency
+            )),
+            Some(mapping) => Ok(tuning.with_keyboard_mapping(mapping)),
+            None => Ok(tuning),
+        }
+    }
+}
+
+impl Tuning {
+    pub fn new(reference: f32, temperament: Temperament, tonic: Note) -> Result<Self, String> {
+        if !is_valid_reference(reference as f64) {
+            return Err(format!("invalid reference frequency: {}", reference));
+        }
+        Ok(Tuning {
+            reference,
+            temperament,
+            tonic,
+            keyboard_mapping: None,
+        })
+    }
+
+    pub fn with_keyboard_mapping(mut self, keyboard_mapping: KeyboardMapping) -> Self {
+        self.keyboard_mapping = Some(keyboard_mapping);
+        self
+    }
+
+    /// The frequency of `pitch` in Hz, `None` for keys a Scala keyboard
+    /// mapping leaves unmapped.
+    pub fn frequency(&self, pitch: Pitch) -> Option<f32> {
+        if let Temperament::Scala(scale) = &self.temperament {
+            let mapping = self
+                .keyboard_mapping
+                .clone()
+                .unwrap_or(KeyboardMapping::linear(self.reference as f64));
+            let size = scale.degrees.len() as i32;
+            let degree = mapping.degree(pitch.index(), size)?;
+            let reference_degree = mapping.degree(mapping.reference_note, size)?;
+            let cents = scale.degree_cents(degree) - scale.degree_cents(reference_degree);
+            return Some((mapping.reference_frequency * 2f64.powf(cents / 1200.0)) as f32);
+        }
+        let cents = self.cents_above_tonic(pitch) - self.cents_above_tonic(A4);
+        Some((self.reference as f64 * 2f64.powf(cents / 1200.0)) as f32)
+    }
+
+    fn cents_above_tonic(&self, pitch: Pitch) -> f64 {
+        let semitones = pitch.index() - self.tonic.semitone() as i32;
+        let octaves = semitones.div_euclid(12) as f64;
+        octaves * 1200.0 + self.temperament.cents()[semitones.rem_euclid(12) as usize]
+    }
+
+    /// How much faster the sample of `sample_pitch`, recorded in 12-TET, has
+    /// to play to sound `pitch` at its tuned frequency.
+    pub fn playback_rate(&self, pitch: Pitch, sample_pitch: Pitch) -> Option<f32> {
+        Some(self.frequency(pitch)? / sample_pitch.frequency())
+    }
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Tuning {
+            reference: 440.0,
+            temperament: Temperament::Equal,
+            tonic: Note::C,
+            keyboard_mapping: None,
+        }
+    }
+}
+
+/// Whether `frequency` in Hz can be tuned to, which it can't unless it is
+/// above zero.
+pub fn is_valid_reference(frequency: f64) -> bool {
+    frequency.is_finite() && frequency > 0.0
+}
+
+/// Resamples interleaved audio with linear interpolation so it plays `rate`
+/// times faster, which raises the pitch by the same ratio. Rates that aren't
+/// above zero give no audio.
+pub fn repitch(frames: &[f32], channels: usize, rate: f32) -> Vec<f32> {
+    if channels == 0 || !(rate.is_finite() && rate > 0.0) {
+        return Vec::new();
+    }
+    if (rate - 1.0).abs() < 1e-6 {
+        return frames.to_vec();
+    }
+    let input_length = frames.len() / channels;
+    let output_length = (input_length as f32 / rate) as usize;
+    let mut output = Vec::with_capacity(output_length * channels);
+    for frame in 0..output_length {
+        let position = frame as f32 * rate;
+        let index = position as usize;
+        let fraction = position - index as f32;
+        let next = (index + 1).min(input_length - 1);
+        for channel in 0..channels {
+            let current = frames[index * channels + channel];
+            let following = frames[next * channels + channel];
+            output.push(current + (following - current) * fraction);
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(found: f64, expected: f64) {
+        assert!(
+            (found - expected).abs() < 0.01,
+            "{} isn't {}",
+            found,
+            expected
+        );
+    }
+
+    #[test]
+    fn scala_pitches_are_cents_or_ratios() {
+        let scale: ScalaScale =
+            "! test.scl\n!\nA test scale\n 4 notes\n!\n100.0\n 3/2 fifth\n7/4\n2\n"
+                .parse()
+                .unwrap();
+        assert_eq!(scale.description, "A test scale");
+        assert_eq!(scale.degrees.len(), 4);
+        assert_close(scale.degrees[0], 100.0);
+        assert_close(scale.degrees[1], 701.955);
+        assert_close(scale.degrees[2], 968.826);
+        assert_close(scale.degrees[3], 1200.0);
+        // The description line may be empty.
+        assert!("\n1\n2/1\n".parse::<ScalaScale>().is_ok());
+    }
+
+    #[test]
+    fn scala_files_with_bad_counts_or_pitches_are_errors() {
+        for scl in [
+            "too few\n3\n100.0\n200.0\n",
+            "none\n0\n",
+            "no count\n",
+            "not a count\nmany\n100.0\n",
+            "not a pitch\n1\nfifth\n",
+            "zero ratio\n1\n0/1\n",
+            "negative ratio\n1\n-3/2\n",
+        ] {
+            assert!(scl.parse::<ScalaScale>().is_err(), "{:?} parsed", scl);
+        }
+    }
+
+    #[test]
+    fn keyboard_mappings_read_every_field() {
+        let kbm = "! test.kbm\n5\n0\n127\n60\n69\n432.0\n3\n! map\n0\nx\n1\n2\nx\n";
+        let mapping: KeyboardMapping = kbm.parse().unwrap();
+        assert_eq!(
+            mapping,
+            KeyboardMapping {
+                middle_note: 60,
+                reference_note: 69,
+                reference_frequency: 432.0,
+                octave_degree: 3,
+                map: vec![Some(0), None, Some(1), Some(2), None],
+            }
+        );
+        assert_eq!(mapping.degree(60, 7), Some(0));
+        assert_eq!(mapping.degree(61, 7), None);
+        assert_eq!(mapping.degree(63, 7), Some(2));
+        // Each repetition of the map moves by the octave degree.
+        assert_eq!(mapping.degree(65, 7), Some(3));
+        assert_eq!(mapping.degree(57, 7), Some(-2));
+    }
+
+    #[test]
+    fn bad_keyboard_mappings_are_errors() {
+        for kbm in [
+            "0\n0\n127\n60\n69\n",
+            "0\n0\n127\n60\n69\n0.0\n0\n",
+            "0\n0\n127\n60\n69\n-440\n0\n",
+            "0\n0\n127\n60\n69\ninf\n0\n",
+            "1\n0\n127\n60\n69\n440\n0\ny\n",
+        ] {
+            assert!(kbm.parse::<KeyboardMapping>().is_err(), "{:?} parsed", kbm);
+        }
+    }
+
+    #[test]
+    fn temperaments_start_on_the_tonic() {
+        let fifth = 701.955;
+        let major_third = 386.314;
+        let equal = Temperament::Equal.cents();
+        assert_eq!(equal[7], 700.0);
+        assert_close(Temperament::Pythagorean.cents()[7], fifth);
+        assert_close(Temperament::Pythagorean.cents()[4], 407.82);
+        assert_close(Temperament::Meantone.cents()[4], major_third);
+        assert_close(Temperament::Meantone.cents()[7], 696.578);
+        assert_eq!(Temperament::WerckmeisterIII.cents(), WERCKMEISTER_III);
+        assert_close(Temperament::JustIntonation.cents()[7], fifth);
+        assert_close(Temperament::JustIntonation.cents()[4], major_third);
+        for temperament in [
+            Temperament::Pythagorean,
+            Temperament::Meantone,
+            Temperament::WerckmeisterIII,
+            Temperament::JustIntonation,
+        ] {
+            let cents = temperament.cents();
+            assert_eq!(cents[0], 0.0);
+            assert!(cents.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+    }
+
+    #[test]
+    fn temperaments_keep_a4_at_the_reference() {
+        let tuning = Tuning::new(415.0, Temperament::Meantone, Note::DsharpEflat).unwrap();
+        let a4 = tuning.frequency(Pitch::new(Note::A, 4)).unwrap();
+        assert!((a4 - 415.0).abs() < 1e-3);
+        let a5 = tuning.frequency(Pitch::new(Note::A, 5)).unwrap();
+        assert!((a5 - 830.0).abs() < 1e-3);
+        let c4 = Tuning::default().frequency(Pitch::new(Note::C, 4)).unwrap();
+        assert!((c4 - 261.626).abs() < 1e-3);
+    }
+
+    #[test]
+    fn scala_degrees_wrap_around_their_period() {
+        // Five equal steps to a 1200 cent period.
+        let scale: ScalaScale = "pentatonic\n5\n240.0\n480.0\n720.0\n960.0\n1200.0\n"
+            .parse()
+            .unwrap();
+        assert_eq!(scale.degree_cents(-1), -240.0);
+        assert_eq!(scale.degree_cents(7), 1680.0);
+        let tuning = Tuning::new(440.0, Temperament::Scala(scale), Note::C).unwrap();
+        let frequency = |note, octave| tuning.frequency(Pitch::new(note, octave)).unwrap() as f64;
+        // With the linear mapping A4, MIDI note 69, is degree 9 and C4 is
+        // degree 0, 2160 cents lower.
+        assert_close(frequency(Note::A, 4), 440.0);
+        assert_close(frequency(Note::C, 4), 440.0 * 2f64.powf(-2160.0 / 1200.0));
+        assert_close(frequency(Note::D, 4), 440.0 * 2f64.powf(-1680.0 / 1200.0));
+        assert_close(frequency(Note::G, 3), 440.0 * 2f64.powf(-3360.0 / 1200.0));
+    }
+
+    #[test]
+    fn unmapped_keys_have_no_frequency() {
+        let scale: ScalaScale = "three\n3\n400.0\n800.0\n2/1\n".parse().unwrap();
+        let mapping: KeyboardMapping = "2\n0\n127\n60\n60\n300\n0\n0\nx\n".parse().unwrap();
+        let tuning = Tuning::new(440.0, Temperament::Scala(scale), Note::C)
+            .unwrap()
+            .with_keyboard_mapping(mapping);
+        assert_eq!(tuning.frequency(Pitch::new(Note::C, 4)), Some(300.0));
+        assert_eq!(tuning.frequency(Pitch::new(Note::CsharpDflat, 4)), None);
+        // An octave degree of 0 moves by the whole scale.
+        let d4 = tuning.frequency(Pitch::new(Note::D, 4)).unwrap();
+        assert!((d4 - 600.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn references_must_be_above_zero() {
+        for reference in [0.0, -440.0, f32::NAN, f32::INFINITY] {
+            assert!(Tuning::new(reference, Temperament::Equal, Note::C).is_err());
+        }
+        assert!(Tuning::new(432.0, Temperament::Equal, Note::C).is_ok());
+    }
+
+    #[test]
+    fn repitch_changes_the_length() {
+        let frames: Vec<f32> = (0..200).map(|sample| sample as f32).collect();
+        assert_eq!(repitch(&frames, 2, 1.0), frames);
+        assert_eq!(repitch(&frames, 2, 2.0).len(), 100);
+        assert_eq!(repitch(&frames, 2, 0.5).len(), 400);
+        assert_eq!(repitch(&frames, 1, 4.0).len(), 50);
+        // Channels stay interleaved.
+        let faster = repitch(&frames, 2, 2.0);
+        assert_eq!(&faster[..4], &[0.0, 1.0, 4.0, 5.0]);
+        assert!(repitch(&[], 2, 1.5).is_empty());
+        for rate in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            assert!(repitch(&frames, 2, rate).is_empty(), "rate {}", rate);
+        }
+        assert!(repitch(&frames, 0, 2.0).is_empty());
+    }
+}